@@ -0,0 +1,282 @@
+use crate::denoise::DenoiseBuffers;
+use crate::film::Film;
+use crate::hit::HitRecord;
+use crate::vec3::Vec3;
+use exr::prelude::*;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// What a camera path saw at its first non-specular vertex. The light terms
+// add up to the radiance of the sample: emission of that vertex (or of the
+// environment if the path escaped), light arriving after one more bounce
+// split into the diffuse and specular lobes, and everything else.
+#[derive(Clone, Default)]
+pub struct AovSample {
+    pub normal: Vec3,
+    pub albedo: Vec3,
+    // distance along the path from the camera, 0 where nothing was hit
+    pub depth: f64,
+    pub position: Vec3,
+    pub uv: (f64, f64),
+    // object ID from Tagged and material address, 0 for none
+    pub object: u32,
+    pub material: usize,
+    pub diffuse: Vec3,
+    pub specular: Vec3,
+    pub emission: Vec3,
+    pub indirect: Vec3,
+    // set once the vertex is recorded
+    pub done: bool,
+    // emission seen by the vertex right after the recorded one
+    pub next_emission: Vec3,
+}
+impl AovSample {
+    pub fn record(&mut self, rec: &HitRecord, albedo: Vec3, emitted: Vec3) {
+        self.normal = rec.nor;
+        self.albedo = albedo;
+        self.position = rec.p;
+        self.uv = (rec.u, rec.v);
+        self.object = rec.object_id;
+        self.material = Arc::as_ptr(&rec.mat_ptr) as *const () as usize;
+        self.emission = emitted;
+        self.done = true;
+    }
+    // Attenuation of the specular bounces and the camera in front of it.
+    pub fn scale_light(&mut self, w: Vec3) {
+        self.albedo = self.albedo.change(w);
+        self.diffuse = self.diffuse.change(w);
+        self.specular = self.specular.change(w);
+        self.emission = self.emission.change(w);
+        self.indirect = self.indirect.change(w);
+    }
+    // For samples the film cut down, the light terms must still add up.
+    pub fn scale_lobes(&mut self, k: f64) {
+        self.diffuse *= k;
+        self.specular *= k;
+        self.emission *= k;
+        self.indirect *= k;
+    }
+}
+
+#[derive(Clone, Default)]
+struct AovPixel {
+    normal: Vec3,
+    albedo: Vec3,
+    depth: f64,
+    position: Vec3,
+    uv: Vec3,
+    diffuse: Vec3,
+    specular: Vec3,
+    emission: Vec3,
+    indirect: Vec3,
+    // sum of squared sample radiance
+    square: Vec3,
+    object: u32,
+    material: u32,
+    count: f64,
+}
+
+fn avg(v: Vec3, p: &AovPixel) -> Vec3 {
+    v / p.count.max(1.0)
+}
+
+// Variance of the pixel mean, from the spread of the samples. The light
+// terms of a sample add up to its radiance.
+fn variance(p: &AovPixel) -> Vec3 {
+    if p.count < 2.0 {
+        return Vec3::zero();
+    }
+    let sum = p.emission + p.diffuse + p.specular + p.indirect;
+    let spread = p.square - sum.change(sum) / p.count;
+    let v = spread / ((p.count - 1.0) * p.count);
+    Vec3::new(v.x.max(0.0), v.y.max(0.0), v.z.max(0.0))
+}
+
+// Per pixel averages of AovSample, box filtered so every sample lands in
+// the pixel it was taken in. IDs do not average, a pixel keeps the ones of
+// its first sample. Material IDs count up in the order materials are first
+// seen.
+pub struct AovFilm {
+    pub width: u32,
+    pub height: u32,
+    pixels: Vec<AovPixel>,
+    materials: HashMap<usize, u32>,
+}
+#[allow(dead_code)]
+impl AovFilm {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![AovPixel::default(); (width * height) as usize],
+            materials: HashMap::new(),
+        }
+    }
+    pub fn add_sample(&mut self, px: f64, py: f64, s: &AovSample) {
+        let x = (px.max(0.0) as u32).min(self.width - 1);
+        let y = (py.max(0.0) as u32).min(self.height - 1);
+        let material = if s.material == 0 {
+            0
+        } else {
+            let next = self.materials.len() as u32 + 1;
+            *self.materials.entry(s.material).or_insert(next)
+        };
+        let p = &mut self.pixels[(y * self.width + x) as usize];
+        if p.count == 0.0 {
+            p.object = s.object;
+            p.material = material;
+        }
+        p.normal += s.normal;
+        p.albedo += s.albedo;
+        p.depth += s.depth;
+        p.position += s.position;
+        p.uv += Vec3::new(s.uv.0, s.uv.1, 0.0);
+        p.diffuse += s.diffuse;
+        p.specular += s.specular;
+        p.emission += s.emission;
+        p.indirect += s.indirect;
+        let col = s.emission + s.diffuse + s.specular + s.indirect;
+        p.square += col.change(col);
+        p.count += 1.0;
+    }
+    // Layer name, channel names and a pixel to channel values map, in the
+    // order they are written.
+    #[allow(clippy::type_complexity)]
+    fn layers() -> Vec<(&'static str, &'static [&'static str], fn(&AovPixel) -> Vec3)> {
+        vec![
+            ("normal", &["X", "Y", "Z"], |p| avg(p.normal, p)),
+            ("albedo", &["R", "G", "B"], |p| avg(p.albedo, p)),
+            ("depth", &["Z"], |p| {
+                Vec3::ones() * (p.depth / p.count.max(1.0))
+            }),
+            ("position", &["X", "Y", "Z"], |p| avg(p.position, p)),
+            ("uv", &["U", "V"], |p| avg(p.uv, p)),
+            ("object_id", &["ID"], |p| Vec3::ones() * p.object as f64),
+            ("material_id", &["ID"], |p| Vec3::ones() * p.material as f64),
+            ("diffuse", &["R", "G", "B"], |p| avg(p.diffuse, p)),
+            ("specular", &["R", "G", "B"], |p| avg(p.specular, p)),
+            ("emission", &["R", "G", "B"], |p| avg(p.emission, p)),
+            ("indirect", &["R", "G", "B"], |p| avg(p.indirect, p)),
+            ("variance", &["R", "G", "B"], variance),
+        ]
+    }
+    // One channel of values, top row first like the saved images.
+    fn channel(&self, f: &dyn Fn(u32, u32) -> f64) -> Vec<f32> {
+        let mut data = Vec::with_capacity((self.width * self.height) as usize);
+        for y in (0..self.height).rev() {
+            for x in 0..self.width {
+                data.push(f(x, y) as f32);
+            }
+        }
+        data
+    }
+    fn layer_channels(&self, only: Option<&str>) -> Vec<(String, Vec<f32>)> {
+        let mut res = Vec::new();
+        for (layer, names, f) in Self::layers() {
+            if only.is_some() && only != Option::Some(layer) {
+                continue;
+            }
+            for (c, name) in names.iter().enumerate() {
+                let data = self
+                    .channel(&|x, y| f(&self.pixels[(y * self.width + x) as usize]).get(c as i32));
+                res.push((format!("{}.{}", layer, name), data));
+            }
+        }
+        res
+    }
+    // One EXR with the beauty image as R, G, B and every AOV as
+    // layer.channel, which compositors read as layers.
+    pub fn save_exr(&self, film: &Film, filename: &str) {
+        let mut channels = Vec::new();
+        for (c, name) in ["R", "G", "B"].iter().enumerate() {
+            let data = self.channel(&|x, y| film.pixel(x, y).get(c as i32));
+            channels.push((name.to_string(), data));
+        }
+        channels.extend(self.layer_channels(Option::None));
+        write_exr(filename, self.width, self.height, channels);
+    }
+    // Every AOV in a file of its own, `prefix`.normal.exr and so on.
+    pub fn save_layers(&self, prefix: &str) {
+        for (layer, _, _) in Self::layers() {
+            let channels = self
+                .layer_channels(Option::Some(layer))
+                .into_iter()
+                .map(|(name, data)| (name[layer.len() + 1..].to_string(), data))
+                .collect();
+            let filename = format!("{}.{}.exr", prefix, layer);
+            write_exr(&filename, self.width, self.height, channels);
+        }
+    }
+    // The beauty image together with what the denoiser needs.
+    pub fn buffers(&self, film: &Film) -> DenoiseBuffers {
+        let mut b = DenoiseBuffers::new(self.width, self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let k = (y * self.width + x) as usize;
+                let p = &self.pixels[k];
+                b.color[k] = film.pixel(x, y);
+                b.variance[k] = variance(p);
+                b.albedo[k] = avg(p.albedo, p);
+                b.normal[k] = avg(p.normal, p);
+            }
+        }
+        b
+    }
+}
+
+pub fn write_exr(filename: &str, width: u32, height: u32, channels: Vec<(String, Vec<f32>)>) {
+    let list: Vec<AnyChannel<FlatSamples>> = channels
+        .into_iter()
+        .map(|(name, data)| AnyChannel::new(name.as_str(), FlatSamples::F32(data)))
+        .collect();
+    let layer = Layer::new(
+        (width as usize, height as usize),
+        LayerAttributes::default(),
+        Encoding::FAST_LOSSLESS,
+        AnyChannels::sort(list.into()),
+    );
+    Image::from_layer(layer)
+        .write()
+        .to_file(filename)
+        .unwrap_or_else(|e| panic!("Cannot write {}: {}", filename, e));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::BoxFilter;
+
+    #[test]
+    fn test_layers_average_and_ids() {
+        let mut aovs = AovFilm::new(2, 1);
+        let mut s = AovSample {
+            normal: Vec3::new(0.0, 1.0, 0.0),
+            depth: 2.0,
+            object: 3,
+            material: 0xbeef,
+            ..AovSample::default()
+        };
+        aovs.add_sample(1.2, 0.5, &s);
+        s.depth = 4.0;
+        s.object = 5;
+        aovs.add_sample(1.7, 0.1, &s);
+        aovs.add_sample(0.5, 0.5, &AovSample::default());
+        let film = Film::new(2, 1, Arc::new(BoxFilter::new(0.5)));
+        let path = std::env::temp_dir().join("raytracer_test_aovs.exr");
+        let filename = path.to_str().unwrap();
+        aovs.save_exr(&film, filename);
+        let image = read_all_flat_layers_from_file(filename).unwrap();
+        let channels = &image.layer_data[0].channel_data.list;
+        let get = |name: &str| -> Vec<f32> {
+            let c = channels.iter().find(|c| c.name.eq(name)).unwrap();
+            c.sample_data.values_as_f32().collect()
+        };
+        assert_eq!(channels.len(), 3 + 29);
+        assert_eq!(get("depth.Z"), vec![0.0, 3.0]);
+        assert_eq!(get("normal.Y"), vec![0.0, 1.0]);
+        // the first sample's object, the first material seen is 1
+        assert_eq!(get("object_id.ID"), vec![0.0, 3.0]);
+        assert_eq!(get("material_id.ID"), vec![0.0, 1.0]);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,10 +1,92 @@
+use crate::distribution::Distribution2D;
+use crate::hit::Hittable;
 use crate::random::*;
 use crate::ray::Ray;
 use crate::vec3::*;
+use std::sync::Arc;
 pub const PI: f64 = std::f64::consts::PI;
 pub fn degrees_to_radians(degrees: f64) -> f64 {
     degrees * PI / 180.0
 }
+
+// x and y are in [0, 1] over the image, y going up.
+pub trait CameraModel: Send + Sync {
+    // A ray and the per channel weight the radiance along it gets.
+    fn generate_ray(&self, x: f64, y: f64) -> (Ray, Vec3);
+    // Just the ray, for callers that only need where it goes. Rendering
+    // goes through generate_ray so no weight is lost.
+    #[allow(dead_code)]
+    fn get_ray(&self, x: f64, y: f64) -> Ray {
+        self.generate_ray(x, y).0
+    }
+    // false for image points no ray goes through, like the corners of a
+    // circular fisheye
+    fn covers(&self, _x: f64, _y: f64) -> bool {
+        true
+    }
+}
+
+// Camera basis, w points backward from lookat to lookfrom.
+fn look_basis(lookfrom: Vec3, lookat: Vec3, vup: Vec3) -> (Vec3, Vec3, Vec3) {
+    let w = (lookfrom - lookat).unit();
+    let u = Vec3::cross(vup, w).unit();
+    let v = Vec3::cross(w, u);
+    (u, v, w)
+}
+
+// Shape of the lens opening. Samples are returned in [-1, 1]^2, the disk and
+// the polygons fit inside the unit circle, an image fills the whole square.
+#[allow(dead_code)]
+pub enum Aperture {
+    Disk,
+    // regular polygon with `blades` sides, rotated by `rotation` degrees
+    Polygon { blades: u32, rotation: f64 },
+    // grayscale mask over [-1, 1]^2, white is open
+    Image(Distribution2D),
+}
+#[allow(dead_code)]
+impl Aperture {
+    pub fn from_image(filename: &str) -> Self {
+        let img = image::open(filename)
+            .unwrap_or_else(|_| panic!("Cannot open aperture {}", filename))
+            .to_luma();
+        let (width, height) = img.dimensions();
+        let func: Vec<Vec<f64>> = (0..height)
+            .map(|j| {
+                (0..width)
+                    .map(|i| img.get_pixel(i, j)[0] as f64 / 255.0)
+                    .collect()
+            })
+            .collect();
+        Aperture::Image(Distribution2D::new(func))
+    }
+    pub fn sample(&self) -> Vec3 {
+        match self {
+            Aperture::Disk => random_in_unit_disk(),
+            Aperture::Polygon { blades, rotation } => {
+                // uniform over one of the triangles between the center and a side
+                let n = (*blades).max(3);
+                let k = random_int(0, n as i32 - 1) as f64;
+                let step = 2.0 * PI / n as f64;
+                let a0 = degrees_to_radians(*rotation) + k * step;
+                let p0 = Vec3::new(a0.cos(), a0.sin(), 0.0);
+                let p1 = Vec3::new((a0 + step).cos(), (a0 + step).sin(), 0.0);
+                let mut s = get_rand01();
+                let mut t = get_rand01();
+                if s + t > 1.0 {
+                    s = 1.0 - s;
+                    t = 1.0 - t;
+                }
+                p0 * s + p1 * t
+            }
+            Aperture::Image(dist) => {
+                let (u, v, _pdf) = dist.sample_continuous(get_rand01(), get_rand01());
+                Vec3::new(u * 2.0 - 1.0, 1.0 - v * 2.0, 0.0)
+            }
+        }
+    }
+}
+
 pub struct Camera {
     pub origin: Vec3,
     pub horizontal: Vec3,
@@ -14,9 +96,17 @@ pub struct Camera {
     pub v: Vec3,
     pub w: Vec3,
     pub lens_radius: f64,
+    pub focus_dist: f64,
     pub time0: f64,
     pub time1: f64,
+    pub aperture: Aperture,
+    // how far the lens barrel clips the aperture toward the image edges,
+    // 0 is off
+    pub cats_eye: f64,
+    // relative focus distance change of each channel, 0 is off
+    pub focus_shift: Vec3,
 }
+#[allow(dead_code)]
 #[allow(clippy::too_many_arguments)]
 impl Camera {
     pub fn new(
@@ -35,9 +125,7 @@ impl Camera {
         let viewport_height = 2.0 * h;
         let viewport_width = viewport_height * aspect_ratio;
 
-        let w = (lookfrom - lookat).unit();
-        let u = Vec3::cross(vup, w).unit();
-        let v = Vec3::cross(w, u);
+        let (u, v, w) = look_basis(lookfrom, lookat, vup);
 
         let origin = lookfrom;
         let horizontal = u * (focus_dist * viewport_width);
@@ -60,17 +148,643 @@ impl Camera {
             v,
             w,
             lens_radius,
+            focus_dist,
             time0,
             time1,
+            aperture: Aperture::Disk,
+            cats_eye: 0.0,
+            focus_shift: Vec3::zero(),
+        }
+    }
+    pub fn set_focus_dist(&mut self, focus_dist: f64) {
+        let scale = focus_dist / self.focus_dist;
+        self.horizontal *= scale;
+        self.vertical *= scale;
+        self.lower_left_corner =
+            self.origin - (self.horizontal / 2.0) - (self.vertical / 2.0) - self.w * focus_dist;
+        self.focus_dist = focus_dist;
+    }
+    // Focuses on whatever the center of pixel (x, y) of a width by height
+    // image sees, keeps the old distance if it sees nothing.
+    pub fn autofocus(&mut self, world: &dyn Hittable, x: u32, y: u32, width: u32, height: u32) {
+        let x = (x as f64 + 0.5) / width as f64;
+        let y = (y as f64 + 0.5) / height as f64;
+        let dir = self.lower_left_corner + self.horizontal * x + self.vertical * y - self.origin;
+        let pinhole = Ray::new(self.origin, dir, self.time0);
+        if let Option::Some(rec) = world.hit(&pinhole, 0.001, f64::INFINITY) {
+            let dis = (rec.p - self.origin) * (-self.w);
+            if dis > 0.0 {
+                self.set_focus_dist(dis);
+            }
         }
     }
-    pub fn get_ray(&self, x: f64, y: f64) -> Ray {
-        let rd = random_in_unit_disk() * self.lens_radius;
+}
+impl CameraModel for Camera {
+    fn generate_ray(&self, x: f64, y: f64) -> (Ray, Vec3) {
+        let a = self.aperture.sample();
+        let mut weight = Vec3::ones();
+        // the barrel is a second stop shifted off axis with the image point
+        let shift = Vec3::new(x - 0.5, y - 0.5, 0.0) * (2.0 * self.cats_eye);
+        if self.cats_eye > 0.0 && (a - shift).length() > 1.0 {
+            weight = Vec3::zero();
+        }
+        let rd = a * self.lens_radius;
         let offset = self.u * rd.x + self.v * rd.y;
-        Ray::new(
+        let mut target = self.lower_left_corner + self.horizontal * x + self.vertical * y;
+        if self.focus_shift.squared_length() > 0.0 {
+            // one channel per ray, each focused at its own distance
+            let c = random_int(0, 2);
+            target = self.origin + (target - self.origin) * (1.0 + self.focus_shift.get(c));
+            let mut w = Vec3::zero();
+            *w.get_mut(c) = 3.0;
+            weight = weight.change(w);
+        }
+        let this_ray = Ray::new(
             self.origin + offset,
-            self.lower_left_corner + self.horizontal * x + self.vertical * y - self.origin - offset,
+            target - self.origin - offset,
             get_rand(self.time0, self.time1),
-        )
+        );
+        (this_ray, weight)
+    }
+}
+
+// Parallel projection, `height` is the visible height in world units.
+pub struct OrthographicCamera {
+    pub origin: Vec3,
+    pub u: Vec3,
+    pub v: Vec3,
+    pub w: Vec3,
+    pub width: f64,
+    pub height: f64,
+    pub time0: f64,
+    pub time1: f64,
+}
+#[allow(dead_code)]
+#[allow(clippy::too_many_arguments)]
+impl OrthographicCamera {
+    pub fn new(
+        lookfrom: Vec3,
+        lookat: Vec3,
+        vup: Vec3,
+        height: f64,
+        aspect_ratio: f64,
+        time0: f64,
+        time1: f64,
+    ) -> Self {
+        let (u, v, w) = look_basis(lookfrom, lookat, vup);
+        Self {
+            origin: lookfrom,
+            u,
+            v,
+            w,
+            width: height * aspect_ratio,
+            height,
+            time0,
+            time1,
+        }
+    }
+}
+impl CameraModel for OrthographicCamera {
+    fn generate_ray(&self, x: f64, y: f64) -> (Ray, Vec3) {
+        let this_ray = Ray::new(
+            self.origin + self.u * ((x - 0.5) * self.width) + self.v * ((y - 0.5) * self.height),
+            -self.w,
+            get_rand(self.time0, self.time1),
+        );
+        (this_ray, Vec3::ones())
+    }
+}
+
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+pub enum FisheyeMapping {
+    // image radius proportional to the angle off axis
+    Equidistant,
+    // image radius proportional to 2 sin(angle / 2), keeps solid angles
+    Equisolid,
+}
+
+// Circular fisheye, the image circle fits the shorter side and `fov` is
+// the full angle across it in degrees.
+pub struct FisheyeCamera {
+    pub origin: Vec3,
+    pub u: Vec3,
+    pub v: Vec3,
+    pub w: Vec3,
+    pub fov: f64,
+    pub aspect_ratio: f64,
+    pub mapping: FisheyeMapping,
+    pub time0: f64,
+    pub time1: f64,
+}
+#[allow(dead_code)]
+#[allow(clippy::too_many_arguments)]
+impl FisheyeCamera {
+    pub fn new(
+        lookfrom: Vec3,
+        lookat: Vec3,
+        vup: Vec3,
+        fov: f64,
+        aspect_ratio: f64,
+        mapping: FisheyeMapping,
+        time0: f64,
+        time1: f64,
+    ) -> Self {
+        let (u, v, w) = look_basis(lookfrom, lookat, vup);
+        Self {
+            origin: lookfrom,
+            u,
+            v,
+            w,
+            fov: degrees_to_radians(fov.min(360.0)),
+            aspect_ratio,
+            mapping,
+            time0,
+            time1,
+        }
+    }
+    // position on the image circle, radius 1 at its edge
+    fn circle_pos(&self, x: f64, y: f64) -> (f64, f64) {
+        let (x, y) = ((x - 0.5) * 2.0, (y - 0.5) * 2.0);
+        if self.aspect_ratio >= 1.0 {
+            (x * self.aspect_ratio, y)
+        } else {
+            (x, y / self.aspect_ratio)
+        }
+    }
+}
+impl CameraModel for FisheyeCamera {
+    fn generate_ray(&self, x: f64, y: f64) -> (Ray, Vec3) {
+        let (cx, cy) = self.circle_pos(x, y);
+        let r = (cx * cx + cy * cy).sqrt().min(1.0);
+        let theta = match self.mapping {
+            FisheyeMapping::Equidistant => r * self.fov / 2.0,
+            FisheyeMapping::Equisolid => 2.0 * (r * (self.fov / 4.0).sin()).asin(),
+        };
+        let phi = cy.atan2(cx);
+        let dir = -self.w * theta.cos() + (self.u * phi.cos() + self.v * phi.sin()) * theta.sin();
+        let this_ray = Ray::new(self.origin, dir, get_rand(self.time0, self.time1));
+        (this_ray, Vec3::ones())
+    }
+    fn covers(&self, x: f64, y: f64) -> bool {
+        let (cx, cy) = self.circle_pos(x, y);
+        cx * cx + cy * cy <= 1.0
+    }
+}
+
+// Equirectangular 360 x 180 panorama centered on lookat, the image should
+// be twice as wide as it is high.
+pub struct PanoramicCamera {
+    pub origin: Vec3,
+    pub u: Vec3,
+    pub v: Vec3,
+    pub w: Vec3,
+    // omni-directional stereo, rays start this far to the right of the
+    // viewing direction, negative for the left eye
+    pub eye_offset: f64,
+    pub time0: f64,
+    pub time1: f64,
+}
+#[allow(dead_code)]
+impl PanoramicCamera {
+    pub fn new(lookfrom: Vec3, lookat: Vec3, vup: Vec3, time0: f64, time1: f64) -> Self {
+        let (u, v, w) = look_basis(lookfrom, lookat, vup);
+        Self {
+            origin: lookfrom,
+            u,
+            v,
+            w,
+            eye_offset: 0.0,
+            time0,
+            time1,
+        }
+    }
+    pub fn new_ods(
+        lookfrom: Vec3,
+        lookat: Vec3,
+        vup: Vec3,
+        eye_offset: f64,
+        time0: f64,
+        time1: f64,
+    ) -> Self {
+        let mut cam = Self::new(lookfrom, lookat, vup, time0, time1);
+        cam.eye_offset = eye_offset;
+        cam
+    }
+}
+impl CameraModel for PanoramicCamera {
+    fn generate_ray(&self, x: f64, y: f64) -> (Ray, Vec3) {
+        let phi = (x - 0.5) * 2.0 * PI;
+        let elevation = (y - 0.5) * PI;
+        let dir =
+            (self.u * phi.sin() - self.w * phi.cos()) * elevation.cos() + self.v * elevation.sin();
+        let right = self.u * phi.cos() + self.w * phi.sin();
+        let this_ray = Ray::new(
+            self.origin + right * self.eye_offset,
+            dir,
+            get_rand(self.time0, self.time1),
+        );
+        (this_ray, Vec3::ones())
+    }
+}
+
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+pub enum Convergence {
+    // both eyes look straight ahead
+    Parallel,
+    // both eyes look at lookat
+    ToeIn,
+}
+
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+pub enum StereoLayout {
+    // left eye on the left half
+    SideBySide,
+    // left eye on the top half
+    TopBottom,
+}
+
+// Two eyes packed into one image, each eye keeps its own aspect ratio so
+// the packed image is twice as wide or twice as high. Render `left` and
+// `right` on their own for separate frames.
+pub struct StereoCamera {
+    pub left: Arc<dyn CameraModel>,
+    pub right: Arc<dyn CameraModel>,
+    pub layout: StereoLayout,
+}
+#[allow(dead_code)]
+#[allow(clippy::too_many_arguments)]
+impl StereoCamera {
+    pub fn new(
+        lookfrom: Vec3,
+        lookat: Vec3,
+        vup: Vec3,
+        vfov: f64,
+        aspect_ratio: f64,
+        aperture: f64,
+        focus_dist: f64,
+        time0: f64,
+        time1: f64,
+        interocular: f64,
+        convergence: Convergence,
+        layout: StereoLayout,
+    ) -> Self {
+        let (u, _v, _w) = look_basis(lookfrom, lookat, vup);
+        let eye = |side: f64| {
+            let offset = u * (side * interocular / 2.0);
+            let target = match convergence {
+                Convergence::Parallel => lookat + offset,
+                Convergence::ToeIn => lookat,
+            };
+            Arc::new(Camera::new(
+                lookfrom + offset,
+                target,
+                vup,
+                vfov,
+                aspect_ratio,
+                aperture,
+                focus_dist,
+                time0,
+                time1,
+            ))
+        };
+        Self {
+            left: eye(-1.0),
+            right: eye(1.0),
+            layout,
+        }
+    }
+    // Omni-directional stereo panorama.
+    pub fn new_ods(
+        lookfrom: Vec3,
+        lookat: Vec3,
+        vup: Vec3,
+        interocular: f64,
+        time0: f64,
+        time1: f64,
+        layout: StereoLayout,
+    ) -> Self {
+        let eye = |side: f64| {
+            Arc::new(PanoramicCamera::new_ods(
+                lookfrom,
+                lookat,
+                vup,
+                side * interocular / 2.0,
+                time0,
+                time1,
+            ))
+        };
+        Self {
+            left: eye(-1.0),
+            right: eye(1.0),
+            layout,
+        }
+    }
+    // the eye and the position in its own image
+    fn split(&self, x: f64, y: f64) -> (&dyn CameraModel, f64, f64) {
+        match self.layout {
+            StereoLayout::SideBySide => {
+                if x < 0.5 {
+                    (&*self.left, x * 2.0, y)
+                } else {
+                    (&*self.right, x * 2.0 - 1.0, y)
+                }
+            }
+            StereoLayout::TopBottom => {
+                if y >= 0.5 {
+                    (&*self.left, x, y * 2.0 - 1.0)
+                } else {
+                    (&*self.right, x, y * 2.0)
+                }
+            }
+        }
+    }
+}
+impl CameraModel for StereoCamera {
+    fn generate_ray(&self, x: f64, y: f64) -> (Ray, Vec3) {
+        let (cam, x, y) = self.split(x, y);
+        cam.generate_ray(x, y)
+    }
+    fn covers(&self, x: f64, y: f64) -> bool {
+        let (cam, x, y) = self.split(x, y);
+        cam.covers(x, y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hit::{HittableList, XyRect};
+    use crate::material::NOMaterial;
+
+    #[track_caller]
+    fn looks((ray, weight): (Ray, Vec3), ori: Vec3, dir: Vec3) {
+        assert_eq!(weight, Vec3::ones());
+        assert!((ray.ori - ori).length() < 1e-9);
+        assert!((ray.dir.unit() - dir).length() < 1e-9);
+    }
+
+    fn lookfrom() -> Vec3 {
+        Vec3::new(0.0, 0.0, 5.0)
+    }
+
+    fn basis() -> (Vec3, Vec3, Vec3) {
+        (lookfrom(), Vec3::zero(), Vec3::new(0.0, 1.0, 0.0))
+    }
+
+    #[test]
+    fn test_orthographic_rays() {
+        let (from, at, up) = basis();
+        let cam = OrthographicCamera::new(from, at, up, 2.0, 2.0, 0.0, 0.0);
+        let fwd = Vec3::new(0.0, 0.0, -1.0);
+        looks(cam.generate_ray(0.5, 0.5), from, fwd);
+        looks(cam.generate_ray(0.0, 1.0), Vec3::new(-2.0, 1.0, 5.0), fwd);
+        looks(cam.generate_ray(1.0, 0.25), Vec3::new(2.0, -0.5, 5.0), fwd);
+    }
+
+    #[test]
+    fn test_fisheye_rays() {
+        let (from, at, up) = basis();
+        let d = |deg: f64| {
+            let a = degrees_to_radians(deg);
+            Vec3::new(a.sin(), 0.0, -a.cos())
+        };
+        for mapping in [FisheyeMapping::Equidistant, FisheyeMapping::Equisolid].iter() {
+            let cam = FisheyeCamera::new(from, at, up, 180.0, 1.0, *mapping, 0.0, 0.0);
+            looks(cam.generate_ray(0.5, 0.5), from, d(0.0));
+            // the rim of the circle looks sideways
+            looks(cam.generate_ray(1.0, 0.5), from, d(90.0));
+            looks(cam.generate_ray(0.5, 0.0), from, Vec3::new(0.0, -1.0, 0.0));
+            assert!(cam.covers(0.5, 0.99) && !cam.covers(0.02, 0.02));
+        }
+        // halfway out, the equisolid mapping bends less
+        let cam = FisheyeCamera::new(
+            from,
+            at,
+            up,
+            180.0,
+            1.0,
+            FisheyeMapping::Equidistant,
+            0.0,
+            0.0,
+        );
+        looks(cam.generate_ray(0.75, 0.5), from, d(45.0));
+        let cam = FisheyeCamera::new(
+            from,
+            at,
+            up,
+            180.0,
+            1.0,
+            FisheyeMapping::Equisolid,
+            0.0,
+            0.0,
+        );
+        let theta = 2.0 * (0.5 * (PI / 4.0).sin()).asin();
+        looks(cam.generate_ray(0.75, 0.5), from, d(theta.to_degrees()));
+        // on a wide image the circle fits the height
+        let cam = FisheyeCamera::new(
+            from,
+            at,
+            up,
+            180.0,
+            2.0,
+            FisheyeMapping::Equidistant,
+            0.0,
+            0.0,
+        );
+        looks(cam.generate_ray(0.75, 0.5), from, d(90.0));
+        assert!(!cam.covers(0.1, 0.5));
+    }
+
+    #[test]
+    fn test_panoramic_rays() {
+        let (from, at, up) = basis();
+        let cam = PanoramicCamera::new(from, at, up, 0.0, 0.0);
+        looks(cam.generate_ray(0.5, 0.5), from, Vec3::new(0.0, 0.0, -1.0));
+        looks(cam.generate_ray(0.75, 0.5), from, Vec3::new(1.0, 0.0, 0.0));
+        looks(cam.generate_ray(0.25, 0.5), from, Vec3::new(-1.0, 0.0, 0.0));
+        // both side edges look backward, top and bottom straight up and down
+        looks(cam.generate_ray(0.0, 0.5), from, Vec3::new(0.0, 0.0, 1.0));
+        looks(cam.generate_ray(1.0, 0.5), from, Vec3::new(0.0, 0.0, 1.0));
+        looks(cam.generate_ray(0.3, 1.0), from, Vec3::new(0.0, 1.0, 0.0));
+        looks(cam.generate_ray(0.6, 0.0), from, Vec3::new(0.0, -1.0, 0.0));
+        looks(
+            cam.generate_ray(0.5, 0.75),
+            from,
+            Vec3::new(0.0, 1.0, -1.0) / 2f64.sqrt(),
+        );
+    }
+
+    #[test]
+    fn test_aperture_samples() {
+        let hexagon = Aperture::Polygon {
+            blades: 6,
+            rotation: 0.0,
+        };
+        let apothem = (PI / 6.0).cos();
+        for _ in 0..2000 {
+            assert!(Aperture::Disk.sample().length() <= 1.0);
+            let p = hexagon.sample();
+            for k in 0..6 {
+                let a = (k as f64 + 0.5) * PI / 3.0;
+                assert!(p.x * a.cos() + p.y * a.sin() <= apothem + 1e-9);
+            }
+        }
+        // an image reaches into the corners of the square, top right only
+        let mask = Aperture::Image(Distribution2D::new(vec![vec![0.0, 1.0], vec![0.0, 0.0]]));
+        let mut outside = false;
+        for _ in 0..2000 {
+            let p = mask.sample();
+            assert!(p.x >= 0.0 && p.x <= 1.0 && p.y >= 0.0 && p.y <= 1.0);
+            outside |= p.length() > 1.0;
+        }
+        assert!(outside);
+    }
+
+    fn thin_lens() -> Camera {
+        let (from, at, up) = (
+            Vec3::zero(),
+            Vec3::new(0.0, 0.0, -1.0),
+            Vec3::new(0.0, 1.0, 0.0),
+        );
+        Camera::new(from, at, up, 90.0, 2.0, 0.5, 1.0, 0.0, 0.0)
+    }
+
+    #[test]
+    fn test_autofocus_pixel() {
+        // the left half of the view is 2 away, the right half 4
+        let mut world = HittableList::default();
+        world.add(Arc::new(XyRect::new(
+            -10.0,
+            0.0,
+            -10.0,
+            10.0,
+            -2.0,
+            Arc::new(NOMaterial {}),
+        )));
+        world.add(Arc::new(XyRect::new(
+            0.0,
+            10.0,
+            -10.0,
+            10.0,
+            -4.0,
+            Arc::new(NOMaterial {}),
+        )));
+        let mut cam = thin_lens();
+        cam.autofocus(&world, 0, 0, 4, 2);
+        assert!((cam.focus_dist - 2.0).abs() < 1e-9);
+        cam.autofocus(&world, 3, 1, 4, 2);
+        assert!((cam.focus_dist - 4.0).abs() < 1e-9);
+        cam.autofocus(&HittableList::default(), 0, 0, 4, 2);
+        assert!((cam.focus_dist - 4.0).abs() < 1e-9);
+        // every lens sample through the pixel center meets at the surface
+        let (x, y) = (3.5 / 4.0, 1.5 / 2.0);
+        let r = cam.get_ray(x, y);
+        let focus = r.pos((-4.0 - r.ori.z) / r.dir.z);
+        assert!(focus.x > 0.0);
+        for _ in 0..100 {
+            let (r, weight) = cam.generate_ray(x, y);
+            assert_eq!(weight, Vec3::ones());
+            assert!(r.ori.length() <= cam.lens_radius + 1e-9);
+            assert!((r.pos((-4.0 - r.ori.z) / r.dir.z) - focus).length() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_ray_weights() {
+        // the barrel clips some lens samples at the corner, none in the middle
+        let mut cam = thin_lens();
+        cam.cats_eye = 1.0;
+        let mut clipped = 0;
+        for _ in 0..1000 {
+            assert_eq!(cam.generate_ray(0.5, 0.5).1, Vec3::ones());
+            let weight = cam.generate_ray(1.0, 1.0).1;
+            assert!(weight == Vec3::ones() || weight == Vec3::zero());
+            clipped += (weight == Vec3::zero()) as i32;
+        }
+        assert!(clipped > 100 && clipped < 900);
+        // with a focus shift each ray carries one channel three times over
+        let mut cam = thin_lens();
+        cam.focus_shift = Vec3::new(0.1, 0.0, -0.1);
+        for _ in 0..100 {
+            let weight = cam.generate_ray(0.3, 0.6).1;
+            assert_eq!(weight.x + weight.y + weight.z, 3.0);
+            assert!(weight.x == 0.0 || weight.y == 0.0);
+        }
+    }
+
+    #[test]
+    fn test_stereo_rays() {
+        let (from, at, up) = basis();
+        let (left, right) = (Vec3::new(-0.1, 0.0, 5.0), Vec3::new(0.1, 0.0, 5.0));
+        let stereo = |convergence, layout| {
+            StereoCamera::new(
+                from,
+                at,
+                up,
+                90.0,
+                1.0,
+                0.0,
+                5.0,
+                0.0,
+                0.0,
+                0.2,
+                convergence,
+                layout,
+            )
+        };
+        let cam = stereo(Convergence::Parallel, StereoLayout::SideBySide);
+        let fwd = Vec3::new(0.0, 0.0, -1.0);
+        looks(cam.generate_ray(0.25, 0.5), left, fwd);
+        looks(cam.generate_ray(0.75, 0.5), right, fwd);
+        // the left edge of each half is the left edge of that eye
+        let edge = Vec3::new(-1.0, 0.0, -1.0) / 2f64.sqrt();
+        looks(cam.generate_ray(0.0, 0.5), left, edge);
+        looks(cam.generate_ray(0.5, 0.5), right, edge);
+        let cam = stereo(Convergence::ToeIn, StereoLayout::TopBottom);
+        looks(cam.generate_ray(0.5, 0.75), left, (at - left).unit());
+        looks(cam.generate_ray(0.5, 0.25), right, (at - right).unit());
+        // the middle line is the bottom edge of the left eye
+        let w = (left - at).unit();
+        let v = Vec3::cross(w, Vec3::cross(up, w)).unit();
+        looks(cam.generate_ray(0.5, 0.5), left, (-w - v).unit());
+    }
+
+    #[test]
+    fn test_ods_rays() {
+        let (from, at, up) = basis();
+        let cam = StereoCamera::new_ods(from, at, up, 0.2, 0.0, 0.0, StereoLayout::SideBySide);
+        // looking forward the eyes sit left and right, looking right they
+        // sit behind and in front, every ray is tangent to the eye circle
+        looks(
+            cam.generate_ray(0.25, 0.5),
+            Vec3::new(-0.1, 0.0, 5.0),
+            Vec3::new(0.0, 0.0, -1.0),
+        );
+        looks(
+            cam.generate_ray(0.75, 0.5),
+            Vec3::new(0.1, 0.0, 5.0),
+            Vec3::new(0.0, 0.0, -1.0),
+        );
+        looks(
+            cam.generate_ray(0.375, 0.5),
+            Vec3::new(0.0, 0.0, 4.9),
+            Vec3::new(1.0, 0.0, 0.0),
+        );
+        looks(
+            cam.generate_ray(0.875, 0.5),
+            Vec3::new(0.0, 0.0, 5.1),
+            Vec3::new(1.0, 0.0, 0.0),
+        );
+        for _ in 0..100 {
+            let (x, y) = (get_rand01(), get_rand01());
+            let r = cam.get_ray(x, y);
+            let off = r.ori - from;
+            assert!((off.length() - 0.1).abs() < 1e-9);
+            assert!((off * r.dir).abs() < 1e-9);
+        }
+        assert!((cam.get_ray(0.1, 1.0).dir.unit() - up).length() < 1e-9);
     }
 }
@@ -0,0 +1,163 @@
+use crate::aabb::Aabb;
+use crate::hit::{HitRecord, Hittable};
+use crate::ray::Ray;
+use std::sync::Arc;
+pub const INF: f64 = f64::MAX;
+
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+pub enum CsgOp {
+    Union,
+    Intersection,
+    // a minus b
+    Difference,
+}
+impl CsgOp {
+    fn inside(&self, in_a: bool, in_b: bool) -> bool {
+        match self {
+            CsgOp::Union => in_a || in_b,
+            CsgOp::Intersection => in_a && in_b,
+            CsgOp::Difference => in_a && !in_b,
+        }
+    }
+}
+
+// Boolean combination of two closed solids. Surfaces taken from b in a
+// difference face inward, which only flips nor_dir since nor always
+// faces the ray.
+pub struct Csg {
+    a: Arc<dyn Hittable>,
+    b: Arc<dyn Hittable>,
+    op: CsgOp,
+    mybox: Option<Aabb>,
+}
+#[allow(dead_code)]
+impl Csg {
+    pub fn new(a: Arc<dyn Hittable>, b: Arc<dyn Hittable>, op: CsgOp) -> Self {
+        let mybox = match op {
+            CsgOp::Union => match (a.bounding_box(0.0, 1.0), b.bounding_box(0.0, 1.0)) {
+                (Option::Some(box_a), Option::Some(box_b)) => {
+                    Option::Some(Aabb::surrounding_box(box_a, box_b))
+                }
+                _ => Option::None,
+            },
+            // the result never leaves a
+            _ => a.bounding_box(0.0, 1.0),
+        };
+        Self { a, b, op, mybox }
+    }
+    pub fn union(a: Arc<dyn Hittable>, b: Arc<dyn Hittable>) -> Self {
+        Self::new(a, b, CsgOp::Union)
+    }
+    pub fn intersection(a: Arc<dyn Hittable>, b: Arc<dyn Hittable>) -> Self {
+        Self::new(a, b, CsgOp::Intersection)
+    }
+    pub fn difference(a: Arc<dyn Hittable>, b: Arc<dyn Hittable>) -> Self {
+        Self::new(a, b, CsgOp::Difference)
+    }
+    // Walks the whole line so the inside state is known wherever the
+    // query interval starts.
+    fn crossings(&self, this_ray: &Ray) -> Vec<HitRecord> {
+        let mut all: Vec<(bool, HitRecord)> = self
+            .a
+            .hit_all(this_ray, -INF, INF)
+            .into_iter()
+            .map(|rec| (true, rec))
+            .chain(
+                self.b
+                    .hit_all(this_ray, -INF, INF)
+                    .into_iter()
+                    .map(|rec| (false, rec)),
+            )
+            .collect();
+        all.sort_by(|x, y| x.1.t.partial_cmp(&y.1.t).unwrap());
+        let (mut in_a, mut in_b, mut inside) = (false, false, false);
+        let mut res = Vec::new();
+        for (from_a, mut rec) in all {
+            if from_a {
+                in_a = rec.nor_dir;
+            } else {
+                in_b = rec.nor_dir;
+            }
+            let now = self.op.inside(in_a, in_b);
+            if now != inside {
+                inside = now;
+                if rec.nor_dir != now {
+                    rec.dndu = -rec.dndu;
+                    rec.dndv = -rec.dndv;
+                }
+                rec.nor_dir = now;
+                res.push(rec);
+            }
+        }
+        res
+    }
+}
+impl Hittable for Csg {
+    fn hit(&self, this_ray: &Ray, tmn: f64, tmx: f64) -> Option<HitRecord> {
+        self.hit_all(this_ray, tmn, tmx).into_iter().next()
+    }
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
+        self.mybox.clone()
+    }
+    fn hit_all(&self, this_ray: &Ray, tmn: f64, tmx: f64) -> Vec<HitRecord> {
+        if let Option::Some(mybox) = &self.mybox {
+            if !mybox.hit(*this_ray, tmn, tmx) {
+                return Vec::new();
+            }
+        }
+        self.crossings(this_ray)
+            .into_iter()
+            .filter(|rec| rec.t >= tmn && rec.t <= tmx)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hit::{Bbox, Sphere};
+    use crate::material::NOMaterial;
+    use crate::vec3::Vec3;
+
+    #[test]
+    fn test_difference_inside_surface() {
+        let mat = Arc::new(NOMaterial {});
+        let big = Arc::new(Sphere::new(Vec3::zero(), 2.0, mat.clone()));
+        let small = Arc::new(Sphere::new(Vec3::new(2.0, 0.0, 0.0), 1.0, mat));
+        let bitten = Csg::difference(big, small);
+        // comes in from +x through the bite, meets the small sphere's
+        // far side first, which faces into the solid
+        let this_ray = Ray::new(Vec3::new(5.0, 0.0, 0.0), Vec3::new(-1.0, 0.0, 0.0), 0.0);
+        let crossings = bitten.hit_all(&this_ray, 0.001, INF);
+        assert_eq!(crossings.len(), 2);
+        assert!((crossings[0].t - 4.0).abs() < 1e-9);
+        assert!(crossings[0].nor_dir);
+        assert!((crossings[0].nor - Vec3::new(1.0, 0.0, 0.0)).length() < 1e-9);
+        assert!((crossings[1].t - 7.0).abs() < 1e-9);
+        assert!(!crossings[1].nor_dir);
+    }
+
+    #[test]
+    fn test_box_through_min_faces() {
+        // a cube with a ball cut out of its middle, entered through the
+        // faces on the min side
+        let mat = Arc::new(NOMaterial {});
+        let cube = Arc::new(Bbox::new(-Vec3::ones(), Vec3::ones(), mat.clone()));
+        let hollow = Csg::difference(cube, Arc::new(Sphere::new(Vec3::zero(), 0.5, mat)));
+        for dir in [
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            Vec3::new(0.0, 0.0, 1.0),
+        ]
+        .iter()
+        {
+            let this_ray = Ray::new(-*dir * 3.0, *dir, 0.0);
+            let crossings = hollow.hit_all(&this_ray, 0.001, INF);
+            let t: Vec<f64> = crossings.iter().map(|rec| rec.t).collect();
+            assert_eq!(t, vec![2.0, 2.5, 3.5, 4.0]);
+            let entering: Vec<bool> = crossings.iter().map(|rec| rec.nor_dir).collect();
+            assert_eq!(entering, vec![true, false, true, false]);
+        }
+    }
+}
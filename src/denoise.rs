@@ -0,0 +1,233 @@
+use crate::aov::write_exr;
+use crate::film::encode_image;
+use crate::vec3::Vec3;
+use exr::prelude::*;
+
+// A noisy image and the features that guide the filter, bottom row first
+// like Film.
+pub struct DenoiseBuffers {
+    pub width: u32,
+    pub height: u32,
+    pub color: Vec<Vec3>,
+    // variance of the pixel mean, not of single samples
+    pub variance: Vec<Vec3>,
+    pub albedo: Vec<Vec3>,
+    pub normal: Vec<Vec3>,
+}
+#[allow(dead_code)]
+impl DenoiseBuffers {
+    pub fn new(width: u32, height: u32) -> Self {
+        let n = (width * height) as usize;
+        Self {
+            width,
+            height,
+            color: vec![Vec3::zero(); n],
+            variance: vec![Vec3::zero(); n],
+            albedo: vec![Vec3::zero(); n],
+            normal: vec![Vec3::zero(); n],
+        }
+    }
+    // Reads the beauty, variance, albedo and normal channels of an EXR
+    // written by AovFilm::save_exr.
+    pub fn load_exr(filename: &str) -> Self {
+        let image = read_all_flat_layers_from_file(filename)
+            .unwrap_or_else(|e| panic!("Cannot read {}: {}", filename, e));
+        let layer = &image.layer_data[0];
+        let (width, height) = (layer.size.width() as u32, layer.size.height() as u32);
+        let channel = |name: &str| -> Vec<f32> {
+            let c = layer
+                .channel_data
+                .list
+                .iter()
+                .find(|c| c.name.eq(name))
+                .unwrap_or_else(|| panic!("Cannot decode {}: no {} channel", filename, name));
+            c.sample_data.values_as_f32().collect()
+        };
+        let vector = |names: [&str; 3]| -> Vec<Vec3> {
+            let (x, y, z) = (channel(names[0]), channel(names[1]), channel(names[2]));
+            // the file is top row first
+            let mut v = Vec::with_capacity(x.len());
+            for row in (0..height as usize).rev() {
+                for k in row * width as usize..(row + 1) * width as usize {
+                    v.push(Vec3::new(x[k] as f64, y[k] as f64, z[k] as f64));
+                }
+            }
+            v
+        };
+        Self {
+            width,
+            height,
+            color: vector(["R", "G", "B"]),
+            variance: vector(["variance.R", "variance.G", "variance.B"]),
+            albedo: vector(["albedo.R", "albedo.G", "albedo.B"]),
+            normal: vector(["normal.X", "normal.Y", "normal.Z"]),
+        }
+    }
+}
+
+// Non-local means on the color, weighted like a joint bilateral filter by
+// how close the normals and albedos are. Patch distances are measured
+// against the pixel variances (Rousselle et al. 2012), so noisy regions get
+// smoothed more and converged ones are left alone. Textures survive because
+// the filter runs on the color divided by the albedo.
+#[derive(Clone, Copy)]
+pub struct Denoiser {
+    // half widths of the search window and of the compared patches
+    pub radius: i32,
+    pub patch: i32,
+    // larger smooths more
+    pub strength: f64,
+    pub sigma_normal: f64,
+    pub sigma_albedo: f64,
+}
+impl Default for Denoiser {
+    fn default() -> Self {
+        Self {
+            radius: 7,
+            patch: 1,
+            strength: 0.45,
+            sigma_normal: 0.3,
+            sigma_albedo: 0.1,
+        }
+    }
+}
+#[allow(dead_code)]
+impl Denoiser {
+    pub fn denoise(&self, b: &DenoiseBuffers) -> Vec<Vec3> {
+        let (w, h) = (b.width as i32, b.height as i32);
+        // surfaces without an albedo, lights and the background, are
+        // filtered as they are
+        let albedo: Vec<Vec3> = b
+            .albedo
+            .iter()
+            .map(|a| {
+                let c = |x: f64| if x < 0.01 { 1.0 } else { x };
+                Vec3::new(c(a.x), c(a.y), c(a.z))
+            })
+            .collect();
+        let mut u = Vec::with_capacity(albedo.len());
+        let mut var = Vec::with_capacity(albedo.len());
+        for (k, a) in albedo.iter().enumerate() {
+            let (c, v) = (b.color[k], b.variance[k]);
+            u.push(Vec3::new(c.x / a.x, c.y / a.y, c.z / a.z));
+            var.push(Vec3::new(
+                v.x / (a.x * a.x),
+                v.y / (a.y * a.y),
+                v.z / (a.z * a.z),
+            ));
+        }
+        let idx = |x: i32, y: i32| (y.clamp(0, h - 1) * w + x.clamp(0, w - 1)) as usize;
+        let k2 = self.strength * self.strength;
+        let patch_size = (3 * (2 * self.patch + 1) * (2 * self.patch + 1)) as f64;
+        let mut res = Vec::with_capacity(u.len());
+        for y in 0..h {
+            for x in 0..w {
+                let p = idx(x, y);
+                let mut sum = Vec3::zero();
+                let mut weight = 0.0;
+                for qy in (y - self.radius).max(0)..=(y + self.radius).min(h - 1) {
+                    for qx in (x - self.radius).max(0)..=(x + self.radius).min(w - 1) {
+                        let q = idx(qx, qy);
+                        let feature = (b.normal[p] - b.normal[q]).squared_length()
+                            / (self.sigma_normal * self.sigma_normal)
+                            + (b.albedo[p] - b.albedo[q]).squared_length()
+                                / (self.sigma_albedo * self.sigma_albedo);
+                        if feature > 20.0 {
+                            continue;
+                        }
+                        let mut d = 0.0;
+                        for dy in -self.patch..=self.patch {
+                            for dx in -self.patch..=self.patch {
+                                let (i, j) = (idx(x + dx, y + dy), idx(qx + dx, qy + dy));
+                                for c in 0..3 {
+                                    let (vi, vj) = (var[i].get(c), var[j].get(c));
+                                    let diff = u[i].get(c) - u[j].get(c);
+                                    // the expected difference of two noisy
+                                    // copies of the same value is removed
+                                    d += (diff * diff - (vi + vi.min(vj)))
+                                        / (1e-10 + k2 * (vi + vj));
+                                }
+                            }
+                        }
+                        let wt = (-(d / patch_size).max(0.0) - feature).exp();
+                        sum += u[q] * wt;
+                        weight += wt;
+                    }
+                }
+                res.push((sum / weight).change(albedo[p]));
+            }
+        }
+        res
+    }
+}
+
+// Writes a denoised image as an 8 bit picture, or as an EXR when the name
+// ends in .exr.
+pub fn save_image(width: u32, height: u32, pixels: &[Vec3], filename: &str) {
+    if filename.ends_with(".exr") {
+        let mut channels = Vec::new();
+        for (c, name) in ["R", "G", "B"].iter().enumerate() {
+            let mut data = Vec::with_capacity(pixels.len());
+            for y in (0..height).rev() {
+                for x in 0..width {
+                    data.push(pixels[(y * width + x) as usize].get(c as i32) as f32);
+                }
+            }
+            channels.push((name.to_string(), data));
+        }
+        write_exr(filename, width, height, channels);
+    } else {
+        encode_image(width, height, |x, y| pixels[(y * width + x) as usize])
+            .save(filename)
+            .unwrap_or_else(|e| panic!("Cannot write {}: {}", filename, e));
+    }
+}
+
+// The standalone command, `denoise <in.exr> <out>` on buffers saved by a
+// render.
+pub fn denoise_command(args: &[String]) {
+    if args.len() != 2 {
+        panic!("usage: denoise <in.exr> <out.png|out.exr>");
+    }
+    let b = DenoiseBuffers::load_exr(&args[0]);
+    let res = Denoiser::default().denoise(&b);
+    save_image(b.width, b.height, &res, &args[1]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::get_rand01;
+
+    #[test]
+    fn test_noise_removed_edges_kept() {
+        // a gray wall meeting a white one, the noise has variance 0.01
+        let (w, h) = (24, 16);
+        let mut b = DenoiseBuffers::new(w, h);
+        for y in 0..h {
+            for x in 0..w {
+                let k = (y * w + x) as usize;
+                let a = if x < w / 2 { 0.2 } else { 0.8 };
+                let noise = (get_rand01() - 0.5) * 12f64.sqrt() * 0.1;
+                b.color[k] = Vec3::ones() * (a + noise);
+                b.variance[k] = Vec3::ones() * 0.01;
+                b.albedo[k] = Vec3::ones() * a;
+                b.normal[k] = Vec3::new(0.0, 0.0, 1.0);
+            }
+        }
+        let res = Denoiser::default().denoise(&b);
+        let error = |img: &[Vec3]| -> f64 {
+            let mut e: f64 = 0.0;
+            for (k, c) in img.iter().enumerate() {
+                e += (c.x - b.albedo[k].x).powi(2);
+            }
+            e / img.len() as f64
+        };
+        assert!(error(&res) < error(&b.color) * 0.2);
+        // nothing bleeds across the edge
+        for y in 0..h {
+            let k = (y * w + w / 2 - 1) as usize;
+            assert!((res[k].x - 0.2).abs() < 0.1);
+        }
+    }
+}
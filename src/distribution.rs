@@ -0,0 +1,195 @@
+// Piecewise constant distributions for importance sampling tabulated
+// functions such as environment maps.
+pub struct Distribution1D {
+    pub func: Vec<f64>,
+    pub cdf: Vec<f64>,
+    pub func_int: f64,
+}
+impl Distribution1D {
+    pub fn new(func: Vec<f64>) -> Self {
+        let n = func.len();
+        let mut cdf = vec![0.0; n + 1];
+        for i in 0..n {
+            cdf[i + 1] = cdf[i] + func[i].abs() / n as f64;
+        }
+        let func_int = cdf[n];
+        if func_int == 0.0 {
+            for (i, c) in cdf.iter_mut().enumerate() {
+                *c = i as f64 / n as f64;
+            }
+        } else {
+            for c in cdf.iter_mut() {
+                *c /= func_int;
+            }
+        }
+        Self {
+            func,
+            cdf,
+            func_int,
+        }
+    }
+    pub fn count(&self) -> usize {
+        self.func.len()
+    }
+    // Returns a sample in [0, 1), its density and the segment it is in.
+    pub fn sample_continuous(&self, u: f64) -> (f64, f64, usize) {
+        // last i with cdf[i] <= u
+        let mut lo = 0;
+        let mut hi = self.count();
+        while hi - lo > 1 {
+            let mid = (lo + hi) / 2;
+            if self.cdf[mid] <= u {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        let offset = lo;
+        let mut du = u - self.cdf[offset];
+        let width = self.cdf[offset + 1] - self.cdf[offset];
+        if width > 0.0 {
+            du /= width;
+        }
+        let pdf = self.pdf(offset);
+        ((offset as f64 + du) / self.count() as f64, pdf, offset)
+    }
+    pub fn pdf(&self, offset: usize) -> f64 {
+        if self.func_int > 0.0 {
+            self.func[offset].abs() / self.func_int
+        } else {
+            1.0
+        }
+    }
+}
+
+// func[v][u], sampled as a marginal over rows times a conditional per row.
+pub struct Distribution2D {
+    pub conditional: Vec<Distribution1D>,
+    pub marginal: Distribution1D,
+}
+impl Distribution2D {
+    pub fn new(func: Vec<Vec<f64>>) -> Self {
+        let conditional: Vec<Distribution1D> = func.into_iter().map(Distribution1D::new).collect();
+        let marginal = Distribution1D::new(conditional.iter().map(|d| d.func_int).collect());
+        Self {
+            conditional,
+            marginal,
+        }
+    }
+    // Returns (u, v) in [0, 1)^2 and the density with respect to area there.
+    pub fn sample_continuous(&self, u0: f64, u1: f64) -> (f64, f64, f64) {
+        let (v, pdf1, row) = self.marginal.sample_continuous(u1);
+        let (u, pdf0, _col) = self.conditional[row].sample_continuous(u0);
+        (u, v, pdf0 * pdf1)
+    }
+    pub fn pdf(&self, u: f64, v: f64) -> f64 {
+        let row = ((v * self.marginal.count() as f64) as usize).min(self.marginal.count() - 1);
+        let cond = &self.conditional[row];
+        let col = ((u * cond.count() as f64) as usize).min(cond.count() - 1);
+        if self.marginal.func_int == 0.0 {
+            return 1.0;
+        }
+        cond.func[col].abs() / self.marginal.func_int
+    }
+}
+
+// Walker's alias method, O(1) sampling of a discrete distribution.
+pub struct AliasTable {
+    pub prob: Vec<f64>,
+    pub alias: Vec<usize>,
+    pub pmf: Vec<f64>,
+}
+impl AliasTable {
+    pub fn new(weights: &[f64]) -> Self {
+        let n = weights.len();
+        let sum: f64 = weights.iter().map(|w| w.max(0.0)).sum();
+        let pmf: Vec<f64> = if sum > 0.0 {
+            weights.iter().map(|w| w.max(0.0) / sum).collect()
+        } else {
+            vec![1.0 / n as f64; n]
+        };
+        let mut prob = vec![1.0; n];
+        let mut alias: Vec<usize> = (0..n).collect();
+        let mut scaled: Vec<f64> = pmf.iter().map(|p| p * n as f64).collect();
+        let mut small: Vec<usize> = Vec::new();
+        let mut large: Vec<usize> = Vec::new();
+        for (i, p) in scaled.iter().enumerate() {
+            if *p < 1.0 {
+                small.push(i);
+            } else {
+                large.push(i);
+            }
+        }
+        while let (Option::Some(s), Option::Some(l)) = (small.pop(), large.pop()) {
+            prob[s] = scaled[s];
+            alias[s] = l;
+            scaled[l] = scaled[l] + scaled[s] - 1.0;
+            if scaled[l] < 1.0 {
+                small.push(l);
+            } else {
+                large.push(l);
+            }
+        }
+        // whatever is left over is 1 up to rounding
+        Self { prob, alias, pmf }
+    }
+    pub fn sample(&self, u0: f64, u1: f64) -> usize {
+        let n = self.prob.len();
+        let i = ((u0 * n as f64) as usize).min(n - 1);
+        if u1 < self.prob[i] {
+            i
+        } else {
+            self.alias[i]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_1d() {
+        let d = Distribution1D::new(vec![1.0, 3.0]);
+        let (x, pdf, offset) = d.sample_continuous(0.5);
+        assert_eq!(offset, 1);
+        assert!((pdf - 1.5).abs() < 1e-12);
+        assert!((x - (0.5 + 0.5 / 3.0)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_zero_1d() {
+        let d = Distribution1D::new(vec![0.0, 0.0, 0.0, 0.0]);
+        let (x, pdf, _offset) = d.sample_continuous(0.3);
+        assert!((x - 0.3).abs() < 1e-12);
+        assert_eq!(pdf, 1.0);
+    }
+
+    #[test]
+    fn test_pdf_2d() {
+        let d = Distribution2D::new(vec![vec![1.0, 0.0], vec![2.0, 1.0]]);
+        let (u, v, pdf) = d.sample_continuous(0.9, 0.9);
+        assert!((d.pdf(u, v) - pdf).abs() < 1e-12);
+        assert_eq!(d.pdf(0.75, 0.25), 0.0);
+    }
+
+    #[test]
+    fn test_alias_table() {
+        let weights = [1.0, 5.0, 0.0, 2.0];
+        let table = AliasTable::new(&weights);
+        let n = weights.len() as f64;
+        let mut recovered = vec![0.0; weights.len()];
+        for i in 0..weights.len() {
+            recovered[i] += table.prob[i] / n;
+            recovered[table.alias[i]] += (1.0 - table.prob[i]) / n;
+        }
+        for i in 0..weights.len() {
+            assert!((recovered[i] - weights[i] / 8.0).abs() < 1e-12);
+        }
+        for i in 0..100 {
+            for j in 0..100 {
+                assert_ne!(table.sample(i as f64 / 100.0, j as f64 / 100.0), 2);
+            }
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use crate::filter::Filter;
+use crate::vec3::Vec3;
+use image::{ImageBuffer, Rgb, RgbImage};
+use std::sync::Arc;
+
+// Accumulates filtered samples. Pixel (x, y) covers [x, x + 1) * [y, y + 1)
+// in raster space with y going up, a sample is splatted into every pixel
+// whose center is inside the filter radius.
+pub struct Film {
+    pub width: u32,
+    pub height: u32,
+    pub sum: Vec<Vec3>,
+    pub weight: Vec<f64>,
+    pub filter: Arc<dyn Filter>,
+    // luminance sum and count of the samples taken inside each pixel
+    pub taken: Vec<(f64, u32)>,
+}
+impl Film {
+    pub fn new(width: u32, height: u32, filter: Arc<dyn Filter>) -> Self {
+        let n = (width * height) as usize;
+        Self {
+            width,
+            height,
+            sum: vec![Vec3::zero(); n],
+            weight: vec![0.0; n],
+            filter,
+            taken: vec![(0.0, 0); n],
+        }
+    }
+    fn taken_index(&self, px: f64, py: f64) -> usize {
+        let x = (px.max(0.0) as u32).min(self.width - 1);
+        let y = (py.max(0.0) as u32).min(self.height - 1);
+        (y * self.width + x) as usize
+    }
+    // Mean luminance and number of the samples so far inside the pixel px,
+    // py falls in.
+    pub fn running_mean(&self, px: f64, py: f64) -> (f64, u32) {
+        let (sum, count) = self.taken[self.taken_index(px, py)];
+        (sum / (count.max(1) as f64), count)
+    }
+    pub fn add_sample(&mut self, px: f64, py: f64, col: Vec3) {
+        let k = self.taken_index(px, py);
+        self.taken[k].0 += col.luminance();
+        self.taken[k].1 += 1;
+        let r = self.filter.radius();
+        let x0 = (px - 0.5 - r).ceil().max(0.0) as u32;
+        let x1 = ((px - 0.5 + r).floor() as i64).min(self.width as i64 - 1);
+        let y0 = (py - 0.5 - r).ceil().max(0.0) as u32;
+        let y1 = ((py - 0.5 + r).floor() as i64).min(self.height as i64 - 1);
+        for y in y0 as i64..=y1 {
+            for x in x0 as i64..=x1 {
+                let w = self
+                    .filter
+                    .evaluate(x as f64 + 0.5 - px, y as f64 + 0.5 - py);
+                if w == 0.0 {
+                    continue;
+                }
+                let k = (y as u32 * self.width + x as u32) as usize;
+                self.sum[k] += col * w;
+                self.weight[k] += w;
+            }
+        }
+    }
+    pub fn pixel(&self, x: u32, y: u32) -> Vec3 {
+        let k = (y * self.width + x) as usize;
+        if self.weight[k] <= 0.0 {
+            Vec3::zero()
+        } else {
+            self.sum[k] / self.weight[k]
+        }
+    }
+    pub fn to_image(&self) -> RgbImage {
+        encode_image(self.width, self.height, |x, y| self.pixel(x, y))
+    }
+}
+
+// Gamma 2 encoded 8 bit image, top row first.
+pub fn encode_image(width: u32, height: u32, pixel: impl Fn(u32, u32) -> Vec3) -> RgbImage {
+    let mut img: RgbImage = ImageBuffer::new(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            let col = pixel(x, y);
+            *img.get_pixel_mut(x, height - 1 - y) = Rgb([
+                (col.x.max(0.0).sqrt() * 255.0) as u8,
+                (col.y.max(0.0).sqrt() * 255.0) as u8,
+                (col.z.max(0.0).sqrt() * 255.0) as u8,
+            ]);
+        }
+    }
+    img
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::{BoxFilter, MitchellFilter, TentFilter};
+    use crate::random::get_rand01;
+
+    #[test]
+    fn test_splatting() {
+        // a box of radius 1 reaches the centers one pixel away, the tent
+        // is already zero there
+        let mut film = Film::new(4, 4, Arc::new(BoxFilter::new(1.0)));
+        film.add_sample(1.5, 1.5, Vec3::ones());
+        for y in 0..4 {
+            for x in 0..4 {
+                let inside = x <= 2 && y <= 2;
+                assert_eq!(film.weight[y * 4 + x] > 0.0, inside);
+            }
+        }
+        let mut film = Film::new(4, 4, Arc::new(TentFilter::new(1.0)));
+        film.add_sample(1.5, 1.5, Vec3::ones());
+        film.add_sample(0.1, 3.9, Vec3::ones());
+        let touched: Vec<usize> = (0..16).filter(|k| film.weight[*k] > 0.0).collect();
+        assert_eq!(touched, vec![5, 12]);
+        assert!((film.weight[5] - 1.0).abs() < 1e-12);
+        assert!((film.weight[12] - 0.6 * 0.6).abs() < 1e-12);
+        assert_eq!(film.pixel(0, 0), Vec3::zero());
+    }
+
+    #[test]
+    fn test_normalized() {
+        // with a negative lobe too, a flat image stays flat at the edges
+        let mut film = Film::new(6, 5, Arc::new(MitchellFilter::new(2.0, 0.0, 0.75)));
+        let col = Vec3::new(0.2, 0.5, 0.9);
+        for _ in 0..3000 {
+            film.add_sample(6.0 * get_rand01(), 5.0 * get_rand01(), col);
+        }
+        for y in 0..5 {
+            for x in 0..6 {
+                assert!((film.pixel(x, y) - col).length() < 1e-9);
+            }
+        }
+    }
+}
@@ -0,0 +1,214 @@
+pub const PI: f64 = std::f64::consts::PI;
+
+// Pixel reconstruction filter, offsets are in pixels from the pixel center.
+pub trait Filter: Send + Sync {
+    fn radius(&self) -> f64;
+    fn evaluate(&self, x: f64, y: f64) -> f64;
+}
+
+pub struct BoxFilter {
+    radius: f64,
+}
+#[allow(dead_code)]
+impl BoxFilter {
+    pub fn new(radius: f64) -> Self {
+        Self { radius }
+    }
+}
+impl Filter for BoxFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+    fn evaluate(&self, x: f64, y: f64) -> f64 {
+        if x.abs() <= self.radius && y.abs() <= self.radius {
+            1.0
+        } else {
+            0.0
+        }
+    }
+}
+
+pub struct TentFilter {
+    radius: f64,
+}
+#[allow(dead_code)]
+impl TentFilter {
+    pub fn new(radius: f64) -> Self {
+        Self { radius }
+    }
+}
+impl Filter for TentFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+    fn evaluate(&self, x: f64, y: f64) -> f64 {
+        (self.radius - x.abs()).max(0.0) * (self.radius - y.abs()).max(0.0)
+    }
+}
+
+pub struct GaussianFilter {
+    radius: f64,
+    alpha: f64,
+    exp: f64,
+}
+#[allow(dead_code)]
+impl GaussianFilter {
+    pub fn new(radius: f64, alpha: f64) -> Self {
+        Self {
+            radius,
+            alpha,
+            exp: (-alpha * radius * radius).exp(),
+        }
+    }
+    fn gaussian(&self, d: f64) -> f64 {
+        ((-self.alpha * d * d).exp() - self.exp).max(0.0)
+    }
+}
+impl Filter for GaussianFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+    fn evaluate(&self, x: f64, y: f64) -> f64 {
+        self.gaussian(x) * self.gaussian(y)
+    }
+}
+
+// Mitchell-Netravali, b = c = 1/3 is the usual choice.
+pub struct MitchellFilter {
+    radius: f64,
+    b: f64,
+    c: f64,
+}
+#[allow(dead_code)]
+impl MitchellFilter {
+    pub fn new(radius: f64, b: f64, c: f64) -> Self {
+        Self { radius, b, c }
+    }
+    fn mitchell(&self, x: f64) -> f64 {
+        let (b, c) = (self.b, self.c);
+        // the cubic is defined over [-2, 2]
+        let x = (2.0 * x / self.radius).abs();
+        if x > 2.0 {
+            0.0
+        } else if x > 1.0 {
+            ((-b - 6.0 * c) * x * x * x
+                + (6.0 * b + 30.0 * c) * x * x
+                + (-12.0 * b - 48.0 * c) * x
+                + (8.0 * b + 24.0 * c))
+                / 6.0
+        } else {
+            ((12.0 - 9.0 * b - 6.0 * c) * x * x * x
+                + (-18.0 + 12.0 * b + 6.0 * c) * x * x
+                + (6.0 - 2.0 * b))
+                / 6.0
+        }
+    }
+}
+impl Filter for MitchellFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+    fn evaluate(&self, x: f64, y: f64) -> f64 {
+        self.mitchell(x) * self.mitchell(y)
+    }
+}
+
+// Lanczos windowed sinc, `tau` is the number of lobes of the window.
+pub struct LanczosFilter {
+    radius: f64,
+    tau: f64,
+}
+#[allow(dead_code)]
+impl LanczosFilter {
+    pub fn new(radius: f64, tau: f64) -> Self {
+        Self { radius, tau }
+    }
+    fn windowed_sinc(&self, x: f64) -> f64 {
+        let x = x.abs();
+        if x > self.radius {
+            0.0
+        } else {
+            sinc(x) * sinc(x / self.tau)
+        }
+    }
+}
+impl Filter for LanczosFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+    fn evaluate(&self, x: f64, y: f64) -> f64 {
+        self.windowed_sinc(x) * self.windowed_sinc(y)
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-5 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filters() -> Vec<Box<dyn Filter>> {
+        vec![
+            Box::new(BoxFilter::new(0.5)),
+            Box::new(TentFilter::new(1.5)),
+            Box::new(GaussianFilter::new(1.5, 2.0)),
+            Box::new(MitchellFilter::new(2.0, 1.0 / 3.0, 1.0 / 3.0)),
+            Box::new(LanczosFilter::new(3.0, 3.0)),
+        ]
+    }
+
+    // Integral over the square of the support by midpoint rule.
+    fn integral(f: &dyn Filter) -> f64 {
+        let n = 400;
+        let r = f.radius();
+        let d = 2.0 * r / n as f64;
+        let mut sum = 0.0;
+        for i in 0..n {
+            for j in 0..n {
+                let (x, y) = (-r + (i as f64 + 0.5) * d, -r + (j as f64 + 0.5) * d);
+                sum += f.evaluate(x, y) * d * d;
+            }
+        }
+        sum
+    }
+
+    #[test]
+    fn test_filter_support() {
+        let radius = [0.5, 1.5, 1.5, 2.0, 3.0];
+        for (f, r) in filters().iter().zip(radius.iter()) {
+            assert_eq!(f.radius(), *r);
+            for a in [*r + 1e-6, *r + 0.5, 10.0 * *r].iter() {
+                assert_eq!(f.evaluate(*a, 0.0), 0.0);
+                assert_eq!(f.evaluate(0.0, -*a), 0.0);
+                assert_eq!(f.evaluate(-*a, 0.1), 0.0);
+                assert_eq!(f.evaluate(*a, *a), 0.0);
+            }
+            // symmetric and highest in the middle
+            let peak = f.evaluate(0.0, 0.0);
+            for (x, y) in [(0.3, 0.1), (0.2, -0.25), (0.45, 0.45)].iter() {
+                let w = f.evaluate(*x, *y);
+                assert_eq!(w, f.evaluate(-*x, *y));
+                assert_eq!(w, f.evaluate(*y, *x));
+                assert!(w <= peak);
+            }
+            assert!(integral(f.as_ref()) > 0.0);
+        }
+    }
+
+    #[test]
+    fn test_filter_integrals() {
+        // box (2r)^2, tent r^4, Mitchell (r / 2)^2 with the cubic's unit area
+        let fs = filters();
+        assert!((integral(fs[0].as_ref()) - 1.0).abs() < 1e-9);
+        assert!((integral(fs[1].as_ref()) - 1.5f64.powi(4)).abs() < 1e-4);
+        assert!((integral(fs[3].as_ref()) - 1.0).abs() < 1e-4);
+        // the windowed sinc rings but still has about unit area
+        assert!((integral(fs[4].as_ref()) - 1.0).abs() < 0.05);
+    }
+}
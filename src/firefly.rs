@@ -0,0 +1,143 @@
+use crate::film::Film;
+use crate::hit::INF;
+use crate::vec3::Vec3;
+use std::cell::Cell;
+
+// Ways to trade bias for less noise from rare bright paths, like the
+// caustics of the glass sphere. Everything they change is counted, so the
+// render stats show when and how much bias went in.
+pub struct Firefly {
+    // largest luminance of the indirect light of one sample, INF for none
+    pub clamp_indirect: f64,
+    // a sample brighter than this many times the running mean of its pixel
+    // is cut down to that, INF for none
+    pub outlier_ratio: f64,
+    // samples a pixel needs before any of them counts as an outlier
+    pub outlier_min_samples: u32,
+    // half angle in degrees of the cone specular bounces are widened to
+    // after the first non-specular one, 0 for none
+    pub regularize: f64,
+    samples: Cell<u64>,
+    energy: Cell<f64>,
+    clamped: Cell<u64>,
+    clamped_energy: Cell<f64>,
+    outliers: Cell<u64>,
+    outlier_energy: Cell<f64>,
+    regularized: Cell<u64>,
+}
+#[allow(dead_code)]
+impl Firefly {
+    pub fn new(clamp_indirect: f64, outlier_ratio: f64, regularize: f64) -> Self {
+        Self {
+            clamp_indirect,
+            outlier_ratio,
+            outlier_min_samples: 16,
+            regularize,
+            samples: Cell::new(0),
+            energy: Cell::new(0.0),
+            clamped: Cell::new(0),
+            clamped_energy: Cell::new(0.0),
+            outliers: Cell::new(0),
+            outlier_energy: Cell::new(0.0),
+            regularized: Cell::new(0),
+        }
+    }
+    pub fn off() -> Self {
+        Self::new(INF, INF, 0.0)
+    }
+    // Scales the indirect light of one camera sample down to the clamp
+    // luminance, once per sample so the stats count samples.
+    pub fn clamp(&self, indirect: Vec3) -> Vec3 {
+        let lum = indirect.luminance();
+        if lum <= self.clamp_indirect {
+            return indirect;
+        }
+        add(&self.clamped, 1);
+        add(&self.clamped_energy, lum - self.clamp_indirect);
+        indirect * (self.clamp_indirect / lum)
+    }
+    // The factor a sample about to go into the film is scaled by, below 1
+    // for outliers. Every sample of the render passes through here.
+    pub fn outlier_scale(&self, film: &Film, px: f64, py: f64, col: Vec3) -> f64 {
+        let lum = col.luminance();
+        add(&self.samples, 1);
+        add(&self.energy, lum);
+        let (mean, count) = film.running_mean(px, py);
+        let bound = mean * self.outlier_ratio;
+        if count < self.outlier_min_samples || lum <= bound || bound <= 0.0 {
+            return 1.0;
+        }
+        add(&self.outliers, 1);
+        add(&self.outlier_energy, lum - bound);
+        bound / lum
+    }
+    pub fn count_regularized(&self) {
+        add(&self.regularized, 1);
+    }
+    pub fn report(&self) {
+        let samples = self.samples.get().max(1) as f64;
+        let energy = self.energy.get() + self.clamped_energy.get();
+        let percent = |e: f64| {
+            if energy > 0.0 {
+                100.0 * e / energy
+            } else {
+                0.0
+            }
+        };
+        if self.clamp_indirect < INF {
+            println!(
+                "indirect clamped at {}: {} samples ({:.3}%), {:.3}% of the light removed",
+                self.clamp_indirect,
+                self.clamped.get(),
+                100.0 * self.clamped.get() as f64 / samples,
+                percent(self.clamped_energy.get())
+            );
+        }
+        if self.outlier_ratio < INF {
+            println!(
+                "outliers over {} times the pixel mean: {} samples ({:.3}%), {:.3}% of the light removed",
+                self.outlier_ratio,
+                self.outliers.get(),
+                100.0 * self.outliers.get() as f64 / samples,
+                percent(self.outlier_energy.get())
+            );
+        }
+        if self.regularize > 0.0 {
+            println!(
+                "specular bounces widened to {} degrees: {}",
+                self.regularize,
+                self.regularized.get()
+            );
+        }
+        if self.clamp_indirect == INF && self.outlier_ratio == INF && self.regularize <= 0.0 {
+            println!("no firefly suppression, the render is unbiased");
+        }
+    }
+}
+
+fn add<T: Copy + std::ops::Add<Output = T>>(c: &Cell<T>, x: T) {
+    c.set(c.get() + x);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::BoxFilter;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_clamp_and_outliers() {
+        let ff = Firefly::new(2.0, 4.0, 0.0);
+        let c = ff.clamp(Vec3::ones() * 10.0);
+        assert!((c.luminance() - 2.0).abs() < 1e-9);
+        assert_eq!(ff.clamp(Vec3::ones()), Vec3::ones());
+        let mut film = Film::new(1, 1, Arc::new(BoxFilter::new(0.5)));
+        for _ in 0..16 {
+            assert_eq!(ff.outlier_scale(&film, 0.5, 0.5, Vec3::ones()), 1.0);
+            film.add_sample(0.5, 0.5, Vec3::ones());
+        }
+        let k = ff.outlier_scale(&film, 0.5, 0.5, Vec3::ones() * 100.0);
+        assert!((k - 0.04).abs() < 1e-9);
+        assert_eq!(ff.outliers.get(), 1);
+    }
+}
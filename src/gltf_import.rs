@@ -0,0 +1,566 @@
+use crate::camera::{Camera, CameraModel, OrthographicCamera};
+use crate::hit::{Cutout, HitRecord, Hittable, HittableList, Tagged};
+use crate::light::{DistantLight, Light, PointLight, SpotLight};
+use crate::material::{Material, Microfacet, NormalMap, ScatterRecord};
+use crate::mesh::{Mesh, TriangleMesh};
+use crate::ray::Ray;
+use crate::texture::{ImageTexture, Texture};
+use crate::transform::Transform;
+use crate::vec3::Vec3;
+use gltf::image::Format;
+use gltf::khr_lights_punctual::Kind;
+use gltf::texture::WrappingMode;
+use std::sync::Arc;
+
+// Everything a glTF file brings along. Meshes are baked into world space,
+// lights are the punctual ones and cameras come in file order.
+pub struct GltfScene {
+    pub world: HittableList,
+    pub delta_lights: Vec<Arc<dyn Light>>,
+    pub cameras: Vec<Arc<dyn CameraModel>>,
+}
+
+// base color factor times texture times vertex color
+struct BaseColor {
+    factor: Vec3,
+    tex: Option<Arc<dyn Texture>>,
+}
+impl Texture for BaseColor {
+    // the factor is linear, the texture is stored as sRGB
+    fn value(&self, u: f64, v: f64, p: Vec3) -> Vec3 {
+        match &self.tex {
+            Option::Some(tex) => {
+                let c = tex.value(u, v, p);
+                self.factor.change(Vec3::new(
+                    srgb_to_linear(c.x),
+                    srgb_to_linear(c.y),
+                    srgb_to_linear(c.z),
+                ))
+            }
+            Option::None => self.factor,
+        }
+    }
+    fn value_at(&self, rec: &HitRecord) -> Vec3 {
+        let col = self.value(rec.u, rec.v, rec.p);
+        match rec.vertex_col {
+            Option::Some(vc) => col.change(vc),
+            Option::None => col,
+        }
+    }
+}
+
+// base color alpha factor times the alpha channel of the texture
+struct Alpha {
+    factor: f64,
+    tex: Option<Arc<dyn Texture>>,
+}
+impl Texture for Alpha {
+    fn value(&self, u: f64, v: f64, p: Vec3) -> Vec3 {
+        let a = match &self.tex {
+            Option::Some(tex) => self.factor * tex.value(u, v, p).x,
+            Option::None => self.factor,
+        };
+        Vec3::new(a, a, a)
+    }
+}
+
+// Adds the emissive term of a glTF material to its surface.
+struct Emissive {
+    base: Arc<dyn Material>,
+    emit: Arc<dyn Texture>,
+}
+impl Material for Emissive {
+    fn scatter(&self, this_ray: &Ray, rec: &HitRecord) -> Option<ScatterRecord> {
+        self.base.scatter(this_ray, rec)
+    }
+    fn scattering_color(
+        &self,
+        this_ray: &Ray,
+        rec: &HitRecord,
+        srec: &ScatterRecord,
+        scattered: &Ray,
+    ) -> Vec3 {
+        self.base.scattering_color(this_ray, rec, srec, scattered)
+    }
+    fn specular_fraction(
+        &self,
+        this_ray: &Ray,
+        rec: &HitRecord,
+        srec: &ScatterRecord,
+        scattered: &Ray,
+    ) -> f64 {
+        self.base.specular_fraction(this_ray, rec, srec, scattered)
+    }
+    fn albedo(&self, this_ray: &Ray, rec: &HitRecord, srec: &ScatterRecord) -> Vec3 {
+        self.base.albedo(this_ray, rec, srec)
+    }
+    fn emitted(&self, _this_ray: &Ray, rec: &HitRecord, u: f64, v: f64, p: Vec3) -> Vec3 {
+        if rec.nor_dir {
+            self.emit.value(u, v, p)
+        } else {
+            Vec3::zero()
+        }
+    }
+}
+
+fn srgb_to_linear(c: f64) -> f64 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+// An image looked up through the wrap modes of a glTF sampler, ImageTexture
+// itself clamps.
+struct Wrapped {
+    tex: Arc<dyn Texture>,
+    wrap_s: WrappingMode,
+    wrap_t: WrappingMode,
+}
+fn wrap(x: f64, mode: WrappingMode) -> f64 {
+    match mode {
+        WrappingMode::ClampToEdge => x,
+        WrappingMode::Repeat => x - x.floor(),
+        WrappingMode::MirroredRepeat => {
+            let t = x.rem_euclid(2.0);
+            if t > 1.0 {
+                2.0 - t
+            } else {
+                t
+            }
+        }
+    }
+}
+impl Texture for Wrapped {
+    fn value(&self, u: f64, v: f64, p: Vec3) -> Vec3 {
+        // v is flipped on import, which wrapping does not care about
+        self.tex
+            .value(wrap(u, self.wrap_s), wrap(v, self.wrap_t), p)
+    }
+}
+
+// color and alpha of one image
+type ImagePair = (Arc<dyn Texture>, Arc<dyn Texture>);
+
+fn sampled(texture: &gltf::Texture, images: &[ImagePair]) -> ImagePair {
+    let (color, alpha) = images[texture.source().index()].clone();
+    let sampler = texture.sampler();
+    let wrapped = |tex: Arc<dyn Texture>| -> Arc<dyn Texture> {
+        Arc::new(Wrapped {
+            tex,
+            wrap_s: sampler.wrap_s(),
+            wrap_t: sampler.wrap_t(),
+        })
+    };
+    (wrapped(color), wrapped(alpha))
+}
+
+// Decoded image as 8 bit RGB plus its alpha channel spread over RGB.
+fn decode_image(img: &gltf::image::Data) -> (ImageTexture, ImageTexture) {
+    let (channels, bytes) = match img.format {
+        Format::R8 => (1, 1),
+        Format::R8G8 => (2, 1),
+        Format::R8G8B8 => (3, 1),
+        Format::R8G8B8A8 => (4, 1),
+        Format::R16 => (1, 2),
+        Format::R16G16 => (2, 2),
+        Format::R16G16B16 => (3, 2),
+        Format::R16G16B16A16 => (4, 2),
+        Format::R32G32B32FLOAT => (3, 4),
+        Format::R32G32B32A32FLOAT => (4, 4),
+    };
+    let channel = |k: usize| -> u8 {
+        let b = &img.pixels[k * bytes..(k + 1) * bytes];
+        match bytes {
+            1 => b[0],
+            2 => (u16::from_ne_bytes([b[0], b[1]]) >> 8) as u8,
+            _ => (f32::from_ne_bytes([b[0], b[1], b[2], b[3]]).clamp(0.0, 1.0) * 255.0) as u8,
+        }
+    };
+    let n = (img.width * img.height) as usize;
+    let mut rgb = Vec::with_capacity(3 * n);
+    let mut alpha = Vec::with_capacity(3 * n);
+    for i in 0..n {
+        let px: Vec<u8> = (0..channels).map(|c| channel(i * channels + c)).collect();
+        match channels {
+            1 => rgb.extend([px[0]; 3].iter()),
+            2 => rgb.extend([px[0], px[1], 0].iter()),
+            _ => rgb.extend(px[..3].iter()),
+        }
+        let a = if channels == 4 { px[3] } else { 255 };
+        alpha.extend([a; 3].iter());
+    }
+    (
+        ImageTexture::from_rgb(rgb, img.width, img.height),
+        ImageTexture::from_rgb(alpha, img.width, img.height),
+    )
+}
+
+struct GltfMaterial {
+    mat: Arc<dyn Material>,
+    alpha: Option<(Arc<dyn Texture>, Option<f64>)>,
+}
+
+fn build_material(m: &gltf::Material, images: &[ImagePair]) -> GltfMaterial {
+    let image = |info: Option<gltf::texture::Info>, what: &str| {
+        let info = info?;
+        if info.tex_coord() != 0 {
+            eprintln!(
+                "glTF: {} uses TEXCOORD_{}, only TEXCOORD_0 is read",
+                what,
+                info.tex_coord()
+            );
+        }
+        Option::Some(sampled(&info.texture(), images))
+    };
+    let pbr = m.pbr_metallic_roughness();
+    let [r, g, b, a] = pbr.base_color_factor();
+    let base_tex = image(pbr.base_color_texture(), "base color");
+    let base_color = Arc::new(BaseColor {
+        factor: Vec3::new(r as f64, g as f64, b as f64),
+        tex: base_tex.as_ref().map(|t| t.0.clone()),
+    });
+    let metal_rough = image(pbr.metallic_roughness_texture(), "metallic roughness").map(|t| t.0);
+    let mut mat: Arc<dyn Material> = Arc::new(Microfacet::newarc(
+        base_color,
+        pbr.metallic_factor() as f64,
+        pbr.roughness_factor() as f64,
+        metal_rough,
+    ));
+    if let Option::Some(normal) = m.normal_texture() {
+        if normal.tex_coord() != 0 {
+            eprintln!(
+                "glTF: normal texture uses TEXCOORD_{}, only TEXCOORD_0 is read",
+                normal.tex_coord()
+            );
+        }
+        let tex = sampled(&normal.texture(), images).0;
+        mat = Arc::new(NormalMap::new(mat, tex));
+    }
+    let [er, eg, eb] = m.emissive_factor();
+    if er > 0.0 || eg > 0.0 || eb > 0.0 {
+        let emit = Arc::new(BaseColor {
+            factor: Vec3::new(er as f64, eg as f64, eb as f64),
+            tex: image(m.emissive_texture(), "emissive").map(|t| t.0),
+        });
+        mat = Arc::new(Emissive { base: mat, emit });
+    }
+    let alpha_tex = || -> Arc<dyn Texture> {
+        Arc::new(Alpha {
+            factor: a as f64,
+            tex: base_tex.as_ref().map(|t| t.1.clone()),
+        })
+    };
+    let alpha = match m.alpha_mode() {
+        gltf::material::AlphaMode::Opaque => Option::None,
+        gltf::material::AlphaMode::Mask => Option::Some((
+            alpha_tex(),
+            Option::Some(m.alpha_cutoff().unwrap_or(0.5) as f64),
+        )),
+        gltf::material::AlphaMode::Blend => Option::Some((alpha_tex(), Option::None)),
+    };
+    GltfMaterial { mat, alpha }
+}
+
+struct Loader {
+    buffers: Vec<gltf::buffer::Data>,
+    materials: Vec<GltfMaterial>,
+    default_material: GltfMaterial,
+    aspect: f64,
+    scene: GltfScene,
+}
+
+impl Loader {
+    fn primitive(&mut self, prim: &gltf::Primitive, to_world: &Transform) {
+        if prim.mode() != gltf::mesh::Mode::Triangles {
+            eprintln!("glTF: skipping a primitive drawn as {:?}", prim.mode());
+            return;
+        }
+        let buffers = &self.buffers;
+        let reader = prim.reader(|b| Option::Some(&buffers[b.index()].0[..]));
+        let positions = match reader.read_positions() {
+            Option::Some(it) => it,
+            Option::None => return,
+        };
+        let mut mesh = TriangleMesh::new(
+            positions
+                .map(|p| to_world.point(Vec3::new(p[0] as f64, p[1] as f64, p[2] as f64)))
+                .collect(),
+            Vec::new(),
+        );
+        if let Option::Some(normals) = reader.read_normals() {
+            mesh.nor = normals
+                .map(|n| {
+                    to_world
+                        .normal(Vec3::new(n[0] as f64, n[1] as f64, n[2] as f64))
+                        .unit()
+                })
+                .collect();
+        }
+        if let Option::Some(uv) = reader.read_tex_coords(0) {
+            // glTF puts v = 0 on the top row, ImageTexture on the bottom one
+            mesh.uv = uv
+                .into_f32()
+                .map(|t| (t[0] as f64, 1.0 - t[1] as f64))
+                .collect();
+        }
+        if let Option::Some(col) = reader.read_colors(0) {
+            mesh.col = col
+                .into_rgb_f32()
+                .map(|c| Vec3::new(c[0] as f64, c[1] as f64, c[2] as f64))
+                .collect();
+        }
+        let indices: Vec<u32> = match reader.read_indices() {
+            Option::Some(it) => it.into_u32().collect(),
+            Option::None => (0..mesh.p.len() as u32).collect(),
+        };
+        let flip = to_world.swaps_handedness();
+        mesh.tris = indices
+            .chunks_exact(3)
+            .map(|t| {
+                if flip {
+                    [t[0], t[2], t[1]]
+                } else {
+                    [t[0], t[1], t[2]]
+                }
+            })
+            .collect();
+        if mesh.tris.is_empty() {
+            return;
+        }
+        let material = match prim.material().index() {
+            Option::Some(i) => &self.materials[i],
+            Option::None => &self.default_material,
+        };
+        let shape: Arc<dyn Hittable> = Arc::new(Mesh::new(Arc::new(mesh), material.mat.clone()));
+        let shape: Arc<dyn Hittable> = match &material.alpha {
+            Option::None => shape,
+            Option::Some((alpha, Option::Some(cutoff))) => {
+                Arc::new(Cutout::new(shape, alpha.clone(), *cutoff))
+            }
+            Option::Some((alpha, Option::None)) => {
+                Arc::new(Cutout::new_stochastic(shape, alpha.clone()))
+            }
+        };
+        // one object ID per primitive
+        let id = self.scene.world.objects.len() as u32 + 1;
+        self.scene.world.add(Arc::new(Tagged::new(shape, id)));
+    }
+    fn camera(&mut self, cam: &gltf::Camera, to_world: &Transform) {
+        // cameras look down -z with +y up
+        let lookfrom = to_world.point(Vec3::zero());
+        let lookat = to_world.point(Vec3::new(0.0, 0.0, -1.0));
+        let vup = to_world.vector(Vec3::new(0.0, 1.0, 0.0));
+        let model: Arc<dyn CameraModel> = match cam.projection() {
+            gltf::camera::Projection::Perspective(p) => Arc::new(Camera::new(
+                lookfrom,
+                lookat,
+                vup,
+                (p.yfov() as f64).to_degrees(),
+                p.aspect_ratio().map_or(self.aspect, |a| a as f64),
+                0.0,
+                1.0,
+                0.0,
+                1.0,
+            )),
+            gltf::camera::Projection::Orthographic(o) => Arc::new(OrthographicCamera::new(
+                lookfrom,
+                lookat,
+                vup,
+                2.0 * o.ymag() as f64,
+                (o.xmag() / o.ymag()) as f64,
+                0.0,
+                1.0,
+            )),
+        };
+        self.scene.cameras.push(model);
+    }
+    // glTF intensities are photometric (candela, lux) and used as they are.
+    fn light(&mut self, light: &gltf::khr_lights_punctual::Light, to_world: &Transform) {
+        let [r, g, b] = light.color();
+        let intensity = Vec3::new(r as f64, g as f64, b as f64) * light.intensity() as f64;
+        let pos = to_world.point(Vec3::zero());
+        let dir = to_world.vector(Vec3::new(0.0, 0.0, -1.0)).unit();
+        let l: Arc<dyn Light> = match light.kind() {
+            Kind::Directional => Arc::new(DistantLight::new(dir, intensity)),
+            Kind::Point => Arc::new(PointLight::new(pos, intensity, 0.0)),
+            Kind::Spot {
+                inner_cone_angle,
+                outer_cone_angle,
+            } => Arc::new(SpotLight::new(
+                pos,
+                pos + dir,
+                intensity,
+                (outer_cone_angle as f64).to_degrees(),
+                (inner_cone_angle as f64).to_degrees(),
+                1.0,
+                Option::None,
+            )),
+        };
+        if light.range().is_some() {
+            eprintln!("glTF: light range is ignored, lights fall off with 1 / d^2");
+        }
+        self.scene.delta_lights.push(l);
+    }
+    fn node(&mut self, node: &gltf::Node, parent: &Transform) {
+        let cols = node.transform().matrix();
+        let mut m = [[0.0; 4]; 4];
+        for (c, col) in cols.iter().enumerate() {
+            for (r, x) in col.iter().enumerate() {
+                m[r][c] = *x as f64;
+            }
+        }
+        let to_world = match Transform::from_matrix(m) {
+            Option::Some(local) => *parent * local,
+            Option::None => {
+                eprintln!(
+                    "glTF: skipping node {} with a singular transform",
+                    node.index()
+                );
+                return;
+            }
+        };
+        if node.skin().is_some() || node.weights().is_some() {
+            eprintln!("glTF: skins and morph targets are ignored, using the rest pose");
+        }
+        if let Option::Some(mesh) = node.mesh() {
+            for prim in mesh.primitives() {
+                self.primitive(&prim, &to_world);
+            }
+        }
+        if let Option::Some(cam) = node.camera() {
+            self.camera(&cam, &to_world);
+        }
+        if let Option::Some(light) = node.light() {
+            self.light(&light, &to_world);
+        }
+        for child in node.children() {
+            self.node(&child, &to_world);
+        }
+    }
+}
+
+// Loads the default scene of a .gltf or .glb file. `aspect` is used for
+// perspective cameras that do not fix their own aspect ratio.
+#[allow(dead_code)]
+pub fn load_gltf(filename: &str, aspect: f64) -> GltfScene {
+    let (doc, buffers, images) =
+        gltf::import(filename).unwrap_or_else(|e| panic!("Cannot read {}: {}", filename, e));
+    let images: Vec<ImagePair> = images
+        .iter()
+        .map(|img| {
+            let (rgb, alpha) = decode_image(img);
+            (
+                Arc::new(rgb) as Arc<dyn Texture>,
+                Arc::new(alpha) as Arc<dyn Texture>,
+            )
+        })
+        .collect();
+    let materials = doc
+        .materials()
+        .map(|m| build_material(&m, &images))
+        .collect();
+    let mut loader = Loader {
+        buffers,
+        materials,
+        // the glTF default material
+        default_material: GltfMaterial {
+            mat: Arc::new(Microfacet::new(Vec3::ones(), 1.0, 1.0)),
+            alpha: Option::None,
+        },
+        aspect,
+        scene: GltfScene {
+            world: HittableList::default(),
+            delta_lights: Vec::new(),
+            cameras: Vec::new(),
+        },
+    };
+    let scene = doc
+        .default_scene()
+        .or_else(|| doc.scenes().next())
+        .unwrap_or_else(|| panic!("Cannot decode {}: no scene", filename));
+    for node in scene.nodes() {
+        loader.node(&node, &Transform::identity());
+    }
+    loader.scene
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_texture_decoding() {
+        // black then mid gray
+        let image: Arc<dyn Texture> =
+            Arc::new(ImageTexture::from_rgb(vec![0, 0, 0, 188, 188, 188], 2, 1));
+        let tex = |mode| Wrapped {
+            tex: image.clone(),
+            wrap_s: mode,
+            wrap_t: mode,
+        };
+        let at = |t: &Wrapped, u: f64| t.value(u, 0.5, Vec3::zero()).x;
+        let repeat = tex(WrappingMode::Repeat);
+        assert_eq!(at(&repeat, 1.75), at(&repeat, 0.75));
+        assert_eq!(at(&repeat, -0.25), at(&repeat, 0.75));
+        let mirror = tex(WrappingMode::MirroredRepeat);
+        assert_eq!(at(&mirror, 1.25), at(&mirror, 0.75));
+        let clamp = tex(WrappingMode::ClampToEdge);
+        assert_eq!(at(&clamp, 1.75), at(&clamp, 0.75));
+        assert_eq!(at(&clamp, -0.75), 0.0);
+        // sRGB 188 is about half the light of white
+        let base = BaseColor {
+            factor: Vec3::ones(),
+            tex: Option::Some(image.clone()),
+        };
+        let c = base.value(0.75, 0.5, Vec3::zero());
+        assert!((c.x - 0.5).abs() < 0.01, "{}", c.x);
+    }
+
+    #[test]
+    fn test_node_hierarchy() {
+        let json = r#"{
+            "asset": {"version": "2.0"},
+            "extensionsUsed": ["KHR_lights_punctual"],
+            "extensions": {"KHR_lights_punctual": {"lights": [
+                {"type": "point", "color": [1, 0.5, 0.5], "intensity": 4}
+            ]}},
+            "scene": 0,
+            "scenes": [{"nodes": [0, 2, 3]}],
+            "nodes": [
+                {"scale": [2, 2, 2], "children": [1]},
+                {"translation": [0, 0, -5], "mesh": 0},
+                {"camera": 0},
+                {"translation": [0, 3, 0], "extensions": {"KHR_lights_punctual": {"light": 0}}}
+            ],
+            "cameras": [{"type": "perspective", "perspective": {"yfov": 0.8, "znear": 0.1}}],
+            "meshes": [{"primitives": [{"attributes": {"POSITION": 0}, "indices": 1}]}],
+            "buffers": [{"byteLength": 44, "uri": "data:application/octet-stream;base64,AACAvwAAgL8AAAAAAACAPwAAgL8AAAAAAAAAAAAAgD8AAAAAAAABAAIAAAA="}],
+            "bufferViews": [
+                {"buffer": 0, "byteOffset": 0, "byteLength": 36},
+                {"buffer": 0, "byteOffset": 36, "byteLength": 6}
+            ],
+            "accessors": [
+                {"bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+                 "min": [-1, -1, 0], "max": [1, 1, 0]},
+                {"bufferView": 1, "componentType": 5123, "count": 3, "type": "SCALAR"}
+            ]
+        }"#;
+        let path = std::env::temp_dir().join("raytracer_test_node_hierarchy.gltf");
+        std::fs::write(&path, json).unwrap();
+        let scene = load_gltf(path.to_str().unwrap(), 1.5);
+        assert_eq!(scene.cameras.len(), 1);
+        assert_eq!(scene.delta_lights.len(), 1);
+        let ray = scene.cameras[0].get_ray(0.5, 0.5);
+        assert!((ray.dir.unit() - Vec3::new(0.0, 0.0, -1.0)).length() < 1e-9);
+        // the triangle is scaled by 2 after moving to z = -5
+        let rec = scene.world.hit(&ray, 0.001, f64::MAX).unwrap();
+        assert!((rec.p - Vec3::new(0.0, 0.0, -10.0)).length() < 1e-9);
+        let edge = Ray::new(Vec3::new(1.9, -1.9, 0.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        assert!(scene.world.hit(&edge, 0.001, f64::MAX).is_some());
+        let ls = scene.delta_lights[0].sample_li(Vec3::zero()).unwrap();
+        assert!((ls.dis - 3.0).abs() < 1e-9);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,349 @@
+use crate::aabb::Aabb;
+use crate::hit::{HitRecord, Hittable};
+use crate::material::Material;
+use crate::mesh::hit_triangle;
+use crate::ray::Ray;
+use crate::vec3::Vec3;
+use std::sync::Arc;
+pub const INF: f64 = f64::MAX;
+
+// Terrain over a regular grid of nx * nz height samples. Each cell is two
+// triangles, rays walk the cells with a 2D DDA so nothing is tessellated.
+// `corner` is the (x0, base, z0) corner, `size` holds the x extent, the
+// height of a sample of 1 and the z extent. u runs along x and v along z.
+pub struct Heightfield {
+    nx: usize,
+    nz: usize,
+    // world space heights, row j holds z = z0 + j * dz
+    heights: Vec<f64>,
+    normals: Vec<Vec3>,
+    corner: Vec3,
+    size: Vec3,
+    dx: f64,
+    dz: f64,
+    hmin: f64,
+    hmax: f64,
+    mp: Arc<dyn Material>,
+}
+#[allow(dead_code)]
+impl Heightfield {
+    pub fn new(
+        samples: Vec<f64>,
+        nx: usize,
+        nz: usize,
+        corner: Vec3,
+        size: Vec3,
+        mp: Arc<dyn Material>,
+    ) -> Self {
+        assert!(nx >= 2 && nz >= 2, "Heightfield needs at least 2x2 samples");
+        assert_eq!(samples.len(), nx * nz);
+        let heights: Vec<f64> = samples.iter().map(|h| corner.y + h * size.y).collect();
+        let hmin = heights.iter().cloned().fold(INF, f64::min);
+        let hmax = heights.iter().cloned().fold(-INF, f64::max);
+        let dx = size.x / (nx - 1) as f64;
+        let dz = size.z / (nz - 1) as f64;
+        let mut res = Self {
+            nx,
+            nz,
+            heights,
+            normals: Vec::new(),
+            corner,
+            size,
+            dx,
+            dz,
+            hmin,
+            hmax,
+            mp,
+        };
+        res.normals = (0..nx * nz)
+            .map(|k| res.vertex_normal(k % nx, k / nx))
+            .collect();
+        res
+    }
+    // Brightness of a grayscale image, the top row is at z0. 16 bit images
+    // keep their precision.
+    pub fn from_image(filename: &str, corner: Vec3, size: Vec3, mp: Arc<dyn Material>) -> Self {
+        let img =
+            image::open(filename).unwrap_or_else(|_| panic!("Cannot open heightmap {}", filename));
+        let (nx, nz, samples) = match img {
+            image::DynamicImage::ImageLuma16(buf) => {
+                let (w, h) = buf.dimensions();
+                let samples = buf.pixels().map(|p| p[0] as f64 / 65535.0).collect();
+                (w, h, samples)
+            }
+            img => {
+                let buf = img.to_luma();
+                let (w, h) = buf.dimensions();
+                let samples = buf.pixels().map(|p| p[0] as f64 / 255.0).collect();
+                (w, h, samples)
+            }
+        };
+        Self::new(samples, nx as usize, nz as usize, corner, size, mp)
+    }
+    // Samples f(u, v) with u, v in [0, 1] on an nx * nz grid.
+    pub fn from_fn<F: Fn(f64, f64) -> f64>(
+        f: F,
+        nx: usize,
+        nz: usize,
+        corner: Vec3,
+        size: Vec3,
+        mp: Arc<dyn Material>,
+    ) -> Self {
+        let mut samples = Vec::with_capacity(nx * nz);
+        for j in 0..nz {
+            for i in 0..nx {
+                samples.push(f(i as f64 / (nx - 1) as f64, j as f64 / (nz - 1) as f64));
+            }
+        }
+        Self::new(samples, nx, nz, corner, size, mp)
+    }
+    fn height(&self, i: usize, j: usize) -> f64 {
+        self.heights[j * self.nx + i]
+    }
+    fn vertex(&self, i: usize, j: usize) -> Vec3 {
+        Vec3::new(
+            self.corner.x + i as f64 * self.dx,
+            self.height(i, j),
+            self.corner.z + j as f64 * self.dz,
+        )
+    }
+    // Central differences, one sided on the border.
+    fn vertex_normal(&self, i: usize, j: usize) -> Vec3 {
+        let (i0, i1) = (i.saturating_sub(1), (i + 1).min(self.nx - 1));
+        let (j0, j1) = (j.saturating_sub(1), (j + 1).min(self.nz - 1));
+        let dhdx = (self.height(i1, j) - self.height(i0, j)) / ((i1 - i0) as f64 * self.dx);
+        let dhdz = (self.height(i, j1) - self.height(i, j0)) / ((j1 - j0) as f64 * self.dz);
+        Vec3::new(-dhdx, 1.0, -dhdz).unit()
+    }
+    // Closest hit with the two triangles of cell (i, j).
+    fn hit_cell(
+        &self,
+        this_ray: &Ray,
+        i: usize,
+        j: usize,
+        tmn: f64,
+        tmx: f64,
+    ) -> Option<HitRecord> {
+        let corners = [(i, j), (i + 1, j), (i + 1, j + 1), (i, j + 1)];
+        let verts = corners.map(|(a, b)| self.vertex(a, b));
+        let mut best: Option<(f64, [usize; 3], f64, f64)> = Option::None;
+        let mut tmx = tmx;
+        for tri in [[0, 1, 2], [0, 2, 3]].iter() {
+            let [a, b, c] = tri.map(|k| verts[k]);
+            if let Option::Some((t, b1, b2)) = hit_triangle(this_ray, a, b, c, tmn, tmx) {
+                tmx = t;
+                best = Option::Some((t, *tri, b1, b2));
+            }
+        }
+        let (t, tri, b1, b2) = best?;
+        let [p0, p1, p2] = tri.map(|k| verts[k]);
+        let idx = tri.map(|k| corners[k].1 * self.nx + corners[k].0);
+        let mut geo = Vec3::cross(p1 - p0, p2 - p0).unit();
+        if geo.y < 0.0 {
+            geo = -geo;
+        }
+        let smooth = (self.normals[idx[0]] * (1.0 - b1 - b2)
+            + self.normals[idx[1]] * b1
+            + self.normals[idx[2]] * b2)
+            .unit();
+        let p = this_ray.pos(t);
+        // the plane of the triangle is y = h + dhdx * x + dhdz * z
+        let dhdx = -geo.x / geo.y;
+        let dhdz = -geo.z / geo.y;
+        let mut rec = HitRecord {
+            p,
+            nor: Vec3::zero(),
+            geo_nor: Vec3::zero(),
+            dpdu: Vec3::new(self.size.x, self.size.x * dhdx, 0.0),
+            dpdv: Vec3::new(0.0, self.size.z * dhdz, self.size.z),
+            dndu: Vec3::zero(),
+            dndv: Vec3::zero(),
+            t,
+            u: ((p.x - self.corner.x) / self.size.x).clamp(0.0, 1.0),
+            v: ((p.z - self.corner.z) / self.size.z).clamp(0.0, 1.0),
+            nor_dir: false,
+            mat_ptr: self.mp.clone(),
+            vertex_col: Option::None,
+            object_id: 0,
+        };
+        rec.set_face_normal(this_ray, geo);
+        rec.nor = if rec.nor_dir { smooth } else { -smooth };
+        Option::Some(rec)
+    }
+}
+impl Hittable for Heightfield {
+    fn hit(&self, this_ray: &Ray, tmn: f64, tmx: f64) -> Option<HitRecord> {
+        let bbox = self.bounding_box(0.0, 0.0)?;
+        let (mut t, t_end) = slab(&bbox, this_ray, tmn, tmx)?;
+        let ori = this_ray.ori;
+        let dir = this_ray.dir;
+        let start = this_ray.pos(t);
+        let cell = |x: f64, d: f64, n: usize| ((x / d).floor().max(0.0) as usize).min(n - 2);
+        let mut i = cell(start.x - self.corner.x, self.dx, self.nx);
+        let mut j = cell(start.z - self.corner.z, self.dz, self.nz);
+        // parameter of the next cell border along x and z, and the step
+        // between two borders
+        let axis = |o: f64, d: f64, c: f64, k: usize, w: f64| {
+            if d > 0.0 {
+                ((c + (k + 1) as f64 * w - o) / d, w / d)
+            } else if d < 0.0 {
+                ((c + k as f64 * w - o) / d, -w / d)
+            } else {
+                (INF, INF)
+            }
+        };
+        let (mut next_x, delta_x) = axis(ori.x, dir.x, self.corner.x, i, self.dx);
+        let (mut next_z, delta_z) = axis(ori.z, dir.z, self.corner.z, j, self.dz);
+        loop {
+            let t_exit = next_x.min(next_z).min(t_end);
+            // skip the triangles when the ray stays above or below the cell
+            let (y0, y1) = (this_ray.pos(t).y, this_ray.pos(t_exit).y);
+            let corners = [
+                self.height(i, j),
+                self.height(i + 1, j),
+                self.height(i, j + 1),
+                self.height(i + 1, j + 1),
+            ];
+            let cmin = corners.iter().cloned().fold(INF, f64::min);
+            let cmax = corners.iter().cloned().fold(-INF, f64::max);
+            if y0.min(y1) <= cmax && y0.max(y1) >= cmin {
+                if let Option::Some(rec) = self.hit_cell(this_ray, i, j, tmn, tmx) {
+                    return Option::Some(rec);
+                }
+            }
+            if t_exit >= t_end {
+                return Option::None;
+            }
+            t = t_exit;
+            if next_x < next_z {
+                if dir.x > 0.0 {
+                    if i + 2 >= self.nx {
+                        return Option::None;
+                    }
+                    i += 1;
+                } else {
+                    if i == 0 {
+                        return Option::None;
+                    }
+                    i -= 1;
+                }
+                next_x += delta_x;
+            } else {
+                if dir.z > 0.0 {
+                    if j + 2 >= self.nz {
+                        return Option::None;
+                    }
+                    j += 1;
+                } else {
+                    if j == 0 {
+                        return Option::None;
+                    }
+                    j -= 1;
+                }
+                next_z += delta_z;
+            }
+        }
+    }
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
+        let pad = 0.001;
+        Option::Some(Aabb::new(
+            Vec3::new(self.corner.x, self.hmin - pad, self.corner.z),
+            Vec3::new(
+                self.corner.x + self.size.x,
+                self.hmax + pad,
+                self.corner.z + self.size.z,
+            ),
+        ))
+    }
+}
+
+// Parameter range of the ray inside the box.
+fn slab(bbox: &Aabb, this_ray: &Ray, tmn: f64, tmx: f64) -> Option<(f64, f64)> {
+    let mut t0 = tmn;
+    let mut t1 = tmx;
+    for i in 0..3 {
+        let inv = 1.0 / this_ray.dir.get(i);
+        let mut near = (bbox.mn.get(i) - this_ray.ori.get(i)) * inv;
+        let mut far = (bbox.mx.get(i) - this_ray.ori.get(i)) * inv;
+        if inv < 0.0 {
+            std::mem::swap(&mut near, &mut far);
+        }
+        // a NaN from 0 * inf means the ray lies on the slab, keep the range
+        if near > t0 {
+            t0 = near;
+        }
+        if far < t1 {
+            t1 = far;
+        }
+        if t1 < t0 {
+            return Option::None;
+        }
+    }
+    Option::Some((t0, t1))
+}
+
+// Smooth value noise summed over octaves, roughly in [0, 1]. Good enough
+// for procedural terrain through Heightfield::from_fn.
+#[allow(dead_code)]
+pub fn fbm_noise(x: f64, z: f64, octaves: u32, seed: u64) -> f64 {
+    let lattice = |i: i64, j: i64, o: u32| -> f64 {
+        let mut h = seed ^ (o as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        for k in [i as u64, j as u64].iter() {
+            h ^= *k;
+            h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            h ^= h >> 31;
+        }
+        (h >> 11) as f64 / (1u64 << 53) as f64
+    };
+    let fade = |t: f64| t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+    let mut sum = 0.0;
+    let mut norm = 0.0;
+    let mut amp = 1.0;
+    let mut freq = 1.0;
+    for o in 0..octaves {
+        let (fx, fz) = (x * freq, z * freq);
+        let (i, j) = (fx.floor() as i64, fz.floor() as i64);
+        let (sx, sz) = (fade(fx - i as f64), fade(fz - j as f64));
+        let a = lattice(i, j, o) * (1.0 - sx) + lattice(i + 1, j, o) * sx;
+        let b = lattice(i, j + 1, o) * (1.0 - sx) + lattice(i + 1, j + 1, o) * sx;
+        sum += amp * (a * (1.0 - sz) + b * sz);
+        norm += amp;
+        amp *= 0.5;
+        freq *= 2.0;
+    }
+    sum / norm
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::Lambertian;
+
+    #[test]
+    fn test_heightfield_matches_surface() {
+        let f = |u: f64, v: f64| (u * 6.0).sin() * (v * 4.0).cos() * 0.5 + 0.5;
+        let hf = Heightfield::from_fn(
+            f,
+            65,
+            65,
+            Vec3::new(-1.0, 0.0, -1.0),
+            Vec3::new(2.0, 0.5, 2.0),
+            Arc::new(Lambertian::new(Vec3::ones())),
+        );
+        for k in 0..200 {
+            let x = -0.95 + 1.9 * (k as f64 * 0.618).fract();
+            let z = -0.95 + 1.9 * (k as f64 * 0.377).fract();
+            let dir = Vec3::new(0.3 * (k as f64).sin(), -1.0, 0.2);
+            let ray = Ray::new(Vec3::new(x, 0.25, z) - dir * 2.0, dir, 0.0);
+            let rec = hf.hit(&ray, 0.0, INF).expect("missed the terrain");
+            let expect = f((rec.p.x + 1.0) / 2.0, (rec.p.z + 1.0) / 2.0) * 0.5;
+            assert!((rec.p.y - expect).abs() < 2e-3);
+            assert!(rec.nor_dir && rec.nor.y > 0.0);
+        }
+        // from below the ray sees the back faces
+        let up = Ray::new(Vec3::new(0.1, -1.0, 0.2), Vec3::new(0.0, 1.0, 0.0), 0.0);
+        let rec = hf.hit(&up, 0.0, INF).unwrap();
+        assert!(!rec.nor_dir && rec.nor.y < 0.0);
+    }
+}
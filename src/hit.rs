@@ -4,38 +4,73 @@ use crate::material::Material;
 use crate::onb::ONB;
 use crate::random::*;
 use crate::ray::Ray;
+use crate::texture::Texture;
 use crate::vec3::Vec3;
 use std::sync::Arc;
 pub const PI: f64 = std::f64::consts::PI;
-pub const INF: f64 = std::f64::MAX;
+pub const INF: f64 = f64::MAX;
 #[derive(Clone)]
 pub struct HitRecord {
     pub p: Vec3,
+    // shading normal, may be perturbed by the material
     pub nor: Vec3,
+    // geometric normal, always on the same side as nor_dir says
+    pub geo_nor: Vec3,
+    // partial derivatives of p with respect to u and v
+    pub dpdu: Vec3,
+    pub dpdv: Vec3,
+    // partial derivatives of the outward normal, zero where it is constant
+    pub dndu: Vec3,
+    pub dndv: Vec3,
     pub t: f64,
     pub u: f64,
     pub v: f64,
     pub nor_dir: bool,
     pub mat_ptr: Arc<dyn Material>,
+    // interpolated per vertex color, only meshes that carry one set it
+    pub vertex_col: Option<Vec3>,
+    // set by the innermost Tagged around the shape, 0 for none
+    pub object_id: u32,
 }
 impl HitRecord {
-    fn set_face_normal(&mut self, this_ray: &Ray, out_nor: Vec3) {
+    pub fn set_face_normal(&mut self, this_ray: &Ray, out_nor: Vec3) {
         self.nor_dir = this_ray.dir * out_nor < 0.0;
         if self.nor_dir {
             self.nor = out_nor;
         } else {
             self.nor = -out_nor;
         }
+        self.geo_nor = self.nor;
     }
 }
-pub trait Hittable {
+pub trait Hittable: Send + Sync {
     fn hit(&self, this_ray: &Ray, tmn: f64, tmx: f64) -> Option<HitRecord>;
     fn bounding_box(&self, t0: f64, t1: f64) -> Option<Aabb>;
+    // Only shapes that can be sampled as lights implement these, putting
+    // anything else in the lights list is a bug in the scene.
     fn pdf_value(&self, _o: Vec3, _v: Vec3) -> f64 {
-        0.0
+        panic!("This Hittable can not be sampled as a light")
     }
     fn random(&self, _o: Vec3) -> Vec3 {
-        Vec3::new(1.0, 0.0, 0.0)
+        panic!("This Hittable can not be sampled as a light")
+    }
+    // Every crossing of the surface in [tmn, tmx] in order, nor_dir tells
+    // entering from leaving for closed shapes.
+    fn hit_all(&self, this_ray: &Ray, tmn: f64, tmx: f64) -> Vec<HitRecord> {
+        let mut res = Vec::new();
+        let mut tmn = tmn;
+        while res.len() < 64 {
+            match self.hit(this_ray, tmn, tmx) {
+                Option::Some(rec) => {
+                    // step past the surface by a distance, t is in units of the
+                    // direction's length
+                    tmn = rec.t + 0.0001 / this_ray.dir.length();
+                    res.push(rec);
+                }
+                Option::None => break,
+            }
+        }
+        res
     }
 }
 pub fn get_sphere_uv(p: Vec3, u: &mut f64, v: &mut f64) {
@@ -44,6 +79,12 @@ pub fn get_sphere_uv(p: Vec3, u: &mut f64, v: &mut f64) {
     *u = 1.0 - (phi + PI) / (2.0 * PI);
     *v = (theta + PI / 2.0) / PI;
 }
+// p is the unit outward normal, matches the parameterization of get_sphere_uv
+pub fn get_sphere_dp(p: Vec3, radius: f64, dpdu: &mut Vec3, dpdv: &mut Vec3) {
+    let rho = (p.x * p.x + p.z * p.z).sqrt().max(1e-12);
+    *dpdu = Vec3::new(p.z, 0.0, -p.x) * (2.0 * PI * radius);
+    *dpdv = Vec3::new(-p.y * p.x / rho, rho, -p.y * p.z / rho) * (PI * radius);
+}
 pub struct Sphere {
     pub center: Vec3,
     pub radius: f64,
@@ -69,11 +110,18 @@ impl Hittable for Sphere {
         let mut rec: HitRecord = HitRecord {
             p: Vec3::zero(),
             nor: Vec3::zero(),
+            geo_nor: Vec3::zero(),
+            dpdu: Vec3::zero(),
+            dpdv: Vec3::zero(),
+            dndu: Vec3::zero(),
+            dndv: Vec3::zero(),
             t: 0.0,
             u: 0.0,
             v: 0.0,
             nor_dir: false,
             mat_ptr: self.mat_ptr.clone(),
+            vertex_col: Option::None,
+            object_id: 0,
         };
         if dt > 0.0 {
             let root = dt.sqrt();
@@ -83,7 +131,10 @@ impl Hittable for Sphere {
                 rec.p = this_ray.pos(t);
                 let out_nor = (rec.p - self.center) / self.radius;
                 rec.set_face_normal(this_ray, out_nor);
-                get_sphere_uv((rec.p - self.center) / self.radius, &mut rec.u, &mut rec.v);
+                get_sphere_uv(out_nor, &mut rec.u, &mut rec.v);
+                get_sphere_dp(out_nor, self.radius, &mut rec.dpdu, &mut rec.dpdv);
+                rec.dndu = rec.dpdu / self.radius;
+                rec.dndv = rec.dpdv / self.radius;
                 // rec.mat_ptr = self.mat_ptr;
                 return Some(rec);
             }
@@ -93,7 +144,10 @@ impl Hittable for Sphere {
                 rec.p = this_ray.pos(t);
                 let out_nor = (rec.p - self.center) / self.radius;
                 rec.set_face_normal(this_ray, out_nor);
-                get_sphere_uv((rec.p - self.center) / self.radius, &mut rec.u, &mut rec.v);
+                get_sphere_uv(out_nor, &mut rec.u, &mut rec.v);
+                get_sphere_dp(out_nor, self.radius, &mut rec.dpdu, &mut rec.dpdv);
+                rec.dndu = rec.dpdu / self.radius;
+                rec.dndv = rec.dpdv / self.radius;
                 // rec.mat_ptr = self.mat_ptr;
                 return Some(rec);
             }
@@ -154,6 +208,13 @@ impl MovingSphere {
         self.center0
             + ((self.center1 - self.center0) * ((time - self.time0) / (self.time1 - self.time0)))
     }
+    fn bounding_sphere(&self) -> (Vec3, f64) {
+        let center = (self.center0 + self.center1) / 2.0;
+        (
+            center,
+            self.radius + (self.center1 - self.center0).length() / 2.0,
+        )
+    }
 }
 impl Hittable for MovingSphere {
     fn hit(&self, this_ray: &Ray, tmn: f64, tmx: f64) -> Option<HitRecord> {
@@ -165,11 +226,18 @@ impl Hittable for MovingSphere {
         let mut rec: HitRecord = HitRecord {
             p: Vec3::zero(),
             nor: Vec3::zero(),
+            geo_nor: Vec3::zero(),
+            dpdu: Vec3::zero(),
+            dpdv: Vec3::zero(),
+            dndu: Vec3::zero(),
+            dndv: Vec3::zero(),
             t: 0.0,
             u: 0.0,
             v: 0.0,
             nor_dir: false,
             mat_ptr: self.mat_ptr.clone(),
+            vertex_col: Option::None,
+            object_id: 0,
         };
         if dt > 0.0 {
             let root = dt.sqrt();
@@ -180,6 +248,9 @@ impl Hittable for MovingSphere {
                 let out_nor = (rec.p - self.center(this_ray.tm)) / self.radius;
                 rec.set_face_normal(this_ray, out_nor);
                 get_sphere_uv(out_nor, &mut rec.u, &mut rec.v);
+                get_sphere_dp(out_nor, self.radius, &mut rec.dpdu, &mut rec.dpdv);
+                rec.dndu = rec.dpdu / self.radius;
+                rec.dndv = rec.dpdv / self.radius;
                 // rec.mat_ptr = self.mat_ptr;
                 return Some(rec);
             }
@@ -190,6 +261,9 @@ impl Hittable for MovingSphere {
                 let out_nor = (rec.p - self.center(this_ray.tm)) / self.radius;
                 rec.set_face_normal(this_ray, out_nor);
                 get_sphere_uv(out_nor, &mut rec.u, &mut rec.v);
+                get_sphere_dp(out_nor, self.radius, &mut rec.dpdu, &mut rec.dpdv);
+                rec.dndu = rec.dpdu / self.radius;
+                rec.dndv = rec.dpdv / self.radius;
                 // rec.mat_ptr = self.mat_ptr;
                 return Some(rec);
             }
@@ -207,6 +281,31 @@ impl Hittable for MovingSphere {
         };
         Some(Aabb::surrounding_box(box0, box1))
     }
+    // Directions are sampled toward a sphere bounding the whole motion,
+    // pdf_value and random have no time to pick a center with.
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+        let (center, radius) = self.bounding_sphere();
+        let dir = center - o;
+        let dis_squared = dir.squared_length();
+        if dis_squared <= radius * radius {
+            return 1.0 / (4.0 * PI);
+        }
+        let costheta_max = (1.0 - radius * radius / dis_squared).sqrt();
+        if v.unit() * dir.unit() < costheta_max {
+            return 0.0;
+        }
+        1.0 / (2.0 * PI * (1.0 - costheta_max))
+    }
+    fn random(&self, o: Vec3) -> Vec3 {
+        let (center, radius) = self.bounding_sphere();
+        let dir = center - o;
+        let dis_squared = dir.squared_length();
+        if dis_squared <= radius * radius {
+            return random_unit_vector();
+        }
+        let uvw = ONB::buildw(dir);
+        uvw.change(random_to_sphere(radius, dis_squared))
+    }
 }
 #[derive(Default)]
 pub struct HittableList {
@@ -275,27 +374,33 @@ impl Hittable for BvhNode {
         if !self.mybox.hit(*this_ray, tmn, tmx) {
             Option::None
         } else {
-            let hit_left = self.left.hit(&this_ray, tmn, tmx);
+            let hit_left = self.left.hit(this_ray, tmn, tmx);
             if let Option::Some(rec_left) = hit_left {
-                let hit_right = self.right.hit(&this_ray, tmn, rec_left.t);
+                let hit_right = self.right.hit(this_ray, tmn, rec_left.t);
                 if let Option::Some(rec_right) = hit_right {
                     Option::Some(rec_right)
                 } else {
                     Option::Some(rec_left)
                 }
             } else {
-                let hit_right = self.right.hit(&this_ray, tmn, tmx);
-                if let Option::Some(rec_right) = hit_right {
-                    Option::Some(rec_right)
-                } else {
-                    Option::None
-                }
+                self.right.hit(this_ray, tmn, tmx)
             }
         }
     }
     fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
         Some(self.mybox.clone())
     }
+    // an even mixture of the two halves
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+        0.5 * self.left.pdf_value(o, v) + 0.5 * self.right.pdf_value(o, v)
+    }
+    fn random(&self, o: Vec3) -> Vec3 {
+        if get_rand01() < 0.5 {
+            self.left.random(o)
+        } else {
+            self.right.random(o)
+        }
+    }
 }
 #[allow(dead_code)]
 impl BvhNode {
@@ -366,11 +471,18 @@ impl Hittable for XyRect {
         let mut rec: HitRecord = HitRecord {
             p: this_ray.pos(t),
             nor: Vec3::zero(),
+            geo_nor: Vec3::zero(),
+            dpdu: Vec3::new(self.x1 - self.x0, 0.0, 0.0),
+            dpdv: Vec3::new(0.0, self.y1 - self.y0, 0.0),
+            dndu: Vec3::zero(),
+            dndv: Vec3::zero(),
             t,
             u: (x - self.x0) / (self.x1 - self.x0),
             v: (y - self.y0) / (self.y1 - self.y0),
             nor_dir: false,
             mat_ptr: self.mp.clone(),
+            vertex_col: Option::None,
+            object_id: 0,
         };
         let outward_normal = Vec3::new(0.0, 0.0, 1.0);
         rec.set_face_normal(this_ray, outward_normal);
@@ -382,6 +494,24 @@ impl Hittable for XyRect {
             Vec3::new(self.x1, self.y1, self.k + 0.001),
         ))
     }
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+        if let Option::Some(rec) = self.hit(&Ray::new(o, v, 0.0), 0.001, INF) {
+            let area = (self.x1 - self.x0) * (self.y1 - self.y0);
+            let dis_squared = rec.t * rec.t * v.squared_length();
+            let cos = ((v * rec.nor) / v.length()).abs();
+            dis_squared / (cos * area)
+        } else {
+            0.0
+        }
+    }
+    fn random(&self, o: Vec3) -> Vec3 {
+        let random_point = Vec3::new(
+            get_rand(self.x0, self.x1),
+            get_rand(self.y0, self.y1),
+            self.k,
+        );
+        random_point - o
+    }
 }
 
 pub struct XzRect {
@@ -420,20 +550,27 @@ impl Hittable for XzRect {
         let mut rec: HitRecord = HitRecord {
             p: this_ray.pos(t),
             nor: Vec3::zero(),
+            geo_nor: Vec3::zero(),
+            dpdu: Vec3::new(self.x1 - self.x0, 0.0, 0.0),
+            dpdv: Vec3::new(0.0, 0.0, self.z1 - self.z0),
+            dndu: Vec3::zero(),
+            dndv: Vec3::zero(),
             t,
             u: (x - self.x0) / (self.x1 - self.x0),
             v: (z - self.z0) / (self.z1 - self.z0),
             nor_dir: false,
             mat_ptr: self.mp.clone(),
+            vertex_col: Option::None,
+            object_id: 0,
         };
         let outward_normal = Vec3::new(0.0, 1.0, 0.0);
         rec.set_face_normal(this_ray, outward_normal);
         Option::Some(rec)
     }
     fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
-        Some(Aabb::new(
-            Vec3::new(self.x0, self.z0, self.k - 0.001),
-            Vec3::new(self.x1, self.z1, self.k + 0.001),
+        Option::Some(Aabb::new(
+            Vec3::new(self.x0, self.k - 0.001, self.z0),
+            Vec3::new(self.x1, self.k + 0.001, self.z1),
         ))
     }
     fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
@@ -492,22 +629,47 @@ impl Hittable for YzRect {
         let mut rec: HitRecord = HitRecord {
             p: this_ray.pos(t),
             nor: Vec3::zero(),
+            geo_nor: Vec3::zero(),
+            dpdu: Vec3::new(0.0, self.y1 - self.y0, 0.0),
+            dpdv: Vec3::new(0.0, 0.0, self.z1 - self.z0),
+            dndu: Vec3::zero(),
+            dndv: Vec3::zero(),
             t,
             u: (y - self.y0) / (self.y1 - self.y0),
             v: (z - self.z0) / (self.z1 - self.z0),
             nor_dir: false,
             mat_ptr: self.mp.clone(),
+            vertex_col: Option::None,
+            object_id: 0,
         };
         let outward_normal = Vec3::new(1.0, 0.0, 0.0);
         rec.set_face_normal(this_ray, outward_normal);
         Option::Some(rec)
     }
     fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
-        Some(Aabb::new(
-            Vec3::new(self.y0, self.z0, self.k - 0.001),
-            Vec3::new(self.y1, self.z1, self.k + 0.001),
+        Option::Some(Aabb::new(
+            Vec3::new(self.k - 0.001, self.y0, self.z0),
+            Vec3::new(self.k + 0.001, self.y1, self.z1),
         ))
     }
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+        if let Option::Some(rec) = self.hit(&Ray::new(o, v, 0.0), 0.001, INF) {
+            let area = (self.y1 - self.y0) * (self.z1 - self.z0);
+            let dis_squared = rec.t * rec.t * v.squared_length();
+            let cos = ((v * rec.nor) / v.length()).abs();
+            dis_squared / (cos * area)
+        } else {
+            0.0
+        }
+    }
+    fn random(&self, o: Vec3) -> Vec3 {
+        let random_point = Vec3::new(
+            self.k,
+            get_rand(self.y0, self.y1),
+            get_rand(self.z0, self.z1),
+        );
+        random_point - o
+    }
 }
 
 pub struct Bbox {
@@ -518,15 +680,17 @@ pub struct Bbox {
 
 impl Bbox {
     pub fn new(boxmn: Vec3, boxmx: Vec3, mat_ptr: Arc<dyn Material>) -> Self {
+        // the rects face +axis, so the faces on the min side are flipped
+        // to make every face point out of the box
         let mut sides = HittableList::default();
-        sides.add(Arc::new(XyRect::new(
+        sides.add(Arc::new(FlipFace::new(Arc::new(XyRect::new(
             boxmn.x,
             boxmx.x,
             boxmn.y,
             boxmx.y,
             boxmn.z,
             mat_ptr.clone(),
-        )));
+        )))));
         sides.add(Arc::new(XyRect::new(
             boxmn.x,
             boxmx.x,
@@ -535,14 +699,14 @@ impl Bbox {
             boxmx.z,
             mat_ptr.clone(),
         )));
-        sides.add(Arc::new(XzRect::new(
+        sides.add(Arc::new(FlipFace::new(Arc::new(XzRect::new(
             boxmn.x,
             boxmx.x,
             boxmn.z,
             boxmx.z,
             boxmn.y,
             mat_ptr.clone(),
-        )));
+        )))));
         sides.add(Arc::new(XzRect::new(
             boxmn.x,
             boxmx.x,
@@ -551,14 +715,14 @@ impl Bbox {
             boxmx.y,
             mat_ptr.clone(),
         )));
-        sides.add(Arc::new(YzRect::new(
+        sides.add(Arc::new(FlipFace::new(Arc::new(YzRect::new(
             boxmn.y,
             boxmx.y,
             boxmn.z,
             boxmx.z,
             boxmn.x,
             mat_ptr.clone(),
-        )));
+        )))));
         sides.add(Arc::new(YzRect::new(
             boxmn.y,
             boxmx.y,
@@ -582,6 +746,12 @@ impl Hittable for Bbox {
     fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
         Some(Aabb::new(self.boxmn, self.boxmx))
     }
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+        self.sides.pdf_value(o, v)
+    }
+    fn random(&self, o: Vec3) -> Vec3 {
+        self.sides.random(o)
+    }
 }
 
 pub struct Translate {
@@ -600,7 +770,6 @@ impl Hittable for Translate {
         let moved_ray = Ray::new(this_ray.ori - self.offset, this_ray.dir, this_ray.tm);
         if let Option::Some(mut rec) = self.ptr.hit(&moved_ray, tmn, tmx) {
             rec.p += self.offset;
-            rec.set_face_normal(&moved_ray, rec.nor);
             Option::Some(rec)
         } else {
             Option::None
@@ -614,6 +783,12 @@ impl Hittable for Translate {
             Option::None
         }
     }
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+        self.ptr.pdf_value(o - self.offset, v)
+    }
+    fn random(&self, o: Vec3) -> Vec3 {
+        self.ptr.random(o - self.offset)
+    }
 }
 
 pub struct Rotatey {
@@ -675,6 +850,23 @@ impl Rotatey {
     }
 }
 
+impl Rotatey {
+    fn rotate(&self, a: Vec3) -> Vec3 {
+        Vec3::new(
+            self.costheta * a.x - self.sintheta * a.z,
+            a.y,
+            self.sintheta * a.x + self.costheta * a.z,
+        )
+    }
+    fn rotate_back(&self, a: Vec3) -> Vec3 {
+        Vec3::new(
+            self.costheta * a.x + self.sintheta * a.z,
+            a.y,
+            -self.sintheta * a.x + self.costheta * a.z,
+        )
+    }
+}
+
 impl Hittable for Rotatey {
     fn hit(&self, this_ray: &Ray, tmn: f64, tmx: f64) -> Option<HitRecord> {
         let mut ori = this_ray.ori;
@@ -685,14 +877,14 @@ impl Hittable for Rotatey {
         dir.z = self.sintheta * this_ray.dir.x + self.costheta * this_ray.dir.z;
         let rotated_ray = Ray::new(ori, dir, this_ray.tm);
         if let Option::Some(mut rec) = self.ptr.hit(&rotated_ray, tmn, tmx) {
-            let mut p = rec.p;
-            let mut nor = rec.nor;
-            p.x = self.costheta * rec.p.x + self.sintheta * rec.p.z;
-            p.z = -self.sintheta * rec.p.x + self.costheta * rec.p.z;
-            nor.x = self.costheta * rec.nor.x + self.sintheta * rec.nor.z;
-            nor.z = -self.sintheta * rec.nor.x + self.costheta * rec.nor.z;
-            rec.p = p;
-            rec.set_face_normal(&rotated_ray, nor);
+            // the normals already face the ray, so nor_dir stays as it is
+            rec.p = self.rotate_back(rec.p);
+            rec.nor = self.rotate_back(rec.nor);
+            rec.geo_nor = self.rotate_back(rec.geo_nor);
+            rec.dpdu = self.rotate_back(rec.dpdu);
+            rec.dpdv = self.rotate_back(rec.dpdv);
+            rec.dndu = self.rotate_back(rec.dndu);
+            rec.dndv = self.rotate_back(rec.dndv);
             Option::Some(rec)
         } else {
             Option::None
@@ -701,6 +893,12 @@ impl Hittable for Rotatey {
     fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
         self.mybox.clone()
     }
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+        self.ptr.pdf_value(self.rotate(o), self.rotate(v))
+    }
+    fn random(&self, o: Vec3) -> Vec3 {
+        self.rotate_back(self.ptr.random(self.rotate(o)))
+    }
 }
 
 pub struct FlipFace {
@@ -715,6 +913,8 @@ impl Hittable for FlipFace {
     fn hit(&self, this_ray: &Ray, tmn: f64, tmx: f64) -> Option<HitRecord> {
         if let Option::Some(mut rec) = self.ptr.hit(this_ray, tmn, tmx) {
             rec.nor_dir = !rec.nor_dir;
+            rec.dndu = -rec.dndu;
+            rec.dndv = -rec.dndv;
             Option::Some(rec)
         } else {
             Option::None
@@ -723,4 +923,298 @@ impl Hittable for FlipFace {
     fn bounding_box(&self, t0: f64, t1: f64) -> Option<Aabb> {
         self.ptr.bounding_box(t0, t1)
     }
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+        self.ptr.pdf_value(o, v)
+    }
+    fn random(&self, o: Vec3) -> Vec3 {
+        self.ptr.random(o)
+    }
+}
+
+// Gives every hit on `ptr` an object ID for the AOVs. A Tagged inside
+// another one keeps its own ID, so a group can be tagged as a whole and
+// some of its parts on their own.
+pub struct Tagged {
+    ptr: Arc<dyn Hittable>,
+    id: u32,
+}
+#[allow(dead_code)]
+impl Tagged {
+    pub fn new(ptr: Arc<dyn Hittable>, id: u32) -> Self {
+        Self { ptr, id }
+    }
+    fn tag(&self, rec: &mut HitRecord) {
+        if rec.object_id == 0 {
+            rec.object_id = self.id;
+        }
+    }
+}
+impl Hittable for Tagged {
+    fn hit(&self, this_ray: &Ray, tmn: f64, tmx: f64) -> Option<HitRecord> {
+        let mut rec = self.ptr.hit(this_ray, tmn, tmx)?;
+        self.tag(&mut rec);
+        Option::Some(rec)
+    }
+    fn bounding_box(&self, t0: f64, t1: f64) -> Option<Aabb> {
+        self.ptr.bounding_box(t0, t1)
+    }
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+        self.ptr.pdf_value(o, v)
+    }
+    fn random(&self, o: Vec3) -> Vec3 {
+        self.ptr.random(o)
+    }
+    fn hit_all(&self, this_ray: &Ray, tmn: f64, tmx: f64) -> Vec<HitRecord> {
+        let mut res = self.ptr.hit_all(this_ray, tmn, tmx);
+        for rec in res.iter_mut() {
+            self.tag(rec);
+        }
+        res
+    }
+}
+
+// Opacity mask on any primitive. Hits where the mask's luminance is below
+// `threshold` are skipped, or with `stochastic` a hit is kept with
+// probability equal to the mask.
+pub struct Cutout {
+    ptr: Arc<dyn Hittable>,
+    alpha: Arc<dyn Texture>,
+    threshold: f64,
+    stochastic: bool,
+}
+#[allow(dead_code)]
+impl Cutout {
+    pub fn new(ptr: Arc<dyn Hittable>, alpha: Arc<dyn Texture>, threshold: f64) -> Self {
+        Self {
+            ptr,
+            alpha,
+            threshold,
+            stochastic: false,
+        }
+    }
+    pub fn new_stochastic(ptr: Arc<dyn Hittable>, alpha: Arc<dyn Texture>) -> Self {
+        Self {
+            ptr,
+            alpha,
+            threshold: 0.0,
+            stochastic: true,
+        }
+    }
+    fn opaque(&self, rec: &HitRecord) -> bool {
+        let alpha = self.alpha.value(rec.u, rec.v, rec.p).luminance();
+        if self.stochastic {
+            get_rand01() < alpha
+        } else {
+            alpha >= self.threshold
+        }
+    }
+}
+impl Hittable for Cutout {
+    fn hit(&self, this_ray: &Ray, tmn: f64, tmx: f64) -> Option<HitRecord> {
+        let mut tmn = tmn;
+        // every ray goes through here, shadow rays included
+        for _i in 0..64 {
+            let rec = self.ptr.hit(this_ray, tmn, tmx)?;
+            if self.opaque(&rec) {
+                return Option::Some(rec);
+            }
+            // step past the surface by a distance, t is in units of the
+            // direction's length
+            tmn = rec.t + 0.0001 / this_ray.dir.length();
+        }
+        Option::None
+    }
+    fn bounding_box(&self, t0: f64, t1: f64) -> Option<Aabb> {
+        self.ptr.bounding_box(t0, t1)
+    }
+    // Light sampling ignores the mask and stays on the whole shape, so the
+    // pdf is a true density. Samples that land in a hole go on to whatever
+    // is behind it, which keeps the estimate unbiased and only wastes them.
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+        self.ptr.pdf_value(o, v)
+    }
+    fn random(&self, o: Vec3) -> Vec3 {
+        self.ptr.random(o)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::material::Lambertian;
+    use crate::mesh::{Mesh, TriangleMesh};
+    use crate::pdf::{ConePDF, PDF};
+    use crate::texture::Texture;
+    use crate::transform::{Transform, Transformed};
+
+    // Transparent in front of z = 0.25, opaque behind it.
+    struct Depth {}
+    impl Texture for Depth {
+        fn value(&self, _u: f64, _v: f64, p: Vec3) -> Vec3 {
+            if p.z < 0.25 {
+                Vec3::zero()
+            } else {
+                Vec3::ones()
+            }
+        }
+    }
+
+    // Light sampling of `shape` seen from `o`. Its density integrates to one
+    // over the sphere, and 1 / pdf of its own samples averages to the solid
+    // angle the shape covers. Both integrals are taken over a cone that
+    // holds the bounding box.
+    pub fn check_light_sampling(shape: &dyn Hittable, o: Vec3) {
+        let b = shape.bounding_box(0.0, 1.0).unwrap();
+        let to = (b.mn + b.mx) * 0.5 - o;
+        let r = (b.mx - b.mn).length() * 0.5;
+        let cone = ConePDF::new(to, (r / to.length()).asin().to_degrees());
+        let n = 100000;
+        let (mut total, mut covered, mut inverse) = (0.0, 0.0, 0.0);
+        for _ in 0..n {
+            let d = cone.generate();
+            total += shape.pdf_value(o, d) / cone.value(d) / n as f64;
+            if shape.hit(&Ray::new(o, d, 0.0), 0.001, INF).is_some() {
+                covered += 1.0 / cone.value(d) / n as f64;
+            }
+            let s = shape.random(o);
+            inverse += 1.0 / shape.pdf_value(o, s) / n as f64;
+        }
+        assert!((total - 1.0).abs() < 0.03, "integral {}", total);
+        assert!(
+            (inverse - covered).abs() < 0.03 * covered,
+            "{} vs {}",
+            inverse,
+            covered
+        );
+    }
+
+    #[test]
+    fn test_light_sampling() {
+        let mat: Arc<dyn Material> = Arc::new(Lambertian::new(Vec3::ones()));
+        let o = Vec3::new(0.3, 0.2, -4.0);
+        let spheres: Vec<Arc<dyn Hittable>> = vec![
+            Arc::new(Sphere::new(Vec3::new(-1.0, 0.0, 0.0), 0.8, mat.clone())),
+            Arc::new(Sphere::new(Vec3::new(0.5, 0.5, 0.5), 0.7, mat.clone())),
+            Arc::new(Sphere::new(Vec3::new(0.5, -1.0, 2.0), 1.0, mat.clone())),
+        ];
+        check_light_sampling(&BvhNode::new(spheres, 3, 0.0, 1.0), o);
+        // a closed tetrahedron, every direction that hits it crosses twice
+        let p = vec![
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(2.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.5, 0.0),
+            Vec3::new(0.5, 0.5, 1.0),
+        ];
+        let tris = vec![[0, 2, 1], [0, 1, 3], [1, 2, 3], [0, 3, 2]];
+        let tetra = Mesh::new(Arc::new(TriangleMesh::new(p, tris)), mat.clone());
+        check_light_sampling(&tetra, o);
+        // squashed and turned, which changes solid angles
+        let t = Transform::rotate(Vec3::new(1.0, 1.0, 0.0), 40.0)
+            * Transform::scale(Vec3::new(2.0, 0.5, 1.0));
+        let ellipsoid = Transformed::new(Arc::new(Sphere::new(Vec3::zero(), 1.0, mat.clone())), t);
+        check_light_sampling(&ellipsoid, o);
+        // the rects and what is built from them, each one seen at a slant
+        let cube = Arc::new(Bbox::new(
+            Vec3::new(-0.5, -0.4, -0.3),
+            Vec3::new(0.6, 0.5, 0.4),
+            mat.clone(),
+        ));
+        let shapes: Vec<Arc<dyn Hittable>> = vec![
+            Arc::new(XyRect::new(-1.0, 0.5, -0.5, 0.8, 0.5, mat.clone())),
+            Arc::new(XzRect::new(-1.0, 1.0, -1.0, 0.5, -1.0, mat.clone())),
+            Arc::new(YzRect::new(-1.0, 0.7, -1.0, 1.0, -1.5, mat.clone())),
+            Arc::new(FlipFace::new(Arc::new(XzRect::new(
+                -1.0, 1.0, -1.0, 0.5, 1.2, mat,
+            )))),
+            cube.clone(),
+            Arc::new(Translate::new(cube.clone(), Vec3::new(0.5, -0.6, 1.0))),
+            Arc::new(Rotatey::new(cube, 35.0)),
+        ];
+        for shape in shapes.iter() {
+            check_light_sampling(shape.as_ref(), o);
+        }
+    }
+
+    #[test]
+    fn test_cutout_long_direction() {
+        let mat = Arc::new(Lambertian::new(Vec3::ones()));
+        let mut layers = HittableList::default();
+        layers.add(Arc::new(XyRect::new(
+            -1.0,
+            1.0,
+            -1.0,
+            1.0,
+            0.0,
+            mat.clone(),
+        )));
+        layers.add(Arc::new(XyRect::new(-1.0, 1.0, -1.0, 1.0, 0.5, mat)));
+        let cutout = Cutout::new(Arc::new(layers), Arc::new(Depth {}), 0.5);
+        // the step past the hole is a distance, not a fraction of t
+        for len in [1.0, 1e4].iter() {
+            let ray = Ray::new(Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 0.0, *len), 0.0);
+            let rec = cutout.hit(&ray, 0.0, INF).unwrap();
+            assert!((rec.p.z - 0.5).abs() < 1e-9, "length {}", len);
+        }
+    }
+
+    #[test]
+    fn test_closed_shapes_face_out() {
+        // entering is the front face and leaving the back face on every
+        // side, through moves and turns too, which glass relies on
+        let mat: Arc<dyn Material> = Arc::new(Lambertian::new(Vec3::ones()));
+        let cube = Arc::new(Bbox::new(-Vec3::ones(), Vec3::ones(), mat.clone()));
+        let shapes: Vec<Arc<dyn Hittable>> = vec![
+            cube.clone(),
+            Arc::new(Translate::new(
+                Arc::new(Sphere::new(Vec3::zero(), 1.0, mat)),
+                Vec3::new(0.3, 0.1, 0.2),
+            )),
+            Arc::new(Rotatey::new(cube.clone(), 30.0)),
+            Arc::new(Translate::new(
+                Arc::new(Rotatey::new(cube, -50.0)),
+                Vec3::new(0.3, 0.1, 0.2),
+            )),
+        ];
+        let axes = [
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            Vec3::new(0.0, 0.0, 1.0),
+        ];
+        for shape in shapes.iter() {
+            for axis in axes.iter() {
+                for side in [-1.0, 1.0].iter() {
+                    let dir = *axis * *side;
+                    let this_ray = Ray::new(Vec3::new(0.1, 0.2, 0.05) - dir * 5.0, dir, 0.0);
+                    let crossings = shape.hit_all(&this_ray, 0.001, INF);
+                    assert_eq!(crossings.len(), 2);
+                    assert!(crossings[0].nor_dir && crossings[0].nor * dir < 0.0);
+                    assert!(!crossings[1].nor_dir && crossings[1].nor * dir < 0.0);
+                    assert!(crossings[0].geo_nor * dir < 0.0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_tagged_ids() {
+        // two tagged spheres and an untagged one inside a tagged BVH, the
+        // innermost tag wins and the BVH no longer hides which one was hit
+        let mat: Arc<dyn Material> = Arc::new(Lambertian::new(Vec3::ones()));
+        let sphere = |x: f64| Arc::new(Sphere::new(Vec3::new(x, 0.0, 0.0), 0.5, mat.clone()));
+        let spheres: Vec<Arc<dyn Hittable>> = vec![
+            Arc::new(Tagged::new(sphere(-2.0), 7)),
+            Arc::new(Tagged::new(sphere(0.0), 8)),
+            sphere(2.0),
+        ];
+        let group = Tagged::new(Arc::new(BvhNode::new(spheres, 3, 0.0, 1.0)), 1);
+        for (x, id) in [(-2.0, 7), (0.0, 8), (2.0, 1)].iter() {
+            let this_ray = Ray::new(Vec3::new(*x, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+            assert_eq!(group.hit(&this_ray, 0.001, INF).unwrap().object_id, *id);
+            let crossings = group.hit_all(&this_ray, 0.001, INF);
+            assert_eq!(crossings.len(), 2);
+            assert!(crossings.iter().all(|rec| rec.object_id == *id));
+        }
+        let this_ray = Ray::new(Vec3::new(0.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+        assert_eq!(sphere(0.0).hit(&this_ray, 0.001, INF).unwrap().object_id, 0);
+    }
 }
@@ -0,0 +1,326 @@
+use crate::distribution::Distribution2D;
+use crate::onb::ONB;
+use crate::random::*;
+use crate::texture::Texture;
+use crate::vec3::Vec3;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+pub const INF: f64 = f64::MAX;
+pub const PI: f64 = std::f64::consts::PI;
+
+pub struct LightSample {
+    // unit direction from the shading point toward the light
+    pub dir: Vec3,
+    pub dis: f64,
+    pub li: Vec3,
+}
+
+// Lights that are not geometry, so BSDF sampled rays never reach them and
+// the integrator has to sample them explicitly.
+pub trait Light: Send + Sync {
+    fn sample_li(&self, p: Vec3) -> Option<LightSample>;
+}
+
+// Point light, `radius` softens the inverse square falloff near the light
+// instead of letting it blow up.
+pub struct PointLight {
+    pos: Vec3,
+    intensity: Vec3,
+    radius: f64,
+}
+#[allow(dead_code)]
+impl PointLight {
+    pub fn new(pos: Vec3, intensity: Vec3, radius: f64) -> Self {
+        Self {
+            pos,
+            intensity,
+            radius,
+        }
+    }
+}
+impl Light for PointLight {
+    fn sample_li(&self, p: Vec3) -> Option<LightSample> {
+        let to_light = self.pos - p;
+        let dis_squared = to_light.squared_length();
+        if dis_squared == 0.0 {
+            return Option::None;
+        }
+        let dis = dis_squared.sqrt();
+        Option::Some(LightSample {
+            dir: to_light / dis,
+            dis,
+            li: self.intensity / (dis_squared + self.radius * self.radius),
+        })
+    }
+}
+
+// Spot light pointing from `pos` to `target`. Full intensity inside
+// `falloff_start` degrees, nothing outside `total_width`, and a smooth
+// falloff with `exponent` in between. The gobo, if any, is mapped over
+// the cone by angle, with (0.5, 0.5) on the axis and the edge of the cone
+// on the circle inscribed in the texture, so cones of 90 degrees and more
+// work too.
+pub struct SpotLight {
+    pos: Vec3,
+    uvw: ONB,
+    intensity: Vec3,
+    cos_total: f64,
+    cos_falloff_start: f64,
+    total: f64,
+    exponent: f64,
+    gobo: Option<Arc<dyn Texture>>,
+}
+#[allow(dead_code)]
+#[allow(clippy::too_many_arguments)]
+impl SpotLight {
+    pub fn new(
+        pos: Vec3,
+        target: Vec3,
+        intensity: Vec3,
+        total_width: f64,
+        falloff_start: f64,
+        exponent: f64,
+        gobo: Option<Arc<dyn Texture>>,
+    ) -> Self {
+        let total = total_width.to_radians();
+        Self {
+            pos,
+            uvw: ONB::buildw(target - pos),
+            intensity,
+            cos_total: total.cos(),
+            cos_falloff_start: falloff_start.min(total_width).to_radians().cos(),
+            total,
+            exponent,
+            gobo,
+        }
+    }
+    fn falloff(&self, w: Vec3) -> f64 {
+        let cos = w * self.uvw.w();
+        if cos < self.cos_total {
+            return 0.0;
+        }
+        if cos >= self.cos_falloff_start {
+            return 1.0;
+        }
+        let delta = (cos - self.cos_total) / (self.cos_falloff_start - self.cos_total);
+        delta.powf(self.exponent)
+    }
+    fn gobo_uv(&self, w: Vec3) -> (f64, f64) {
+        let (x, y) = (w * self.uvw.u(), w * self.uvw.v());
+        let side = (x * x + y * y).sqrt();
+        if side == 0.0 {
+            return (0.5, 0.5);
+        }
+        let r = (w * self.uvw.w()).clamp(-1.0, 1.0).acos() / self.total / side;
+        (0.5 + 0.5 * r * x, 0.5 + 0.5 * r * y)
+    }
+}
+impl Light for SpotLight {
+    fn sample_li(&self, p: Vec3) -> Option<LightSample> {
+        let to_light = self.pos - p;
+        let dis_squared = to_light.squared_length();
+        if dis_squared == 0.0 {
+            return Option::None;
+        }
+        let dis = dis_squared.sqrt();
+        let dir = to_light / dis;
+        let falloff = self.falloff(-dir);
+        if falloff == 0.0 {
+            return Option::None;
+        }
+        let mut li = self.intensity * (falloff / dis_squared);
+        if let Option::Some(gobo) = &self.gobo {
+            let (u, v) = self.gobo_uv(-dir);
+            li = li.change(gobo.value(u, v, p));
+        }
+        Option::Some(LightSample { dir, dis, li })
+    }
+}
+
+// Light from infinitely far away, `dir` is the direction the light travels.
+pub struct DistantLight {
+    dir: Vec3,
+    radiance: Vec3,
+}
+#[allow(dead_code)]
+impl DistantLight {
+    pub fn new(dir: Vec3, radiance: Vec3) -> Self {
+        Self {
+            dir: dir.unit(),
+            radiance,
+        }
+    }
+}
+impl Light for DistantLight {
+    fn sample_li(&self, _p: Vec3) -> Option<LightSample> {
+        Option::Some(LightSample {
+            dir: -self.dir,
+            dis: INF,
+            li: self.radiance,
+        })
+    }
+}
+
+// Lights infinitely far away that rays leaving the scene can see, and that
+// are sampled by direction only.
+pub trait InfiniteLight: Send + Sync {
+    // Radiance arriving along a ray that leaves the scene in `dir`.
+    fn value(&self, dir: Vec3) -> Vec3;
+    // Density of random() with respect to solid angle.
+    fn pdf_value(&self, dir: Vec3) -> f64;
+    fn random(&self) -> Vec3;
+}
+
+// Equirectangular environment around the scene, +y is up and the top row of
+// the image is straight up. Directions are importance sampled by
+// luminance * sin(theta) so bright spots like the sun get found.
+pub struct EnvironmentLight {
+    width: usize,
+    height: usize,
+    data: Vec<Vec3>,
+    intensity: f64,
+    // radians around +y
+    rotation: f64,
+    distribution: Distribution2D,
+}
+#[allow(dead_code)]
+impl EnvironmentLight {
+    // Loads a .hdr or .exr lat-long map, rotation is in degrees around +y.
+    pub fn new(filename: &str, intensity: f64, rotation: f64) -> Self {
+        let (width, height, data) = if filename.to_lowercase().ends_with(".exr") {
+            read_exr(filename)
+        } else {
+            read_hdr(filename)
+        };
+        Self::from_pixels(width, height, data, intensity, rotation)
+    }
+    pub fn from_pixels(
+        width: usize,
+        height: usize,
+        data: Vec<Vec3>,
+        intensity: f64,
+        rotation: f64,
+    ) -> Self {
+        assert_eq!(data.len(), width * height, "Environment map size mismatch");
+        let mut func = Vec::with_capacity(height);
+        for j in 0..height {
+            let sin_theta = (PI * (j as f64 + 0.5) / height as f64).sin();
+            let row = &data[j * width..(j + 1) * width];
+            func.push(
+                row.iter()
+                    .map(|c| c.luminance().max(0.0) * sin_theta)
+                    .collect(),
+            );
+        }
+        Self {
+            width,
+            height,
+            data,
+            intensity,
+            rotation: rotation.to_radians(),
+            distribution: Distribution2D::new(func),
+        }
+    }
+    fn dir_to_uv(&self, dir: Vec3) -> (f64, f64) {
+        let d = dir.unit();
+        let theta = d.y.clamp(-1.0, 1.0).acos();
+        let phi = (d.z.atan2(d.x) - self.rotation).rem_euclid(2.0 * PI);
+        (phi / (2.0 * PI), theta / PI)
+    }
+    fn uv_to_dir(&self, u: f64, v: f64) -> Vec3 {
+        let theta = v * PI;
+        let phi = u * 2.0 * PI + self.rotation;
+        Vec3::new(
+            theta.sin() * phi.cos(),
+            theta.cos(),
+            theta.sin() * phi.sin(),
+        )
+    }
+}
+impl InfiniteLight for EnvironmentLight {
+    fn value(&self, dir: Vec3) -> Vec3 {
+        let (u, v) = self.dir_to_uv(dir);
+        let i = ((u * self.width as f64) as usize).min(self.width - 1);
+        let j = ((v * self.height as f64) as usize).min(self.height - 1);
+        self.data[j * self.width + i] * self.intensity
+    }
+    fn pdf_value(&self, dir: Vec3) -> f64 {
+        let (u, v) = self.dir_to_uv(dir);
+        let sin_theta = (v * PI).sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        self.distribution.pdf(u, v) / (2.0 * PI * PI * sin_theta)
+    }
+    fn random(&self) -> Vec3 {
+        let (u, v, _pdf) = self
+            .distribution
+            .sample_continuous(get_rand01(), get_rand01());
+        self.uv_to_dir(u, v)
+    }
+}
+
+fn read_hdr(filename: &str) -> (usize, usize, Vec<Vec3>) {
+    let file = File::open(filename).unwrap_or_else(|_| panic!("Cannot open {}", filename));
+    let decoder = image::hdr::HdrDecoder::new(BufReader::new(file))
+        .unwrap_or_else(|e| panic!("Cannot decode {}: {}", filename, e));
+    let meta = decoder.metadata();
+    let pixels = decoder
+        .read_image_hdr()
+        .unwrap_or_else(|e| panic!("Cannot decode {}: {}", filename, e));
+    let data = pixels
+        .iter()
+        .map(|p| Vec3::new(p[0] as f64, p[1] as f64, p[2] as f64))
+        .collect();
+    (meta.width as usize, meta.height as usize, data)
+}
+
+fn read_exr(filename: &str) -> (usize, usize, Vec<Vec3>) {
+    let image = exr::prelude::read_first_rgba_layer_from_file(
+        filename,
+        |resolution, _| {
+            (
+                resolution.width(),
+                vec![Vec3::zero(); resolution.width() * resolution.height()],
+            )
+        },
+        |(width, data): &mut (usize, Vec<Vec3>), pos, (r, g, b, _a): (f32, f32, f32, f32)| {
+            data[pos.y() * *width + pos.x()] = Vec3::new(r as f64, g as f64, b as f64);
+        },
+    )
+    .unwrap_or_else(|e| panic!("Cannot read {}: {}", filename, e));
+    let (width, data) = image.layer_data.channel_data.pixels;
+    let height = data.len() / width;
+    (width, height, data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gobo_by_angle() {
+        // a cone wider than a hemisphere still maps onto the texture, the
+        // distance from the center grows with the angle off the axis
+        let spot = SpotLight::new(
+            Vec3::zero(),
+            Vec3::new(0.0, 0.0, 1.0),
+            Vec3::ones(),
+            120.0,
+            90.0,
+            1.0,
+            Option::None,
+        );
+        assert_eq!(spot.gobo_uv(Vec3::new(0.0, 0.0, 1.0)), (0.5, 0.5));
+        for degrees in [10.0f64, 45.0, 90.0, 100.0, 120.0] {
+            let a = degrees.to_radians();
+            for phi in [0.3f64, 2.0, 4.5] {
+                let w = Vec3::new(a.sin() * phi.cos(), a.sin() * phi.sin(), a.cos());
+                let (u, v) = spot.gobo_uv(w);
+                let r = ((u - 0.5).powi(2) + (v - 0.5).powi(2)).sqrt();
+                assert!((r - 0.5 * degrees / 120.0).abs() < 1e-9);
+            }
+        }
+    }
+}
@@ -0,0 +1,323 @@
+use crate::aabb::Aabb;
+use crate::distribution::AliasTable;
+use crate::hit::*;
+use crate::random::*;
+use crate::ray::Ray;
+use crate::vec3::Vec3;
+use std::sync::Arc;
+
+fn hit_any(objects: &[Arc<dyn Hittable>], this_ray: &Ray, tmn: f64, tmx: f64) -> Option<HitRecord> {
+    let mut rec: Option<HitRecord> = Option::None;
+    let mut tmx = tmx;
+    for object in objects.iter() {
+        if let Option::Some(tmp) = object.hit(this_ray, tmn, tmx) {
+            tmx = tmp.t;
+            rec = Option::Some(tmp);
+        }
+    }
+    rec
+}
+
+fn light_box(object: &Arc<dyn Hittable>) -> Aabb {
+    object
+        .bounding_box(0.0, 1.0)
+        .expect("Lights need a bounding box")
+}
+
+// Lights picked in proportion to their power instead of uniformly.
+pub struct PowerLights {
+    pub objects: Vec<Arc<dyn Hittable>>,
+    table: AliasTable,
+}
+#[allow(dead_code)]
+impl PowerLights {
+    // `power` is the emitted power of each light, or anything proportional
+    // to it such as emission luminance times area.
+    pub fn new(objects: Vec<Arc<dyn Hittable>>, power: &[f64]) -> Self {
+        assert_eq!(objects.len(), power.len(), "One power per light");
+        assert!(!objects.is_empty(), "No lights");
+        Self {
+            objects,
+            table: AliasTable::new(power),
+        }
+    }
+}
+impl Hittable for PowerLights {
+    fn hit(&self, this_ray: &Ray, tmn: f64, tmx: f64) -> Option<HitRecord> {
+        hit_any(&self.objects, this_ray, tmn, tmx)
+    }
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
+        let mut output_box = light_box(&self.objects[0]);
+        for object in self.objects.iter().skip(1) {
+            output_box = Aabb::surrounding_box(output_box, light_box(object));
+        }
+        Option::Some(output_box)
+    }
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+        let mut sum = 0.0;
+        for (object, pmf) in self.objects.iter().zip(self.table.pmf.iter()) {
+            if *pmf > 0.0 {
+                sum += pmf * object.pdf_value(o, v);
+            }
+        }
+        sum
+    }
+    fn random(&self, o: Vec3) -> Vec3 {
+        self.objects[self.table.sample(get_rand01(), get_rand01())].random(o)
+    }
+}
+
+enum LightNode {
+    Leaf(usize),
+    Inner(usize, usize),
+}
+struct LightBvhNode {
+    node: LightNode,
+    mybox: Aabb,
+    power: f64,
+}
+
+// BVH over the lights that picks one by its estimated contribution at the
+// shading point, power over squared distance to each node's box. The same
+// walk gives the pmf, so pdf_value matches random exactly.
+pub struct LightBvh {
+    objects: Vec<Arc<dyn Hittable>>,
+    nodes: Vec<LightBvhNode>,
+}
+#[allow(dead_code)]
+impl LightBvh {
+    pub fn new(objects: Vec<Arc<dyn Hittable>>, power: &[f64]) -> Self {
+        assert_eq!(objects.len(), power.len(), "One power per light");
+        assert!(!objects.is_empty(), "No lights");
+        let mut bvh = Self {
+            objects,
+            nodes: Vec::new(),
+        };
+        let mut ids: Vec<usize> = (0..power.len()).collect();
+        bvh.build(&mut ids, power);
+        bvh
+    }
+    fn build(&mut self, ids: &mut [usize], power: &[f64]) -> usize {
+        if ids.len() == 1 {
+            self.nodes.push(LightBvhNode {
+                node: LightNode::Leaf(ids[0]),
+                mybox: light_box(&self.objects[ids[0]]),
+                power: power[ids[0]].max(0.0),
+            });
+            return self.nodes.len() - 1;
+        }
+        let centroid = |i: usize| {
+            let b = light_box(&self.objects[i]);
+            (b.mn + b.mx) / 2.0
+        };
+        let mut mn = centroid(ids[0]);
+        let mut mx = mn;
+        for i in ids.iter() {
+            let c = centroid(*i);
+            for a in 0..3 {
+                *mn.get_mut(a) = mn.get(a).min(c.get(a));
+                *mx.get_mut(a) = mx.get(a).max(c.get(a));
+            }
+        }
+        let extent = mx - mn;
+        let axis = if extent.x > extent.y && extent.x > extent.z {
+            0
+        } else if extent.y > extent.z {
+            1
+        } else {
+            2
+        };
+        ids.sort_by(|a, b| {
+            centroid(*a)
+                .get(axis)
+                .partial_cmp(&centroid(*b).get(axis))
+                .unwrap()
+        });
+        let mid = ids.len() / 2;
+        let (ids0, ids1) = ids.split_at_mut(mid);
+        let left = self.build(ids0, power);
+        let right = self.build(ids1, power);
+        self.nodes.push(LightBvhNode {
+            node: LightNode::Inner(left, right),
+            mybox: Aabb::surrounding_box(
+                self.nodes[left].mybox.clone(),
+                self.nodes[right].mybox.clone(),
+            ),
+            power: self.nodes[left].power + self.nodes[right].power,
+        });
+        self.nodes.len() - 1
+    }
+    fn root(&self) -> usize {
+        self.nodes.len() - 1
+    }
+    fn importance(&self, node: usize, o: Vec3) -> f64 {
+        let b = &self.nodes[node].mybox;
+        let center = (b.mn + b.mx) / 2.0;
+        let half_diag_squared = (b.mx - b.mn).squared_length() / 4.0;
+        // inside or close to a box every distance inside it is possible
+        let dis_squared = (center - o).squared_length().max(half_diag_squared);
+        if dis_squared == 0.0 {
+            return self.nodes[node].power;
+        }
+        self.nodes[node].power / dis_squared
+    }
+    // probability of walking from `node` into `left`
+    fn left_prob(&self, left: usize, right: usize, o: Vec3) -> f64 {
+        let il = self.importance(left, o);
+        let ir = self.importance(right, o);
+        if il + ir == 0.0 {
+            0.5
+        } else {
+            il / (il + ir)
+        }
+    }
+    // A light has no density off its own surface, so subtrees whose box
+    // the ray misses add nothing.
+    fn pdf_rec(&self, node: usize, o: Vec3, v: Vec3, prob: f64) -> f64 {
+        if prob == 0.0 || !self.nodes[node].mybox.hit(Ray::new(o, v, 0.0), 0.0, INF) {
+            return 0.0;
+        }
+        match self.nodes[node].node {
+            LightNode::Leaf(i) => prob * self.objects[i].pdf_value(o, v),
+            LightNode::Inner(left, right) => {
+                let p = self.left_prob(left, right, o);
+                self.pdf_rec(left, o, v, prob * p) + self.pdf_rec(right, o, v, prob * (1.0 - p))
+            }
+        }
+    }
+    // Probability that random(o) picks light i.
+    pub fn pmf(&self, o: Vec3, i: usize) -> f64 {
+        self.pmf_rec(self.root(), o, i).unwrap_or(0.0)
+    }
+    fn pmf_rec(&self, node: usize, o: Vec3, i: usize) -> Option<f64> {
+        match self.nodes[node].node {
+            LightNode::Leaf(j) => {
+                if i == j {
+                    Option::Some(1.0)
+                } else {
+                    Option::None
+                }
+            }
+            LightNode::Inner(left, right) => {
+                let p = self.left_prob(left, right, o);
+                if let Option::Some(q) = self.pmf_rec(left, o, i) {
+                    Option::Some(p * q)
+                } else {
+                    self.pmf_rec(right, o, i).map(|q| (1.0 - p) * q)
+                }
+            }
+        }
+    }
+}
+impl Hittable for LightBvh {
+    fn hit(&self, this_ray: &Ray, tmn: f64, tmx: f64) -> Option<HitRecord> {
+        hit_any(&self.objects, this_ray, tmn, tmx)
+    }
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
+        Option::Some(self.nodes[self.root()].mybox.clone())
+    }
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+        self.pdf_rec(self.root(), o, v, 1.0)
+    }
+    fn random(&self, o: Vec3) -> Vec3 {
+        let mut node = self.root();
+        loop {
+            match self.nodes[node].node {
+                LightNode::Leaf(i) => return self.objects[i].random(o),
+                LightNode::Inner(left, right) => {
+                    node = if get_rand01() < self.left_prob(left, right, o) {
+                        left
+                    } else {
+                        right
+                    };
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hit::tests::check_light_sampling;
+    use crate::material::{Lambertian, Material};
+
+    #[test]
+    fn test_light_bvh_sampling() {
+        // a row of spheres that do not overlap as seen from o
+        let mat = Arc::new(Lambertian::new(Vec3::ones()));
+        let mut objects: Vec<Arc<dyn Hittable>> = Vec::new();
+        let mut power = Vec::new();
+        for k in 0..8 {
+            let c = Vec3::new(3.0 * k as f64 - 10.0, (k % 3) as f64, 10.0 + k as f64);
+            objects.push(Arc::new(Sphere::new(c, 0.5, mat.clone())));
+            power.push(1.0 + (k * 7 % 5) as f64);
+        }
+        let bvh = LightBvh::new(objects.clone(), &power);
+        let o = Vec3::new(1.0, 0.5, -2.0);
+        let pmf: Vec<f64> = (0..objects.len()).map(|i| bvh.pmf(o, i)).collect();
+        assert!((pmf.iter().sum::<f64>() - 1.0).abs() < 1e-12);
+        let n = 100000;
+        let mut picked = vec![0; objects.len()];
+        for _ in 0..n {
+            let d = bvh.random(o);
+            // the pruned walk gives the same density as the full mixture
+            let full: f64 = (0..objects.len())
+                .map(|i| pmf[i] * objects[i].pdf_value(o, d))
+                .sum();
+            assert!((bvh.pdf_value(o, d) - full).abs() < 1e-9 * full);
+            let ray = Ray::new(o, d, 0.0);
+            let i = (0..objects.len())
+                .find(|i| objects[*i].hit(&ray, 0.001, INF).is_some())
+                .unwrap();
+            picked[i] += 1;
+        }
+        for i in 0..objects.len() {
+            let freq = picked[i] as f64 / n as f64;
+            assert!(
+                (freq - pmf[i]).abs() < 0.01,
+                "light {}: {} vs {}",
+                i,
+                freq,
+                pmf[i]
+            );
+        }
+        // and no density where no light is
+        assert_eq!(bvh.pdf_value(o, Vec3::new(0.0, 0.0, -1.0)), 0.0);
+    }
+
+    #[test]
+    fn test_rect_lights() {
+        // the Cornell ceiling light alone, then with a wall panel and a
+        // back panel, seen from inside the box
+        let mat: Arc<dyn Material> = Arc::new(Lambertian::new(Vec3::ones()));
+        let ceiling: Arc<dyn Hittable> =
+            Arc::new(XzRect::new(213.0, 343.0, 227.0, 332.0, 554.0, mat.clone()));
+        let o = Vec3::new(278.0, 100.0, 278.0);
+        let single = LightBvh::new(vec![ceiling.clone()], &[1.0]);
+        for _ in 0..1000 {
+            assert!(single.pdf_value(o, single.random(o)) > 0.0);
+        }
+        let objects: Vec<Arc<dyn Hittable>> = vec![
+            ceiling,
+            Arc::new(YzRect::new(200.0, 400.0, 100.0, 300.0, 555.0, mat.clone())),
+            Arc::new(XyRect::new(100.0, 250.0, 300.0, 450.0, 500.0, mat)),
+        ];
+        let power = [4.0, 1.0, 2.0];
+        let bvh = LightBvh::new(objects.clone(), &power);
+        let lights = PowerLights::new(objects.clone(), &power);
+        for _ in 0..1000 {
+            let d = bvh.random(o);
+            let full: f64 = (0..objects.len())
+                .map(|i| bvh.pmf(o, i) * objects[i].pdf_value(o, d))
+                .sum();
+            assert!(full > 0.0);
+            assert!((bvh.pdf_value(o, d) - full).abs() < 1e-9 * full);
+            assert!(lights.pdf_value(o, lights.random(o)) > 0.0);
+        }
+        // the helper needs to see all of them inside one cone
+        let far = Vec3::new(278.0, -500.0, -200.0);
+        check_light_sampling(&bvh, far);
+        check_light_sampling(&lights, far);
+    }
+}
@@ -1,6 +1,6 @@
 #![allow(clippy::float_cmp)]
+#![allow(clippy::upper_case_acronyms)]
 pub use image::Rgb;
-use image::{ImageBuffer, RgbImage};
 use indicatif::ProgressBar;
 use std::sync::Arc;
 // use std;
@@ -11,19 +11,45 @@ pub use ray::Ray;
 mod hit;
 use hit::*;
 mod camera;
-use camera::Camera;
+use camera::*;
 mod material;
 use material::*;
-pub const INF: f64 = std::f64::MAX;
+pub const INF: f64 = f64::MAX;
 pub const PI: f64 = std::f64::consts::PI;
 mod random;
 use random::*;
 mod texture;
 // use texture::*;
 mod aabb;
+mod aov;
+use aov::{AovFilm, AovSample};
+mod csg;
+mod denoise;
+use denoise::Denoiser;
+mod distribution;
+mod film;
+use film::Film;
+mod filter;
+mod firefly;
+use firefly::Firefly;
+mod gltf_import;
+mod heightfield;
+use filter::*;
+mod mesh;
 mod onb;
+mod pbrt;
 mod pdf;
+mod planar;
+mod ply;
+mod quadric;
 use pdf::*;
+mod light;
+mod lightbvh;
+mod scene;
+mod sdf;
+mod sky;
+mod transform;
+use scene::Scene;
 
 // fn get_color(this_ray: &Ray, world: &HittableList, depth: i32) -> Vec3 {
 //     if depth <= 0 {
@@ -41,30 +67,138 @@ use pdf::*;
 //     (Vec3::new(1.0, 1.0, 1.0) * (1.0 - k)) + (Vec3::new(0.5, 0.7, 1.0) * k)
 // }
 
+// Direct light from the delta lights, which no scattered ray can ever hit.
+// The part of it that comes through glossy reflection is added to
+// `specular` if given.
+fn delta_light(
+    this_ray: &Ray,
+    rec: &HitRecord,
+    srec: &ScatterRecord,
+    scene: &Scene,
+    mut specular: Option<&mut Vec3>,
+) -> Vec3 {
+    let mut col = Vec3::zero();
+    for light in scene.delta_lights.iter() {
+        if let Option::Some(ls) = light.sample_li(rec.p) {
+            let shadow_ray = Ray::new(rec.p, ls.dir, this_ray.tm);
+            let f = rec
+                .mat_ptr
+                .scattering_color(this_ray, rec, srec, &shadow_ray);
+            if f == Vec3::zero()
+                || scene
+                    .world
+                    .hit(&shadow_ray, 0.001, ls.dis - 0.001)
+                    .is_some()
+            {
+                continue;
+            }
+            let li = ls.li.change(f);
+            if let Option::Some(spec) = specular.as_deref_mut() {
+                *spec += li
+                    * rec
+                        .mat_ptr
+                        .specular_fraction(this_ray, rec, srec, &shadow_ray);
+            }
+            col += li;
+        }
+    }
+    col
+}
+
+// A specular bounce widened to a cone around the mirror direction, so light
+// sampling can find the lights through it.
+fn regularized_color(
+    this_ray: &Ray,
+    srec: &ScatterRecord,
+    scene: &Scene,
+    ff: &Firefly,
+    depth: i32,
+) -> Vec3 {
+    ff.count_regularized();
+    let ori = srec.specular_ray.ori;
+    let cone: Arc<dyn PDF> = Arc::new(ConePDF::new(srec.specular_ray.dir, ff.regularize));
+    let p: Arc<dyn PDF> = match scene.light_pdf(ori) {
+        Option::Some(light_ptr) => Arc::new(MixturePDF::new(light_ptr, cone.clone())),
+        Option::None => cone.clone(),
+    };
+    let mut scattered = Ray::new(ori, p.generate(), this_ray.tm);
+    let mut pdf = p.value(scattered.dir);
+    // lights can not be sampled from inside a light sphere
+    if !(pdf > 0.0 && pdf.is_finite()) {
+        scattered = Ray::new(ori, cone.generate(), this_ray.tm);
+        pdf = cone.value(scattered.dir);
+    }
+    let f = cone.value(scattered.dir);
+    if f <= 0.0 {
+        return Vec3::zero();
+    }
+    get_color(&scattered, scene, ff, depth - 1, true, Option::None).change(srec.atten_col) * f / pdf
+}
+
+// `aov`, if given, is filled at the first non-specular vertex. The vertex
+// after that one only reports its emission into it, which tells light from
+// one more bounce apart from the rest, and the rest is what gets clamped.
+// `bounced` is set once the path went through a non-specular vertex.
 fn get_color(
     this_ray: &Ray,
-    background: Vec3,
-    world: &HittableList,
-    lights: Arc<dyn Hittable>,
+    scene: &Scene,
+    ff: &Firefly,
     depth: i32,
+    bounced: bool,
+    mut aov: Option<&mut AovSample>,
 ) -> Vec3 {
     if depth <= 0 {
         return Vec3::zero();
     }
-    if let Option::Some(rec) = world.hit(this_ray, 0.001, INF) {
+    let hit = scene.world.hit(this_ray, 0.001, INF);
+    if let Option::Some(s) = aov.as_deref_mut() {
+        if s.done {
+            s.next_emission = match &hit {
+                Option::Some(rec) => rec.mat_ptr.emitted(this_ray, rec, rec.u, rec.v, rec.p),
+                Option::None => scene.escaped(this_ray.dir),
+            };
+            aov = Option::None;
+        }
+    }
+    if let Option::Some(rec) = hit {
         let emitted = rec.mat_ptr.emitted(this_ray, &rec, rec.u, rec.v, rec.p);
+        if let Option::Some(s) = aov.as_deref_mut() {
+            s.depth += rec.t * this_ray.dir.length();
+        }
         if let Option::Some(srec) = rec.mat_ptr.scatter(this_ray, &rec) {
+            if srec.is_specular && bounced && ff.regularize > 0.0 {
+                return emitted + regularized_color(this_ray, &srec, scene, ff, depth);
+            }
             if srec.is_specular {
-                return srec.atten_col.change(get_color(
+                let col = get_color(
                     &srec.specular_ray,
-                    background,
-                    world,
-                    lights.clone(),
+                    scene,
+                    ff,
                     depth - 1,
-                ));
+                    bounced,
+                    aov.as_deref_mut(),
+                );
+                if let Option::Some(s) = aov {
+                    s.scale_light(srec.atten_col);
+                }
+                return srec.atten_col.change(col);
             }
-            let light_ptr = Arc::new(HittablePDF::new(lights.clone(), rec.p));
-            let p = MixturePDF::new(light_ptr, srec.pdf_ptr);
+            let mut specular = Vec3::zero();
+            let split = match aov.as_deref_mut() {
+                Option::Some(s) => {
+                    let albedo = rec.mat_ptr.albedo(this_ray, &rec, &srec);
+                    s.record(&rec, albedo, emitted);
+                    Option::Some(&mut specular)
+                }
+                Option::None => Option::None,
+            };
+            let direct = delta_light(this_ray, &rec, &srec, scene, split);
+            let p: Arc<dyn PDF> = match scene.light_pdf(rec.p) {
+                Option::Some(light_ptr) => {
+                    Arc::new(MixturePDF::new(light_ptr, srec.pdf_ptr.clone()))
+                }
+                Option::None => srec.pdf_ptr.clone(),
+            };
             // let on_light = Vec3::new(get_rand(213.0, 343.0), 554.0, get_rand(227.0, 332.0));
             // let to_light = on_light - rec.p;
             // let _dis_squared = to_light.squared_length();
@@ -91,16 +225,91 @@ fn get_color(
 
             let scattered = Ray::new(rec.p, p.generate(), this_ray.tm);
             let pdf = p.value(scattered.dir);
-            return emitted
-                + get_color(&scattered, background, world, lights.clone(), depth - 1)
-                    .change(srec.atten_col)
-                    * rec.mat_ptr.scattering_pdf(this_ray, &rec, &scattered)
-                    / pdf;
+            // a glossy lobe can reflect below the surface
+            let (weight, incoming) = if pdf > 0.0 {
+                (
+                    rec.mat_ptr
+                        .scattering_color(this_ray, &rec, &srec, &scattered)
+                        / pdf,
+                    get_color(&scattered, scene, ff, depth - 1, true, aov.as_deref_mut()),
+                )
+            } else {
+                (Vec3::zero(), Vec3::zero())
+            };
+            let col = incoming.change(weight);
+            if let Option::Some(s) = aov {
+                let once = s.next_emission.change(weight);
+                let glossy = rec
+                    .mat_ptr
+                    .specular_fraction(this_ray, &rec, &srec, &scattered);
+                s.specular = specular + once * glossy;
+                s.diffuse = direct - specular + once * (1.0 - glossy);
+                s.indirect = col - once;
+            }
+            return emitted + direct + col;
+        }
+        if let Option::Some(s) = aov {
+            s.record(&rec, Vec3::zero(), emitted);
         }
         emitted
     } else {
-        background
+        let col = scene.escaped(this_ray.dir);
+        if let Option::Some(s) = aov {
+            s.emission = col;
+            s.done = true;
+        }
+        col
+    }
+}
+fn render(
+    scene: &Scene,
+    cam: &dyn CameraModel,
+    film: &mut Film,
+    mut aovs: Option<&mut AovFilm>,
+    ff: &Firefly,
+    sam_num: i32,
+    max_dep: i32,
+) {
+    let bar = ProgressBar::new(film.width as u64);
+    for x in 0..film.width {
+        for y in 0..film.height {
+            for _i in 0..sam_num {
+                let px = x as f64 + get_rand01();
+                let py = y as f64 + get_rand01();
+                let (cx, cy) = (px / film.width as f64, py / film.height as f64);
+                let mut aov = AovSample::default();
+                let mut col = Vec3::zero();
+                if cam.covers(cx, cy) {
+                    let (this_ray, weight) = cam.generate_ray(cx, cy);
+                    if weight != Vec3::zero() {
+                        // the clamp needs the split too, to know which
+                        // part of the sample is indirect
+                        let split = if aovs.is_some() || ff.clamp_indirect < INF {
+                            Option::Some(&mut aov)
+                        } else {
+                            Option::None
+                        };
+                        col = get_color(&this_ray, scene, ff, max_dep, false, split).change(weight);
+                        aov.scale_light(weight);
+                        let indirect = ff.clamp(aov.indirect);
+                        col += indirect - aov.indirect;
+                        aov.indirect = indirect;
+                    }
+                }
+                let k = ff.outlier_scale(film, px, py, col);
+                if k < 1.0 {
+                    col *= k;
+                    aov.scale_lobes(k);
+                }
+                film.add_sample(px, py, col);
+                if let Option::Some(a) = aovs.as_deref_mut() {
+                    a.add_sample(px, py, &aov);
+                }
+            }
+        }
+        bar.inc(1);
     }
+    bar.finish();
 }
 /*
 pub fn random_scene() -> HittableList {
@@ -288,10 +497,21 @@ pub fn cornellbox() -> HittableList {
     let box2 = Arc::new(Translate::new(box2, Vec3::new(130.0, 0.0, 65.0)));
     objects.add(box2);*/
 
-    objects
+    // an object ID for each wall, the light, the box and the sphere
+    let ids = objects.objects.iter().enumerate();
+    HittableList {
+        objects: ids
+            .map(|(i, o)| Arc::new(Tagged::new(o.clone(), i as u32 + 1)) as Arc<dyn Hittable>)
+            .collect(),
+    }
 }
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(|a| a.as_str()) == Option::Some("denoise") {
+        denoise::denoise_command(&args[2..]);
+        return;
+    }
     let x = Vec3::new(1.0, 1.0, 1.0);
     println!("{:?}", x);
 
@@ -299,6 +519,12 @@ fn main() {
     let image_height: u32 = 600;
     let sam_num: i32 = 1000;
     let max_dep: i32 = 50;
+    // `--denoise` also writes output/test_denoised.png, `--aovs` writes
+    // output/test.exr with the beauty image and every AOV
+    let denoise = args.iter().any(|a| a == "--denoise");
+    let write_aovs = args.iter().any(|a| a == "--aovs");
+    // e.g. Firefly::new(10.0, 20.0, 5.0) for the caustics of the glass sphere
+    let firefly = Firefly::off();
 
     let vup = Vec3::new(0.0, 1.0, 0.0);
     let dist_to_focus = 10.0;
@@ -360,9 +586,7 @@ fn main() {
     );
 
     let image_width: u32 = ((image_height as f64) * aspect_ratio) as u32;
-    let mut img: RgbImage = ImageBuffer::new(image_width, image_height);
-    let bar = ProgressBar::new(image_width as u64);
-    // reflect(Vec3::ones(), Vec3::ones());
+    let mut film = Film::new(image_width, image_height, Arc::new(BoxFilter::new(0.5)));
 
     let mut lights = HittableList::default();
 
@@ -383,27 +607,38 @@ fn main() {
     ));
     lights.add(glass_sphere);
 
-    let lights = Arc::new(lights);
+    let scene = Scene {
+        world,
+        lights: Option::Some(Arc::new(lights)),
+        delta_lights: Vec::new(),
+        background,
+        infinite_lights: Vec::new(),
+    };
 
-    for x in 0..image_width {
-        for y in 0..image_height {
-            let pixel = img.get_pixel_mut(x, image_height - 1 - y);
-            let mut color: Vec3 = Vec3::zero();
-            for _i in 0..sam_num {
-                let dx = (x as f64 + get_rand01()) / (image_width as f64);
-                let dy = (y as f64 + get_rand01()) / (image_height as f64);
-                let this_ray = cam.get_ray(dx, dy);
-                color += get_color(&this_ray, background, &world, lights.clone(), max_dep);
-            }
-            *pixel = Rgb([
-                ((color.x / sam_num as f64).sqrt() * 255.0) as u8,
-                ((color.y / sam_num as f64).sqrt() * 255.0) as u8,
-                ((color.z / sam_num as f64).sqrt() * 255.0) as u8,
-            ]);
+    // only collected when something reads them, they slow every sample
+    let mut aovs = if write_aovs || denoise {
+        Option::Some(AovFilm::new(image_width, image_height))
+    } else {
+        Option::None
+    };
+    render(
+        &scene,
+        &cam,
+        &mut film,
+        aovs.as_mut(),
+        &firefly,
+        sam_num,
+        max_dep,
+    );
+    firefly.report();
+    film.to_image().save("output/test.png").unwrap();
+    if let Option::Some(aovs) = &aovs {
+        if write_aovs {
+            aovs.save_exr(&film, "output/test.exr");
+        }
+        if denoise {
+            let res = Denoiser::default().denoise(&aovs.buffers(&film));
+            denoise::save_image(image_width, image_height, &res, "output/test_denoised.png");
         }
-        bar.inc(1);
     }
-
-    img.save("output/test.png").unwrap();
-    bar.finish();
 }
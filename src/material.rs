@@ -12,17 +12,54 @@ pub struct ScatterRecord {
     pub is_specular: bool,
     pub atten_col: Vec3,
     pub pdf_ptr: Arc<dyn PDF>,
+    // what layered and blended materials scattered off, so they can
+    // evaluate it later, empty for the others
+    pub parts: Vec<ScatterPart>,
 }
-pub trait Material {
+pub struct ScatterPart {
+    pub weight: f64,
+    pub mat: Arc<dyn Material>,
+    pub srec: ScatterRecord,
+}
+pub trait Material: Send + Sync {
     fn scatter(&self, _this_ray: &Ray, _rec: &HitRecord) -> Option<ScatterRecord> {
         Option::None
     }
+    // BSDF times cosine toward `scattered`, for materials that keep their
+    // color in the scatter attenuation.
     fn scattering_pdf(&self, _this_ray: &Ray, _rec: &HitRecord, _scattered: &Ray) -> f64 {
         0.0
     }
+    // The same in color and times the attenuation of `srec`, the
+    // non-specular scatter it is asked for. Materials whose lobes differ in
+    // color implement this instead of scattering_pdf.
+    fn scattering_color(
+        &self,
+        this_ray: &Ray,
+        rec: &HitRecord,
+        srec: &ScatterRecord,
+        scattered: &Ray,
+    ) -> Vec3 {
+        srec.atten_col * self.scattering_pdf(this_ray, rec, scattered)
+    }
     fn emitted(&self, _this_ray: &Ray, _rec: &HitRecord, _u: f64, _v: f64, _p: Vec3) -> Vec3 {
         Vec3::zero()
     }
+    // How much of scattering_color toward `scattered` is glossy reflection
+    // rather than diffuse, only used to split the lobe AOVs.
+    fn specular_fraction(
+        &self,
+        _this_ray: &Ray,
+        _rec: &HitRecord,
+        _srec: &ScatterRecord,
+        _scattered: &Ray,
+    ) -> f64 {
+        0.0
+    }
+    // Surface color for the albedo AOV.
+    fn albedo(&self, _this_ray: &Ray, _rec: &HitRecord, srec: &ScatterRecord) -> Vec3 {
+        srec.atten_col
+    }
 }
 
 pub struct Lambertian {
@@ -54,8 +91,9 @@ impl Material for Lambertian {
         Option::Some(ScatterRecord {
             specular_ray: Ray::new(Vec3::zero(), Vec3::zero(), 0.0),
             is_specular: false,
-            atten_col: self.albedo.value(rec.u, rec.v, rec.p),
+            atten_col: self.albedo.value_at(rec),
             pdf_ptr: Arc::new(CosPDF::new(rec.nor)),
+            parts: Vec::new(),
         })
     }
     fn scattering_pdf(&self, _this_ray: &Ray, rec: &HitRecord, scattered: &Ray) -> f64 {
@@ -69,6 +107,114 @@ impl Material for Lambertian {
     }
 }
 
+// Oren-Nayar rough diffuse, sigma is the standard deviation of the facet
+// slope angle in degrees. sigma = 0 is Lambertian.
+pub struct OrenNayar {
+    albedo: Arc<dyn Texture>,
+    a: f64,
+    b: f64,
+}
+#[allow(dead_code)]
+impl OrenNayar {
+    pub fn new(albedo: Vec3, sigma: f64) -> Self {
+        Self::newarc(Arc::new(SolidColor::new(albedo)), sigma)
+    }
+    pub fn newarc(albedo: Arc<dyn Texture>, sigma: f64) -> Self {
+        let sigma = sigma * PI / 180.0;
+        let sigma2 = sigma * sigma;
+        Self {
+            albedo,
+            a: 1.0 - sigma2 / (2.0 * (sigma2 + 0.33)),
+            b: 0.45 * sigma2 / (sigma2 + 0.09),
+        }
+    }
+}
+impl Material for OrenNayar {
+    fn scatter(&self, _this_ray: &Ray, rec: &HitRecord) -> Option<ScatterRecord> {
+        Option::Some(ScatterRecord {
+            specular_ray: Ray::new(Vec3::zero(), Vec3::zero(), 0.0),
+            is_specular: false,
+            atten_col: self.albedo.value_at(rec),
+            pdf_ptr: Arc::new(CosPDF::new(rec.nor)),
+            parts: Vec::new(),
+        })
+    }
+    fn scattering_pdf(&self, this_ray: &Ray, rec: &HitRecord, scattered: &Ray) -> f64 {
+        let uvw = ONB::buildw(rec.nor);
+        let wi = -this_ray.dir.unit();
+        let wo = scattered.dir.unit();
+        let cos_i = wi * uvw.w();
+        let cos_o = wo * uvw.w();
+        if cos_o <= 0.0 {
+            return 0.0;
+        }
+        let sin_i = (1.0 - cos_i * cos_i).max(0.0).sqrt();
+        let sin_o = (1.0 - cos_o * cos_o).max(0.0).sqrt();
+        // cos(phi_i - phi_o) from the projections onto the tangent plane
+        let mut max_cos = 0.0;
+        if sin_i > 1e-4 && sin_o > 1e-4 {
+            let dcos =
+                (wi * uvw.u() * (wo * uvw.u()) + wi * uvw.v() * (wo * uvw.v())) / (sin_i * sin_o);
+            max_cos = dcos.max(0.0);
+        }
+        let (sin_alpha, tan_beta) = if cos_i.abs() > cos_o.abs() {
+            (sin_o, sin_i / cos_i.abs())
+        } else {
+            (sin_i, sin_o / cos_o.abs())
+        };
+        cos_o / PI * (self.a + self.b * max_cos * sin_alpha * tan_beta)
+    }
+}
+
+// Disney (Burley) diffuse. The Fresnel-like factor darkens grazing angles on
+// smooth surfaces and adds retro-reflection on rough ones.
+pub struct DisneyDiffuse {
+    base_color: Arc<dyn Texture>,
+    roughness: f64,
+}
+#[allow(dead_code)]
+impl DisneyDiffuse {
+    pub fn new(base_color: Vec3, roughness: f64) -> Self {
+        Self::newarc(Arc::new(SolidColor::new(base_color)), roughness)
+    }
+    pub fn newarc(base_color: Arc<dyn Texture>, roughness: f64) -> Self {
+        Self {
+            base_color,
+            roughness,
+        }
+    }
+}
+impl Material for DisneyDiffuse {
+    fn scatter(&self, _this_ray: &Ray, rec: &HitRecord) -> Option<ScatterRecord> {
+        Option::Some(ScatterRecord {
+            specular_ray: Ray::new(Vec3::zero(), Vec3::zero(), 0.0),
+            is_specular: false,
+            atten_col: self.base_color.value_at(rec),
+            pdf_ptr: Arc::new(CosPDF::new(rec.nor)),
+            parts: Vec::new(),
+        })
+    }
+    fn scattering_pdf(&self, this_ray: &Ray, rec: &HitRecord, scattered: &Ray) -> f64 {
+        let wi = -this_ray.dir.unit();
+        let wo = scattered.dir.unit();
+        let cos_v = (wi * rec.nor).max(0.0);
+        let cos_l = wo * rec.nor;
+        if cos_l <= 0.0 {
+            return 0.0;
+        }
+        let h = wi + wo;
+        let cos_d = if h.squared_length() > 0.0 {
+            wo * h.unit()
+        } else {
+            0.0
+        };
+        let fd90 = 0.5 + 2.0 * self.roughness * cos_d * cos_d;
+        let fl = 1.0 + (fd90 - 1.0) * (1.0 - cos_l).powi(5);
+        let fv = 1.0 + (fd90 - 1.0) * (1.0 - cos_v).powi(5);
+        cos_l / PI * fl * fv
+    }
+}
+
 pub struct Metal {
     albedo: Vec3,
     fuzz: f64,
@@ -91,6 +237,7 @@ impl Material for Metal {
             is_specular: true,
             atten_col: self.albedo,
             pdf_ptr: Arc::new(NOPDF {}),
+            parts: Vec::new(),
         })
     }
 }
@@ -131,6 +278,7 @@ impl Material for Dielectric {
                 is_specular: true,
                 atten_col,
                 pdf_ptr: Arc::new(NOPDF {}),
+                parts: Vec::new(),
             });
         }
         let reflect_prob = schlick(cos_theta, etai_over_etat);
@@ -146,6 +294,7 @@ impl Material for Dielectric {
                 is_specular: true,
                 atten_col,
                 pdf_ptr: Arc::new(NOPDF {}),
+                parts: Vec::new(),
             });
         }
         let refracted = refract(unit_dir, rec.nor, etai_over_etat);
@@ -159,8 +308,318 @@ impl Material for Dielectric {
             is_specular: true,
             atten_col,
             pdf_ptr: Arc::new(NOPDF {}),
+            parts: Vec::new(),
+        })
+    }
+}
+
+// A thin dielectric coat over any base material (car paint, varnish,
+// lacquer). The coat is a GGX reflection lobe with Fresnel, the rest of the
+// light goes through it to the base, is absorbed on the way in and out and
+// leaves with the exit transmittance. Both are sampled with one mixture
+// pdf, only a specular base has to be picked against the coat.
+pub struct Coated {
+    base: Arc<dyn Material>,
+    ref_idx: f64,
+    roughness: f64,
+    // absorption coefficient times coat thickness, per channel
+    absorption: Vec3,
+}
+#[allow(dead_code)]
+impl Coated {
+    pub fn new(base: Arc<dyn Material>, ref_idx: f64, roughness: f64, absorption: Vec3) -> Self {
+        Self {
+            base,
+            ref_idx,
+            roughness: roughness.min(1.0),
+            absorption,
+        }
+    }
+    // one pass through the layer, its length scales with 1 / cos of the
+    // refracted ray
+    fn transmittance(&self, cos: f64) -> Vec3 {
+        let sin2 = (1.0 - cos * cos) / (self.ref_idx * self.ref_idx);
+        let cos_t = (1.0 - sin2).max(0.0).sqrt().max(1e-4);
+        Vec3::new(
+            (-self.absorption.x / cos_t).exp(),
+            (-self.absorption.y / cos_t).exp(),
+            (-self.absorption.z / cos_t).exp(),
+        )
+    }
+    fn alpha(&self) -> f64 {
+        // like Microfacet, a smoother coat is a mirror anyway
+        self.roughness.clamp(0.03, 1.0).powi(2)
+    }
+    // What the base gets to see of light leaving it toward `wi`, with the
+    // viewer at `cos_o`.
+    fn through(&self, cos_o: f64, wi: Vec3, rec: &HitRecord) -> Vec3 {
+        let cos_i = (wi.unit() * rec.nor).abs();
+        self.transmittance(cos_o).change(self.transmittance(cos_i))
+            * ((1.0 - schlick(cos_o, self.ref_idx)) * (1.0 - schlick(cos_i, self.ref_idx)))
+    }
+    // The coat lobe toward `wi`, D G F / (4 cos_o cos_i) times cos_i.
+    fn coat(&self, this_ray: &Ray, rec: &HitRecord, wi: Vec3) -> f64 {
+        let (wo, wi) = (-this_ray.dir.unit(), wi.unit());
+        let (cos_o, cos_i) = (wo * rec.nor, wi * rec.nor);
+        if cos_o <= 0.0 || cos_i <= 0.0 || wi * rec.geo_nor <= 0.0 {
+            return 0.0;
+        }
+        let h = (wo + wi).unit();
+        let alpha = self.alpha();
+        ggx_d(h * rec.nor, alpha)
+            * smith_g1(cos_o, alpha)
+            * smith_g1(cos_i, alpha)
+            * schlick(wo * h, self.ref_idx)
+            / (4.0 * cos_o)
+    }
+    fn coat_pdf(&self, this_ray: &Ray, rec: &HitRecord) -> Arc<dyn PDF> {
+        Arc::new(GgxPDF::new(rec.nor, -this_ray.dir.unit(), self.alpha()))
+    }
+    fn colors(
+        &self,
+        this_ray: &Ray,
+        rec: &HitRecord,
+        srec: &ScatterRecord,
+        scattered: &Ray,
+    ) -> (Vec3, Vec3) {
+        let coat = srec.atten_col * self.coat(this_ray, rec, scattered.dir);
+        let base = match srec.parts.first() {
+            Option::Some(part) => {
+                let cos_o = ((-this_ray.dir.unit()) * rec.nor).max(0.0);
+                part.mat
+                    .scattering_color(this_ray, rec, &part.srec, scattered)
+                    .change(self.through(cos_o, scattered.dir, rec))
+                    .change(srec.atten_col)
+            }
+            Option::None => Vec3::zero(),
+        };
+        (coat, base)
+    }
+}
+impl Material for Coated {
+    fn scatter(&self, this_ray: &Ray, rec: &HitRecord) -> Option<ScatterRecord> {
+        let cos_o = ((-this_ray.dir.unit()) * rec.nor).max(0.0);
+        let f = schlick(cos_o, self.ref_idx);
+        let coat_only = |atten_col: Vec3| ScatterRecord {
+            specular_ray: Ray::new(Vec3::zero(), Vec3::zero(), 0.0),
+            is_specular: false,
+            atten_col,
+            pdf_ptr: self.coat_pdf(this_ray, rec),
+            parts: Vec::new(),
+        };
+        let base = match self.base.scatter(this_ray, rec) {
+            Option::Some(base) => base,
+            Option::None => return Option::Some(coat_only(Vec3::ones())),
+        };
+        if base.is_specular {
+            // a delta lobe can not be mixed, take it with probability 1 - F
+            // which cancels the 1 - F on the way in
+            if get_rand01() < f {
+                return Option::Some(coat_only(Vec3::ones() / f));
+            }
+            let mut base = base;
+            let cos_i = (base.specular_ray.dir.unit() * rec.nor).abs();
+            base.atten_col = base
+                .atten_col
+                .change(self.transmittance(cos_o).change(self.transmittance(cos_i)))
+                * (1.0 - schlick(cos_i, self.ref_idx));
+            return Option::Some(base);
+        }
+        // sample the coat about as often as it reflects
+        let t = self.transmittance(cos_o);
+        let through = (base.atten_col.change(t.change(t)) * (1.0 - f)).luminance();
+        let p_coat = (f / (f + through)).clamp(0.05, 0.95);
+        Option::Some(ScatterRecord {
+            specular_ray: Ray::new(Vec3::zero(), Vec3::zero(), 0.0),
+            is_specular: false,
+            atten_col: Vec3::ones(),
+            pdf_ptr: Arc::new(MixturePDF::weighted(vec![
+                (self.coat_pdf(this_ray, rec), p_coat),
+                (base.pdf_ptr.clone(), 1.0 - p_coat),
+            ])),
+            parts: vec![ScatterPart {
+                weight: 1.0,
+                mat: self.base.clone(),
+                srec: base,
+            }],
         })
     }
+    fn scattering_color(
+        &self,
+        this_ray: &Ray,
+        rec: &HitRecord,
+        srec: &ScatterRecord,
+        scattered: &Ray,
+    ) -> Vec3 {
+        let (coat, base) = self.colors(this_ray, rec, srec, scattered);
+        coat + base
+    }
+    fn specular_fraction(
+        &self,
+        this_ray: &Ray,
+        rec: &HitRecord,
+        srec: &ScatterRecord,
+        scattered: &Ray,
+    ) -> f64 {
+        let (coat, base) = self.colors(this_ray, rec, srec, scattered);
+        let total = (coat + base).luminance();
+        if total <= 0.0 {
+            return 0.0;
+        }
+        let glossy = match srec.parts.first() {
+            Option::Some(part) => part
+                .mat
+                .specular_fraction(this_ray, rec, &part.srec, scattered),
+            Option::None => 0.0,
+        };
+        (coat.luminance() + base.luminance() * glossy) / total
+    }
+    fn albedo(&self, this_ray: &Ray, rec: &HitRecord, srec: &ScatterRecord) -> Vec3 {
+        let cos_o = ((-this_ray.dir.unit()) * rec.nor).max(0.0);
+        let f = schlick(cos_o, self.ref_idx);
+        match srec.parts.first() {
+            Option::Some(part) => {
+                let t = self.transmittance(cos_o);
+                Vec3::ones() * f
+                    + part
+                        .mat
+                        .albedo(this_ray, rec, &part.srec)
+                        .change(t.change(t))
+                        * (1.0 - f)
+            }
+            Option::None => Vec3::ones() * f,
+        }
+    }
+    fn emitted(&self, this_ray: &Ray, rec: &HitRecord, u: f64, v: f64, p: Vec3) -> Vec3 {
+        let cos = ((-this_ray.dir.unit()) * rec.nor).max(0.0);
+        self.base
+            .emitted(this_ray, rec, u, v, p)
+            .change(self.transmittance(cos))
+            * (1.0 - schlick(cos, self.ref_idx))
+    }
+}
+
+// GGX microfacet reflection over a diffuse base, parameterized like the
+// glTF metallic-roughness model. `metal_rough`, when given, scales
+// roughness by its green and metallic by its blue channel. Both lobes are
+// sampled with one mixture pdf weighted by their luminance, Fresnel is
+// evaluated toward the viewer so the lobe weights do not depend on the
+// scattered direction.
+pub struct Microfacet {
+    base_color: Arc<dyn Texture>,
+    metallic: f64,
+    roughness: f64,
+    metal_rough: Option<Arc<dyn Texture>>,
+}
+#[allow(dead_code)]
+impl Microfacet {
+    pub fn new(base_color: Vec3, metallic: f64, roughness: f64) -> Self {
+        Self::newarc(
+            Arc::new(SolidColor::new(base_color)),
+            metallic,
+            roughness,
+            Option::None,
+        )
+    }
+    pub fn newarc(
+        base_color: Arc<dyn Texture>,
+        metallic: f64,
+        roughness: f64,
+        metal_rough: Option<Arc<dyn Texture>>,
+    ) -> Self {
+        Self {
+            base_color,
+            metallic,
+            roughness,
+            metal_rough,
+        }
+    }
+    // (specular weight, diffuse weight, alpha, probability of specular)
+    fn lobes(&self, this_ray: &Ray, rec: &HitRecord) -> (Vec3, Vec3, f64, f64) {
+        let (mut metallic, mut roughness) = (self.metallic, self.roughness);
+        if let Option::Some(tex) = &self.metal_rough {
+            let c = tex.value_at(rec);
+            roughness *= c.y;
+            metallic *= c.z;
+        }
+        let metallic = metallic.clamp(0.0, 1.0);
+        // alpha below 1e-3 only adds noise, the lobe is a mirror by then
+        let alpha = roughness.clamp(0.03, 1.0).powi(2);
+        let base = self.base_color.value_at(rec);
+        let cos = ((-this_ray.dir.unit()) * rec.nor).clamp(0.0, 1.0);
+        let f = (1.0 - cos).powi(5);
+        let f_dielectric = 0.04 + 0.96 * f;
+        let f_metal = base + (Vec3::ones() - base) * f;
+        let spec = f_metal * metallic + Vec3::ones() * (f_dielectric * (1.0 - metallic));
+        let diffuse = base * ((1.0 - metallic) * (1.0 - f_dielectric));
+        let (ls, ld) = (spec.luminance(), diffuse.luminance());
+        let p_spec = if ls + ld > 0.0 { ls / (ls + ld) } else { 1.0 };
+        (spec, diffuse, alpha, p_spec)
+    }
+    // (specular, diffuse) toward `scattered`
+    fn colors(&self, this_ray: &Ray, rec: &HitRecord, scattered: &Ray) -> (Vec3, Vec3) {
+        let wi = scattered.dir.unit();
+        let wo = -this_ray.dir.unit();
+        let (cos_o, cos_i) = (wo * rec.nor, wi * rec.nor);
+        if cos_o <= 0.0 || cos_i <= 0.0 || wi * rec.geo_nor <= 0.0 {
+            return (Vec3::zero(), Vec3::zero());
+        }
+        let (spec, diffuse, alpha, _) = self.lobes(this_ray, rec);
+        let h = (wo + wi).unit();
+        // D G / (4 cos_o cos_i) times cos_i
+        let ggx = ggx_d(h * rec.nor, alpha) * smith_g1(cos_o, alpha) * smith_g1(cos_i, alpha)
+            / (4.0 * cos_o);
+        (spec * ggx, diffuse * (cos_i / PI))
+    }
+}
+fn smith_g1(cos: f64, alpha: f64) -> f64 {
+    let a2 = alpha * alpha;
+    2.0 * cos / (cos + (a2 + (1.0 - a2) * cos * cos).sqrt())
+}
+impl Material for Microfacet {
+    fn scatter(&self, this_ray: &Ray, rec: &HitRecord) -> Option<ScatterRecord> {
+        let (_, _, alpha, p_spec) = self.lobes(this_ray, rec);
+        let wo = -this_ray.dir.unit();
+        Option::Some(ScatterRecord {
+            specular_ray: Ray::new(Vec3::zero(), Vec3::zero(), 0.0),
+            is_specular: false,
+            atten_col: Vec3::ones(),
+            pdf_ptr: Arc::new(MixturePDF::weighted(vec![
+                (Arc::new(GgxPDF::new(rec.nor, wo, alpha)), p_spec),
+                (Arc::new(CosPDF::new(rec.nor)), 1.0 - p_spec),
+            ])),
+            parts: Vec::new(),
+        })
+    }
+    fn scattering_color(
+        &self,
+        this_ray: &Ray,
+        rec: &HitRecord,
+        _srec: &ScatterRecord,
+        scattered: &Ray,
+    ) -> Vec3 {
+        let (spec, diffuse) = self.colors(this_ray, rec, scattered);
+        spec + diffuse
+    }
+    fn specular_fraction(
+        &self,
+        this_ray: &Ray,
+        rec: &HitRecord,
+        _srec: &ScatterRecord,
+        scattered: &Ray,
+    ) -> f64 {
+        let (spec, diffuse) = self.colors(this_ray, rec, scattered);
+        let total = (spec + diffuse).luminance();
+        if total > 0.0 {
+            spec.luminance() / total
+        } else {
+            0.0
+        }
+    }
+    fn albedo(&self, this_ray: &Ray, rec: &HitRecord, _srec: &ScatterRecord) -> Vec3 {
+        let (spec, diffuse, _, _) = self.lobes(this_ray, rec);
+        spec + diffuse
+    }
 }
 
 pub struct DiffuseLight {
@@ -201,3 +660,697 @@ impl Material for NOMaterial {
         unreachable!()
     }
 }
+
+// Turn an outward shading normal into the record's shading normal. The
+// geometric normal is left untouched so that later code can tell the
+// two apart.
+fn set_shading_normal(this_ray: &Ray, rec: &HitRecord, out_nor: Vec3) -> HitRecord {
+    let mut rec = rec.clone();
+    let mut nor = if rec.nor_dir { out_nor } else { -out_nor };
+    if nor * rec.geo_nor < 0.0 {
+        nor = -nor;
+    }
+    // A shading normal facing away from the viewer makes the surface go
+    // black, so bend it back until it is just visible.
+    let wo = -this_ray.dir.unit();
+    let cos = nor * wo;
+    if cos < 0.01 {
+        nor = (nor + wo * (0.01 - cos)).unit();
+    }
+    rec.nor = nor;
+    rec
+}
+fn outward_normal(rec: &HitRecord) -> Vec3 {
+    if rec.nor_dir {
+        rec.geo_nor
+    } else {
+        -rec.geo_nor
+    }
+}
+// A direction that is on different sides of the shading and the geometric
+// surface would leak light through the object.
+fn leaks(rec: &HitRecord, dir: Vec3) -> bool {
+    (dir * rec.nor > 0.0) != (dir * rec.geo_nor > 0.0)
+}
+
+// Both mapping materials shade through `base` with a perturbed normal.
+fn shaded_scatter(
+    base: &Arc<dyn Material>,
+    this_ray: &Ray,
+    rec: &HitRecord,
+) -> Option<ScatterRecord> {
+    let srec = base.scatter(this_ray, rec)?;
+    if srec.is_specular && leaks(rec, srec.specular_ray.dir) {
+        return Option::None;
+    }
+    Option::Some(srec)
+}
+fn shaded_scattering_color(
+    base: &Arc<dyn Material>,
+    this_ray: &Ray,
+    rec: &HitRecord,
+    srec: &ScatterRecord,
+    scattered: &Ray,
+) -> Vec3 {
+    if leaks(rec, scattered.dir) {
+        Vec3::zero()
+    } else {
+        base.scattering_color(this_ray, rec, srec, scattered)
+    }
+}
+
+// Tangent-space normal map, `map` stores (n + 1) / 2 like most tools export.
+pub struct NormalMap {
+    base: Arc<dyn Material>,
+    map: Arc<dyn Texture>,
+}
+#[allow(dead_code)]
+impl NormalMap {
+    pub fn new(base: Arc<dyn Material>, map: Arc<dyn Texture>) -> Self {
+        Self { base, map }
+    }
+    fn shade(&self, this_ray: &Ray, rec: &HitRecord) -> HitRecord {
+        let n = outward_normal(rec);
+        let tangent = rec.dpdu - n * (n * rec.dpdu);
+        let uvw = if tangent.squared_length() > 1e-16 {
+            let t = tangent.unit();
+            let mut b = Vec3::cross(n, t);
+            if b * rec.dpdv < 0.0 {
+                b = -b;
+            }
+            ONB { axis: [t, b, n] }
+        } else {
+            ONB::buildw(n)
+        };
+        let c = self.map.value(rec.u, rec.v, rec.p) * 2.0 - 1.0;
+        if c.squared_length() == 0.0 {
+            return rec.clone();
+        }
+        set_shading_normal(this_ray, rec, uvw.change(c).unit())
+    }
+}
+impl Material for NormalMap {
+    fn scatter(&self, this_ray: &Ray, rec: &HitRecord) -> Option<ScatterRecord> {
+        shaded_scatter(&self.base, this_ray, &self.shade(this_ray, rec))
+    }
+    fn scattering_color(
+        &self,
+        this_ray: &Ray,
+        rec: &HitRecord,
+        srec: &ScatterRecord,
+        scattered: &Ray,
+    ) -> Vec3 {
+        let rec = self.shade(this_ray, rec);
+        shaded_scattering_color(&self.base, this_ray, &rec, srec, scattered)
+    }
+    fn emitted(&self, this_ray: &Ray, rec: &HitRecord, u: f64, v: f64, p: Vec3) -> Vec3 {
+        self.base.emitted(this_ray, rec, u, v, p)
+    }
+    fn specular_fraction(
+        &self,
+        this_ray: &Ray,
+        rec: &HitRecord,
+        srec: &ScatterRecord,
+        scattered: &Ray,
+    ) -> f64 {
+        let rec = self.shade(this_ray, rec);
+        self.base.specular_fraction(this_ray, &rec, srec, scattered)
+    }
+    fn albedo(&self, this_ray: &Ray, rec: &HitRecord, srec: &ScatterRecord) -> Vec3 {
+        self.base.albedo(this_ray, &self.shade(this_ray, rec), srec)
+    }
+}
+
+// Scalar bump map, the luminance of `bump` times `scale` is the displacement
+// along the normal.
+pub struct BumpMap {
+    base: Arc<dyn Material>,
+    bump: Arc<dyn Texture>,
+    scale: f64,
+}
+#[allow(dead_code)]
+impl BumpMap {
+    pub fn new(base: Arc<dyn Material>, bump: Arc<dyn Texture>, scale: f64) -> Self {
+        Self { base, bump, scale }
+    }
+    fn displacement(&self, u: f64, v: f64, p: Vec3) -> f64 {
+        self.bump.value(u, v, p).luminance() * self.scale
+    }
+    fn shade(&self, this_ray: &Ray, rec: &HitRecord) -> HitRecord {
+        let n = outward_normal(rec);
+        if Vec3::cross(rec.dpdu, rec.dpdv).squared_length() == 0.0 {
+            return rec.clone();
+        }
+        let du = 0.0005;
+        let dv = 0.0005;
+        let d = self.displacement(rec.u, rec.v, rec.p);
+        let d_u = self.displacement(rec.u + du, rec.v, rec.p + rec.dpdu * du);
+        let d_v = self.displacement(rec.u, rec.v + dv, rec.p + rec.dpdv * dv);
+        // the displaced surface p + n * d, differentiated
+        let dpdu = rec.dpdu + n * ((d_u - d) / du) + rec.dndu * d;
+        let dpdv = rec.dpdv + n * ((d_v - d) / dv) + rec.dndv * d;
+        let mut bumped = Vec3::cross(dpdu, dpdv);
+        if bumped.squared_length() == 0.0 {
+            return rec.clone();
+        }
+        // dpdu x dpdv need not point outward, keep the side of n
+        if bumped * n < 0.0 {
+            bumped = -bumped;
+        }
+        set_shading_normal(this_ray, rec, bumped.unit())
+    }
+}
+impl Material for BumpMap {
+    fn scatter(&self, this_ray: &Ray, rec: &HitRecord) -> Option<ScatterRecord> {
+        shaded_scatter(&self.base, this_ray, &self.shade(this_ray, rec))
+    }
+    fn scattering_color(
+        &self,
+        this_ray: &Ray,
+        rec: &HitRecord,
+        srec: &ScatterRecord,
+        scattered: &Ray,
+    ) -> Vec3 {
+        let rec = self.shade(this_ray, rec);
+        shaded_scattering_color(&self.base, this_ray, &rec, srec, scattered)
+    }
+    fn emitted(&self, this_ray: &Ray, rec: &HitRecord, u: f64, v: f64, p: Vec3) -> Vec3 {
+        self.base.emitted(this_ray, rec, u, v, p)
+    }
+    fn specular_fraction(
+        &self,
+        this_ray: &Ray,
+        rec: &HitRecord,
+        srec: &ScatterRecord,
+        scattered: &Ray,
+    ) -> f64 {
+        let rec = self.shade(this_ray, rec);
+        self.base.specular_fraction(this_ray, &rec, srec, scattered)
+    }
+    fn albedo(&self, this_ray: &Ray, rec: &HitRecord, srec: &ScatterRecord) -> Vec3 {
+        self.base.albedo(this_ray, &self.shade(this_ray, rec), srec)
+    }
+}
+
+// Scatters off materials blended with weights that sum to one. Delta lobes
+// can not be blended, one of them is taken with its weight as the
+// probability. Otherwise all the others are sampled with one mixture of
+// their pdfs and evaluated together by blend_color.
+fn blend_scatter(
+    this_ray: &Ray,
+    rec: &HitRecord,
+    mats: &[(f64, &Arc<dyn Material>)],
+) -> Option<ScatterRecord> {
+    let mut parts = Vec::new();
+    let mut deltas = Vec::new();
+    for (weight, mat) in mats.iter() {
+        if *weight <= 0.0 {
+            continue;
+        }
+        if let Option::Some(srec) = mat.scatter(this_ray, rec) {
+            let part = ScatterPart {
+                weight: *weight,
+                mat: (*mat).clone(),
+                srec,
+            };
+            if part.srec.is_specular {
+                deltas.push(part);
+            } else {
+                parts.push(part);
+            }
+        }
+    }
+    let p_delta: f64 = deltas.iter().map(|d| d.weight).sum();
+    let mut r = get_rand01();
+    if r < p_delta {
+        for d in deltas {
+            if r < d.weight {
+                return Option::Some(d.srec);
+            }
+            r -= d.weight;
+        }
+    }
+    if parts.is_empty() {
+        return Option::None;
+    }
+    let pdf = parts
+        .iter()
+        .map(|part| (part.srec.pdf_ptr.clone(), part.weight))
+        .collect();
+    Option::Some(ScatterRecord {
+        specular_ray: Ray::new(Vec3::zero(), Vec3::zero(), 0.0),
+        is_specular: false,
+        atten_col: Vec3::ones() / (1.0 - p_delta),
+        pdf_ptr: Arc::new(MixturePDF::weighted(pdf)),
+        parts,
+    })
+}
+// The blended colors and their glossy share.
+fn blend_color(
+    this_ray: &Ray,
+    rec: &HitRecord,
+    srec: &ScatterRecord,
+    scattered: &Ray,
+) -> (Vec3, f64) {
+    let mut col = Vec3::zero();
+    let mut glossy = 0.0;
+    for part in srec.parts.iter() {
+        let c = part
+            .mat
+            .scattering_color(this_ray, rec, &part.srec, scattered)
+            .change(srec.atten_col)
+            * part.weight;
+        col += c;
+        glossy += c.luminance()
+            * part
+                .mat
+                .specular_fraction(this_ray, rec, &part.srec, scattered);
+    }
+    let lum = col.luminance();
+    (col, if lum > 0.0 { glossy / lum } else { 0.0 })
+}
+fn blend_albedo(this_ray: &Ray, rec: &HitRecord, srec: &ScatterRecord) -> Vec3 {
+    let mut albedo = Vec3::zero();
+    for part in srec.parts.iter() {
+        albedo += part.mat.albedo(this_ray, rec, &part.srec) * part.weight;
+    }
+    albedo
+}
+
+// Blend of two materials by a mask, mask = 0 is all `a` and 1 is all `b`.
+// Emission is blended too, so a DiffuseLight on one side gives a partially
+// emissive surface.
+pub struct MixMaterial {
+    a: Arc<dyn Material>,
+    b: Arc<dyn Material>,
+    mask: Arc<dyn Texture>,
+}
+#[allow(dead_code)]
+impl MixMaterial {
+    pub fn new(a: Arc<dyn Material>, b: Arc<dyn Material>, mask: Arc<dyn Texture>) -> Self {
+        Self { a, b, mask }
+    }
+    fn weight(&self, u: f64, v: f64, p: Vec3) -> f64 {
+        self.mask.value(u, v, p).luminance().clamp(0.0, 1.0)
+    }
+}
+impl Material for MixMaterial {
+    fn scatter(&self, this_ray: &Ray, rec: &HitRecord) -> Option<ScatterRecord> {
+        let w = self.weight(rec.u, rec.v, rec.p);
+        blend_scatter(this_ray, rec, &[(1.0 - w, &self.a), (w, &self.b)])
+    }
+    fn scattering_color(
+        &self,
+        this_ray: &Ray,
+        rec: &HitRecord,
+        srec: &ScatterRecord,
+        scattered: &Ray,
+    ) -> Vec3 {
+        blend_color(this_ray, rec, srec, scattered).0
+    }
+    fn emitted(&self, this_ray: &Ray, rec: &HitRecord, u: f64, v: f64, p: Vec3) -> Vec3 {
+        let w = self.weight(u, v, p);
+        self.a.emitted(this_ray, rec, u, v, p) * (1.0 - w)
+            + self.b.emitted(this_ray, rec, u, v, p) * w
+    }
+    fn specular_fraction(
+        &self,
+        this_ray: &Ray,
+        rec: &HitRecord,
+        srec: &ScatterRecord,
+        scattered: &Ray,
+    ) -> f64 {
+        blend_color(this_ray, rec, srec, scattered).1
+    }
+    fn albedo(&self, this_ray: &Ray, rec: &HitRecord, srec: &ScatterRecord) -> Vec3 {
+        blend_albedo(this_ray, rec, srec)
+    }
+}
+
+// Picks a material by height and slope, for terrain. The first band that
+// holds the hit wins and the last band is the fallback. Slope is the angle
+// in degrees between the outward shading normal and +y. The height and
+// slope of each hit are shifted by up to half of `blend_height` and
+// `blend_slope`, and the bands the shifted hits fall in are blended, so
+// neighbouring bands fade into each other.
+pub struct TerrainBand {
+    pub mat: Arc<dyn Material>,
+    pub height: (f64, f64),
+    pub slope: (f64, f64),
+}
+pub struct TerrainMaterial {
+    bands: Vec<TerrainBand>,
+    blend_height: f64,
+    blend_slope: f64,
+}
+#[allow(dead_code)]
+impl TerrainMaterial {
+    pub fn new(blend_height: f64, blend_slope: f64) -> Self {
+        Self {
+            bands: Vec::new(),
+            blend_height,
+            blend_slope,
+        }
+    }
+    pub fn add(&mut self, mat: Arc<dyn Material>, height: (f64, f64), slope: (f64, f64)) {
+        self.bands.push(TerrainBand { mat, height, slope });
+    }
+    fn pick(&self, h: f64, s: f64) -> usize {
+        let band = self.bands.iter().position(|b| {
+            (b.height.0..=b.height.1).contains(&h) && (b.slope.0..=b.slope.1).contains(&s)
+        });
+        assert!(!self.bands.is_empty(), "TerrainMaterial has no bands");
+        band.unwrap_or(self.bands.len() - 1)
+    }
+    // How much of each band is at the hit, over evenly spaced shifts.
+    fn weights(&self, rec: &HitRecord) -> Vec<(f64, &Arc<dyn Material>)> {
+        let n = 16;
+        let up = if rec.nor_dir { rec.nor } else { -rec.nor };
+        let slope = up.y.clamp(-1.0, 1.0).acos().to_degrees();
+        let mut w = vec![0.0; self.bands.len()];
+        for k in 0..n {
+            let shift = (k as f64 + 0.5) / n as f64 - 0.5;
+            let h = rec.p.y + shift * self.blend_height;
+            let s = slope + shift * self.blend_slope;
+            w[self.pick(h, s)] += 1.0 / n as f64;
+        }
+        w.into_iter()
+            .zip(self.bands.iter().map(|b| &b.mat))
+            .collect()
+    }
+}
+impl Material for TerrainMaterial {
+    fn scatter(&self, this_ray: &Ray, rec: &HitRecord) -> Option<ScatterRecord> {
+        blend_scatter(this_ray, rec, &self.weights(rec))
+    }
+    fn scattering_color(
+        &self,
+        this_ray: &Ray,
+        rec: &HitRecord,
+        srec: &ScatterRecord,
+        scattered: &Ray,
+    ) -> Vec3 {
+        blend_color(this_ray, rec, srec, scattered).0
+    }
+    fn emitted(&self, this_ray: &Ray, rec: &HitRecord, u: f64, v: f64, p: Vec3) -> Vec3 {
+        let mut col = Vec3::zero();
+        for (w, mat) in self.weights(rec) {
+            if w > 0.0 {
+                col += mat.emitted(this_ray, rec, u, v, p) * w;
+            }
+        }
+        col
+    }
+    fn specular_fraction(
+        &self,
+        this_ray: &Ray,
+        rec: &HitRecord,
+        srec: &ScatterRecord,
+        scattered: &Ray,
+    ) -> f64 {
+        blend_color(this_ray, rec, srec, scattered).1
+    }
+    fn albedo(&self, this_ray: &Ray, rec: &HitRecord, srec: &ScatterRecord) -> Vec3 {
+        blend_albedo(this_ray, rec, srec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hit::{Hittable, Sphere};
+
+    // Where a ray down the z axis meets the unit sphere.
+    fn sphere_hit(mat: Arc<dyn Material>) -> (Ray, HitRecord) {
+        let this_ray = Ray::new(Vec3::new(0.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+        let sphere = Sphere::new(Vec3::zero(), 1.0, mat);
+        let rec = sphere.hit(&this_ray, 0.001, f64::MAX).unwrap();
+        (this_ray, rec)
+    }
+
+    // A hit on the unit sphere seen from `theta_i` degrees off the normal.
+    fn incoming_at(theta_i: f64) -> (Ray, HitRecord) {
+        let (this_ray, rec) = sphere_hit(Arc::new(Lambertian::new(Vec3::ones())));
+        let uvw = ONB::buildw(rec.nor);
+        let t = theta_i.to_radians();
+        let incoming = Ray::new(
+            rec.p + uvw.local(t.sin(), 0.0, t.cos()),
+            -uvw.local(t.sin(), 0.0, t.cos()),
+            this_ray.tm,
+        );
+        (incoming, rec)
+    }
+
+    // Reflected fraction of light arriving at `theta_i` degrees, the
+    // cosine weighted BRDF integrated over the hemisphere by midpoint rule.
+    fn hemisphere_albedo(mat: &dyn Material, theta_i: f64) -> f64 {
+        let (incoming, rec) = incoming_at(theta_i);
+        let srec = mat.scatter(&incoming, &rec).unwrap();
+        let uvw = ONB::buildw(rec.nor);
+        let n = 200;
+        let (dt, dp) = (PI / 2.0 / n as f64, 2.0 * PI / n as f64);
+        let mut sum = 0.0;
+        for i in 0..n {
+            let theta = (i as f64 + 0.5) * dt;
+            for j in 0..n {
+                let phi = (j as f64 + 0.5) * dp;
+                let dir = uvw.local(
+                    theta.sin() * phi.cos(),
+                    theta.sin() * phi.sin(),
+                    theta.cos(),
+                );
+                let scattered = Ray::new(rec.p, dir, 0.0);
+                let f = mat.scattering_color(&incoming, &rec, &srec, &scattered);
+                sum += f.luminance() * theta.sin() * dt * dp;
+            }
+        }
+        sum
+    }
+
+    #[test]
+    fn test_oren_nayar_smooth_is_lambertian() {
+        let lambert = Lambertian::new(Vec3::ones());
+        let smooth = OrenNayar::new(Vec3::ones(), 0.0);
+        let (this_ray, rec) = sphere_hit(Arc::new(Lambertian::new(Vec3::ones())));
+        for k in 0..16 {
+            let dir = random_unit_vector();
+            let scattered = Ray::new(rec.p, dir, 0.0);
+            let a = lambert.scattering_pdf(&this_ray, &rec, &scattered);
+            let b = smooth.scattering_pdf(&this_ray, &rec, &scattered);
+            assert!((a - b).abs() < 1e-12, "direction {}", k);
+        }
+    }
+
+    #[test]
+    fn test_diffuse_white_furnace() {
+        let lambert = Lambertian::new(Vec3::ones());
+        assert!((hemisphere_albedo(&lambert, 30.0) - 1.0).abs() < 1e-3);
+        for theta_i in [0.0, 45.0, 80.0].iter() {
+            for sigma in [0.0, 20.0, 60.0].iter() {
+                let a = hemisphere_albedo(&OrenNayar::new(Vec3::ones(), *sigma), *theta_i);
+                assert!(
+                    a <= 1.0 + 1e-3 && a > 0.5,
+                    "sigma {} at {}: {}",
+                    sigma,
+                    theta_i,
+                    a
+                );
+            }
+            // smooth Disney diffuse only loses light at grazing angles
+            let a = hemisphere_albedo(&DisneyDiffuse::new(Vec3::ones(), 0.0), *theta_i);
+            assert!(a <= 1.0 + 1e-3 && a > 0.7, "disney at {}: {}", theta_i, a);
+        }
+    }
+
+    #[test]
+    fn test_coated_white_furnace() {
+        let base: Arc<dyn Material> = Arc::new(Lambertian::new(Vec3::ones()));
+        for roughness in [0.3, 0.6, 1.0].iter() {
+            let coated = Coated::new(base.clone(), 1.5, *roughness, Vec3::zero());
+            for theta_i in [0.0, 45.0, 80.0].iter() {
+                let a = hemisphere_albedo(&coated, *theta_i);
+                // single scattering GGX loses light at grazing angles
+                let low = if *theta_i < 60.0 { 0.85 } else { 0.5 };
+                assert!(
+                    a <= 1.0 + 1e-3 && a > low,
+                    "roughness {} at {}: {}",
+                    roughness,
+                    theta_i,
+                    a
+                );
+            }
+        }
+    }
+
+    // The albedo estimated by sampling the scatter pdf, and the integral of
+    // that pdf over the sphere. GGX reflections below the surface are drawn
+    // but have no density there, so the integral can fall a little short
+    // of one.
+    fn sampled_albedo(mat: &dyn Material, theta_i: f64) -> (f64, f64) {
+        let (incoming, rec) = incoming_at(theta_i);
+        let srec = mat.scatter(&incoming, &rec).unwrap();
+        assert!(!srec.is_specular);
+        let n = 200000;
+        let mut estimate = 0.0;
+        let mut total = 0.0;
+        for _ in 0..n {
+            let scattered = Ray::new(rec.p, srec.pdf_ptr.generate(), 0.0);
+            let pdf = srec.pdf_ptr.value(scattered.dir);
+            // reflections about microfacets can point below the surface
+            if pdf > 0.0 {
+                let f = mat.scattering_color(&incoming, &rec, &srec, &scattered);
+                estimate += f.luminance() / pdf / n as f64;
+            }
+            // uniform directions over the sphere
+            let d = random_unit_vector();
+            total += srec.pdf_ptr.value(d) * 4.0 * PI / n as f64;
+        }
+        (estimate, total)
+    }
+
+    #[test]
+    fn test_coated_sampling() {
+        let base: Arc<dyn Material> = Arc::new(Lambertian::new(Vec3::new(0.8, 0.4, 0.2)));
+        let coated = Coated::new(base, 1.5, 0.5, Vec3::new(0.1, 0.0, 0.0));
+        let (estimate, total) = sampled_albedo(&coated, 30.0);
+        let a = hemisphere_albedo(&coated, 30.0);
+        assert!((estimate - a).abs() < 0.02 * a, "{} vs {}", estimate, a);
+        assert!(total < 1.02 && total > 0.9, "{}", total);
+    }
+
+    #[test]
+    fn test_microfacet_lobes() {
+        // red plastic, the white highlight and the red diffuse come from
+        // every scatter
+        let plastic = Microfacet::new(Vec3::new(0.8, 0.1, 0.1), 0.0, 0.5);
+        let (incoming, rec) = incoming_at(30.0);
+        let srec = plastic.scatter(&incoming, &rec).unwrap();
+        let mirror = Ray::new(rec.p, reflect(incoming.dir.unit(), rec.nor), 0.0);
+        let c = plastic.scattering_color(&incoming, &rec, &srec, &mirror);
+        assert!(c.y > 0.0 && c.x > c.y);
+        let glossy = plastic.specular_fraction(&incoming, &rec, &srec, &mirror);
+        assert!(glossy > 0.0 && glossy < 1.0);
+        for mat in [plastic, Microfacet::new(Vec3::ones(), 1.0, 0.5)].iter() {
+            let (estimate, total) = sampled_albedo(mat, 30.0);
+            let a = hemisphere_albedo(mat, 30.0);
+            assert!(a <= 1.0 + 1e-3);
+            assert!((estimate - a).abs() < 0.02 * a, "{} vs {}", estimate, a);
+            assert!(total < 1.02 && total > 0.9, "{}", total);
+        }
+    }
+
+    #[test]
+    fn test_mix_material_blends() {
+        let red: Arc<dyn Material> = Arc::new(Lambertian::new(Vec3::new(1.0, 0.0, 0.0)));
+        let blue: Arc<dyn Material> = Arc::new(Lambertian::new(Vec3::new(0.0, 0.0, 1.0)));
+        let mask = Arc::new(SolidColor::new(Vec3::ones() * 0.25));
+        let mix = MixMaterial::new(red, blue, mask.clone());
+        let (this_ray, rec) = sphere_hit(Arc::new(Lambertian::new(Vec3::ones())));
+        let scattered = Ray::new(rec.p, rec.nor, 0.0);
+        // the same answer every time, with both colors in it
+        for _ in 0..4 {
+            let srec = mix.scatter(&this_ray, &rec).unwrap();
+            let c = mix.scattering_color(&this_ray, &rec, &srec, &scattered);
+            assert!((c - Vec3::new(0.75, 0.0, 0.25) / PI).length() < 1e-9);
+        }
+        // a mirror half is taken half the time, the rest carries twice the
+        // weight of the diffuse half
+        let mirror: Arc<dyn Material> = Arc::new(Metal::new(Vec3::ones(), 0.0));
+        let white: Arc<dyn Material> = Arc::new(Lambertian::new(Vec3::ones()));
+        let half = Arc::new(SolidColor::new(Vec3::ones() * 0.5));
+        let mix = MixMaterial::new(white, mirror, half);
+        let n = 4000;
+        let mut specular = 0;
+        for _ in 0..n {
+            let srec = mix.scatter(&this_ray, &rec).unwrap();
+            if srec.is_specular {
+                specular += 1;
+            } else {
+                let c = mix.scattering_color(&this_ray, &rec, &srec, &scattered);
+                assert!((c - Vec3::ones() / PI).length() < 1e-9);
+            }
+        }
+        assert!((specular as f64 / n as f64 - 0.5).abs() < 0.05);
+    }
+
+    #[test]
+    fn test_terrain_bands_blend() {
+        let mut terrain = TerrainMaterial::new(4.0, 0.0);
+        terrain.add(
+            Arc::new(Lambertian::new(Vec3::ones())),
+            (-1e9, 10.0),
+            (0.0, 90.0),
+        );
+        terrain.add(
+            Arc::new(Lambertian::new(Vec3::zero())),
+            (-1e9, 1e9),
+            (0.0, 90.0),
+        );
+        let (this_ray, mut rec) = sphere_hit(Arc::new(Lambertian::new(Vec3::ones())));
+        let scattered = Ray::new(rec.p, rec.nor, 0.0);
+        for (y, expect) in [(0.0, 1.0), (9.0, 0.75), (10.0, 0.5), (12.0, 0.0)].iter() {
+            rec.p.y = *y;
+            let srec = terrain.scatter(&this_ray, &rec).unwrap();
+            let c = terrain.scattering_color(&this_ray, &rec, &srec, &scattered);
+            assert!((c.x * PI - expect).abs() < 1e-9, "at {}: {}", y, c.x * PI);
+        }
+    }
+
+    struct Ramp {}
+    impl Texture for Ramp {
+        fn value(&self, u: f64, _v: f64, _p: Vec3) -> Vec3 {
+            Vec3::ones() * (2.0 + 0.5 * u)
+        }
+    }
+
+    #[test]
+    fn test_set_shading_normal() {
+        let (this_ray, rec) = sphere_hit(Arc::new(Lambertian::new(Vec3::ones())));
+        // pointing into the surface gets flipped to the geometric side
+        let flipped = set_shading_normal(&this_ray, &rec, Vec3::new(0.0, 0.0, 1.0));
+        assert!((flipped.nor - rec.geo_nor).length() < 1e-9);
+        assert_eq!(flipped.geo_nor, rec.geo_nor);
+        // one facing away from the viewer is bent back until it is visible
+        let grazing = set_shading_normal(&this_ray, &rec, Vec3::new(1.0, 0.0, -0.001).unit());
+        let wo = -this_ray.dir.unit();
+        assert!(grazing.nor * wo >= 0.0099);
+        assert!((grazing.nor.length() - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_normal_map() {
+        let base: Arc<dyn Material> = Arc::new(Lambertian::new(Vec3::ones()));
+        let (this_ray, rec) = sphere_hit(base.clone());
+        let flat = NormalMap::new(
+            base.clone(),
+            Arc::new(SolidColor::new(Vec3::new(0.5, 0.5, 1.0))),
+        );
+        assert!((flat.shade(&this_ray, &rec).nor - rec.nor).length() < 1e-9);
+        // halfway between the normal and the u tangent
+        let tilted = NormalMap::new(base, Arc::new(SolidColor::new(Vec3::new(0.75, 0.5, 0.75))));
+        let nor = tilted.shade(&this_ray, &rec).nor;
+        let half = std::f64::consts::FRAC_1_SQRT_2;
+        assert!((nor * rec.dpdu.unit() - half).abs() < 1e-6);
+        assert!((nor * rec.nor - half).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_bump_map_on_curved_surface() {
+        let base: Arc<dyn Material> = Arc::new(Lambertian::new(Vec3::ones()));
+        let (this_ray, rec) = sphere_hit(base.clone());
+        let bump = BumpMap::new(base, Arc::new(Ramp {}), 1.0);
+        let nor = bump.shade(&this_ray, &rec).nor;
+        // a sphere pushed out to radius 1 + d has its u tangent stretched by
+        // that much, which makes the slope of d tilt the normal less
+        let d = 2.0 + 0.5 * rec.u;
+        let tilt = -(nor * rec.dpdu.unit()) / (nor * rec.nor);
+        let expect = 0.5 / (rec.dpdu.length() * (1.0 + d));
+        assert!((tilt - expect).abs() < 1e-6 * expect.max(1.0));
+    }
+
+    #[test]
+    fn test_missing_texture_is_cyan() {
+        let tex = ImageTexture::new("no/such/texture.png");
+        assert_eq!(tex.value(0.5, 0.5, Vec3::zero()), Vec3::new(0.0, 1.0, 1.0));
+    }
+}
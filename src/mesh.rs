@@ -0,0 +1,406 @@
+use crate::aabb::Aabb;
+use crate::hit::{HitRecord, Hittable};
+use crate::material::Material;
+use crate::planar::planar_pdf;
+use crate::random::*;
+use crate::ray::Ray;
+use crate::vec3::Vec3;
+use std::sync::Arc;
+pub const INF: f64 = f64::MAX;
+
+// Shared vertex data of a triangle mesh. `nor`, `uv` and `col` are either
+// empty or hold one entry per vertex. Triangles wind counterclockwise
+// around their front face.
+#[derive(Default)]
+pub struct TriangleMesh {
+    pub p: Vec<Vec3>,
+    pub nor: Vec<Vec3>,
+    pub uv: Vec<(f64, f64)>,
+    pub col: Vec<Vec3>,
+    pub tris: Vec<[u32; 3]>,
+}
+#[allow(dead_code)]
+impl TriangleMesh {
+    pub fn new(p: Vec<Vec3>, tris: Vec<[u32; 3]>) -> Self {
+        Self {
+            p,
+            tris,
+            ..Default::default()
+        }
+    }
+    // One Hittable per triangle, for a BvhNode or the lights list.
+    pub fn triangles(self: &Arc<Self>, mp: Arc<dyn Material>) -> Vec<Arc<dyn Hittable>> {
+        (0..self.tris.len())
+            .map(|k| Arc::new(Triangle::new(self.clone(), k, mp.clone())) as Arc<dyn Hittable>)
+            .collect()
+    }
+    fn corners(&self, k: usize) -> [Vec3; 3] {
+        let [a, b, c] = self.tris[k];
+        [self.p[a as usize], self.p[b as usize], self.p[c as usize]]
+    }
+    fn tri_box(&self, k: usize) -> (Vec3, Vec3) {
+        let [a, b, c] = self.corners(k);
+        let mut mn = a;
+        let mut mx = a;
+        for q in [b, c].iter() {
+            for i in 0..3 {
+                *mn.get_mut(i) = mn.get(i).min(q.get(i));
+                *mx.get_mut(i) = mx.get(i).max(q.get(i));
+            }
+        }
+        (mn, mx)
+    }
+    fn lerp<T: Copy>(&self, data: &[T], k: usize, f: impl Fn(T, T, T) -> T) -> Option<T> {
+        if data.is_empty() {
+            return Option::None;
+        }
+        let [a, b, c] = self.tris[k];
+        Option::Some(f(data[a as usize], data[b as usize], data[c as usize]))
+    }
+    // Record for a hit with triangle k at barycentric (b1, b2).
+    fn record(
+        &self,
+        this_ray: &Ray,
+        k: usize,
+        t: f64,
+        b1: f64,
+        b2: f64,
+        mp: &Arc<dyn Material>,
+    ) -> HitRecord {
+        let b0 = 1.0 - b1 - b2;
+        let [p0, p1, p2] = self.corners(k);
+        let mut geo = Vec3::cross(p1 - p0, p2 - p0).unit();
+        let (u, v) = self
+            .lerp(&self.uv, k, |a, b, c| {
+                (
+                    a.0 * b0 + b.0 * b1 + c.0 * b2,
+                    a.1 * b0 + b.1 * b1 + c.1 * b2,
+                )
+            })
+            .unwrap_or((b1, b2));
+        let (mut dpdu, mut dpdv) = (p1 - p0, p2 - p0);
+        // vertex normals vary over the triangle the way positions do
+        let ns = if self.nor.is_empty() {
+            Option::None
+        } else {
+            Option::Some(self.tris[k].map(|i| self.nor[i as usize]))
+        };
+        let (mut dndu, mut dndv) = match ns {
+            Option::Some([n0, n1, n2]) => (n1 - n0, n2 - n0),
+            Option::None => (Vec3::zero(), Vec3::zero()),
+        };
+        if !self.uv.is_empty() {
+            let [uv0, uv1, uv2] = self.tris[k].map(|i| self.uv[i as usize]);
+            let (du02, dv02) = (uv0.0 - uv2.0, uv0.1 - uv2.1);
+            let (du12, dv12) = (uv1.0 - uv2.0, uv1.1 - uv2.1);
+            let det = du02 * dv12 - dv02 * du12;
+            if det.abs() > 1e-12 {
+                let (dp02, dp12) = (p0 - p2, p1 - p2);
+                dpdu = (dp02 * dv12 - dp12 * dv02) / det;
+                dpdv = (dp12 * du02 - dp02 * du12) / det;
+                if let Option::Some([n0, n1, n2]) = ns {
+                    let (dn02, dn12) = (n0 - n2, n1 - n2);
+                    dndu = (dn02 * dv12 - dn12 * dv02) / det;
+                    dndv = (dn12 * du02 - dn02 * du12) / det;
+                }
+            }
+        }
+        let shading = self
+            .lerp(&self.nor, k, |a, b, c| a * b0 + b * b1 + c * b2)
+            .filter(|n| n.squared_length() > 0.0)
+            .map(|n| n.unit());
+        // the normals in the file win over the winding
+        if let Option::Some(n) = shading {
+            if n * geo < 0.0 {
+                geo = -geo;
+            }
+        }
+        let mut rec = HitRecord {
+            p: this_ray.pos(t),
+            nor: Vec3::zero(),
+            geo_nor: Vec3::zero(),
+            dpdu,
+            dpdv,
+            dndu,
+            dndv,
+            t,
+            u,
+            v,
+            nor_dir: false,
+            mat_ptr: mp.clone(),
+            vertex_col: self.lerp(&self.col, k, |a, b, c| a * b0 + b * b1 + c * b2),
+            object_id: 0,
+        };
+        rec.set_face_normal(this_ray, geo);
+        if let Option::Some(n) = shading {
+            rec.nor = if rec.nor_dir { n } else { -n };
+        }
+        rec
+    }
+}
+
+// Moller-Trumbore, returns t and the barycentric weights of b and c.
+pub fn hit_triangle(
+    this_ray: &Ray,
+    a: Vec3,
+    b: Vec3,
+    c: Vec3,
+    tmn: f64,
+    tmx: f64,
+) -> Option<(f64, f64, f64)> {
+    let e1 = b - a;
+    let e2 = c - a;
+    let pv = Vec3::cross(this_ray.dir, e2);
+    let det = e1 * pv;
+    if det.abs() < 1e-12 {
+        return Option::None;
+    }
+    let inv = 1.0 / det;
+    let tv = this_ray.ori - a;
+    let b1 = (tv * pv) * inv;
+    if !(0.0..=1.0).contains(&b1) {
+        return Option::None;
+    }
+    let qv = Vec3::cross(tv, e1);
+    let b2 = (this_ray.dir * qv) * inv;
+    if b2 < 0.0 || b1 + b2 > 1.0 {
+        return Option::None;
+    }
+    let t = (e2 * qv) * inv;
+    if t < tmn || t > tmx {
+        return Option::None;
+    }
+    Option::Some((t, b1, b2))
+}
+
+// A single triangle of a mesh, can be sampled as a light.
+pub struct Triangle {
+    mesh: Arc<TriangleMesh>,
+    k: usize,
+    mp: Arc<dyn Material>,
+}
+#[allow(dead_code)]
+impl Triangle {
+    pub fn new(mesh: Arc<TriangleMesh>, k: usize, mp: Arc<dyn Material>) -> Self {
+        Self { mesh, k, mp }
+    }
+}
+impl Hittable for Triangle {
+    fn hit(&self, this_ray: &Ray, tmn: f64, tmx: f64) -> Option<HitRecord> {
+        let [a, b, c] = self.mesh.corners(self.k);
+        let (t, b1, b2) = hit_triangle(this_ray, a, b, c, tmn, tmx)?;
+        Option::Some(self.mesh.record(this_ray, self.k, t, b1, b2, &self.mp))
+    }
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
+        let (mn, mx) = self.mesh.tri_box(self.k);
+        let pad = Vec3::new(0.0001, 0.0001, 0.0001);
+        Option::Some(Aabb::new(mn - pad, mx + pad))
+    }
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+        let [a, b, c] = self.mesh.corners(self.k);
+        planar_pdf(self, Vec3::cross(b - a, c - a).length() / 2.0, o, v)
+    }
+    fn random(&self, o: Vec3) -> Vec3 {
+        let [a, b, c] = self.mesh.corners(self.k);
+        let s = get_rand01().sqrt();
+        let r = get_rand01();
+        a * (1.0 - s) + b * (s * (1.0 - r)) + c * (s * r) - o
+    }
+}
+
+// Node of the flattened BVH, the first child directly follows its parent.
+struct MeshNode {
+    mybox: Aabb,
+    // leaves hold tris[start..start + count], inner nodes have count 0
+    start: u32,
+    count: u32,
+    second: u32,
+    axis: u8,
+}
+
+// Whole mesh behind one flat BVH, much lighter than a BvhNode over
+// millions of Triangles.
+pub struct Mesh {
+    mesh: Arc<TriangleMesh>,
+    nodes: Vec<MeshNode>,
+    tris: Vec<u32>,
+    mp: Arc<dyn Material>,
+    // running sum of the triangle areas, for light sampling
+    areas: Vec<f64>,
+}
+#[allow(dead_code)]
+impl Mesh {
+    pub fn new(mesh: Arc<TriangleMesh>, mp: Arc<dyn Material>) -> Self {
+        assert!(!mesh.tris.is_empty(), "Mesh has no triangles");
+        let boxes: Vec<(Vec3, Vec3)> = (0..mesh.tris.len()).map(|k| mesh.tri_box(k)).collect();
+        let mut tris: Vec<u32> = (0..mesh.tris.len() as u32).collect();
+        let mut nodes = Vec::with_capacity(2 * tris.len() / 4 + 1);
+        build(&boxes, &mut tris, 0, &mut nodes);
+        let mut areas = Vec::with_capacity(mesh.tris.len());
+        let mut sum = 0.0;
+        for k in 0..mesh.tris.len() {
+            let [a, b, c] = mesh.corners(k);
+            sum += Vec3::cross(b - a, c - a).length() / 2.0;
+            areas.push(sum);
+        }
+        Self {
+            mesh,
+            nodes,
+            tris,
+            mp,
+            areas,
+        }
+    }
+}
+
+// Median split along the longest axis of the centroids, four triangles
+// to a leaf.
+fn build(boxes: &[(Vec3, Vec3)], tris: &mut [u32], start: usize, nodes: &mut Vec<MeshNode>) {
+    let mut mn = Vec3::new(INF, INF, INF);
+    let mut mx = Vec3::new(-INF, -INF, -INF);
+    let mut cmn = mn;
+    let mut cmx = mx;
+    for &k in tris.iter() {
+        let (a, b) = boxes[k as usize];
+        let c = (a + b) * 0.5;
+        for i in 0..3 {
+            *mn.get_mut(i) = mn.get(i).min(a.get(i));
+            *mx.get_mut(i) = mx.get(i).max(b.get(i));
+            *cmn.get_mut(i) = cmn.get(i).min(c.get(i));
+            *cmx.get_mut(i) = cmx.get(i).max(c.get(i));
+        }
+    }
+    let pad = Vec3::new(0.0001, 0.0001, 0.0001);
+    let me = nodes.len();
+    nodes.push(MeshNode {
+        mybox: Aabb::new(mn - pad, mx + pad),
+        start: start as u32,
+        count: tris.len() as u32,
+        second: 0,
+        axis: 0,
+    });
+    if tris.len() <= 4 {
+        return;
+    }
+    let ext = cmx - cmn;
+    let axis = if ext.x > ext.y && ext.x > ext.z {
+        0
+    } else if ext.y > ext.z {
+        1
+    } else {
+        2
+    };
+    let mid = tris.len() / 2;
+    let centroid = |k: &u32| {
+        let (a, b) = boxes[*k as usize];
+        a.get(axis) + b.get(axis)
+    };
+    tris.select_nth_unstable_by(mid, |a, b| centroid(a).partial_cmp(&centroid(b)).unwrap());
+    let (left, right) = tris.split_at_mut(mid);
+    build(boxes, left, start, nodes);
+    let second = nodes.len() as u32;
+    build(boxes, right, start + mid, nodes);
+    nodes[me].count = 0;
+    nodes[me].second = second;
+    nodes[me].axis = axis as u8;
+}
+
+impl Hittable for Mesh {
+    fn hit(&self, this_ray: &Ray, tmn: f64, tmx: f64) -> Option<HitRecord> {
+        let mut closest = tmx;
+        let mut best = Option::None;
+        let mut stack = vec![0u32];
+        while let Option::Some(i) = stack.pop() {
+            let node = &self.nodes[i as usize];
+            if !node.mybox.hit(*this_ray, tmn, closest) {
+                continue;
+            }
+            if node.count > 0 {
+                let range = node.start as usize..(node.start + node.count) as usize;
+                for &k in self.tris[range].iter() {
+                    let [a, b, c] = self.mesh.corners(k as usize);
+                    if let Option::Some((t, b1, b2)) = hit_triangle(this_ray, a, b, c, tmn, closest)
+                    {
+                        closest = t;
+                        best = Option::Some((k as usize, t, b1, b2));
+                    }
+                }
+            } else if this_ray.dir.get(node.axis as i32) < 0.0 {
+                // nearer child last so it is popped first
+                stack.push(i + 1);
+                stack.push(node.second);
+            } else {
+                stack.push(node.second);
+                stack.push(i + 1);
+            }
+        }
+        let (k, t, b1, b2) = best?;
+        Option::Some(self.mesh.record(this_ray, k, t, b1, b2, &self.mp))
+    }
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
+        Option::Some(self.nodes[0].mybox.clone())
+    }
+    // Uniform over the area of the whole mesh, summed over every triangle
+    // the direction goes through.
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+        let area = self.areas[self.areas.len() - 1];
+        let mut pdf = 0.0;
+        for rec in self.hit_all(&Ray::new(o, v, 0.0), 0.001, INF) {
+            let dis_squared = rec.t * rec.t * v.squared_length();
+            let cos = ((v * rec.geo_nor) / v.length()).abs();
+            pdf += dis_squared / (cos * area);
+        }
+        pdf
+    }
+    fn random(&self, o: Vec3) -> Vec3 {
+        let r = get_rand01() * self.areas[self.areas.len() - 1];
+        let k = self
+            .areas
+            .partition_point(|&a| a < r)
+            .min(self.areas.len() - 1);
+        let [a, b, c] = self.mesh.corners(k);
+        let s = get_rand01().sqrt();
+        let r = get_rand01();
+        a * (1.0 - s) + b * (s * (1.0 - r)) + c * (s * r) - o
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::Lambertian;
+
+    #[test]
+    fn test_mesh_matches_triangles() {
+        let mut p = Vec::new();
+        let mut tris = Vec::new();
+        for k in 0..300u32 {
+            let c = Vec3::new(
+                (k as f64 * 0.618).fract(),
+                (k as f64 * 0.377).fract(),
+                (k as f64 * 0.733).fract(),
+            ) * 10.0;
+            p.push(c);
+            p.push(c + Vec3::new(1.0, 0.2, 0.0));
+            p.push(c + Vec3::new(0.1, 1.0, 0.5));
+            tris.push([3 * k, 3 * k + 1, 3 * k + 2]);
+        }
+        let mesh = Arc::new(TriangleMesh::new(p, tris));
+        let mp: Arc<dyn Material> = Arc::new(Lambertian::new(Vec3::ones()));
+        let whole = Mesh::new(mesh.clone(), mp.clone());
+        let single = mesh.triangles(mp);
+        for k in 0..500 {
+            let o = Vec3::new(5.0, 5.0, -20.0);
+            let target =
+                Vec3::new((k as f64 * 0.271).fract(), (k as f64 * 0.829).fract(), 0.5) * 10.0;
+            let ray = Ray::new(o, target - o, 0.0);
+            let brute = single
+                .iter()
+                .filter_map(|s| s.hit(&ray, 0.001, INF))
+                .map(|r| r.t)
+                .fold(INF, f64::min);
+            let fast = whole.hit(&ray, 0.001, INF).map_or(INF, |r| r.t);
+            assert_eq!(brute, fast);
+        }
+    }
+}
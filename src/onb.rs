@@ -12,7 +12,7 @@ impl ONB {
         } else {
             Vec3::new(1.0, 0.0, 0.0)
         };
-        let axis1 = Vec3::cross(axis2, a);
+        let axis1 = Vec3::cross(axis2, a).unit();
         let axis0 = Vec3::cross(axis1, axis2);
         Self {
             axis: [axis0, axis1, axis2],
@@ -37,3 +37,32 @@ impl ONB {
         self.axis[a]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::random_in_unit_sphere;
+
+    #[test]
+    fn test_orthonormal() {
+        let mut normals = vec![
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, -3.0, 0.0),
+            Vec3::new(0.0, 0.0, 0.5),
+            Vec3::new(0.95, 0.3, 0.1),
+            Vec3::new(1.0, 1.0, 1.0),
+        ];
+        for _ in 0..100 {
+            normals.push(random_in_unit_sphere());
+        }
+        for n in normals.iter() {
+            let uvw = ONB::buildw(*n);
+            assert!((uvw.w() - n.unit()).length() < 1e-9);
+            for i in 0..3 {
+                assert!((uvw.get(i).length() - 1.0).abs() < 1e-9);
+                assert!((uvw.get(i) * uvw.get((i + 1) % 3)).abs() < 1e-9);
+            }
+            assert!((Vec3::cross(uvw.u(), uvw.v()) - uvw.w()).length() < 1e-9);
+        }
+    }
+}
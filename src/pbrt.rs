@@ -0,0 +1,1111 @@
+use crate::camera::{Camera, CameraModel, OrthographicCamera};
+use crate::filter::*;
+use crate::hit::{BvhNode, FlipFace, HitRecord, Hittable, HittableList, Sphere, Tagged};
+use crate::light::{DistantLight, EnvironmentLight, InfiniteLight, Light, PointLight, SpotLight};
+use crate::material::*;
+use crate::mesh::{Mesh, TriangleMesh};
+use crate::ply::load_ply;
+use crate::ray::Ray;
+use crate::texture::{ImageTexture, SolidColor, Texture};
+use crate::transform::{Transform, Transformed};
+use crate::vec3::Vec3;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// What a pbrt-v3 scene maps onto. Shapes are in world space, `lights` holds
+// the emitting ones for light sampling.
+#[allow(dead_code)]
+pub struct PbrtScene {
+    pub world: HittableList,
+    pub lights: HittableList,
+    pub delta_lights: Vec<Arc<dyn Light>>,
+    pub infinite_lights: Vec<Arc<dyn InfiniteLight>>,
+    pub camera: Arc<dyn CameraModel>,
+    pub filter: Arc<dyn Filter>,
+    pub width: u32,
+    pub height: u32,
+    pub spp: i32,
+    pub max_depth: i32,
+    // Film filename, if the scene names one
+    pub output: Option<String>,
+    // each distinct warning once, in the order they came up
+    pub warnings: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Str(String),
+    Num(f64),
+    Open,
+    Close,
+}
+
+fn tokenize(src: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = src.chars().peekable();
+    while let Option::Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '#' {
+            for c in chars.by_ref() {
+                if c == '\n' {
+                    break;
+                }
+            }
+        } else if c == '[' || c == ']' {
+            chars.next();
+            tokens.push(if c == '[' { Token::Open } else { Token::Close });
+        } else if c == '"' {
+            chars.next();
+            let mut s = String::new();
+            loop {
+                match chars.next() {
+                    Option::Some('"') => break,
+                    Option::Some('\\') => match chars.next() {
+                        Option::Some('n') => s.push('\n'),
+                        Option::Some('t') => s.push('\t'),
+                        Option::Some(e) => s.push(e),
+                        Option::None => return Err("unterminated string".to_string()),
+                    },
+                    Option::Some(c) => s.push(c),
+                    Option::None => return Err("unterminated string".to_string()),
+                }
+            }
+            tokens.push(Token::Str(s));
+        } else {
+            let mut s = String::new();
+            while let Option::Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == '"' || c == '[' || c == ']' || c == '#' {
+                    break;
+                }
+                s.push(c);
+                chars.next();
+            }
+            if c.is_ascii_digit() || c == '-' || c == '+' || c == '.' {
+                let x = s.parse().map_err(|_| format!("bad number {}", s))?;
+                tokens.push(Token::Num(x));
+            } else {
+                tokens.push(Token::Word(s));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+// One `"type name" value` pair of a directive's parameter list.
+#[derive(Clone)]
+struct Param {
+    ty: String,
+    name: String,
+    nums: Vec<f64>,
+    strs: Vec<String>,
+}
+
+#[derive(Clone, Default)]
+struct ParamSet {
+    params: Vec<Param>,
+}
+impl ParamSet {
+    fn parse(tokens: &[Token]) -> Result<Self, String> {
+        let mut params = Vec::new();
+        let mut k = 0;
+        while k < tokens.len() {
+            let decl = match &tokens[k] {
+                Token::Str(s) => s,
+                t => return Err(format!("expected a parameter, found {:?}", t)),
+            };
+            let words: Vec<&str> = decl.split_whitespace().collect();
+            if words.len() != 2 {
+                return Err(format!("bad parameter \"{}\"", decl));
+            }
+            k += 1;
+            let values = if tokens.get(k) == Option::Some(&Token::Open) {
+                let end = tokens[k..]
+                    .iter()
+                    .position(|t| *t == Token::Close)
+                    .ok_or_else(|| format!("no ] after \"{}\"", decl))?;
+                let values = &tokens[k + 1..k + end];
+                k += end + 1;
+                values
+            } else {
+                k += 1;
+                tokens
+                    .get(k - 1..k)
+                    .ok_or_else(|| format!("no value for \"{}\"", decl))?
+            };
+            let mut p = Param {
+                ty: words[0].to_string(),
+                name: words[1].to_string(),
+                nums: Vec::new(),
+                strs: Vec::new(),
+            };
+            for v in values.iter() {
+                match v {
+                    Token::Num(x) => p.nums.push(*x),
+                    Token::Str(s) => p.strs.push(s.clone()),
+                    t => return Err(format!("bad value {:?} for \"{}\"", t, decl)),
+                }
+            }
+            params.push(p);
+        }
+        Ok(Self { params })
+    }
+    fn get(&self, name: &str) -> Option<&Param> {
+        self.params.iter().find(|p| p.name == name)
+    }
+    fn float(&self, name: &str, default: f64) -> f64 {
+        self.get(name)
+            .and_then(|p| p.nums.first().copied())
+            .unwrap_or(default)
+    }
+    fn floats(&self, name: &str) -> &[f64] {
+        self.get(name).map_or(&[], |p| &p.nums[..])
+    }
+    fn point(&self, name: &str, default: Vec3) -> Vec3 {
+        match self.floats(name) {
+            [x, y, z, ..] => Vec3::new(*x, *y, *z),
+            _ => default,
+        }
+    }
+    fn string(&self, name: &str) -> Option<String> {
+        self.get(name).and_then(|p| p.strs.first().cloned())
+    }
+    fn bool(&self, name: &str, default: bool) -> bool {
+        self.string(name).map_or(default, |s| s == "true")
+    }
+}
+
+// The numbers of a directive like Translate, brackets or not.
+fn numbers(directive: &str, args: &[Token], n: usize) -> Result<Vec<f64>, String> {
+    let nums: Vec<f64> = args
+        .iter()
+        .filter_map(|t| match t {
+            Token::Num(x) => Option::Some(*x),
+            _ => Option::None,
+        })
+        .collect();
+    if nums.len() != n {
+        return Err(format!("{} takes {} numbers", directive, n));
+    }
+    Ok(nums)
+}
+
+// `n` leading strings like a shape type, then the parameter list.
+fn strings(directive: &str, args: &[Token], n: usize) -> Result<(Vec<String>, ParamSet), String> {
+    let mut names = Vec::new();
+    for t in args.iter().take(n) {
+        match t {
+            Token::Str(s) => names.push(s.clone()),
+            _ => break,
+        }
+    }
+    if names.len() != n {
+        return Err(format!("{} takes {} quoted names", directive, n));
+    }
+    Ok((names, ParamSet::parse(&args[n..])?))
+}
+
+// pbrt's LookAt as a world to camera transform. The camera looks down +z
+// with +y up and +x to the right of the image.
+fn look_at(pos: Vec3, look: Vec3, up: Vec3) -> Result<Transform, String> {
+    let dir = look - pos;
+    let right = Vec3::cross(up, dir);
+    if dir.length() == 0.0 || right.length() == 0.0 {
+        return Err("LookAt up vector is parallel to the view direction".to_string());
+    }
+    let (dir, right) = (dir.unit(), right.unit());
+    let new_up = Vec3::cross(dir, right);
+    let m = [
+        [right.x, new_up.x, dir.x, pos.x],
+        [right.y, new_up.y, dir.y, pos.y],
+        [right.z, new_up.z, dir.z, pos.z],
+        [0.0, 0.0, 0.0, 1.0],
+    ];
+    Ok(Transform::from_matrix(m).unwrap().inverse())
+}
+
+// Trowbridge-Reitz roughness remapping from pbrt-v3.
+fn roughness_to_alpha(roughness: f64) -> f64 {
+    let x = roughness.max(1e-3).ln();
+    1.62142 + 0.819955 * x + 0.1734 * x * x + 0.0171201 * x * x * x + 0.000640711 * x * x * x * x
+}
+
+// Diffuse area light with "bool twosided" set.
+struct TwoSidedLight {
+    emit: Vec3,
+}
+impl Material for TwoSidedLight {
+    fn emitted(&self, _this_ray: &Ray, _rec: &HitRecord, _u: f64, _v: f64, _p: Vec3) -> Vec3 {
+        self.emit
+    }
+}
+
+// pbrt environment maps have +z up in light space, EnvironmentLight has +y
+// up, so directions swap y and z on the way in and out.
+struct PbrtEnvironment {
+    env: EnvironmentLight,
+    to_world: Transform,
+}
+impl PbrtEnvironment {
+    fn to_env(&self, dir: Vec3) -> Vec3 {
+        let d = self.to_world.inverse().vector(dir);
+        Vec3::new(d.x, d.z, d.y)
+    }
+}
+impl InfiniteLight for PbrtEnvironment {
+    fn value(&self, dir: Vec3) -> Vec3 {
+        self.env.value(self.to_env(dir))
+    }
+    fn pdf_value(&self, dir: Vec3) -> f64 {
+        self.env.pdf_value(self.to_env(dir))
+    }
+    fn random(&self) -> Vec3 {
+        let d = self.env.random();
+        self.to_world.vector(Vec3::new(d.x, d.z, d.y)).unit()
+    }
+}
+
+#[derive(Clone)]
+struct Attributes {
+    ctm: Transform,
+    // None for pbrt's "none" material, whose shapes are not drawn
+    material: Option<Arc<dyn Material>>,
+    // radiance and two sidedness of AreaLightSource
+    area_light: Option<(Vec3, bool)>,
+    reverse: bool,
+}
+
+struct Parser {
+    dir: PathBuf,
+    attrs: Attributes,
+    attr_stack: Vec<Attributes>,
+    transform_stack: Vec<Transform>,
+    coord_systems: HashMap<String, Transform>,
+    named_materials: HashMap<String, Option<Arc<dyn Material>>>,
+    textures: HashMap<String, Arc<dyn Texture>>,
+    instances: HashMap<String, Option<Arc<dyn Hittable>>>,
+    // name and shapes of the ObjectBegin being read
+    instance: Option<(String, Vec<Arc<dyn Hittable>>)>,
+    // type, parameters and world to camera transform
+    camera: (String, ParamSet, Transform),
+    film: ParamSet,
+    filter: Arc<dyn Filter>,
+    spp: i32,
+    max_depth: i32,
+    objects: Vec<Arc<dyn Hittable>>,
+    lights: HittableList,
+    delta_lights: Vec<Arc<dyn Light>>,
+    infinite_lights: Vec<Arc<dyn InfiniteLight>>,
+    warnings: Vec<String>,
+    // files being read, innermost last, so an Include can not loop
+    including: Vec<PathBuf>,
+}
+
+impl Parser {
+    fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            attrs: Attributes {
+                ctm: Transform::identity(),
+                material: Option::Some(Arc::new(Lambertian::new(Vec3::ones() * 0.5))),
+                area_light: Option::None,
+                reverse: false,
+            },
+            attr_stack: Vec::new(),
+            transform_stack: Vec::new(),
+            coord_systems: HashMap::new(),
+            named_materials: HashMap::new(),
+            textures: HashMap::new(),
+            instances: HashMap::new(),
+            instance: Option::None,
+            camera: (
+                "perspective".to_string(),
+                ParamSet::default(),
+                Transform::identity(),
+            ),
+            film: ParamSet::default(),
+            filter: Arc::new(BoxFilter::new(0.5)),
+            spp: 16,
+            max_depth: 6,
+            objects: Vec::new(),
+            lights: HittableList::default(),
+            delta_lights: Vec::new(),
+            infinite_lights: Vec::new(),
+            warnings: Vec::new(),
+            including: Vec::new(),
+        }
+    }
+    fn warn(&mut self, msg: String) {
+        if !self.warnings.contains(&msg) {
+            eprintln!("pbrt: {}", msg);
+            self.warnings.push(msg);
+        }
+    }
+    // File names are relative to the directory of the main scene file.
+    fn resolve(&self, name: &str) -> String {
+        self.dir.join(name).to_string_lossy().into_owned()
+    }
+    fn parse(&mut self, src: &str) -> Result<(), String> {
+        let tokens = tokenize(src)?;
+        let mut i = 0;
+        while i < tokens.len() {
+            let name = match &tokens[i] {
+                Token::Word(w) => w,
+                t => return Err(format!("expected a directive, found {:?}", t)),
+            };
+            let end = tokens[i + 1..]
+                .iter()
+                .position(|t| matches!(t, Token::Word(_)))
+                .map_or(tokens.len(), |k| i + 1 + k);
+            self.directive(name, &tokens[i + 1..end])?;
+            i = end;
+        }
+        Ok(())
+    }
+    fn concat(&mut self, t: Transform) {
+        self.attrs.ctm = self.attrs.ctm * t;
+    }
+    fn directive(&mut self, name: &str, args: &[Token]) -> Result<(), String> {
+        match name {
+            "Identity" => self.attrs.ctm = Transform::identity(),
+            "Translate" => {
+                let v = numbers(name, args, 3)?;
+                self.concat(Transform::translate(Vec3::new(v[0], v[1], v[2])));
+            }
+            "Scale" => {
+                let v = numbers(name, args, 3)?;
+                self.concat(Transform::scale(Vec3::new(v[0], v[1], v[2])));
+            }
+            "Rotate" => {
+                let v = numbers(name, args, 4)?;
+                self.concat(Transform::rotate(Vec3::new(v[1], v[2], v[3]), v[0]));
+            }
+            "LookAt" => {
+                let v = numbers(name, args, 9)?;
+                self.concat(look_at(
+                    Vec3::new(v[0], v[1], v[2]),
+                    Vec3::new(v[3], v[4], v[5]),
+                    Vec3::new(v[6], v[7], v[8]),
+                )?);
+            }
+            "Transform" | "ConcatTransform" => {
+                // given column by column
+                let v = numbers(name, args, 16)?;
+                let mut m = [[0.0; 4]; 4];
+                for (k, x) in v.iter().enumerate() {
+                    m[k % 4][k / 4] = *x;
+                }
+                match Transform::from_matrix(m) {
+                    Option::Some(t) if name == "Transform" => self.attrs.ctm = t,
+                    Option::Some(t) => self.concat(t),
+                    Option::None => self.warn(format!("singular {} is ignored", name)),
+                }
+            }
+            "CoordinateSystem" => {
+                let (s, _) = strings(name, args, 1)?;
+                self.coord_systems.insert(s[0].clone(), self.attrs.ctm);
+            }
+            "CoordSysTransform" => {
+                let (s, _) = strings(name, args, 1)?;
+                match self.coord_systems.get(&s[0]) {
+                    Option::Some(t) => self.attrs.ctm = *t,
+                    Option::None => self.warn(format!("unknown coordinate system {}", s[0])),
+                }
+            }
+            "ReverseOrientation" => self.attrs.reverse = !self.attrs.reverse,
+            "AttributeBegin" => self.attr_stack.push(self.attrs.clone()),
+            "AttributeEnd" => match self.attr_stack.pop() {
+                Option::Some(a) => self.attrs = a,
+                Option::None => self.warn("unmatched AttributeEnd".to_string()),
+            },
+            "TransformBegin" => self.transform_stack.push(self.attrs.ctm),
+            "TransformEnd" => match self.transform_stack.pop() {
+                Option::Some(t) => self.attrs.ctm = t,
+                Option::None => self.warn("unmatched TransformEnd".to_string()),
+            },
+            "Camera" => {
+                let (s, ps) = strings(name, args, 1)?;
+                self.coord_systems
+                    .insert("camera".to_string(), self.attrs.ctm.inverse());
+                self.camera = (s[0].clone(), ps, self.attrs.ctm);
+            }
+            "Film" => {
+                let (s, ps) = strings(name, args, 1)?;
+                if s[0] != "image" {
+                    self.warn(format!("film {} is read as image", s[0]));
+                }
+                if ps.get("cropwindow").is_some() {
+                    self.warn("cropwindow is ignored".to_string());
+                }
+                self.film = ps;
+            }
+            "Sampler" => {
+                let (_, ps) = strings(name, args, 1)?;
+                self.spp = ps.float("pixelsamples", 16.0) as i32;
+            }
+            "Integrator" => {
+                let (s, ps) = strings(name, args, 1)?;
+                if s[0] != "path" && s[0] != "volpath" {
+                    self.warn(format!("integrator {} is rendered as path", s[0]));
+                }
+                // pbrt counts bounces, get_color counts path vertices
+                self.max_depth = ps.float("maxdepth", 5.0) as i32 + 1;
+            }
+            "PixelFilter" => {
+                let (s, ps) = strings(name, args, 1)?;
+                self.pixel_filter(&s[0], &ps);
+            }
+            // the world always goes into a BVH
+            "Accelerator" => {}
+            "WorldBegin" => {
+                self.attrs.ctm = Transform::identity();
+                self.coord_systems
+                    .insert("world".to_string(), Transform::identity());
+            }
+            "WorldEnd" => {}
+            "Material" => {
+                let (s, ps) = strings(name, args, 1)?;
+                self.attrs.material = self.material(&s[0], &ps);
+            }
+            "MakeNamedMaterial" => {
+                let (s, ps) = strings(name, args, 1)?;
+                let ty = ps.string("type").unwrap_or_default();
+                let mat = self.material(&ty, &ps);
+                self.named_materials.insert(s[0].clone(), mat);
+            }
+            "NamedMaterial" => {
+                let (s, _) = strings(name, args, 1)?;
+                match self.named_materials.get(&s[0]) {
+                    Option::Some(mat) => self.attrs.material = mat.clone(),
+                    Option::None => self.warn(format!("unknown material {}", s[0])),
+                }
+            }
+            "Texture" => {
+                let (s, ps) = strings(name, args, 3)?;
+                self.texture(&s[0], &s[2], &ps);
+            }
+            "LightSource" => {
+                let (s, ps) = strings(name, args, 1)?;
+                self.light(&s[0], &ps);
+            }
+            "AreaLightSource" => {
+                let (s, ps) = strings(name, args, 1)?;
+                if s[0] != "diffuse" {
+                    self.warn(format!("area light {} is read as diffuse", s[0]));
+                }
+                let l = self.color(&ps, "L", Vec3::ones());
+                let scale = self.color(&ps, "scale", Vec3::ones());
+                self.attrs.area_light = Option::Some((l.change(scale), ps.bool("twosided", false)));
+            }
+            "Shape" => {
+                let (s, ps) = strings(name, args, 1)?;
+                self.shape(&s[0], &ps)?;
+            }
+            "ObjectBegin" => {
+                let (s, _) = strings(name, args, 1)?;
+                if self.instance.is_some() {
+                    self.warn("nested ObjectBegin, the outer object ends here".to_string());
+                    self.end_object();
+                }
+                self.attr_stack.push(self.attrs.clone());
+                self.instance = Option::Some((s[0].clone(), Vec::new()));
+            }
+            "ObjectEnd" => {
+                self.end_object();
+                if let Option::Some(a) = self.attr_stack.pop() {
+                    self.attrs = a;
+                }
+            }
+            "ObjectInstance" => {
+                let (s, _) = strings(name, args, 1)?;
+                match self.instances.get(&s[0]).cloned() {
+                    Option::Some(Option::Some(obj)) => {
+                        let ctm = self.attrs.ctm;
+                        if ctm.m == Transform::identity().m {
+                            self.push_object(obj);
+                        } else {
+                            self.push_object(Arc::new(Transformed::new(obj, ctm)));
+                        }
+                    }
+                    Option::Some(Option::None) => {}
+                    Option::None => self.warn(format!("unknown object {}", s[0])),
+                }
+            }
+            "Include" => {
+                let (s, _) = strings(name, args, 1)?;
+                let path = self.resolve(&s[0]);
+                let src = std::fs::read_to_string(&path)
+                    .map_err(|e| format!("cannot read {}: {}", path, e))?;
+                let real = std::fs::canonicalize(&path).unwrap_or_else(|_| PathBuf::from(&path));
+                if self.including.contains(&real) {
+                    return Err(format!("{} includes itself", path));
+                }
+                self.including.push(real);
+                let res = self.parse(&src);
+                self.including.pop();
+                res?;
+            }
+            _ => self.warn(format!("unsupported directive {}", name)),
+        }
+        Ok(())
+    }
+    fn end_object(&mut self) {
+        match self.instance.take() {
+            Option::Some((name, mut shapes)) => {
+                let obj: Option<Arc<dyn Hittable>> = match shapes.len() {
+                    0 => Option::None,
+                    1 => shapes.pop(),
+                    n => Option::Some(Arc::new(BvhNode::new(shapes, n, 0.0, 1.0))),
+                };
+                self.instances.insert(name, obj);
+            }
+            Option::None => self.warn("unmatched ObjectEnd".to_string()),
+        }
+    }
+    // rgb and color as they are, spectra as their average
+    fn color(&mut self, ps: &ParamSet, name: &str, default: Vec3) -> Vec3 {
+        let p = match ps.get(name) {
+            Option::Some(p) => p,
+            Option::None => return default,
+        };
+        match (p.ty.as_str(), &p.nums[..]) {
+            ("rgb", [r, g, b, ..]) | ("color", [r, g, b, ..]) => Vec3::new(*r, *g, *b),
+            ("float", [x, ..]) => Vec3::ones() * *x,
+            ("spectrum", [_, _, ..]) => {
+                self.warn("spectra are approximated by their average value".to_string());
+                let values: Vec<f64> = p.nums.iter().skip(1).step_by(2).copied().collect();
+                Vec3::ones() * (values.iter().sum::<f64>() / values.len() as f64)
+            }
+            _ => {
+                self.warn(format!("{} {} is not supported", p.ty, name));
+                default
+            }
+        }
+    }
+    fn spectrum_texture(&mut self, ps: &ParamSet, name: &str, default: Vec3) -> Arc<dyn Texture> {
+        if let Option::Some(p) = ps.get(name) {
+            if p.ty == "texture" {
+                let tex = p.strs.first().cloned().unwrap_or_default();
+                match self.textures.get(&tex) {
+                    Option::Some(t) => return t.clone(),
+                    Option::None => self.warn(format!("unknown texture {}", tex)),
+                }
+                return Arc::new(SolidColor::new(default));
+            }
+        }
+        Arc::new(SolidColor::new(self.color(ps, name, default)))
+    }
+    // pbrt roughness as Microfacet's, whose alpha is roughness squared
+    fn roughness(&mut self, ps: &ParamSet, default: f64) -> f64 {
+        let mut r = ps.float("roughness", default);
+        if ps.get("uroughness").is_some() || ps.get("vroughness").is_some() {
+            self.warn("anisotropic roughness is averaged".to_string());
+            r = 0.5 * (ps.float("uroughness", r) + ps.float("vroughness", r));
+        }
+        let alpha = if ps.bool("remaproughness", true) {
+            roughness_to_alpha(r)
+        } else {
+            r
+        };
+        alpha.sqrt()
+    }
+    fn material(&mut self, ty: &str, ps: &ParamSet) -> Option<Arc<dyn Material>> {
+        if ps.get("bumpmap").is_some() {
+            self.warn("bump maps are ignored".to_string());
+        }
+        let mat: Arc<dyn Material> = match ty {
+            "" | "none" => return Option::None,
+            "matte" => {
+                let kd = self.spectrum_texture(ps, "Kd", Vec3::ones() * 0.5);
+                let sigma = ps.float("sigma", 0.0);
+                if sigma > 0.0 {
+                    Arc::new(OrenNayar::newarc(kd, sigma))
+                } else {
+                    Arc::new(Lambertian::newarc(kd))
+                }
+            }
+            "plastic" => {
+                if ps.get("Ks").is_some() {
+                    self.warn("plastic Ks is ignored, the coat reflects like glass".to_string());
+                }
+                let kd = self.spectrum_texture(ps, "Kd", Vec3::ones() * 0.25);
+                let roughness = self.roughness(ps, 0.1);
+                Arc::new(Microfacet::newarc(kd, 0.0, roughness, Option::None))
+            }
+            "metal" => {
+                // copper, pbrt's default
+                let eta = self.color(ps, "eta", Vec3::new(0.200438, 0.924033, 1.10221));
+                let k = self.color(ps, "k", Vec3::new(3.91295, 2.45285, 2.14219));
+                let mut f0 = Vec3::zero();
+                for c in 0..3 {
+                    let (n, k) = (eta.get(c), k.get(c));
+                    *f0.get_mut(c) =
+                        ((n - 1.0) * (n - 1.0) + k * k) / ((n + 1.0) * (n + 1.0) + k * k);
+                }
+                Arc::new(Microfacet::new(f0, 1.0, self.roughness(ps, 0.01)))
+            }
+            "mirror" => Arc::new(Metal::new(self.color(ps, "Kr", Vec3::ones() * 0.9), 0.0)),
+            "glass" => {
+                if self.color(ps, "Kr", Vec3::ones()) != Vec3::ones()
+                    || self.color(ps, "Kt", Vec3::ones()) != Vec3::ones()
+                {
+                    self.warn("glass Kr and Kt are ignored".to_string());
+                }
+                if ps.float("uroughness", 0.0) > 0.0 || ps.float("vroughness", 0.0) > 0.0 {
+                    self.warn("rough glass is rendered smooth".to_string());
+                }
+                Arc::new(Dielectric::new(ps.float("eta", ps.float("index", 1.5))))
+            }
+            _ => {
+                self.warn(format!("unsupported material {}, using matte", ty));
+                Arc::new(Lambertian::new(Vec3::ones() * 0.5))
+            }
+        };
+        Option::Some(mat)
+    }
+    fn texture(&mut self, name: &str, class: &str, ps: &ParamSet) {
+        let tex: Arc<dyn Texture> = match class {
+            "imagemap" => match ps.string("filename") {
+                Option::Some(f) => Arc::new(ImageTexture::new(&self.resolve(&f))),
+                Option::None => {
+                    self.warn(format!("imagemap {} has no filename", name));
+                    return;
+                }
+            },
+            "constant" => Arc::new(SolidColor::new(self.color(ps, "value", Vec3::ones()))),
+            _ => {
+                self.warn(format!("unsupported texture {}", class));
+                return;
+            }
+        };
+        self.textures.insert(name.to_string(), tex);
+    }
+    fn pixel_filter(&mut self, ty: &str, ps: &ParamSet) {
+        let default = match ty {
+            "box" => 0.5,
+            "sinc" => 4.0,
+            _ => 2.0,
+        };
+        let r = ps.float("xwidth", default);
+        if ps.float("ywidth", default) != r {
+            self.warn("filter ywidth is ignored, xwidth is used both ways".to_string());
+        }
+        self.filter = match ty {
+            "box" => Arc::new(BoxFilter::new(r)),
+            "triangle" => Arc::new(TentFilter::new(r)),
+            "gaussian" => Arc::new(GaussianFilter::new(r, ps.float("alpha", 2.0))),
+            "mitchell" => Arc::new(MitchellFilter::new(
+                r,
+                ps.float("B", 1.0 / 3.0),
+                ps.float("C", 1.0 / 3.0),
+            )),
+            "sinc" => Arc::new(LanczosFilter::new(r, ps.float("tau", 3.0))),
+            _ => {
+                self.warn(format!("unsupported filter {}, using box", ty));
+                Arc::new(BoxFilter::new(0.5))
+            }
+        };
+    }
+    fn light(&mut self, ty: &str, ps: &ParamSet) {
+        let scale = self.color(ps, "scale", Vec3::ones());
+        let ctm = self.attrs.ctm;
+        match ty {
+            "point" => {
+                let i = self.color(ps, "I", Vec3::ones()).change(scale);
+                let pos = ctm.point(ps.point("from", Vec3::zero()));
+                self.delta_lights
+                    .push(Arc::new(PointLight::new(pos, i, 0.0)));
+            }
+            "spot" => {
+                let i = self.color(ps, "I", Vec3::ones()).change(scale);
+                let from = ctm.point(ps.point("from", Vec3::zero()));
+                let to = ctm.point(ps.point("to", Vec3::new(0.0, 0.0, 1.0)));
+                let cone = ps.float("coneangle", 30.0);
+                let delta = ps.float("conedelta", 5.0);
+                self.delta_lights.push(Arc::new(SpotLight::new(
+                    from,
+                    to,
+                    i,
+                    cone,
+                    cone - delta,
+                    4.0,
+                    Option::None,
+                )));
+            }
+            "distant" => {
+                let l = self.color(ps, "L", Vec3::ones()).change(scale);
+                let from = ps.point("from", Vec3::zero());
+                let to = ps.point("to", Vec3::new(0.0, 0.0, 1.0));
+                let dir = ctm.vector(to - from).unit();
+                self.delta_lights.push(Arc::new(DistantLight::new(dir, l)));
+            }
+            "infinite" => {
+                let l = self.color(ps, "L", Vec3::ones()).change(scale);
+                let mut env = Option::None;
+                if let Option::Some(map) = ps.string("mapname") {
+                    let lower = map.to_lowercase();
+                    if lower.ends_with(".exr") || lower.ends_with(".hdr") {
+                        if l.x != l.y || l.y != l.z {
+                            self.warn("colored L on an environment map is averaged".to_string());
+                        }
+                        let intensity = (l.x + l.y + l.z) / 3.0;
+                        env = Option::Some(EnvironmentLight::new(
+                            &self.resolve(&map),
+                            intensity,
+                            0.0,
+                        ));
+                    } else {
+                        self.warn(format!(
+                            "environment map {} is not .exr or .hdr, using a constant",
+                            map
+                        ));
+                    }
+                }
+                // a single column still samples by sin(theta)
+                let env = env
+                    .unwrap_or_else(|| EnvironmentLight::from_pixels(1, 32, vec![l; 32], 1.0, 0.0));
+                self.infinite_lights
+                    .push(Arc::new(PbrtEnvironment { env, to_world: ctm }));
+            }
+            _ => self.warn(format!("unsupported light {}", ty)),
+        }
+    }
+    fn shape(&mut self, ty: &str, ps: &ParamSet) -> Result<(), String> {
+        let (mat, emits): (Arc<dyn Material>, bool) =
+            match (self.attrs.area_light, &self.attrs.material) {
+                (Option::Some((l, true)), _) => (Arc::new(TwoSidedLight { emit: l }), true),
+                (Option::Some((l, false)), _) => (Arc::new(DiffuseLight::new(l)), true),
+                (Option::None, Option::Some(mat)) => (mat.clone(), false),
+                // interfaces between media, nothing to draw
+                (Option::None, Option::None) => return Ok(()),
+            };
+        if ps.get("alpha").is_some() {
+            self.warn("alpha textures are ignored".to_string());
+        }
+        let ctm = self.attrs.ctm;
+        match ty {
+            "sphere" => {
+                let radius = ps.float("radius", 1.0);
+                if ["zmin", "zmax", "phimax"]
+                    .iter()
+                    .any(|n| ps.get(n).is_some())
+                {
+                    self.warn("partial spheres are rendered whole".to_string());
+                }
+                match ctm.uniform_scale() {
+                    Option::Some(s) => {
+                        let sphere =
+                            Arc::new(Sphere::new(ctm.point(Vec3::zero()), radius * s, mat));
+                        let shape: Arc<dyn Hittable> = if self.attrs.reverse {
+                            Arc::new(FlipFace::new(sphere.clone()))
+                        } else {
+                            sphere.clone()
+                        };
+                        let lights: Vec<Arc<dyn Hittable>> =
+                            if emits { vec![sphere] } else { Vec::new() };
+                        self.add(shape, lights);
+                    }
+                    Option::None => {
+                        if emits {
+                            self.warn(
+                                "area light spheres with a non-uniform scale are not light sampled"
+                                    .to_string(),
+                            );
+                        }
+                        let sphere = Arc::new(Sphere::new(Vec3::zero(), radius, mat));
+                        self.add(Arc::new(Transformed::new(sphere, ctm)), Vec::new());
+                    }
+                }
+            }
+            "trianglemesh" => {
+                let mesh = triangle_mesh(ps)?;
+                self.add_mesh(mesh, mat, emits);
+            }
+            "plymesh" => match ps.string("filename") {
+                Option::Some(f) => {
+                    let mesh = load_ply(&self.resolve(&f));
+                    self.add_mesh(mesh, mat, emits);
+                }
+                Option::None => self.warn("plymesh without a filename".to_string()),
+            },
+            _ => self.warn(format!("unsupported shape {}", ty)),
+        }
+        Ok(())
+    }
+    // Bakes the current transform into the vertices. Without normals the
+    // winding decides the front, which mirroring and ReverseOrientation flip.
+    fn add_mesh(&mut self, mut mesh: TriangleMesh, mat: Arc<dyn Material>, emits: bool) {
+        if mesh.tris.is_empty() {
+            return;
+        }
+        let ctm = self.attrs.ctm;
+        for p in mesh.p.iter_mut() {
+            *p = ctm.point(*p);
+        }
+        for n in mesh.nor.iter_mut() {
+            *n = ctm.normal(*n).unit();
+        }
+        if ctm.swaps_handedness() != self.attrs.reverse {
+            for t in mesh.tris.iter_mut() {
+                t.swap(1, 2);
+            }
+        }
+        // the whole mesh is one light, sampled by area through its own BVH
+        let shape: Arc<dyn Hittable> = Arc::new(Mesh::new(Arc::new(mesh), mat));
+        let lights = if emits {
+            vec![shape.clone()]
+        } else {
+            Vec::new()
+        };
+        self.add(shape, lights);
+    }
+    fn add(&mut self, shape: Arc<dyn Hittable>, lights: Vec<Arc<dyn Hittable>>) {
+        if let Option::Some((_, shapes)) = &mut self.instance {
+            shapes.push(shape);
+            if !lights.is_empty() {
+                self.warn("area lights in object instances are not light sampled".to_string());
+            }
+            return;
+        }
+        self.push_object(shape);
+        for l in lights {
+            self.lights.add(l);
+        }
+    }
+    // Every shape and instance gets its own object ID.
+    fn push_object(&mut self, obj: Arc<dyn Hittable>) {
+        let id = self.objects.len() as u32 + 1;
+        self.objects.push(Arc::new(Tagged::new(obj, id)));
+    }
+    fn build_camera(&mut self, aspect: f64) -> Arc<dyn CameraModel> {
+        let (ty, ps, world_to_camera) = self.camera.clone();
+        let to_world = world_to_camera.inverse();
+        let pos = to_world.point(Vec3::zero());
+        let dir = to_world.vector(Vec3::new(0.0, 0.0, 1.0));
+        let up = to_world.vector(Vec3::new(0.0, 1.0, 0.0));
+        let right = to_world.vector(Vec3::new(1.0, 0.0, 0.0));
+        if ps.get("screenwindow").is_some() {
+            self.warn("screenwindow is ignored".to_string());
+        }
+        let aspect = ps.float("frameaspectratio", aspect);
+        // pbrt cameras are left handed, so the image is mirrored whenever
+        // the right handed basis puts the camera's +x on the left
+        if ty == "orthographic" {
+            let height = if aspect >= 1.0 { 2.0 } else { 2.0 / aspect };
+            let mut cam = OrthographicCamera::new(pos, pos + dir, up, height, aspect, 0.0, 1.0);
+            if cam.u * right < 0.0 {
+                cam.u = -cam.u;
+            }
+            return Arc::new(cam);
+        }
+        if ty != "perspective" {
+            self.warn(format!("camera {} is rendered as perspective", ty));
+        }
+        // fov spans the shorter side of the image
+        let fov = ps.float("fov", 90.0);
+        let vfov = if aspect >= 1.0 {
+            fov
+        } else {
+            2.0 * ((fov.to_radians() / 2.0).tan() / aspect)
+                .atan()
+                .to_degrees()
+        };
+        let lens_radius = ps.float("lensradius", 0.0);
+        let focus = if lens_radius > 0.0 {
+            ps.float("focaldistance", 1e6)
+        } else {
+            1.0
+        };
+        let mut cam = Camera::new(
+            pos,
+            pos + dir,
+            up,
+            vfov,
+            aspect,
+            2.0 * lens_radius,
+            focus,
+            0.0,
+            1.0,
+        );
+        if cam.u * right < 0.0 {
+            cam.lower_left_corner += cam.horizontal;
+            cam.horizontal = -cam.horizontal;
+            cam.u = -cam.u;
+        }
+        Arc::new(cam)
+    }
+    fn finish(mut self) -> PbrtScene {
+        let width = self.film.float("xresolution", 1280.0) as u32;
+        let height = self.film.float("yresolution", 720.0) as u32;
+        let camera = self.build_camera(width as f64 / height as f64);
+        let mut world = HittableList::default();
+        match self.objects.len() {
+            0 => {}
+            1 => world.add(self.objects.pop().unwrap()),
+            n => world.add(Arc::new(BvhNode::new(self.objects, n, 0.0, 1.0))),
+        }
+        PbrtScene {
+            world,
+            lights: self.lights,
+            delta_lights: self.delta_lights,
+            infinite_lights: self.infinite_lights,
+            camera,
+            filter: self.filter,
+            width,
+            height,
+            spp: self.spp,
+            max_depth: self.max_depth,
+            output: self.film.string("filename"),
+            warnings: self.warnings,
+        }
+    }
+}
+
+fn triangle_mesh(ps: &ParamSet) -> Result<TriangleMesh, String> {
+    let p = ps.floats("P");
+    let mut indices: Vec<u32> = ps.floats("indices").iter().map(|&i| i as u32).collect();
+    if indices.is_empty() && p.len() == 9 {
+        indices = vec![0, 1, 2];
+    }
+    if !p.len().is_multiple_of(3) || !indices.len().is_multiple_of(3) {
+        return Err("trianglemesh P and indices must come in threes".to_string());
+    }
+    let nv = p.len() / 3;
+    if indices.iter().any(|&i| i as usize >= nv) {
+        return Err("trianglemesh index out of range".to_string());
+    }
+    let mut mesh = TriangleMesh::new(
+        p.chunks_exact(3)
+            .map(|v| Vec3::new(v[0], v[1], v[2]))
+            .collect(),
+        indices
+            .chunks_exact(3)
+            .map(|t| [t[0], t[1], t[2]])
+            .collect(),
+    );
+    let n = ps.floats("N");
+    if n.len() == p.len() {
+        mesh.nor = n
+            .chunks_exact(3)
+            .map(|v| Vec3::new(v[0], v[1], v[2]))
+            .collect();
+    }
+    let uv = match ps.floats("uv") {
+        [] => ps.floats("st"),
+        uv => uv,
+    };
+    if uv.len() == 2 * nv {
+        mesh.uv = uv.chunks_exact(2).map(|t| (t[0], t[1])).collect();
+    }
+    Ok(mesh)
+}
+
+// Parses a pbrt-v3 scene, `dir` is where relative file names point.
+pub fn parse_pbrt(src: &str, dir: &Path) -> Result<PbrtScene, String> {
+    let mut parser = Parser::new(dir);
+    parser.parse(src)?;
+    Ok(parser.finish())
+}
+
+// Loads a .pbrt file. Anything it uses that has no counterpart here is
+// reported once on stderr and listed in `warnings`.
+#[allow(dead_code)]
+pub fn load_pbrt(filename: &str) -> PbrtScene {
+    let src = std::fs::read_to_string(filename)
+        .unwrap_or_else(|e| panic!("Cannot read {}: {}", filename, e));
+    let dir = Path::new(filename)
+        .parent()
+        .unwrap_or_else(|| Path::new(""));
+    parse_pbrt(&src, dir).unwrap_or_else(|e| panic!("Cannot decode {}: {}", filename, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_scene() {
+        let src = r#"
+            LookAt 0 0 -5  0 0 0  0 1 0 # camera looking down +z
+            Camera "perspective" "float fov" [ 40 ]
+            Film "image" "integer xresolution" [ 200 ] "integer yresolution" 100
+                "string filename" "out.exr"
+            Sampler "halton" "integer pixelsamples" 8
+            Integrator "path" "integer maxdepth" [ 3 ]
+            WorldBegin
+            LightSource "point" "rgb I" [ 10 10 10 ] "point from" [ 0 4 0 ]
+            Material "matte" "rgb Kd" [ 0.8 0.2 0.2 ]
+            AttributeBegin
+                Translate 1 0 0
+                Shape "sphere" "float radius" 0.5
+            AttributeEnd
+            ObjectBegin "tri"
+                Shape "trianglemesh" "integer indices" [ 0 1 2 ]
+                    "point P" [ -1 -1 0  1 -1 0  0 1 0 ]
+            ObjectEnd
+            AttributeBegin
+                Translate -1 0 2
+                Scale 1 2 1
+                ObjectInstance "tri"
+            AttributeEnd
+            AttributeBegin
+                AreaLightSource "diffuse" "rgb L" [ 4 4 4 ]
+                Translate 0 3 0
+                Shape "sphere" "float radius" 0.25
+            AttributeEnd
+            Shape "disk"
+            MakeNamedMedium "fog" "string type" "homogeneous"
+            WorldEnd
+        "#;
+        let scene = parse_pbrt(src, Path::new(".")).unwrap();
+        assert_eq!((scene.width, scene.height, scene.spp), (200, 100, 8));
+        assert_eq!(scene.max_depth, 4);
+        assert_eq!(scene.output.as_deref(), Option::Some("out.exr"));
+        assert_eq!(scene.delta_lights.len(), 1);
+        assert_eq!(scene.lights.objects.len(), 1);
+        assert!(scene
+            .warnings
+            .contains(&"unsupported shape disk".to_string()));
+        assert!(scene
+            .warnings
+            .contains(&"unsupported directive MakeNamedMedium".to_string()));
+        // +x is on the right of the image, as in pbrt
+        let ray = scene.camera.get_ray(0.637, 0.5);
+        let rec = scene.world.hit(&ray, 0.001, f64::MAX).unwrap();
+        assert!(rec.p.x > 0.5 && rec.p.z < 0.0);
+        // the instance is stretched to y in [-2, 2] at z = 2
+        let ray = scene.camera.get_ray(0.363, 0.5);
+        let rec = scene.world.hit(&ray, 0.001, f64::MAX).unwrap();
+        assert!((rec.p.z - 2.0).abs() < 1e-9);
+        assert!((rec.nor.length() - 1.0).abs() < 1e-9);
+        let up = Ray::new(Vec3::new(-1.0, 2.1, -5.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+        assert!(scene.world.hit(&up, 0.001, f64::MAX).is_none());
+        let up = Ray::new(Vec3::new(-1.0, 1.5, -5.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+        assert!(scene.world.hit(&up, 0.001, f64::MAX).is_some());
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = std::env::temp_dir().join(format!("pbrt_include_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, src: &str| std::fs::write(dir.join(name), src).unwrap();
+        write(
+            "shape.pbrt",
+            "Material \"plastic\" \"rgb Ks\" [ 0.5 0.5 0.5 ]\nShape \"sphere\"\n",
+        );
+        write("a.pbrt", "Include \"b.pbrt\"\n");
+        write("b.pbrt", "Include \"a.pbrt\"\n");
+        // the same file twice is fine, a file inside itself is not
+        let twice = "WorldBegin Include \"shape.pbrt\" Include \"shape.pbrt\" WorldEnd";
+        let scene = parse_pbrt(twice, &dir).unwrap();
+        assert!(scene
+            .warnings
+            .iter()
+            .any(|w| w.starts_with("plastic Ks is ignored")));
+        let err = parse_pbrt("Include \"a.pbrt\"", &dir).err().unwrap();
+        assert!(err.ends_with("a.pbrt includes itself"), "{}", err);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,11 +1,12 @@
 use crate::hit::*;
+use crate::light::InfiniteLight;
 use crate::onb::ONB;
 use crate::random::*;
 use crate::vec3::Vec3;
 pub const PI: f64 = std::f64::consts::PI;
 use std::sync::Arc;
 
-pub trait PDF {
+pub trait PDF: Send + Sync {
     fn value(&self, direction: Vec3) -> f64;
     fn generate(&self) -> Vec3;
 }
@@ -50,24 +51,59 @@ impl PDF for HittablePDF {
     }
 }
 
+pub struct InfiniteLightPDF {
+    pub ptr: Arc<dyn InfiniteLight>,
+}
+impl InfiniteLightPDF {
+    pub fn new(ptr: Arc<dyn InfiniteLight>) -> Self {
+        Self { ptr }
+    }
+}
+impl PDF for InfiniteLightPDF {
+    fn value(&self, direction: Vec3) -> f64 {
+        self.ptr.pdf_value(direction)
+    }
+    fn generate(&self) -> Vec3 {
+        self.ptr.random()
+    }
+}
+
+// Draws from one of the pdfs, picked with the given weights.
 pub struct MixturePDF {
-    pub p: [Arc<dyn PDF>; 2],
+    pub p: Vec<(Arc<dyn PDF>, f64)>,
 }
 impl MixturePDF {
     pub fn new(p0: Arc<dyn PDF>, p1: Arc<dyn PDF>) -> Self {
-        Self { p: [p0, p1] }
+        Self::weighted(vec![(p0, 0.5), (p1, 0.5)])
+    }
+    // the weights are normalized here
+    pub fn weighted(mut p: Vec<(Arc<dyn PDF>, f64)>) -> Self {
+        let sum: f64 = p.iter().map(|(_, w)| w).sum();
+        for (_, w) in p.iter_mut() {
+            *w /= sum;
+        }
+        Self { p }
     }
 }
 impl PDF for MixturePDF {
     fn value(&self, direction: Vec3) -> f64 {
-        0.5 * self.p[0].value(direction) + 0.5 * self.p[1].value(direction)
+        self.p
+            .iter()
+            .filter(|(_, w)| *w > 0.0)
+            .map(|(pdf, w)| w * pdf.value(direction))
+            .sum()
     }
     fn generate(&self) -> Vec3 {
-        if get_rand01() < 0.5 {
-            self.p[0].generate()
-        } else {
-            self.p[1].generate()
+        let mut r = get_rand01();
+        for (pdf, w) in self.p.iter() {
+            if r < *w {
+                return pdf.generate();
+            }
+            r -= w;
         }
+        // rounding can leave r just above the last weight
+        let last = self.p.iter().rev().find(|(_, w)| *w > 0.0);
+        last.expect("MixturePDF has no weights").0.generate()
     }
 }
 
@@ -80,3 +116,84 @@ impl PDF for NOPDF {
         unreachable!()
     }
 }
+
+// GGX normal distribution, cos_h is the cosine between the normal and the
+// half vector.
+pub fn ggx_d(cos_h: f64, alpha: f64) -> f64 {
+    if cos_h <= 0.0 {
+        return 0.0;
+    }
+    let a2 = alpha * alpha;
+    let k = cos_h * cos_h * (a2 - 1.0) + 1.0;
+    a2 / (PI * k * k)
+}
+
+// Reflection about a half vector drawn from D(h) * cos_h, wo is the unit
+// direction toward the viewer.
+pub struct GgxPDF {
+    pub uvw: ONB,
+    pub wo: Vec3,
+    pub alpha: f64,
+}
+impl GgxPDF {
+    pub fn new(n: Vec3, wo: Vec3, alpha: f64) -> Self {
+        Self {
+            uvw: ONB::buildw(n),
+            wo,
+            alpha,
+        }
+    }
+}
+impl PDF for GgxPDF {
+    fn value(&self, direction: Vec3) -> f64 {
+        let wi = direction.unit();
+        if wi * self.uvw.w() <= 0.0 {
+            return 0.0;
+        }
+        let h = (self.wo + wi).unit();
+        let cos_h = h * self.uvw.w();
+        ggx_d(cos_h, self.alpha) * cos_h / (4.0 * (self.wo * h).abs())
+    }
+    fn generate(&self) -> Vec3 {
+        let r1 = get_rand01();
+        let r2 = get_rand01();
+        let tan2 = self.alpha * self.alpha * r1 / (1.0 - r1);
+        let cos = 1.0 / (1.0 + tan2).sqrt();
+        let sin = (1.0 - cos * cos).max(0.0).sqrt();
+        let phi = 2.0 * PI * r2;
+        let h = self
+            .uvw
+            .change(Vec3::new(sin * phi.cos(), sin * phi.sin(), cos));
+        h * (2.0 * (self.wo * h)) - self.wo
+    }
+}
+
+// Uniform over the directions within a cone around an axis.
+pub struct ConePDF {
+    pub uvw: ONB,
+    pub cos_max: f64,
+}
+impl ConePDF {
+    pub fn new(axis: Vec3, degrees: f64) -> Self {
+        Self {
+            uvw: ONB::buildw(axis),
+            cos_max: degrees.to_radians().cos(),
+        }
+    }
+}
+impl PDF for ConePDF {
+    fn value(&self, direction: Vec3) -> f64 {
+        if direction.unit() * self.uvw.w() < self.cos_max {
+            0.0
+        } else {
+            1.0 / (2.0 * PI * (1.0 - self.cos_max))
+        }
+    }
+    fn generate(&self) -> Vec3 {
+        let cos = 1.0 - get_rand01() * (1.0 - self.cos_max);
+        let sin = (1.0 - cos * cos).max(0.0).sqrt();
+        let phi = 2.0 * PI * get_rand01();
+        self.uvw
+            .change(Vec3::new(sin * phi.cos(), sin * phi.sin(), cos))
+    }
+}
@@ -0,0 +1,489 @@
+use crate::aabb::Aabb;
+use crate::distribution::Distribution1D;
+use crate::hit::{HitRecord, Hittable};
+use crate::material::Material;
+use crate::onb::ONB;
+use crate::random::*;
+use crate::ray::Ray;
+use crate::vec3::{random_in_unit_disk, Vec3};
+use std::sync::Arc;
+pub const PI: f64 = std::f64::consts::PI;
+pub const INF: f64 = f64::MAX;
+
+// Box around points on a plane, padded so it is never flat.
+fn planar_box(points: &[Vec3]) -> Aabb {
+    let mut mn = Vec3::new(INF, INF, INF);
+    let mut mx = Vec3::new(-INF, -INF, -INF);
+    for p in points {
+        for i in 0..3 {
+            *mn.get_mut(i) = mn.get(i).min(p.get(i));
+            *mx.get_mut(i) = mx.get(i).max(p.get(i));
+        }
+    }
+    let pad = Vec3::new(0.001, 0.001, 0.001);
+    Aabb::new(mn - pad, mx + pad)
+}
+
+// Ray parameter where it meets the plane n * p = d.
+fn plane_t(this_ray: &Ray, nor: Vec3, d: f64, tmn: f64, tmx: f64) -> Option<f64> {
+    let denom = nor * this_ray.dir;
+    if denom.abs() < 1e-12 {
+        return Option::None;
+    }
+    let t = (d - nor * this_ray.ori) / denom;
+    if t < tmn || t > tmx {
+        return Option::None;
+    }
+    Option::Some(t)
+}
+
+// Solid angle density of sampling a planar light of `area` uniformly.
+pub fn planar_pdf(shape: &dyn Hittable, area: f64, o: Vec3, v: Vec3) -> f64 {
+    if let Option::Some(rec) = shape.hit(&Ray::new(o, v, 0.0), 0.001, INF) {
+        let dis_squared = rec.t * rec.t * v.squared_length();
+        let cos = ((v * rec.nor) / v.length()).abs();
+        dis_squared / (cos * area)
+    } else {
+        0.0
+    }
+}
+
+// Parallelogram with a corner q and edges u and v, the front face is
+// on the side of u x v.
+pub struct Quad {
+    q: Vec3,
+    u: Vec3,
+    v: Vec3,
+    nor: Vec3,
+    d: f64,
+    // maps a point on the plane to (alpha, beta)
+    w: Vec3,
+    area: f64,
+    mp: Arc<dyn Material>,
+}
+#[allow(dead_code)]
+impl Quad {
+    pub fn new(q: Vec3, u: Vec3, v: Vec3, mp: Arc<dyn Material>) -> Self {
+        let n = Vec3::cross(u, v);
+        let nor = n.unit();
+        Self {
+            q,
+            u,
+            v,
+            nor,
+            d: nor * q,
+            w: n / n.squared_length(),
+            area: n.length(),
+            mp,
+        }
+    }
+}
+impl Hittable for Quad {
+    fn hit(&self, this_ray: &Ray, tmn: f64, tmx: f64) -> Option<HitRecord> {
+        let t = plane_t(this_ray, self.nor, self.d, tmn, tmx)?;
+        let p = this_ray.pos(t);
+        let planar = p - self.q;
+        let alpha = self.w * Vec3::cross(planar, self.v);
+        let beta = self.w * Vec3::cross(self.u, planar);
+        if !(0.0..=1.0).contains(&alpha) || !(0.0..=1.0).contains(&beta) {
+            return Option::None;
+        }
+        let mut rec = HitRecord {
+            p,
+            nor: Vec3::zero(),
+            geo_nor: Vec3::zero(),
+            dpdu: self.u,
+            dpdv: self.v,
+            dndu: Vec3::zero(),
+            dndv: Vec3::zero(),
+            t,
+            u: alpha,
+            v: beta,
+            nor_dir: false,
+            mat_ptr: self.mp.clone(),
+            vertex_col: Option::None,
+            object_id: 0,
+        };
+        rec.set_face_normal(this_ray, self.nor);
+        Option::Some(rec)
+    }
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
+        Option::Some(planar_box(&[
+            self.q,
+            self.q + self.u,
+            self.q + self.v,
+            self.q + self.u + self.v,
+        ]))
+    }
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+        planar_pdf(self, self.area, o, v)
+    }
+    fn random(&self, o: Vec3) -> Vec3 {
+        self.q + self.u * get_rand01() + self.v * get_rand01() - o
+    }
+}
+
+// Disk facing `normal`, u goes around and v goes out from the center.
+pub struct Disk {
+    center: Vec3,
+    radius: f64,
+    uvw: ONB,
+    d: f64,
+    mp: Arc<dyn Material>,
+}
+#[allow(dead_code)]
+impl Disk {
+    pub fn new(center: Vec3, normal: Vec3, radius: f64, mp: Arc<dyn Material>) -> Self {
+        let uvw = ONB::buildw(normal);
+        Self {
+            center,
+            radius,
+            d: uvw.w() * center,
+            uvw,
+            mp,
+        }
+    }
+}
+impl Hittable for Disk {
+    fn hit(&self, this_ray: &Ray, tmn: f64, tmx: f64) -> Option<HitRecord> {
+        let t = plane_t(this_ray, self.uvw.w(), self.d, tmn, tmx)?;
+        let p = this_ray.pos(t);
+        let a = (p - self.center) * self.uvw.u();
+        let b = (p - self.center) * self.uvw.v();
+        let r = (a * a + b * b).sqrt();
+        if r > self.radius {
+            return Option::None;
+        }
+        let phi = b.atan2(a).rem_euclid(2.0 * PI);
+        let radial = self.uvw.u() * phi.cos() + self.uvw.v() * phi.sin();
+        let tangent = self.uvw.v() * phi.cos() - self.uvw.u() * phi.sin();
+        let mut rec = HitRecord {
+            p,
+            nor: Vec3::zero(),
+            geo_nor: Vec3::zero(),
+            dpdu: tangent * (2.0 * PI * r),
+            dpdv: radial * self.radius,
+            dndu: Vec3::zero(),
+            dndv: Vec3::zero(),
+            t,
+            u: phi / (2.0 * PI),
+            v: r / self.radius,
+            nor_dir: false,
+            mat_ptr: self.mp.clone(),
+            vertex_col: Option::None,
+            object_id: 0,
+        };
+        rec.set_face_normal(this_ray, self.uvw.w());
+        Option::Some(rec)
+    }
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
+        // extent of the disk along each axis is radius * sin(normal, axis)
+        let n = self.uvw.w();
+        let e = Vec3::new(
+            (1.0 - n.x * n.x).max(0.0).sqrt(),
+            (1.0 - n.y * n.y).max(0.0).sqrt(),
+            (1.0 - n.z * n.z).max(0.0).sqrt(),
+        ) * self.radius;
+        Option::Some(planar_box(&[self.center - e, self.center + e]))
+    }
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+        planar_pdf(self, PI * self.radius * self.radius, o, v)
+    }
+    fn random(&self, o: Vec3) -> Vec3 {
+        let d = random_in_unit_disk() * self.radius;
+        self.center + self.uvw.u() * d.x + self.uvw.v() * d.y - o
+    }
+}
+
+fn cross2(a: (f64, f64), b: (f64, f64), c: (f64, f64)) -> f64 {
+    (b.0 - a.0) * (c.1 - a.1) - (b.1 - a.1) * (c.0 - a.0)
+}
+
+fn in_triangle(p: (f64, f64), a: (f64, f64), b: (f64, f64), c: (f64, f64)) -> bool {
+    let d0 = cross2(a, b, p);
+    let d1 = cross2(b, c, p);
+    let d2 = cross2(c, a, p);
+    (d0 >= 0.0 && d1 >= 0.0 && d2 >= 0.0) || (d0 <= 0.0 && d1 <= 0.0 && d2 <= 0.0)
+}
+
+// Ear clipping of a simple polygon, either winding. Vertices on a straight
+// run of edges cut off nothing, they are dropped before they can pass as
+// ears or block one through rounding.
+fn triangulate(pts: &[(f64, f64)]) -> Vec<[usize; 3]> {
+    let n = pts.len();
+    let mut idx: Vec<usize> = (0..n).collect();
+    let mut twice_area = 0.0;
+    let (mut mn, mut mx) = ((INF, INF), (-INF, -INF));
+    for i in 0..n {
+        let (a, b) = (pts[i], pts[(i + 1) % n]);
+        twice_area += a.0 * b.1 - b.0 * a.1;
+        mn = (mn.0.min(a.0), mn.1.min(a.1));
+        mx = (mx.0.max(a.0), mx.1.max(a.1));
+    }
+    if twice_area < 0.0 {
+        idx.reverse();
+    }
+    let size = (mx.0 - mn.0).max(mx.1 - mn.1);
+    let eps = 1e-9 * size * size;
+    // counterclockwise from here, points on the edges count as inside
+    let inside =
+        |p, a, b, c| cross2(a, b, p) >= -eps && cross2(b, c, p) >= -eps && cross2(c, a, p) >= -eps;
+    let mut tris = Vec::new();
+    while idx.len() > 3 {
+        let m = idx.len();
+        let corner = |i: usize| (idx[(i + m - 1) % m], idx[i], idx[(i + 1) % m]);
+        if let Option::Some(i) = (0..m).find(|&i| {
+            let (a, b, c) = corner(i);
+            cross2(pts[a], pts[b], pts[c]).abs() <= eps
+        }) {
+            idx.remove(i);
+            continue;
+        }
+        let ear = (0..m).find(|&i| {
+            let (a, b, c) = corner(i);
+            cross2(pts[a], pts[b], pts[c]) > 0.0
+                && !idx
+                    .iter()
+                    .any(|&k| k != a && k != b && k != c && inside(pts[k], pts[a], pts[b], pts[c]))
+        });
+        // only degenerate input has no ear
+        let i = match ear {
+            Option::Some(i) => i,
+            Option::None => break,
+        };
+        let (a, b, c) = corner(i);
+        tris.push([a, b, c]);
+        idx.remove(i);
+    }
+    if idx.len() == 3 {
+        tris.push([idx[0], idx[1], idx[2]]);
+    }
+    tris
+}
+
+// Flat simple polygon, convex or not. The front face is the side the
+// vertices go counterclockwise around, uv spans its bounds in the plane.
+pub struct Polygon {
+    vertices: Vec<Vec3>,
+    uvw: ONB,
+    d: f64,
+    pts: Vec<(f64, f64)>,
+    tris: Vec<[usize; 3]>,
+    tri_dist: Distribution1D,
+    area: f64,
+    mn: (f64, f64),
+    mx: (f64, f64),
+    mp: Arc<dyn Material>,
+}
+#[allow(dead_code)]
+impl Polygon {
+    pub fn new(vertices: Vec<Vec3>, mp: Arc<dyn Material>) -> Self {
+        // Newell's normal works for any simple polygon
+        let n = vertices.len();
+        let mut nor = Vec3::zero();
+        for i in 0..n {
+            let (a, b) = (vertices[i], vertices[(i + 1) % n]);
+            nor += Vec3::new(
+                (a.y - b.y) * (a.z + b.z),
+                (a.z - b.z) * (a.x + b.x),
+                (a.x - b.x) * (a.y + b.y),
+            );
+        }
+        let uvw = ONB::buildw(nor);
+        let pts: Vec<(f64, f64)> = vertices
+            .iter()
+            .map(|&p| (p * uvw.u(), p * uvw.v()))
+            .collect();
+        let tris = triangulate(&pts);
+        let areas: Vec<f64> = tris
+            .iter()
+            .map(|t| cross2(pts[t[0]], pts[t[1]], pts[t[2]]).abs() / 2.0)
+            .collect();
+        let mut mn = (INF, INF);
+        let mut mx = (-INF, -INF);
+        for p in &pts {
+            mn = (mn.0.min(p.0), mn.1.min(p.1));
+            mx = (mx.0.max(p.0), mx.1.max(p.1));
+        }
+        Self {
+            d: uvw.w() * vertices[0],
+            vertices,
+            uvw,
+            pts,
+            tris,
+            area: areas.iter().sum(),
+            tri_dist: Distribution1D::new(areas),
+            mn,
+            mx,
+            mp,
+        }
+    }
+}
+impl Hittable for Polygon {
+    fn hit(&self, this_ray: &Ray, tmn: f64, tmx: f64) -> Option<HitRecord> {
+        let t = plane_t(this_ray, self.uvw.w(), self.d, tmn, tmx)?;
+        let p = this_ray.pos(t);
+        let q = (p * self.uvw.u(), p * self.uvw.v());
+        if !self
+            .tris
+            .iter()
+            .any(|t| in_triangle(q, self.pts[t[0]], self.pts[t[1]], self.pts[t[2]]))
+        {
+            return Option::None;
+        }
+        let (su, sv) = (self.mx.0 - self.mn.0, self.mx.1 - self.mn.1);
+        let mut rec = HitRecord {
+            p,
+            nor: Vec3::zero(),
+            geo_nor: Vec3::zero(),
+            dpdu: self.uvw.u() * su,
+            dpdv: self.uvw.v() * sv,
+            dndu: Vec3::zero(),
+            dndv: Vec3::zero(),
+            t,
+            u: (q.0 - self.mn.0) / su,
+            v: (q.1 - self.mn.1) / sv,
+            nor_dir: false,
+            mat_ptr: self.mp.clone(),
+            vertex_col: Option::None,
+            object_id: 0,
+        };
+        rec.set_face_normal(this_ray, self.uvw.w());
+        Option::Some(rec)
+    }
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
+        Option::Some(planar_box(&self.vertices))
+    }
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+        planar_pdf(self, self.area, o, v)
+    }
+    fn random(&self, o: Vec3) -> Vec3 {
+        let (_x, _pdf, k) = self.tri_dist.sample_continuous(get_rand01());
+        let t = self.tris[k];
+        let (a, b, c) = (
+            self.vertices[t[0]],
+            self.vertices[t[1]],
+            self.vertices[t[2]],
+        );
+        let su = get_rand01().sqrt();
+        let r = get_rand01();
+        a * (1.0 - su) + b * (su * (1.0 - r)) + c * (su * r) - o
+    }
+}
+
+// Infinite plane through `point`, uv repeats every `scale` units. It has
+// no finite area so it can not be a light.
+pub struct Plane {
+    point: Vec3,
+    uvw: ONB,
+    d: f64,
+    scale: f64,
+    mp: Arc<dyn Material>,
+}
+#[allow(dead_code)]
+impl Plane {
+    pub fn new(point: Vec3, normal: Vec3, scale: f64, mp: Arc<dyn Material>) -> Self {
+        let uvw = ONB::buildw(normal);
+        Self {
+            point,
+            d: uvw.w() * point,
+            uvw,
+            scale,
+            mp,
+        }
+    }
+}
+impl Hittable for Plane {
+    fn hit(&self, this_ray: &Ray, tmn: f64, tmx: f64) -> Option<HitRecord> {
+        let t = plane_t(this_ray, self.uvw.w(), self.d, tmn, tmx)?;
+        let p = this_ray.pos(t);
+        let mut rec = HitRecord {
+            p,
+            nor: Vec3::zero(),
+            geo_nor: Vec3::zero(),
+            dpdu: self.uvw.u() * self.scale,
+            dpdv: self.uvw.v() * self.scale,
+            dndu: Vec3::zero(),
+            dndv: Vec3::zero(),
+            t,
+            u: ((p - self.point) * self.uvw.u() / self.scale).rem_euclid(1.0),
+            v: ((p - self.point) * self.uvw.v() / self.scale).rem_euclid(1.0),
+            nor_dir: false,
+            mat_ptr: self.mp.clone(),
+            vertex_col: Option::None,
+            object_id: 0,
+        };
+        rec.set_face_normal(this_ray, self.uvw.w());
+        Option::Some(rec)
+    }
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
+        // big but finite, infinities would turn the slab test into NaNs
+        let e = Vec3::new(1e8, 1e8, 1e8);
+        Option::Some(Aabb::new(self.point - e, self.point + e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_triangulate_concave() {
+        // an L shape of area 3, clockwise
+        let pts = vec![
+            (0.0, 0.0),
+            (0.0, 2.0),
+            (1.0, 2.0),
+            (1.0, 1.0),
+            (2.0, 1.0),
+            (2.0, 0.0),
+        ];
+        let tris = triangulate(&pts);
+        assert_eq!(tris.len(), 4);
+        let area: f64 = tris
+            .iter()
+            .map(|t| cross2(pts[t[0]], pts[t[1]], pts[t[2]]).abs() / 2.0)
+            .sum();
+        assert!((area - 3.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_triangulate_collinear() {
+        // (1, 1) lies on the line through the two left vertices, turned so
+        // rounding makes it look like a thin ear
+        let (s, c) = 26f64.to_radians().sin_cos();
+        let turned = |pts: &[(f64, f64)]| -> Vec<(f64, f64)> {
+            pts.iter()
+                .map(|p| (p.0 * c - p.1 * s, p.0 * s + p.1 * c))
+                .collect()
+        };
+        let shapes = [
+            turned(&[(1.0, 1.0), (2.0, 2.0), (-3.0, 1.0), (-2.0, 1.0), (2.0, 0.5)]),
+            // a square with extra vertices along three of its sides
+            turned(&[
+                (0.0, 0.0),
+                (1.0, 0.0),
+                (2.0, 0.0),
+                (3.0, 0.0),
+                (3.0, 1.5),
+                (3.0, 3.0),
+                (0.0, 3.0),
+                (0.0, 2.0),
+                (0.0, 1.0),
+            ]),
+        ];
+        for pts in shapes.iter() {
+            let n = pts.len();
+            let mut expected = 0.0;
+            for i in 0..n {
+                let (a, b) = (pts[i], pts[(i + 1) % n]);
+                expected += (a.0 * b.1 - b.0 * a.1) / 2.0;
+            }
+            let area: f64 = triangulate(pts)
+                .iter()
+                .map(|t| cross2(pts[t[0]], pts[t[1]], pts[t[2]]).abs() / 2.0)
+                .sum();
+            assert!((area - expected.abs()).abs() < 1e-9);
+        }
+    }
+}
@@ -0,0 +1,377 @@
+use crate::mesh::TriangleMesh;
+use crate::vec3::Vec3;
+use memmap2::Mmap;
+use std::fs::File;
+
+#[derive(Clone, Copy, PartialEq)]
+enum PlyFormat {
+    Ascii,
+    BinaryLe,
+    BinaryBe,
+}
+
+#[derive(Clone, Copy)]
+enum PlyType {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+impl PlyType {
+    fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "char" | "int8" => Ok(PlyType::I8),
+            "uchar" | "uint8" => Ok(PlyType::U8),
+            "short" | "int16" => Ok(PlyType::I16),
+            "ushort" | "uint16" => Ok(PlyType::U16),
+            "int" | "int32" => Ok(PlyType::I32),
+            "uint" | "uint32" => Ok(PlyType::U32),
+            "float" | "float32" => Ok(PlyType::F32),
+            "double" | "float64" => Ok(PlyType::F64),
+            _ => Err(format!("unknown property type {}", name)),
+        }
+    }
+    fn size(self) -> usize {
+        match self {
+            PlyType::I8 | PlyType::U8 => 1,
+            PlyType::I16 | PlyType::U16 => 2,
+            PlyType::I32 | PlyType::U32 | PlyType::F32 => 4,
+            PlyType::F64 => 8,
+        }
+    }
+    // Colors stored as integers go from 0 to the largest value of the type.
+    fn color_scale(self) -> f64 {
+        match self {
+            PlyType::U8 | PlyType::I8 => 1.0 / 255.0,
+            PlyType::U16 | PlyType::I16 => 1.0 / 65535.0,
+            _ => 1.0,
+        }
+    }
+}
+
+struct PlyProperty {
+    name: String,
+    ty: PlyType,
+    // type of the length in front of a list property
+    list: Option<PlyType>,
+}
+
+struct PlyElement {
+    name: String,
+    count: usize,
+    props: Vec<PlyProperty>,
+}
+
+struct PlyReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    format: PlyFormat,
+}
+impl<'a> PlyReader<'a> {
+    fn read(&mut self, ty: PlyType) -> Result<f64, String> {
+        if self.format == PlyFormat::Ascii {
+            while self.pos < self.data.len() && self.data[self.pos].is_ascii_whitespace() {
+                self.pos += 1;
+            }
+            let start = self.pos;
+            while self.pos < self.data.len() && !self.data[self.pos].is_ascii_whitespace() {
+                self.pos += 1;
+            }
+            if start == self.pos {
+                return Err("unexpected end of file".to_string());
+            }
+            let token = std::str::from_utf8(&self.data[start..self.pos]).unwrap_or("");
+            return token
+                .parse::<f64>()
+                .map_err(|_| format!("bad number {}", token));
+        }
+        let n = ty.size();
+        if self.pos + n > self.data.len() {
+            return Err("unexpected end of file".to_string());
+        }
+        let mut b = [0u8; 8];
+        b[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+        self.pos += n;
+        if self.format == PlyFormat::BinaryBe {
+            b[..n].reverse();
+        }
+        Ok(match ty {
+            PlyType::I8 => b[0] as i8 as f64,
+            PlyType::U8 => b[0] as f64,
+            PlyType::I16 => i16::from_le_bytes([b[0], b[1]]) as f64,
+            PlyType::U16 => u16::from_le_bytes([b[0], b[1]]) as f64,
+            PlyType::I32 => i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            PlyType::U32 => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            PlyType::F32 => f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            PlyType::F64 => f64::from_le_bytes(b),
+        })
+    }
+}
+
+// Where each vertex property goes: position, normal, uv and color.
+fn vertex_slot(name: &str) -> Option<usize> {
+    let slot = match name {
+        "x" => 0,
+        "y" => 1,
+        "z" => 2,
+        "nx" => 3,
+        "ny" => 4,
+        "nz" => 5,
+        "u" | "s" | "texture_u" | "texture_s" => 6,
+        "v" | "t" | "texture_v" | "texture_t" => 7,
+        "red" | "r" | "diffuse_red" => 8,
+        "green" | "g" | "diffuse_green" => 9,
+        "blue" | "b" | "diffuse_blue" => 10,
+        _ => return Option::None,
+    };
+    Option::Some(slot)
+}
+
+fn parse_header(data: &[u8]) -> Result<(PlyFormat, Vec<PlyElement>, usize), String> {
+    let end = data
+        .windows(10)
+        .position(|w| w == b"end_header")
+        .ok_or("no end_header")?;
+    let body = data[end..]
+        .iter()
+        .position(|&c| c == b'\n')
+        .map(|k| end + k + 1)
+        .ok_or("no data after the header")?;
+    let header = String::from_utf8_lossy(&data[..end]);
+    let mut lines = header.lines();
+    if lines.next().map(|l| l.trim()) != Option::Some("ply") {
+        return Err("not a PLY file".to_string());
+    }
+    let mut format = Option::None;
+    let mut elements: Vec<PlyElement> = Vec::new();
+    for line in lines {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["format", f, _] => {
+                format = Option::Some(match *f {
+                    "ascii" => PlyFormat::Ascii,
+                    "binary_little_endian" => PlyFormat::BinaryLe,
+                    "binary_big_endian" => PlyFormat::BinaryBe,
+                    _ => return Err(format!("unknown format {}", f)),
+                })
+            }
+            ["element", name, count] => elements.push(PlyElement {
+                name: name.to_string(),
+                count: count
+                    .parse()
+                    .map_err(|_| format!("bad element count {}", count))?,
+                props: Vec::new(),
+            }),
+            ["property", "list", count_ty, ty, name] => elements
+                .last_mut()
+                .ok_or("property before any element")?
+                .props
+                .push(PlyProperty {
+                    name: name.to_string(),
+                    ty: PlyType::parse(ty)?,
+                    list: Option::Some(PlyType::parse(count_ty)?),
+                }),
+            ["property", ty, name] => elements
+                .last_mut()
+                .ok_or("property before any element")?
+                .props
+                .push(PlyProperty {
+                    name: name.to_string(),
+                    ty: PlyType::parse(ty)?,
+                    list: Option::None,
+                }),
+            _ => {}
+        }
+    }
+    Ok((format.ok_or("no format line")?, elements, body))
+}
+
+// Parses an ASCII or binary PLY into a mesh, polygons are split into
+// fans. Elements other than vertex and face are skipped.
+pub fn parse_ply(data: &[u8]) -> Result<TriangleMesh, String> {
+    let (format, elements, body) = parse_header(data)?;
+    let mut reader = PlyReader {
+        data,
+        pos: body,
+        format,
+    };
+    let mut mesh = TriangleMesh::default();
+    let mut face_idx = Vec::new();
+    for el in elements.iter() {
+        match el.name.as_str() {
+            "vertex" => {
+                let slots: Vec<Option<usize>> =
+                    el.props.iter().map(|p| vertex_slot(&p.name)).collect();
+                let has = |k: usize| slots.contains(&Option::Some(k));
+                if !(has(0) && has(1) && has(2)) {
+                    return Err("vertices have no position".to_string());
+                }
+                let (has_nor, has_uv, has_col) = (has(3), has(6), has(8));
+                let mut col_scale = 1.0;
+                for (p, s) in el.props.iter().zip(slots.iter()) {
+                    if *s == Option::Some(8) {
+                        col_scale = p.ty.color_scale();
+                    }
+                }
+                mesh.p.reserve(el.count);
+                for _ in 0..el.count {
+                    let mut v = [0.0; 11];
+                    for (p, s) in el.props.iter().zip(slots.iter()) {
+                        if let Option::Some(ct) = p.list {
+                            let n = reader.read(ct)? as usize;
+                            for _ in 0..n {
+                                reader.read(p.ty)?;
+                            }
+                            continue;
+                        }
+                        let x = reader.read(p.ty)?;
+                        if let Option::Some(k) = s {
+                            v[*k] = x;
+                        }
+                    }
+                    mesh.p.push(Vec3::new(v[0], v[1], v[2]));
+                    if has_nor {
+                        mesh.nor.push(Vec3::new(v[3], v[4], v[5]));
+                    }
+                    if has_uv {
+                        mesh.uv.push((v[6], v[7]));
+                    }
+                    if has_col {
+                        mesh.col.push(Vec3::new(v[8], v[9], v[10]) * col_scale);
+                    }
+                }
+            }
+            "face" => {
+                mesh.tris.reserve(el.count);
+                for _ in 0..el.count {
+                    for p in el.props.iter() {
+                        let n = match p.list {
+                            Option::Some(ct) => reader.read(ct)? as usize,
+                            Option::None => 1,
+                        };
+                        let indices = p.name == "vertex_indices" || p.name == "vertex_index";
+                        face_idx.clear();
+                        for _ in 0..n {
+                            let x = reader.read(p.ty)?;
+                            if indices {
+                                face_idx.push(x as u32);
+                            }
+                        }
+                        for k in 2..face_idx.len() {
+                            mesh.tris.push([face_idx[0], face_idx[k - 1], face_idx[k]]);
+                        }
+                    }
+                }
+            }
+            _ => {
+                for _ in 0..el.count {
+                    for p in el.props.iter() {
+                        let n = match p.list {
+                            Option::Some(ct) => reader.read(ct)? as usize,
+                            Option::None => 1,
+                        };
+                        for _ in 0..n {
+                            reader.read(p.ty)?;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    let nv = mesh.p.len() as u32;
+    if mesh.tris.iter().flatten().any(|&i| i >= nv) {
+        return Err("face refers to a missing vertex".to_string());
+    }
+    Ok(mesh)
+}
+
+// The file is memory mapped, so even huge scans are parsed straight from
+// the page cache.
+#[allow(dead_code)]
+pub fn load_ply(filename: &str) -> TriangleMesh {
+    let file = File::open(filename).unwrap_or_else(|_| panic!("Cannot open {}", filename));
+    // safe as long as nobody truncates the file while it is being read
+    let data =
+        unsafe { Mmap::map(&file) }.unwrap_or_else(|e| panic!("Cannot read {}: {}", filename, e));
+    parse_ply(&data).unwrap_or_else(|e| panic!("Cannot decode {}: {}", filename, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hit::tests::check_light_sampling;
+    use crate::material::DiffuseLight;
+    use crate::mesh::Mesh;
+    use std::sync::Arc;
+
+    fn header(format: &str) -> Vec<u8> {
+        format!(
+            "ply\nformat {} 1.0\ncomment test\nelement vertex 4\n\
+             property float x\nproperty float y\nproperty float z\n\
+             property uchar red\nproperty uchar green\nproperty uchar blue\n\
+             element face 1\nproperty list uchar int vertex_indices\nend_header\n",
+            format
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn test_formats_agree() {
+        let pos = [
+            [0.0f32, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [0.0, 1.0, 0.5],
+        ];
+        let col = [[255u8, 0, 0], [0, 255, 0], [0, 0, 255], [51, 51, 51]];
+        let mut ascii = header("ascii");
+        for (p, c) in pos.iter().zip(col.iter()) {
+            ascii
+                .extend(format!("{} {} {} {} {} {}\n", p[0], p[1], p[2], c[0], c[1], c[2]).bytes());
+        }
+        ascii.extend(b"4 0 1 2 3\n");
+        let binary = |be: bool| {
+            let mut data = header(if be {
+                "binary_big_endian"
+            } else {
+                "binary_little_endian"
+            });
+            for (p, c) in pos.iter().zip(col.iter()) {
+                for x in p.iter() {
+                    data.extend(if be { x.to_be_bytes() } else { x.to_le_bytes() });
+                }
+                data.extend(c.iter());
+            }
+            data.push(4);
+            for i in 0..4i32 {
+                data.extend(if be { i.to_be_bytes() } else { i.to_le_bytes() });
+            }
+            data
+        };
+        for data in [ascii, binary(false), binary(true)].iter() {
+            let mesh = parse_ply(data).unwrap();
+            assert_eq!(mesh.tris, vec![[0, 1, 2], [0, 2, 3]]);
+            assert_eq!(mesh.p[3], Vec3::new(0.0, 1.0, 0.5));
+            assert_eq!(mesh.col[1], Vec3::new(0.0, 1.0, 0.0));
+            assert!((mesh.col[3].x - 0.2).abs() < 1e-12);
+            assert!(mesh.nor.is_empty() && mesh.uv.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_light_sampled() {
+        // an emissive closed tetrahedron straight from a file
+        let data = "ply\nformat ascii 1.0\nelement vertex 4\n\
+                    property float x\nproperty float y\nproperty float z\n\
+                    element face 4\nproperty list uchar int vertex_indices\nend_header\n\
+                    0 0 0\n2 0 0\n0 1.5 0\n0.5 0.5 1\n\
+                    3 0 2 1\n3 0 1 3\n3 1 2 3\n3 0 3 2\n";
+        let mesh = parse_ply(data.as_bytes()).unwrap();
+        let light = Mesh::new(Arc::new(mesh), Arc::new(DiffuseLight::new(Vec3::ones())));
+        check_light_sampling(&light, Vec3::new(-0.5, 2.0, 3.0));
+    }
+}
@@ -0,0 +1,969 @@
+use crate::aabb::Aabb;
+use crate::hit::{HitRecord, Hittable};
+use crate::material::Material;
+use crate::random::*;
+use crate::ray::Ray;
+use crate::vec3::Vec3;
+use std::sync::Arc;
+pub const PI: f64 = std::f64::consts::PI;
+pub const INF: f64 = f64::MAX;
+
+// Shapes here are surfaces of revolution around the y axis through
+// `center`. phi goes from +x toward +z and is cut at `phi_max` degrees,
+// y is limited to [ymin, ymax] relative to the center.
+
+// Real roots of a x^2 + b x + c, in increasing order.
+pub fn solve_quadratic(a: f64, b: f64, c: f64) -> Vec<f64> {
+    if a.abs() < 1e-12 {
+        if b.abs() < 1e-12 {
+            return Vec::new();
+        }
+        return vec![-c / b];
+    }
+    let disc = b * b - 4.0 * a * c;
+    if disc < 0.0 {
+        return Vec::new();
+    }
+    // avoids cancellation between b and the square root
+    let q = if b < 0.0 {
+        -0.5 * (b - disc.sqrt())
+    } else {
+        -0.5 * (b + disc.sqrt())
+    };
+    if q == 0.0 {
+        return vec![0.0, 0.0];
+    }
+    let (r0, r1) = (q / a, c / q);
+    if r0 < r1 {
+        vec![r0, r1]
+    } else {
+        vec![r1, r0]
+    }
+}
+
+// Real roots of x^3 + a x^2 + b x + c.
+pub fn solve_cubic(a: f64, b: f64, c: f64) -> Vec<f64> {
+    let p = b - a * a / 3.0;
+    let q = 2.0 * a * a * a / 27.0 - a * b / 3.0 + c;
+    let shift = -a / 3.0;
+    let disc = q * q / 4.0 + p * p * p / 27.0;
+    if disc > 0.0 {
+        let s = disc.sqrt();
+        vec![(-q / 2.0 + s).cbrt() + (-q / 2.0 - s).cbrt() + shift]
+    } else if p.abs() < 1e-300 {
+        vec![shift]
+    } else {
+        let m = 2.0 * (-p / 3.0).sqrt();
+        let theta = (3.0 * q / (p * m)).clamp(-1.0, 1.0).acos() / 3.0;
+        (0..3)
+            .map(|k| m * (theta - 2.0 * PI * k as f64 / 3.0).cos() + shift)
+            .collect()
+    }
+}
+
+fn eval_quartic(c: &[f64; 5], x: f64) -> f64 {
+    (((c[0] * x + c[1]) * x + c[2]) * x + c[3]) * x + c[4]
+}
+
+// Real roots of c[0] x^4 + c[1] x^3 + c[2] x^2 + c[3] x + c[4], in
+// increasing order. Ferrari's method followed by Newton polishing, which
+// cleans up most of the precision lost in the resolvent cubic.
+pub fn solve_quartic(c: &[f64; 5]) -> Vec<f64> {
+    if c[0].abs() < 1e-12 {
+        return Vec::new();
+    }
+    let (b, cc, d, e) = (c[1] / c[0], c[2] / c[0], c[3] / c[0], c[4] / c[0]);
+    // x = y - b / 4 gives y^4 + p y^2 + q y + r
+    let b2 = b * b;
+    let p = cc - 3.0 * b2 / 8.0;
+    let q = d - b * cc / 2.0 + b2 * b / 8.0;
+    let r = e - b * d / 4.0 + b2 * cc / 16.0 - 3.0 * b2 * b2 / 256.0;
+    let mut ys = Vec::new();
+    if q.abs() < 1e-12 {
+        for z in solve_quadratic(1.0, p, r) {
+            if z >= 0.0 {
+                ys.push(z.sqrt());
+                ys.push(-z.sqrt());
+            }
+        }
+    } else {
+        let m = solve_cubic(p, p * p / 4.0 - r, -q * q / 8.0)
+            .into_iter()
+            .fold(-INF, f64::max);
+        if m <= 0.0 {
+            return Vec::new();
+        }
+        let s = (2.0 * m).sqrt();
+        ys.extend(solve_quadratic(1.0, -s, p / 2.0 + m + s * q / (4.0 * m)));
+        ys.extend(solve_quadratic(1.0, s, p / 2.0 + m - s * q / (4.0 * m)));
+    }
+    let mut roots: Vec<f64> = ys
+        .into_iter()
+        .map(|y| {
+            let mut x = y - b / 4.0;
+            for _i in 0..2 {
+                let f = eval_quartic(c, x);
+                let df = ((4.0 * c[0] * x + 3.0 * c[1]) * x + 2.0 * c[2]) * x + c[3];
+                if df.abs() > 1e-12 {
+                    x -= f / df;
+                }
+            }
+            x
+        })
+        .collect();
+    roots.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    roots
+}
+
+fn phi_of(p: Vec3) -> f64 {
+    p.z.atan2(p.x).rem_euclid(2.0 * PI)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn record(
+    this_ray: &Ray,
+    t: f64,
+    out_nor: Vec3,
+    u: f64,
+    v: f64,
+    dpdu: Vec3,
+    dpdv: Vec3,
+    (dndu, dndv): (Vec3, Vec3),
+    mp: &Arc<dyn Material>,
+) -> HitRecord {
+    let mut rec = HitRecord {
+        p: this_ray.pos(t),
+        nor: Vec3::zero(),
+        geo_nor: Vec3::zero(),
+        dpdu,
+        dpdv,
+        dndu,
+        dndv,
+        t,
+        u,
+        v,
+        nor_dir: false,
+        mat_ptr: mp.clone(),
+        vertex_col: Option::None,
+        object_id: 0,
+    };
+    rec.set_face_normal(this_ray, out_nor.unit());
+    rec
+}
+
+// Solid angle density of picking a point uniformly by area, summed over
+// every point of the shape along the direction.
+fn area_pdf(shape: &dyn Hittable, area: f64, o: Vec3, v: Vec3) -> f64 {
+    let mut sum = 0.0;
+    for rec in shape.hit_all(&Ray::new(o, v, 0.0), 0.0001 / v.length(), INF) {
+        let dis_squared = rec.t * rec.t * v.squared_length();
+        let cos = ((v * rec.geo_nor) / v.length()).abs();
+        sum += dis_squared / (cos * area);
+    }
+    sum
+}
+
+// x in [lo, hi] with f(x) = target, for f increasing with derivative df.
+// Newton steps that leave the bracket are replaced by bisection.
+fn invert(f: impl Fn(f64) -> f64, df: impl Fn(f64) -> f64, target: f64, lo: f64, hi: f64) -> f64 {
+    let (mut lo, mut hi) = (lo, hi);
+    let mut x = (lo + hi) / 2.0;
+    for _i in 0..64 {
+        let e = f(x) - target;
+        if e.abs() < 1e-12 * (1.0 + target.abs()) {
+            break;
+        }
+        if e > 0.0 {
+            hi = x;
+        } else {
+            lo = x;
+        }
+        let next = x - e / df(x);
+        x = if next > lo && next < hi {
+            next
+        } else {
+            (lo + hi) / 2.0
+        };
+    }
+    x
+}
+
+fn revolution_box(center: Vec3, rmax: f64, ymin: f64, ymax: f64) -> Aabb {
+    Aabb::new(
+        center + Vec3::new(-rmax, ymin - 0.001, -rmax),
+        center + Vec3::new(rmax, ymax + 0.001, rmax),
+    )
+}
+
+// Derivatives of the unit outward normal of a surface of revolution, given
+// the normal n as (radial, up) parts and the derivative of n along v. Going
+// around the axis only turns the normal, by its radial part.
+fn revolution_dn(
+    radial: Vec3,
+    tangent: Vec3,
+    phi_max: f64,
+    n: (f64, f64),
+    dn_dv: (f64, f64),
+) -> (Vec3, Vec3) {
+    let len = (n.0 * n.0 + n.1 * n.1).sqrt();
+    let (nr, ny) = (n.0 / len, n.1 / len);
+    let along = nr * dn_dv.0 + ny * dn_dv.1;
+    let (dr, dy) = ((dn_dv.0 - nr * along) / len, (dn_dv.1 - ny * along) / len);
+    (
+        tangent * (phi_max * nr),
+        radial * dr + Vec3::new(0.0, dy, 0.0),
+    )
+}
+
+// Point at height y and radius r, and the unit vectors along phi.
+fn around(r: f64, y: f64, phi: f64) -> (Vec3, Vec3, Vec3) {
+    let radial = Vec3::new(phi.cos(), 0.0, phi.sin());
+    let tangent = Vec3::new(-phi.sin(), 0.0, phi.cos());
+    (radial * r + Vec3::new(0.0, y, 0.0), radial, tangent)
+}
+
+pub struct Cylinder {
+    center: Vec3,
+    radius: f64,
+    ymin: f64,
+    ymax: f64,
+    phi_max: f64,
+    capped: bool,
+    mp: Arc<dyn Material>,
+}
+#[allow(dead_code)]
+#[allow(clippy::too_many_arguments)]
+impl Cylinder {
+    pub fn new(
+        center: Vec3,
+        radius: f64,
+        ymin: f64,
+        ymax: f64,
+        phi_max: f64,
+        capped: bool,
+        mp: Arc<dyn Material>,
+    ) -> Self {
+        Self {
+            center,
+            radius,
+            ymin,
+            ymax,
+            phi_max: phi_max.clamp(0.0, 360.0).to_radians(),
+            capped,
+            mp,
+        }
+    }
+    fn side_area(&self) -> f64 {
+        self.phi_max * self.radius * (self.ymax - self.ymin)
+    }
+    fn cap_area(&self) -> f64 {
+        self.phi_max * self.radius * self.radius / 2.0
+    }
+}
+impl Hittable for Cylinder {
+    fn hit(&self, this_ray: &Ray, tmn: f64, mut tmx: f64) -> Option<HitRecord> {
+        let o = this_ray.ori - self.center;
+        let d = this_ray.dir;
+        let mut best = Option::None;
+        let roots = solve_quadratic(
+            d.x * d.x + d.z * d.z,
+            2.0 * (o.x * d.x + o.z * d.z),
+            o.x * o.x + o.z * o.z - self.radius * self.radius,
+        );
+        for t in roots {
+            if t < tmn || t > tmx {
+                continue;
+            }
+            let p = o + d * t;
+            let phi = phi_of(p);
+            if p.y < self.ymin || p.y > self.ymax || phi > self.phi_max {
+                continue;
+            }
+            let (_q, radial, tangent) = around(self.radius, p.y, phi);
+            best = Option::Some(record(
+                this_ray,
+                t,
+                radial,
+                phi / self.phi_max,
+                (p.y - self.ymin) / (self.ymax - self.ymin),
+                tangent * (self.phi_max * self.radius),
+                Vec3::new(0.0, self.ymax - self.ymin, 0.0),
+                revolution_dn(radial, tangent, self.phi_max, (1.0, 0.0), (0.0, 0.0)),
+                &self.mp,
+            ));
+            tmx = t;
+            break;
+        }
+        if self.capped && d.y != 0.0 {
+            for (y, ny) in [(self.ymin, -1.0), (self.ymax, 1.0)].iter() {
+                let t = (y - o.y) / d.y;
+                if t < tmn || t > tmx {
+                    continue;
+                }
+                let p = o + d * t;
+                let r = (p.x * p.x + p.z * p.z).sqrt();
+                let phi = phi_of(p);
+                if r > self.radius || phi > self.phi_max {
+                    continue;
+                }
+                let (_q, radial, tangent) = around(r, *y, phi);
+                best = Option::Some(record(
+                    this_ray,
+                    t,
+                    Vec3::new(0.0, *ny, 0.0),
+                    phi / self.phi_max,
+                    r / self.radius,
+                    tangent * (self.phi_max * r),
+                    radial * self.radius,
+                    (Vec3::zero(), Vec3::zero()),
+                    &self.mp,
+                ));
+                tmx = t;
+            }
+        }
+        best
+    }
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
+        Option::Some(revolution_box(
+            self.center,
+            self.radius,
+            self.ymin,
+            self.ymax,
+        ))
+    }
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+        let caps = if self.capped {
+            2.0 * self.cap_area()
+        } else {
+            0.0
+        };
+        area_pdf(self, self.side_area() + caps, o, v)
+    }
+    fn random(&self, o: Vec3) -> Vec3 {
+        let phi = get_rand01() * self.phi_max;
+        let caps = if self.capped {
+            2.0 * self.cap_area()
+        } else {
+            0.0
+        };
+        let pick = get_rand01() * (self.side_area() + caps);
+        let p = if pick < self.side_area() {
+            around(self.radius, get_rand(self.ymin, self.ymax), phi).0
+        } else {
+            let y = if pick < self.side_area() + self.cap_area() {
+                self.ymin
+            } else {
+                self.ymax
+            };
+            around(self.radius * get_rand01().sqrt(), y, phi).0
+        };
+        self.center + p - o
+    }
+}
+
+// Cone with its base of `radius` at y = 0 and its apex at y = height.
+pub struct Cone {
+    center: Vec3,
+    radius: f64,
+    height: f64,
+    ymin: f64,
+    ymax: f64,
+    phi_max: f64,
+    mp: Arc<dyn Material>,
+}
+#[allow(dead_code)]
+#[allow(clippy::too_many_arguments)]
+impl Cone {
+    pub fn new(
+        center: Vec3,
+        radius: f64,
+        height: f64,
+        ymin: f64,
+        ymax: f64,
+        phi_max: f64,
+        mp: Arc<dyn Material>,
+    ) -> Self {
+        Self {
+            center,
+            radius,
+            height,
+            ymin: ymin.clamp(0.0, height),
+            ymax: ymax.clamp(0.0, height),
+            phi_max: phi_max.clamp(0.0, 360.0).to_radians(),
+            mp,
+        }
+    }
+    fn radius_at(&self, y: f64) -> f64 {
+        self.radius * (1.0 - y / self.height)
+    }
+    fn area(&self) -> f64 {
+        let (r0, r1) = (self.radius_at(self.ymin), self.radius_at(self.ymax));
+        let slant =
+            ((r0 - r1) * (r0 - r1) + (self.ymax - self.ymin) * (self.ymax - self.ymin)).sqrt();
+        self.phi_max / 2.0 * (r0 + r1) * slant
+    }
+}
+impl Hittable for Cone {
+    fn hit(&self, this_ray: &Ray, tmn: f64, tmx: f64) -> Option<HitRecord> {
+        let o = this_ray.ori - self.center;
+        let d = this_ray.dir;
+        let k = self.radius / self.height;
+        let k2 = k * k;
+        let h = self.height - o.y;
+        let roots = solve_quadratic(
+            d.x * d.x + d.z * d.z - k2 * d.y * d.y,
+            2.0 * (o.x * d.x + o.z * d.z + k2 * h * d.y),
+            o.x * o.x + o.z * o.z - k2 * h * h,
+        );
+        for t in roots {
+            if t < tmn || t > tmx {
+                continue;
+            }
+            let p = o + d * t;
+            let phi = phi_of(p);
+            if p.y < self.ymin || p.y > self.ymax || phi > self.phi_max {
+                continue;
+            }
+            let r = self.radius_at(p.y);
+            let (_q, radial, tangent) = around(r, p.y, phi);
+            return Option::Some(record(
+                this_ray,
+                t,
+                radial + Vec3::new(0.0, k, 0.0),
+                phi / self.phi_max,
+                (p.y - self.ymin) / (self.ymax - self.ymin),
+                tangent * (self.phi_max * r),
+                (Vec3::new(0.0, 1.0, 0.0) - radial * k) * (self.ymax - self.ymin),
+                revolution_dn(radial, tangent, self.phi_max, (1.0, k), (0.0, 0.0)),
+                &self.mp,
+            ));
+        }
+        Option::None
+    }
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
+        Option::Some(revolution_box(
+            self.center,
+            self.radius_at(self.ymin),
+            self.ymin,
+            self.ymax,
+        ))
+    }
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+        area_pdf(self, self.area(), o, v)
+    }
+    fn random(&self, o: Vec3) -> Vec3 {
+        // area grows with the radius, so r^2 is uniform
+        let (r0, r1) = (self.radius_at(self.ymin), self.radius_at(self.ymax));
+        let r = (r1 * r1 + get_rand01() * (r0 * r0 - r1 * r1)).sqrt();
+        let y = self.height * (1.0 - r / self.radius);
+        self.center + around(r, y, get_rand01() * self.phi_max).0 - o
+    }
+}
+
+// Paraboloid opening upward from its tip at the center, `radius` wide at
+// y = height.
+pub struct Paraboloid {
+    center: Vec3,
+    // r^2 = a y
+    a: f64,
+    ymin: f64,
+    ymax: f64,
+    phi_max: f64,
+    mp: Arc<dyn Material>,
+}
+#[allow(dead_code)]
+#[allow(clippy::too_many_arguments)]
+impl Paraboloid {
+    pub fn new(
+        center: Vec3,
+        radius: f64,
+        height: f64,
+        ymin: f64,
+        ymax: f64,
+        phi_max: f64,
+        mp: Arc<dyn Material>,
+    ) -> Self {
+        Self {
+            center,
+            a: radius * radius / height,
+            ymin: ymin.max(0.0),
+            ymax: ymax.max(0.0),
+            phi_max: phi_max.clamp(0.0, 360.0).to_radians(),
+            mp,
+        }
+    }
+    // (a y + a^2 / 4)^(3/2), the area up to y is proportional to it
+    fn area_term(&self, y: f64) -> f64 {
+        (self.a * y + self.a * self.a / 4.0).powf(1.5)
+    }
+    fn area(&self) -> f64 {
+        self.phi_max * 2.0 / (3.0 * self.a)
+            * (self.area_term(self.ymax) - self.area_term(self.ymin))
+    }
+}
+impl Hittable for Paraboloid {
+    fn hit(&self, this_ray: &Ray, tmn: f64, tmx: f64) -> Option<HitRecord> {
+        let o = this_ray.ori - self.center;
+        let d = this_ray.dir;
+        let roots = solve_quadratic(
+            d.x * d.x + d.z * d.z,
+            2.0 * (o.x * d.x + o.z * d.z) - self.a * d.y,
+            o.x * o.x + o.z * o.z - self.a * o.y,
+        );
+        for t in roots {
+            if t < tmn || t > tmx {
+                continue;
+            }
+            let p = o + d * t;
+            let phi = phi_of(p);
+            if p.y < self.ymin || p.y > self.ymax || phi > self.phi_max {
+                continue;
+            }
+            let r = (self.a * p.y).max(0.0).sqrt();
+            let (_q, radial, tangent) = around(r, p.y, phi);
+            return Option::Some(record(
+                this_ray,
+                t,
+                Vec3::new(2.0 * p.x, -self.a, 2.0 * p.z),
+                phi / self.phi_max,
+                (p.y - self.ymin) / (self.ymax - self.ymin),
+                tangent * (self.phi_max * r),
+                (Vec3::new(0.0, 1.0, 0.0) + radial * (self.a / (2.0 * r.max(1e-12))))
+                    * (self.ymax - self.ymin),
+                revolution_dn(
+                    radial,
+                    tangent,
+                    self.phi_max,
+                    (2.0 * r, -self.a),
+                    (self.a / r.max(1e-12) * (self.ymax - self.ymin), 0.0),
+                ),
+                &self.mp,
+            ));
+        }
+        Option::None
+    }
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
+        Option::Some(revolution_box(
+            self.center,
+            (self.a * self.ymax).sqrt(),
+            self.ymin,
+            self.ymax,
+        ))
+    }
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+        area_pdf(self, self.area(), o, v)
+    }
+    fn random(&self, o: Vec3) -> Vec3 {
+        let (s0, s1) = (self.area_term(self.ymin), self.area_term(self.ymax));
+        let s = s0 + get_rand01() * (s1 - s0);
+        let y = ((s.powf(2.0 / 3.0) - self.a * self.a / 4.0) / self.a).max(0.0);
+        let r = (self.a * y).sqrt();
+        self.center + around(r, y, get_rand01() * self.phi_max).0 - o
+    }
+}
+
+// Hyperboloid of one sheet, r^2 = waist^2 + (slope y)^2.
+pub struct Hyperboloid {
+    center: Vec3,
+    waist: f64,
+    slope: f64,
+    ymin: f64,
+    ymax: f64,
+    phi_max: f64,
+    mp: Arc<dyn Material>,
+}
+#[allow(dead_code)]
+#[allow(clippy::too_many_arguments)]
+impl Hyperboloid {
+    pub fn new(
+        center: Vec3,
+        waist: f64,
+        slope: f64,
+        ymin: f64,
+        ymax: f64,
+        phi_max: f64,
+        mp: Arc<dyn Material>,
+    ) -> Self {
+        Self {
+            center,
+            waist,
+            slope,
+            ymin,
+            ymax,
+            phi_max: phi_max.clamp(0.0, 360.0).to_radians(),
+            mp,
+        }
+    }
+    fn radius_at(&self, y: f64) -> f64 {
+        (self.waist * self.waist + self.slope * self.slope * y * y).sqrt()
+    }
+    // area per unit phi and y is sqrt(a + b y^2)
+    fn ab(&self) -> (f64, f64) {
+        let k2 = self.slope * self.slope;
+        (self.waist * self.waist, k2 * (1.0 + k2))
+    }
+    fn area_integral(&self, y: f64) -> f64 {
+        let (a, b) = self.ab();
+        if b < 1e-12 {
+            a.sqrt() * y
+        } else if a < 1e-12 {
+            b.sqrt() * y * y.abs() / 2.0
+        } else {
+            y / 2.0 * (a + b * y * y).sqrt() + a / (2.0 * b.sqrt()) * (y * (b / a).sqrt()).asinh()
+        }
+    }
+    fn area(&self) -> f64 {
+        self.phi_max * (self.area_integral(self.ymax) - self.area_integral(self.ymin))
+    }
+}
+impl Hittable for Hyperboloid {
+    fn hit(&self, this_ray: &Ray, tmn: f64, tmx: f64) -> Option<HitRecord> {
+        let o = this_ray.ori - self.center;
+        let d = this_ray.dir;
+        let k2 = self.slope * self.slope;
+        let roots = solve_quadratic(
+            d.x * d.x + d.z * d.z - k2 * d.y * d.y,
+            2.0 * (o.x * d.x + o.z * d.z - k2 * o.y * d.y),
+            o.x * o.x + o.z * o.z - k2 * o.y * o.y - self.waist * self.waist,
+        );
+        for t in roots {
+            if t < tmn || t > tmx {
+                continue;
+            }
+            let p = o + d * t;
+            let phi = phi_of(p);
+            if p.y < self.ymin || p.y > self.ymax || phi > self.phi_max {
+                continue;
+            }
+            let r = self.radius_at(p.y);
+            let (_q, radial, tangent) = around(r, p.y, phi);
+            return Option::Some(record(
+                this_ray,
+                t,
+                Vec3::new(p.x, -k2 * p.y, p.z),
+                phi / self.phi_max,
+                (p.y - self.ymin) / (self.ymax - self.ymin),
+                tangent * (self.phi_max * r),
+                (Vec3::new(0.0, 1.0, 0.0) + radial * (k2 * p.y / r.max(1e-12)))
+                    * (self.ymax - self.ymin),
+                revolution_dn(
+                    radial,
+                    tangent,
+                    self.phi_max,
+                    (r, -k2 * p.y),
+                    (
+                        k2 * p.y / r.max(1e-12) * (self.ymax - self.ymin),
+                        -k2 * (self.ymax - self.ymin),
+                    ),
+                ),
+                &self.mp,
+            ));
+        }
+        Option::None
+    }
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
+        let rmax = self.radius_at(self.ymin).max(self.radius_at(self.ymax));
+        Option::Some(revolution_box(self.center, rmax, self.ymin, self.ymax))
+    }
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+        area_pdf(self, self.area(), o, v)
+    }
+    fn random(&self, o: Vec3) -> Vec3 {
+        // inverts the area along y
+        let (a, b) = self.ab();
+        let (s0, s1) = (self.area_integral(self.ymin), self.area_integral(self.ymax));
+        let y = invert(
+            |y| self.area_integral(y),
+            |y| (a + b * y * y).sqrt(),
+            s0 + get_rand01() * (s1 - s0),
+            self.ymin,
+            self.ymax,
+        );
+        self.center + around(self.radius_at(y), y, get_rand01() * self.phi_max).0 - o
+    }
+}
+
+// Torus around the y axis, theta goes around the tube from the outer
+// equator upward.
+pub struct Torus {
+    center: Vec3,
+    major: f64,
+    minor: f64,
+    ymin: f64,
+    ymax: f64,
+    phi_max: f64,
+    mp: Arc<dyn Material>,
+}
+#[allow(dead_code)]
+#[allow(clippy::too_many_arguments)]
+impl Torus {
+    pub fn new(
+        center: Vec3,
+        major: f64,
+        minor: f64,
+        ymin: f64,
+        ymax: f64,
+        phi_max: f64,
+        mp: Arc<dyn Material>,
+    ) -> Self {
+        Self {
+            center,
+            major,
+            minor,
+            ymin: ymin.clamp(-minor, minor),
+            ymax: ymax.clamp(-minor, minor),
+            phi_max: phi_max.clamp(0.0, 360.0).to_radians(),
+            mp,
+        }
+    }
+    fn area(&self) -> f64 {
+        let (rr, r) = (self.major, self.minor);
+        let g = |theta: f64| r * (rr * theta + r * theta.sin());
+        let (a0, a1) = ((self.ymin / r).asin(), (self.ymax / r).asin());
+        // the outer half of the tube, then the inner half
+        self.phi_max * (g(a1) - g(a0) + g(PI - a0) - g(PI - a1))
+    }
+}
+impl Hittable for Torus {
+    fn hit(&self, this_ray: &Ray, tmn: f64, tmx: f64) -> Option<HitRecord> {
+        let d = this_ray.dir;
+        let dd = d * d;
+        // start from the point closest to the center to keep the
+        // coefficients small
+        let ts = -((this_ray.ori - self.center) * d) / dd;
+        let o = this_ray.ori - self.center + d * ts;
+        let bound = self.major + self.minor;
+        if o.squared_length() > bound * bound {
+            return Option::None;
+        }
+        let r2 = self.major * self.major;
+        let od = o * d;
+        let k = o * o + r2 - self.minor * self.minor;
+        let coef = [
+            dd * dd,
+            4.0 * dd * od,
+            2.0 * dd * k + 4.0 * od * od - 4.0 * r2 * (d.x * d.x + d.z * d.z),
+            4.0 * od * k - 8.0 * r2 * (o.x * d.x + o.z * d.z),
+            k * k - 4.0 * r2 * (o.x * o.x + o.z * o.z),
+        ];
+        for root in solve_quartic(&coef) {
+            let t = root + ts;
+            if t < tmn || t > tmx {
+                continue;
+            }
+            let p = this_ray.ori - self.center + d * t;
+            let phi = phi_of(p);
+            if p.y < self.ymin || p.y > self.ymax || phi > self.phi_max {
+                continue;
+            }
+            let rho = (p.x * p.x + p.z * p.z).sqrt();
+            let theta = p.y.atan2(rho - self.major).rem_euclid(2.0 * PI);
+            let (_q, radial, tangent) = around(rho, p.y, phi);
+            let tube = radial * theta.cos() + Vec3::new(0.0, theta.sin(), 0.0);
+            let tube_dir = Vec3::new(0.0, theta.cos(), 0.0) - radial * theta.sin();
+            return Option::Some(record(
+                this_ray,
+                t,
+                tube,
+                phi / self.phi_max,
+                theta / (2.0 * PI),
+                tangent * (self.phi_max * rho),
+                tube_dir * (2.0 * PI * self.minor),
+                revolution_dn(
+                    radial,
+                    tangent,
+                    self.phi_max,
+                    (theta.cos(), theta.sin()),
+                    (-theta.sin() * 2.0 * PI, theta.cos() * 2.0 * PI),
+                ),
+                &self.mp,
+            ));
+        }
+        Option::None
+    }
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
+        Option::Some(revolution_box(
+            self.center,
+            self.major + self.minor,
+            self.ymin,
+            self.ymax,
+        ))
+    }
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+        area_pdf(self, self.area(), o, v)
+    }
+    fn random(&self, o: Vec3) -> Vec3 {
+        // picks the outer or the inner arc of the tube by area, then
+        // inverts the area along it
+        let (rr, r) = (self.major, self.minor);
+        let g = |theta: f64| r * (rr * theta + r * theta.sin());
+        let (a0, a1) = ((self.ymin / r).asin(), (self.ymax / r).asin());
+        let outer = g(a1) - g(a0);
+        let mut s = get_rand01() * (outer + g(PI - a0) - g(PI - a1));
+        let (lo, hi) = if s < outer {
+            s += g(a0);
+            (a0, a1)
+        } else {
+            s += g(PI - a1) - outer;
+            (PI - a1, PI - a0)
+        };
+        let theta = invert(g, |theta| r * (rr + r * theta.cos()), s, lo, hi);
+        let p = around(
+            rr + r * theta.cos(),
+            r * theta.sin(),
+            get_rand01() * self.phi_max,
+        )
+        .0;
+        self.center + p - o
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quartic_distinct() {
+        // (x - 1)(x - 2)(x + 3)(x - 0.5)
+        let roots = solve_quartic(&[1.0, -0.5, -7.0, 9.5, -3.0]);
+        let expect = [-3.0, 0.5, 1.0, 2.0];
+        assert_eq!(roots.len(), 4);
+        for (r, e) in roots.iter().zip(expect.iter()) {
+            assert!((r - e).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_quartic_biquadratic() {
+        // x^4 - 5 x^2 + 4 = (x^2 - 1)(x^2 - 4), and x^4 + 1 with no real root
+        let roots = solve_quartic(&[2.0, 0.0, -10.0, 0.0, 8.0]);
+        let expect = [-2.0, -1.0, 1.0, 2.0];
+        for (r, e) in roots.iter().zip(expect.iter()) {
+            assert!((r - e).abs() < 1e-9);
+        }
+        assert!(solve_quartic(&[1.0, 0.0, 0.0, 0.0, 1.0]).is_empty());
+    }
+
+    #[test]
+    fn test_torus_hole() {
+        let mat: Arc<dyn Material> = Arc::new(crate::material::NOMaterial {});
+        let torus = Torus::new(Vec3::zero(), 2.0, 0.5, -1.0, 1.0, 360.0, mat);
+        // straight down through the hole misses, through the tube hits
+        let miss = Ray::new(Vec3::new(0.0, 5.0, 0.0), Vec3::new(0.0, -1.0, 0.0), 0.0);
+        assert!(torus.hit(&miss, 0.001, INF).is_none());
+        let ray = Ray::new(Vec3::new(-10.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), 0.0);
+        let rec = torus.hit(&ray, 0.001, INF).unwrap();
+        assert!((rec.t - 7.5).abs() < 1e-9);
+        assert!((rec.nor - Vec3::new(-1.0, 0.0, 0.0)).length() < 1e-9);
+    }
+
+    #[test]
+    fn test_normal_derivatives() {
+        let mat: Arc<dyn Material> = Arc::new(crate::material::NOMaterial {});
+        let shapes: Vec<(Arc<dyn Hittable>, f64)> = vec![
+            (
+                Arc::new(Cylinder::new(
+                    Vec3::zero(),
+                    1.0,
+                    -1.0,
+                    1.0,
+                    360.0,
+                    false,
+                    mat.clone(),
+                )),
+                0.3,
+            ),
+            (
+                Arc::new(Cone::new(
+                    Vec3::zero(),
+                    1.0,
+                    2.0,
+                    0.0,
+                    1.5,
+                    360.0,
+                    mat.clone(),
+                )),
+                0.5,
+            ),
+            (
+                Arc::new(Paraboloid::new(
+                    Vec3::zero(),
+                    1.0,
+                    1.0,
+                    0.0,
+                    1.0,
+                    360.0,
+                    mat.clone(),
+                )),
+                0.5,
+            ),
+            (
+                Arc::new(Hyperboloid::new(
+                    Vec3::zero(),
+                    0.5,
+                    1.0,
+                    -1.0,
+                    1.0,
+                    360.0,
+                    mat.clone(),
+                )),
+                0.4,
+            ),
+            (
+                Arc::new(Torus::new(Vec3::zero(), 2.0, 0.5, -1.0, 1.0, 360.0, mat)),
+                0.3,
+            ),
+        ];
+        let outward = |rec: &HitRecord| if rec.nor_dir { rec.nor } else { -rec.nor };
+        for (shape, y) in shapes {
+            let dir = Vec3::new(-1.0, 0.0, -0.2);
+            let a = shape
+                .hit(&Ray::new(Vec3::new(8.0, y, 2.0), dir, 0.0), 0.001, INF)
+                .unwrap();
+            let b = shape
+                .hit(
+                    &Ray::new(Vec3::new(8.0, y + 1e-4, 2.0 + 1e-4), dir, 0.0),
+                    0.001,
+                    INF,
+                )
+                .unwrap();
+            let dn = outward(&b) - outward(&a);
+            let predicted = a.dndu * (b.u - a.u) + a.dndv * (b.v - a.v);
+            assert!(dn.length() > 1e-6);
+            assert!((dn - predicted).length() < 0.02 * dn.length());
+        }
+    }
+
+    #[test]
+    fn test_sampling_by_area() {
+        let mat: Arc<dyn Material> = Arc::new(crate::material::NOMaterial {});
+        let n = 20000;
+        // a thin band around the equator of the tube, the outer arc has
+        // (R + r) / 2R of its area
+        let torus = Torus::new(Vec3::zero(), 2.0, 0.5, 0.0, 0.001, 360.0, mat.clone());
+        let mut outer = 0.0;
+        for _ in 0..n {
+            let p = torus.random(Vec3::zero());
+            let rho = (p.x * p.x + p.z * p.z).sqrt();
+            assert!(p.y >= 0.0 && p.y <= 0.001 + 1e-12);
+            assert!(((rho - 2.0).powi(2) + p.y * p.y - 0.25).abs() < 1e-9);
+            outer += (rho > 2.0) as i32 as f64 / n as f64;
+        }
+        assert!((outer - 0.625).abs() < 0.02, "{}", outer);
+        let hyperboloid = Hyperboloid::new(Vec3::zero(), 0.5, 2.0, -0.5, 2.0, 360.0, mat);
+        let (s0, s1) = (
+            hyperboloid.area_integral(-0.5),
+            hyperboloid.area_integral(2.0),
+        );
+        let expect = (hyperboloid.area_integral(0.5) - s0) / (s1 - s0);
+        let mut below = 0.0;
+        for _ in 0..n {
+            let p = hyperboloid.random(Vec3::zero());
+            assert!(p.y >= -0.5 && p.y <= 2.0);
+            let r = (p.x * p.x + p.z * p.z).sqrt();
+            assert!((r - hyperboloid.radius_at(p.y)).abs() < 1e-9);
+            below += (p.y < 0.5) as i32 as f64 / n as f64;
+        }
+        assert!((below - expect).abs() < 0.02, "{} vs {}", below, expect);
+    }
+}
@@ -0,0 +1,80 @@
+use crate::hit::*;
+use crate::light::{InfiniteLight, Light};
+use crate::pdf::*;
+use crate::vec3::Vec3;
+use std::sync::Arc;
+
+pub struct Scene {
+    pub world: HittableList,
+    // emitting shapes, sampled toward for direct lighting
+    pub lights: Option<Arc<dyn Hittable>>,
+    // point, spot and distant lights
+    pub delta_lights: Vec<Arc<dyn Light>>,
+    pub background: Vec3,
+    // environment maps, sky and sun
+    pub infinite_lights: Vec<Arc<dyn InfiniteLight>>,
+}
+impl Scene {
+    // Radiance of a ray that leaves the scene.
+    pub fn escaped(&self, dir: Vec3) -> Vec3 {
+        let mut col = self.background;
+        for light in self.infinite_lights.iter() {
+            col += light.value(dir);
+        }
+        col
+    }
+    // Pdf over everything that can be light sampled from p: the area lights
+    // and each infinite light, all picked equally often.
+    pub fn light_pdf(&self, p: Vec3) -> Option<Arc<dyn PDF>> {
+        let mut pdfs: Vec<(Arc<dyn PDF>, f64)> = Vec::new();
+        if let Option::Some(lights) = &self.lights {
+            pdfs.push((Arc::new(HittablePDF::new(lights.clone(), p)), 1.0));
+        }
+        for light in self.infinite_lights.iter() {
+            pdfs.push((Arc::new(InfiniteLightPDF::new(light.clone())), 1.0));
+        }
+        match pdfs.len() {
+            0 => Option::None,
+            1 => Option::Some(pdfs.remove(0).0),
+            _ => Option::Some(Arc::new(MixturePDF::weighted(pdfs))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Flat {
+        pdf: f64,
+    }
+    impl InfiniteLight for Flat {
+        fn value(&self, _dir: Vec3) -> Vec3 {
+            Vec3::zero()
+        }
+        fn pdf_value(&self, _dir: Vec3) -> f64 {
+            self.pdf
+        }
+        fn random(&self) -> Vec3 {
+            Vec3::new(0.0, 1.0, 0.0)
+        }
+    }
+
+    #[test]
+    fn test_light_pdf_weights() {
+        let mut scene = Scene {
+            world: HittableList::default(),
+            lights: Option::None,
+            delta_lights: Vec::new(),
+            background: Vec3::zero(),
+            infinite_lights: Vec::new(),
+        };
+        assert!(scene.light_pdf(Vec3::zero()).is_none());
+        for pdf in [1.0, 2.0, 6.0].iter() {
+            scene.infinite_lights.push(Arc::new(Flat { pdf: *pdf }));
+        }
+        // the mean of the three, not 1/4, 1/4 and 1/2 of them
+        let p = scene.light_pdf(Vec3::zero()).unwrap();
+        assert!((p.value(Vec3::new(0.0, 1.0, 0.0)) - 3.0).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,539 @@
+use crate::aabb::Aabb;
+use crate::hit::{get_sphere_dp, get_sphere_uv, HitRecord, Hittable};
+use crate::material::Material;
+use crate::onb::ONB;
+use crate::ray::Ray;
+use crate::vec3::Vec3;
+use std::sync::Arc;
+
+// Signed distance, negative inside.
+pub trait Sdf: Send + Sync {
+    fn distance(&self, p: Vec3) -> f64;
+    // Bound on how fast the distance changes, steps are divided by it.
+    // Anything that bends space needs more than 1.
+    fn lipschitz(&self) -> f64 {
+        1.0
+    }
+}
+
+// A user supplied distance function.
+pub struct SdfFn<F: Fn(Vec3) -> f64 + Send + Sync> {
+    f: F,
+    lipschitz: f64,
+}
+#[allow(dead_code)]
+impl<F: Fn(Vec3) -> f64 + Send + Sync> SdfFn<F> {
+    pub fn new(f: F, lipschitz: f64) -> Self {
+        Self { f, lipschitz }
+    }
+}
+impl<F: Fn(Vec3) -> f64 + Send + Sync> Sdf for SdfFn<F> {
+    fn distance(&self, p: Vec3) -> f64 {
+        (self.f)(p)
+    }
+    fn lipschitz(&self) -> f64 {
+        self.lipschitz
+    }
+}
+
+pub struct SdfSphere {
+    center: Vec3,
+    radius: f64,
+}
+#[allow(dead_code)]
+impl SdfSphere {
+    pub fn new(center: Vec3, radius: f64) -> Self {
+        Self { center, radius }
+    }
+}
+impl Sdf for SdfSphere {
+    fn distance(&self, p: Vec3) -> f64 {
+        (p - self.center).length() - self.radius
+    }
+}
+
+pub struct SdfBox {
+    center: Vec3,
+    half: Vec3,
+}
+#[allow(dead_code)]
+impl SdfBox {
+    pub fn new(center: Vec3, half: Vec3) -> Self {
+        Self { center, half }
+    }
+}
+impl Sdf for SdfBox {
+    fn distance(&self, p: Vec3) -> f64 {
+        let p = p - self.center;
+        let q = Vec3::new(
+            p.x.abs() - self.half.x,
+            p.y.abs() - self.half.y,
+            p.z.abs() - self.half.z,
+        );
+        let outside = Vec3::new(q.x.max(0.0), q.y.max(0.0), q.z.max(0.0)).length();
+        outside + q.x.max(q.y).max(q.z).min(0.0)
+    }
+}
+
+// Torus around the y axis.
+pub struct SdfTorus {
+    center: Vec3,
+    major: f64,
+    minor: f64,
+}
+#[allow(dead_code)]
+impl SdfTorus {
+    pub fn new(center: Vec3, major: f64, minor: f64) -> Self {
+        Self {
+            center,
+            major,
+            minor,
+        }
+    }
+}
+impl Sdf for SdfTorus {
+    fn distance(&self, p: Vec3) -> f64 {
+        let p = p - self.center;
+        let rho = (p.x * p.x + p.z * p.z).sqrt() - self.major;
+        (rho * rho + p.y * p.y).sqrt() - self.minor
+    }
+}
+
+// Mandelbulb fractal around the origin, fits in a radius of about 1.2.
+pub struct Mandelbulb {
+    power: f64,
+    iterations: u32,
+}
+#[allow(dead_code)]
+impl Mandelbulb {
+    pub fn new(power: f64, iterations: u32) -> Self {
+        Self { power, iterations }
+    }
+}
+impl Sdf for Mandelbulb {
+    fn distance(&self, p: Vec3) -> f64 {
+        let mut z = p;
+        let mut dr = 1.0;
+        let mut r = z.length();
+        for _i in 0..self.iterations {
+            r = z.length();
+            if !(1e-12..=2.0).contains(&r) {
+                break;
+            }
+            let theta = (z.y / r).acos() * self.power;
+            let phi = z.z.atan2(z.x) * self.power;
+            dr = r.powf(self.power - 1.0) * self.power * dr + 1.0;
+            z = Vec3::new(
+                theta.sin() * phi.cos(),
+                theta.cos(),
+                theta.sin() * phi.sin(),
+            ) * r.powf(self.power)
+                + p;
+        }
+        0.5 * r.max(1e-12).ln() * r / dr
+    }
+    // the distance estimate overshoots near the surface
+    fn lipschitz(&self) -> f64 {
+        2.0
+    }
+}
+
+pub struct SdfUnion {
+    a: Arc<dyn Sdf>,
+    b: Arc<dyn Sdf>,
+}
+#[allow(dead_code)]
+impl SdfUnion {
+    pub fn new(a: Arc<dyn Sdf>, b: Arc<dyn Sdf>) -> Self {
+        Self { a, b }
+    }
+}
+impl Sdf for SdfUnion {
+    fn distance(&self, p: Vec3) -> f64 {
+        self.a.distance(p).min(self.b.distance(p))
+    }
+    fn lipschitz(&self) -> f64 {
+        self.a.lipschitz().max(self.b.lipschitz())
+    }
+}
+
+pub struct SdfIntersection {
+    a: Arc<dyn Sdf>,
+    b: Arc<dyn Sdf>,
+}
+#[allow(dead_code)]
+impl SdfIntersection {
+    pub fn new(a: Arc<dyn Sdf>, b: Arc<dyn Sdf>) -> Self {
+        Self { a, b }
+    }
+}
+impl Sdf for SdfIntersection {
+    fn distance(&self, p: Vec3) -> f64 {
+        self.a.distance(p).max(self.b.distance(p))
+    }
+    fn lipschitz(&self) -> f64 {
+        self.a.lipschitz().max(self.b.lipschitz())
+    }
+}
+
+// a minus b
+pub struct SdfSubtraction {
+    a: Arc<dyn Sdf>,
+    b: Arc<dyn Sdf>,
+}
+#[allow(dead_code)]
+impl SdfSubtraction {
+    pub fn new(a: Arc<dyn Sdf>, b: Arc<dyn Sdf>) -> Self {
+        Self { a, b }
+    }
+}
+impl Sdf for SdfSubtraction {
+    fn distance(&self, p: Vec3) -> f64 {
+        self.a.distance(p).max(-self.b.distance(p))
+    }
+    fn lipschitz(&self) -> f64 {
+        self.a.lipschitz().max(self.b.lipschitz())
+    }
+}
+
+// Polynomial smooth minimum, blends within about k of where a and b meet.
+pub fn smooth_min(a: f64, b: f64, k: f64) -> f64 {
+    if k <= 0.0 {
+        return a.min(b);
+    }
+    let h = (0.5 + 0.5 * (b - a) / k).clamp(0.0, 1.0);
+    b + (a - b) * h - k * h * (1.0 - h)
+}
+
+pub struct SmoothUnion {
+    a: Arc<dyn Sdf>,
+    b: Arc<dyn Sdf>,
+    k: f64,
+}
+#[allow(dead_code)]
+impl SmoothUnion {
+    pub fn new(a: Arc<dyn Sdf>, b: Arc<dyn Sdf>, k: f64) -> Self {
+        Self { a, b, k }
+    }
+}
+impl Sdf for SmoothUnion {
+    fn distance(&self, p: Vec3) -> f64 {
+        smooth_min(self.a.distance(p), self.b.distance(p), self.k)
+    }
+    fn lipschitz(&self) -> f64 {
+        self.a.lipschitz().max(self.b.lipschitz())
+    }
+}
+
+// Infinite copies every `period`, a zero component does not repeat.
+// The inner shape should fit in one cell.
+pub struct SdfRepeat {
+    inner: Arc<dyn Sdf>,
+    period: Vec3,
+}
+#[allow(dead_code)]
+impl SdfRepeat {
+    pub fn new(inner: Arc<dyn Sdf>, period: Vec3) -> Self {
+        Self { inner, period }
+    }
+}
+impl Sdf for SdfRepeat {
+    fn distance(&self, p: Vec3) -> f64 {
+        let mut q = p;
+        for i in 0..3 {
+            let c = self.period.get(i);
+            if c > 0.0 {
+                *q.get_mut(i) = p.get(i) - c * (p.get(i) / c).round();
+            }
+        }
+        self.inner.distance(q)
+    }
+    fn lipschitz(&self) -> f64 {
+        self.inner.lipschitz()
+    }
+}
+
+// Twists around the y axis by `rate` radians per unit of height. `extent`
+// is the largest distance from the axis the shape reaches.
+pub struct SdfTwist {
+    inner: Arc<dyn Sdf>,
+    rate: f64,
+    extent: f64,
+}
+#[allow(dead_code)]
+impl SdfTwist {
+    pub fn new(inner: Arc<dyn Sdf>, rate: f64, extent: f64) -> Self {
+        Self {
+            inner,
+            rate,
+            extent,
+        }
+    }
+}
+impl Sdf for SdfTwist {
+    fn distance(&self, p: Vec3) -> f64 {
+        let a = self.rate * p.y;
+        let (s, c) = a.sin_cos();
+        self.inner
+            .distance(Vec3::new(c * p.x - s * p.z, p.y, s * p.x + c * p.z))
+    }
+    fn lipschitz(&self) -> f64 {
+        let k = self.rate * self.extent;
+        self.inner.lipschitz() * (1.0 + k * k).sqrt()
+    }
+}
+
+// Rounds edges off by `radius`, the shape grows by the same amount.
+pub struct SdfRound {
+    inner: Arc<dyn Sdf>,
+    radius: f64,
+}
+#[allow(dead_code)]
+impl SdfRound {
+    pub fn new(inner: Arc<dyn Sdf>, radius: f64) -> Self {
+        Self { inner, radius }
+    }
+}
+impl Sdf for SdfRound {
+    fn distance(&self, p: Vec3) -> f64 {
+        self.inner.distance(p) - self.radius
+    }
+    fn lipschitz(&self) -> f64 {
+        self.inner.lipschitz()
+    }
+}
+
+// Adds sine ripples of `amplitude` with `frequency` in each axis.
+pub struct SdfDisplace {
+    inner: Arc<dyn Sdf>,
+    amplitude: f64,
+    frequency: f64,
+}
+#[allow(dead_code)]
+impl SdfDisplace {
+    pub fn new(inner: Arc<dyn Sdf>, amplitude: f64, frequency: f64) -> Self {
+        Self {
+            inner,
+            amplitude,
+            frequency,
+        }
+    }
+}
+impl Sdf for SdfDisplace {
+    fn distance(&self, p: Vec3) -> f64 {
+        let f = self.frequency;
+        let d = (f * p.x).sin() * (f * p.y).sin() * (f * p.z).sin();
+        self.inner.distance(p) + self.amplitude * d
+    }
+    fn lipschitz(&self) -> f64 {
+        self.inner.lipschitz() + self.amplitude * self.frequency * 3.0_f64.sqrt()
+    }
+}
+
+// Sphere traces `sdf` inside `mybox`, which must contain the whole
+// surface. uv is a spherical mapping around the box center.
+pub struct SdfObject {
+    sdf: Arc<dyn Sdf>,
+    mybox: Aabb,
+    eps: f64,
+    mp: Arc<dyn Material>,
+}
+#[allow(dead_code)]
+impl SdfObject {
+    pub fn new(sdf: Arc<dyn Sdf>, mybox: Aabb, mp: Arc<dyn Material>) -> Self {
+        let eps = (mybox.mx - mybox.mn).length() * 1e-5;
+        Self {
+            sdf,
+            mybox,
+            eps,
+            mp,
+        }
+    }
+    fn normal(&self, p: Vec3) -> Vec3 {
+        let h = self.eps;
+        let d = |dx: f64, dy: f64, dz: f64| self.sdf.distance(p + Vec3::new(dx, dy, dz));
+        let g = Vec3::new(
+            d(h, 0.0, 0.0) - d(-h, 0.0, 0.0),
+            d(0.0, h, 0.0) - d(0.0, -h, 0.0),
+            d(0.0, 0.0, h) - d(0.0, 0.0, -h),
+        );
+        if g.squared_length() == 0.0 {
+            Vec3::new(0.0, 1.0, 0.0)
+        } else {
+            g.unit()
+        }
+    }
+    // Spherical uv around the center of the box. The derivatives follow
+    // the surface, seen from the center, as it turns with u and v.
+    fn uv(&self, p: Vec3, out_nor: Vec3) -> (f64, f64, Vec3, Vec3) {
+        let dir = p - (self.mybox.mn + self.mybox.mx) / 2.0;
+        let rho = dir.length();
+        if rho == 0.0 {
+            let uvw = ONB::buildw(out_nor);
+            return (0.0, 0.0, uvw.u(), uvw.v());
+        }
+        let dir = dir / rho;
+        let (mut u, mut v) = (0.0, 0.0);
+        get_sphere_uv(dir, &mut u, &mut v);
+        let (mut du, mut dv) = (Vec3::zero(), Vec3::zero());
+        get_sphere_dp(dir, rho, &mut du, &mut dv);
+        // slides along dir back onto the tangent plane, or straight onto
+        // it where the surface runs nearly along dir
+        let cos = dir * out_nor;
+        let along = if cos.abs() > 1e-3 { dir / cos } else { out_nor };
+        (
+            u,
+            v,
+            du - along * (du * out_nor),
+            dv - along * (dv * out_nor),
+        )
+    }
+    // Change of the normal along a tangent, by differences of the gradient.
+    fn normal_change(&self, p: Vec3, tangent: Vec3) -> Vec3 {
+        let len = tangent.length();
+        if len == 0.0 {
+            return Vec3::zero();
+        }
+        let h = 16.0 * self.eps / len;
+        (self.normal(p + tangent * h) - self.normal(p - tangent * h)) / (2.0 * h)
+    }
+    // where the ray is inside the box, in ray parameters
+    fn box_span(&self, this_ray: &Ray, tmn: f64, tmx: f64) -> Option<(f64, f64)> {
+        // max and min drop NaNs, so a NaN ray would pass the slabs and
+        // march the whole box
+        if (this_ray.ori.squared_length() + this_ray.dir.squared_length()).is_nan() {
+            return Option::None;
+        }
+        let (mut t0, mut t1) = (tmn, tmx);
+        for i in 0..3 {
+            let inv = 1.0 / this_ray.dir.get(i);
+            let mut a = (self.mybox.mn.get(i) - this_ray.ori.get(i)) * inv;
+            let mut b = (self.mybox.mx.get(i) - this_ray.ori.get(i)) * inv;
+            if inv < 0.0 {
+                std::mem::swap(&mut a, &mut b);
+            }
+            t0 = t0.max(a);
+            t1 = t1.min(b);
+            if t1 <= t0 {
+                return Option::None;
+            }
+        }
+        Option::Some((t0, t1))
+    }
+}
+impl Hittable for SdfObject {
+    fn hit(&self, this_ray: &Ray, tmn: f64, tmx: f64) -> Option<HitRecord> {
+        let (t0, t1) = self.box_span(this_ray, tmn, tmx)?;
+        let len = this_ray.dir.length();
+        let lip = self.sdf.lipschitz();
+        // march in world units along the unit direction
+        let (mut s, s1) = (t0 * len, t1 * len);
+        // a ray that starts in the box may be leaving the surface it
+        // starts on, so nothing counts until it gets away from it. Steps
+        // grow meanwhile, a ray along a flat face would never get away.
+        let mut leaving = t0 <= tmn;
+        let mut min_step = self.eps;
+        let mut steps = 0;
+        while s <= s1 && steps < 512 {
+            let p = this_ray.pos(s / len);
+            let d = self.sdf.distance(p).abs() / lip;
+            if d >= self.eps {
+                leaving = false;
+            } else if !leaving {
+                let t = s / len;
+                let out_nor = self.normal(p);
+                let (u, v, dpdu, dpdv) = self.uv(p, out_nor);
+                let mut rec = HitRecord {
+                    p,
+                    nor: Vec3::zero(),
+                    geo_nor: Vec3::zero(),
+                    dpdu,
+                    dpdv,
+                    dndu: self.normal_change(p, dpdu),
+                    dndv: self.normal_change(p, dpdv),
+                    t,
+                    u,
+                    v,
+                    nor_dir: false,
+                    mat_ptr: self.mp.clone(),
+                    vertex_col: Option::None,
+                    object_id: 0,
+                };
+                rec.set_face_normal(this_ray, out_nor);
+                return Option::Some(rec);
+            }
+            s += d.max(min_step);
+            if leaving {
+                min_step *= 2.0;
+            }
+            steps += 1;
+        }
+        Option::None
+    }
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
+        Option::Some(self.mybox.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::NOMaterial;
+
+    #[test]
+    fn test_trace_sphere() {
+        let sdf = Arc::new(SdfSphere::new(Vec3::zero(), 1.0));
+        let mybox = Aabb::new(Vec3::new(-1.0, -1.0, -1.0), Vec3::new(1.0, 1.0, 1.0));
+        let obj = SdfObject::new(sdf, mybox, Arc::new(NOMaterial {}));
+        let this_ray = Ray::new(Vec3::new(0.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 2.0), 0.0);
+        let rec = obj.hit(&this_ray, 0.001, f64::MAX).unwrap();
+        assert!((rec.t - 2.0).abs() < 1e-4);
+        assert!((rec.nor - Vec3::new(0.0, 0.0, -1.0)).length() < 1e-4);
+        assert!(rec.nor_dir);
+        // from inside, the far wall faces away
+        let inside = Ray::new(rec.p, Vec3::new(0.0, 0.0, 1.0), 0.0);
+        let rec = obj.hit(&inside, 0.001, f64::MAX).unwrap();
+        assert!((rec.p.z - 1.0).abs() < 1e-4);
+        assert!(!rec.nor_dir);
+    }
+
+    #[test]
+    fn test_smooth_min() {
+        assert!((smooth_min(1.0, 5.0, 0.5) - 1.0).abs() < 1e-12);
+        assert!(smooth_min(1.0, 1.0, 0.5) < 1.0);
+    }
+
+    #[test]
+    fn test_surface_derivatives() {
+        // on a sphere around the center of the box they match the analytic
+        // sphere, with the normal turning as fast as the point moves
+        let center = Vec3::new(0.5, 0.0, 0.0);
+        let sdf = Arc::new(SdfSphere::new(center, 2.0));
+        let mybox = Aabb::new(center - Vec3::ones() * 2.0, center + Vec3::ones() * 2.0);
+        let obj = SdfObject::new(sdf, mybox, Arc::new(NOMaterial {}));
+        let this_ray = Ray::new(Vec3::new(3.0, 4.0, 1.0), Vec3::new(-0.7, -1.0, -0.4), 0.0);
+        let rec = obj.hit(&this_ray, 0.001, f64::MAX).unwrap();
+        let (mut dpdu, mut dpdv) = (Vec3::zero(), Vec3::zero());
+        get_sphere_dp((rec.p - center).unit(), 2.0, &mut dpdu, &mut dpdv);
+        assert!((rec.dpdu - dpdu).length() < 1e-3 * dpdu.length());
+        assert!((rec.dpdv - dpdv).length() < 1e-3 * dpdv.length());
+        assert!((rec.dndu - dpdu / 2.0).length() < 1e-3 * dpdu.length());
+        assert!((rec.dndv - dpdv / 2.0).length() < 1e-3 * dpdv.length());
+        // on a flat face of a box a small step along dpdu stays on the face
+        // and moves u alone
+        let sdf = Arc::new(SdfBox::new(Vec3::zero(), Vec3::new(1.0, 0.5, 0.8)));
+        let mybox = Aabb::new(-Vec3::new(1.0, 0.5, 0.8), Vec3::new(1.0, 0.5, 0.8));
+        let obj = SdfObject::new(sdf.clone(), mybox, Arc::new(NOMaterial {}));
+        let this_ray = Ray::new(Vec3::new(0.3, 0.2, 5.0), Vec3::new(0.1, -0.05, -1.0), 0.0);
+        let rec = obj.hit(&this_ray, 0.001, f64::MAX).unwrap();
+        let h = 1e-4;
+        for (tangent, du, dv) in [(rec.dpdu, h, 0.0), (rec.dpdv, 0.0, h)].iter() {
+            let q = rec.p + *tangent * h;
+            assert!(sdf.distance(q).abs() < 1e-6);
+            let (mut u, mut v) = (0.0, 0.0);
+            get_sphere_uv(q.unit(), &mut u, &mut v);
+            assert!((u - rec.u - du).abs() < 1e-6 && (v - rec.v - dv).abs() < 1e-6);
+        }
+        assert!(rec.dndu.length() < 1e-6 && rec.dndv.length() < 1e-6);
+    }
+}
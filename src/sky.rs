@@ -0,0 +1,284 @@
+use crate::light::{EnvironmentLight, InfiniteLight};
+use crate::onb::ONB;
+use crate::random::*;
+use crate::vec3::Vec3;
+pub const PI: f64 = std::f64::consts::PI;
+
+// Angular radius of the sun seen from the earth, in degrees.
+pub const SUN_RADIUS: f64 = 0.2665;
+
+// Direction toward the sun, +y is up, north is -z and east is +x. Both
+// angles are in degrees, azimuth goes clockwise from north.
+pub fn sun_direction(elevation: f64, azimuth: f64) -> Vec3 {
+    let el = elevation.to_radians();
+    let az = azimuth.to_radians();
+    Vec3::new(el.cos() * az.sin(), el.sin(), -el.cos() * az.cos())
+}
+
+fn day_of_year(year: i32, month: u32, day: u32) -> u32 {
+    let before: [u32; 12] = [0, 31, 59, 90, 120, 151, 181, 212, 243, 273, 304, 334];
+    let leap = (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+    let doy = before[month as usize - 1] + day;
+    if leap && month > 2 {
+        doy + 1
+    } else {
+        doy
+    }
+}
+
+// Sun (elevation, azimuth) in degrees for a place and a UTC date and time,
+// with the NOAA approximation. Latitude is positive north, longitude
+// positive east.
+#[allow(dead_code)]
+pub fn sun_position(
+    latitude: f64,
+    longitude: f64,
+    year: i32,
+    month: u32,
+    day: u32,
+    hours_utc: f64,
+) -> (f64, f64) {
+    let doy = day_of_year(year, month, day) as f64;
+    let g = 2.0 * PI / 365.0 * (doy - 1.0 + (hours_utc - 12.0) / 24.0);
+    let eqtime = 229.18
+        * (0.000075 + 0.001868 * g.cos()
+            - 0.032077 * g.sin()
+            - 0.014615 * (2.0 * g).cos()
+            - 0.040849 * (2.0 * g).sin());
+    let decl = 0.006918 - 0.399912 * g.cos() + 0.070257 * g.sin() - 0.006758 * (2.0 * g).cos()
+        + 0.000907 * (2.0 * g).sin()
+        - 0.002697 * (3.0 * g).cos()
+        + 0.00148 * (3.0 * g).sin();
+    let true_solar_minutes = hours_utc * 60.0 + eqtime + 4.0 * longitude;
+    let ha = (true_solar_minutes / 4.0 - 180.0).to_radians();
+    let lat = latitude.to_radians();
+    let cos_zenith = (lat.sin() * decl.sin() + lat.cos() * decl.cos() * ha.cos()).clamp(-1.0, 1.0);
+    let elevation = 90.0 - cos_zenith.acos().to_degrees();
+    // measured from south, then turned to go from north
+    let azimuth = ha
+        .sin()
+        .atan2(ha.cos() * lat.sin() - decl.tan() * lat.cos())
+        .to_degrees()
+        + 180.0;
+    (elevation, azimuth.rem_euclid(360.0))
+}
+
+// Preetham, Shirley and Smits analytic daylight model.
+pub struct PreethamSky {
+    sun_dir: Vec3,
+    theta_s: f64,
+    perez_y: [f64; 5],
+    perez_cx: [f64; 5],
+    perez_cy: [f64; 5],
+    zenith: Vec3,
+    intensity: f64,
+}
+#[allow(dead_code)]
+impl PreethamSky {
+    // The model gives luminance in kcd/m^2, `intensity` scales it into the
+    // renderer's units.
+    pub fn new(sun_dir: Vec3, turbidity: f64, intensity: f64) -> Self {
+        let t = turbidity;
+        let sun_dir = sun_dir.unit();
+        // the model is only fitted for the sun above the horizon
+        let theta_s = sun_dir.y.clamp(0.0, 1.0).acos().min(PI / 2.0 - 0.01);
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let zy = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let (t1, t2, t3) = (theta_s, theta_s * theta_s, theta_s * theta_s * theta_s);
+        let zx = t * t * (0.00166 * t3 - 0.00375 * t2 + 0.00209 * t1)
+            + t * (-0.02903 * t3 + 0.06377 * t2 - 0.03202 * t1 + 0.00394)
+            + (0.11693 * t3 - 0.21196 * t2 + 0.06052 * t1 + 0.25886);
+        let zyc = t * t * (0.00275 * t3 - 0.00610 * t2 + 0.00317 * t1)
+            + t * (-0.04214 * t3 + 0.08970 * t2 - 0.04153 * t1 + 0.00516)
+            + (0.15346 * t3 - 0.26756 * t2 + 0.06670 * t1 + 0.26688);
+        Self {
+            sun_dir,
+            theta_s,
+            perez_y: [
+                0.1787 * t - 1.4630,
+                -0.3554 * t + 0.4275,
+                -0.0227 * t + 5.3251,
+                0.1206 * t - 2.5771,
+                -0.0670 * t + 0.3703,
+            ],
+            perez_cx: [
+                -0.0193 * t - 0.2592,
+                -0.0665 * t + 0.0008,
+                -0.0004 * t + 0.2125,
+                -0.0641 * t - 0.8989,
+                -0.0033 * t + 0.0452,
+            ],
+            perez_cy: [
+                -0.0167 * t - 0.2608,
+                -0.0950 * t + 0.0092,
+                -0.0079 * t + 0.2102,
+                -0.0441 * t - 1.6537,
+                -0.0109 * t + 0.0529,
+            ],
+            zenith: Vec3::new(zx, zyc, zy),
+            intensity,
+        }
+    }
+    fn perez(c: &[f64; 5], theta: f64, gamma: f64) -> f64 {
+        (1.0 + c[0] * (c[1] / theta.cos().max(0.01)).exp())
+            * (1.0 + c[2] * (c[3] * gamma).exp() + c[4] * gamma.cos() * gamma.cos())
+    }
+    // Sky radiance for a direction above the horizon, linear sRGB.
+    pub fn radiance(&self, dir: Vec3) -> Vec3 {
+        let d = dir.unit();
+        let theta = d.y.max(0.0).acos();
+        let gamma = (d * self.sun_dir).clamp(-1.0, 1.0).acos();
+        let f = |c: &[f64; 5], z: f64| {
+            z * Self::perez(c, theta, gamma) / Self::perez(c, 0.0, self.theta_s)
+        };
+        let x = f(&self.perez_cx, self.zenith.x);
+        let y = f(&self.perez_cy, self.zenith.y);
+        let lum = f(&self.perez_y, self.zenith.z) * self.intensity;
+        xyy_to_rgb(x, y, lum)
+    }
+}
+
+fn xyy_to_rgb(x: f64, y: f64, lum: f64) -> Vec3 {
+    if y <= 0.0 {
+        return Vec3::zero();
+    }
+    let cx = x * lum / y;
+    let cz = (1.0 - x - y) * lum / y;
+    Vec3::new(
+        (3.2406 * cx - 1.5372 * lum - 0.4986 * cz).max(0.0),
+        (-0.9689 * cx + 1.8758 * lum + 0.0415 * cz).max(0.0),
+        (0.0557 * cx - 0.2040 * lum + 1.0570 * cz).max(0.0),
+    )
+}
+
+// Sun radiance after the atmosphere, with Rayleigh and turbidity dependent
+// aerosol extinction along the relative air mass. Same units as the sky.
+fn sun_radiance(sun_dir: Vec3, turbidity: f64, intensity: f64) -> Vec3 {
+    let elevation = sun_dir.unit().y.asin().to_degrees();
+    if elevation <= 0.0 {
+        return Vec3::zero();
+    }
+    let zenith = 90.0 - elevation;
+    let air_mass = 1.0 / (zenith.to_radians().cos() + 0.15 * (93.885 - zenith).powf(-1.253));
+    let beta = 0.04608 * turbidity - 0.04586;
+    // red, green and blue wavelengths in micrometres
+    let lambda = [0.68, 0.55, 0.44];
+    let t: Vec<f64> = lambda
+        .iter()
+        .map(|l: &f64| {
+            let tau = 0.008735 * l.powf(-4.08) + beta * l.powf(-1.3);
+            (-tau * air_mass).exp()
+        })
+        .collect();
+    // about 1.6e9 cd/m^2 outside the atmosphere
+    Vec3::new(t[0], t[1], t[2]) * (1.6e6 * intensity)
+}
+
+// The sun disk as a light, sampled uniformly over its cone.
+pub struct SunLight {
+    uvw: ONB,
+    cos_max: f64,
+    radiance: Vec3,
+}
+#[allow(dead_code)]
+impl SunLight {
+    pub fn new(sun_dir: Vec3, radiance: Vec3) -> Self {
+        Self {
+            uvw: ONB::buildw(sun_dir),
+            cos_max: SUN_RADIUS.to_radians().cos(),
+            radiance,
+        }
+    }
+    pub fn solid_angle(&self) -> f64 {
+        2.0 * PI * (1.0 - self.cos_max)
+    }
+}
+impl InfiniteLight for SunLight {
+    fn value(&self, dir: Vec3) -> Vec3 {
+        if dir.unit() * self.uvw.w() >= self.cos_max {
+            self.radiance
+        } else {
+            Vec3::zero()
+        }
+    }
+    fn pdf_value(&self, dir: Vec3) -> f64 {
+        if dir.unit() * self.uvw.w() >= self.cos_max {
+            1.0 / self.solid_angle()
+        } else {
+            0.0
+        }
+    }
+    fn random(&self) -> Vec3 {
+        let z = 1.0 - get_rand01() * (1.0 - self.cos_max);
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let phi = 2.0 * PI * get_rand01();
+        self.uvw.local(r * phi.cos(), r * phi.sin(), z)
+    }
+}
+
+// Builds the sky, baked into an importance sampled environment map, and
+// the matching sun. Below the horizon is a diffuse ground lit by both.
+#[allow(dead_code)]
+pub fn sun_sky(
+    elevation: f64,
+    azimuth: f64,
+    turbidity: f64,
+    ground_albedo: Vec3,
+    intensity: f64,
+) -> (EnvironmentLight, SunLight) {
+    let sun_dir = sun_direction(elevation, azimuth);
+    let sky = PreethamSky::new(sun_dir, turbidity, intensity);
+    let sun = SunLight::new(sun_dir, sun_radiance(sun_dir, turbidity, intensity));
+
+    let (width, height) = (512, 256);
+    let mut data = vec![Vec3::zero(); width * height];
+    // irradiance on the ground from the sky and the sun
+    let mut ground = sun.radiance * (sun.solid_angle() * sun_dir.y.max(0.0));
+    for j in 0..height / 2 {
+        let theta = PI * (j as f64 + 0.5) / height as f64;
+        for i in 0..width {
+            let phi = 2.0 * PI * (i as f64 + 0.5) / width as f64;
+            let dir = Vec3::new(
+                theta.sin() * phi.cos(),
+                theta.cos(),
+                theta.sin() * phi.sin(),
+            );
+            let col = sky.radiance(dir);
+            data[j * width + i] = col;
+            let d_omega = (2.0 * PI / width as f64) * (PI / height as f64) * theta.sin();
+            ground += col * (theta.cos() * d_omega);
+        }
+    }
+    let ground = ground.change(ground_albedo) / PI;
+    for c in data.iter_mut().skip(width * (height / 2)) {
+        *c = ground;
+    }
+    (
+        EnvironmentLight::from_pixels(width, height, data, 1.0, 0.0),
+        sun,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_equinox_noon() {
+        let (elevation, _azimuth) = sun_position(0.0, 0.0, 2020, 3, 20, 12.0);
+        assert!(elevation > 87.0);
+    }
+
+    #[test]
+    fn test_winter_noon() {
+        let (elevation, azimuth) = sun_position(60.0, 0.0, 2019, 12, 21, 12.0);
+        assert!((elevation - 6.6).abs() < 0.5);
+        assert!((azimuth - 180.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn test_sun_direction() {
+        let d = sun_direction(0.0, 90.0);
+        assert!((d - Vec3::new(1.0, 0.0, 0.0)).length() < 1e-12);
+    }
+}
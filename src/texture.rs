@@ -1,7 +1,12 @@
+use crate::hit::HitRecord;
 use crate::vec3::*;
 use std::sync::Arc;
-pub trait Texture {
+pub trait Texture: Send + Sync {
     fn value(&self, u: f64, v: f64, p: Vec3) -> Vec3;
+    // Lookup at a hit, for textures that need more of it than (u, v, p).
+    fn value_at(&self, rec: &HitRecord) -> Vec3 {
+        self.value(rec.u, rec.v, rec.p)
+    }
 }
 
 pub struct SolidColor {
@@ -46,3 +51,80 @@ impl Texture for CheckerTexture {
         }
     }
 }
+
+pub struct ImageTexture {
+    pub data: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+}
+#[allow(dead_code)]
+impl ImageTexture {
+    // A missing or broken file shows up as cyan instead of stopping the
+    // render.
+    pub fn new(filename: &str) -> Self {
+        match image::open(filename) {
+            Ok(img) => {
+                let img = img.to_rgb();
+                let (width, height) = img.dimensions();
+                Self {
+                    data: img.into_raw(),
+                    width,
+                    height,
+                }
+            }
+            Err(e) => {
+                eprintln!("Cannot open texture {}: {}", filename, e);
+                Self {
+                    data: Vec::new(),
+                    width: 0,
+                    height: 0,
+                }
+            }
+        }
+    }
+    // Already decoded 8 bit RGB rows, top row first.
+    pub fn from_rgb(data: Vec<u8>, width: u32, height: u32) -> Self {
+        assert_eq!(data.len(), (3 * width * height) as usize);
+        Self {
+            data,
+            width,
+            height,
+        }
+    }
+}
+impl Texture for ImageTexture {
+    fn value(&self, u: f64, v: f64, _p: Vec3) -> Vec3 {
+        if self.data.is_empty() {
+            return Vec3::new(0.0, 1.0, 1.0);
+        }
+        let u = u.clamp(0.0, 1.0);
+        let v = 1.0 - v.clamp(0.0, 1.0);
+        let i = ((u * self.width as f64) as u32).min(self.width - 1);
+        let j = ((v * self.height as f64) as u32).min(self.height - 1);
+        let k = (3 * (j * self.width + i)) as usize;
+        Vec3::new(
+            self.data[k] as f64 / 255.0,
+            self.data[k + 1] as f64 / 255.0,
+            self.data[k + 2] as f64 / 255.0,
+        )
+    }
+}
+
+// Per vertex colors of a mesh, anything hit without them gets `fallback`.
+pub struct VertexColorTexture {
+    pub fallback: Vec3,
+}
+#[allow(dead_code)]
+impl VertexColorTexture {
+    pub fn new(fallback: Vec3) -> Self {
+        Self { fallback }
+    }
+}
+impl Texture for VertexColorTexture {
+    fn value(&self, _u: f64, _v: f64, _p: Vec3) -> Vec3 {
+        self.fallback
+    }
+    fn value_at(&self, rec: &HitRecord) -> Vec3 {
+        rec.vertex_col.unwrap_or(self.fallback)
+    }
+}
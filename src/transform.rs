@@ -0,0 +1,384 @@
+use crate::aabb::Aabb;
+use crate::hit::{HitRecord, Hittable, INF};
+use crate::ray::Ray;
+use crate::vec3::Vec3;
+use std::ops::Mul;
+use std::sync::Arc;
+
+pub type Matrix4 = [[f64; 4]; 4];
+
+const IDENTITY: Matrix4 = [
+    [1.0, 0.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0, 0.0],
+    [0.0, 0.0, 1.0, 0.0],
+    [0.0, 0.0, 0.0, 1.0],
+];
+
+fn mul(a: &Matrix4, b: &Matrix4) -> Matrix4 {
+    let mut r = [[0.0; 4]; 4];
+    for (i, row) in r.iter_mut().enumerate() {
+        for (j, x) in row.iter_mut().enumerate() {
+            *x = (0..4).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    r
+}
+
+fn transpose(m: &Matrix4) -> Matrix4 {
+    let mut r = [[0.0; 4]; 4];
+    for (i, row) in r.iter_mut().enumerate() {
+        for (j, x) in row.iter_mut().enumerate() {
+            *x = m[j][i];
+        }
+    }
+    r
+}
+
+// Gauss-Jordan with partial pivoting, None when singular.
+fn invert(m: &Matrix4) -> Option<Matrix4> {
+    let mut a = *m;
+    let mut r = IDENTITY;
+    for c in 0..4 {
+        let pivot = (c..4).max_by(|&x, &y| a[x][c].abs().partial_cmp(&a[y][c].abs()).unwrap())?;
+        if a[pivot][c].abs() < 1e-12 {
+            return Option::None;
+        }
+        a.swap(c, pivot);
+        r.swap(c, pivot);
+        let inv = 1.0 / a[c][c];
+        for j in 0..4 {
+            a[c][j] *= inv;
+            r[c][j] *= inv;
+        }
+        for i in 0..4 {
+            if i != c {
+                let f = a[i][c];
+                for j in 0..4 {
+                    a[i][j] -= f * a[c][j];
+                    r[i][j] -= f * r[c][j];
+                }
+            }
+        }
+    }
+    Option::Some(r)
+}
+
+// Affine transform, a row major matrix that maps column vectors together
+// with its inverse.
+#[derive(Clone, Copy, Debug)]
+pub struct Transform {
+    pub m: Matrix4,
+    pub inv: Matrix4,
+}
+#[allow(dead_code)]
+impl Transform {
+    pub fn identity() -> Self {
+        Self {
+            m: IDENTITY,
+            inv: IDENTITY,
+        }
+    }
+    // None when the matrix can not be inverted, e.g. a zero scale.
+    pub fn from_matrix(m: Matrix4) -> Option<Self> {
+        let inv = invert(&m)?;
+        Option::Some(Self { m, inv })
+    }
+    pub fn translate(d: Vec3) -> Self {
+        let mut m = IDENTITY;
+        let mut inv = IDENTITY;
+        for i in 0..3 {
+            m[i][3] = d.get(i as i32);
+            inv[i][3] = -d.get(i as i32);
+        }
+        Self { m, inv }
+    }
+    pub fn scale(s: Vec3) -> Self {
+        let mut m = IDENTITY;
+        let mut inv = IDENTITY;
+        for i in 0..3 {
+            m[i][i] = s.get(i as i32);
+            inv[i][i] = 1.0 / s.get(i as i32);
+        }
+        Self { m, inv }
+    }
+    // Counterclockwise about `axis` when looking against it.
+    pub fn rotate(axis: Vec3, degrees: f64) -> Self {
+        let a = axis.unit();
+        let (s, c) = degrees.to_radians().sin_cos();
+        let m = [
+            [
+                a.x * a.x + (1.0 - a.x * a.x) * c,
+                a.x * a.y * (1.0 - c) - a.z * s,
+                a.x * a.z * (1.0 - c) + a.y * s,
+                0.0,
+            ],
+            [
+                a.x * a.y * (1.0 - c) + a.z * s,
+                a.y * a.y + (1.0 - a.y * a.y) * c,
+                a.y * a.z * (1.0 - c) - a.x * s,
+                0.0,
+            ],
+            [
+                a.x * a.z * (1.0 - c) - a.y * s,
+                a.y * a.z * (1.0 - c) + a.x * s,
+                a.z * a.z + (1.0 - a.z * a.z) * c,
+                0.0,
+            ],
+            [0.0, 0.0, 0.0, 1.0],
+        ];
+        // rotations are orthogonal
+        Self {
+            m,
+            inv: transpose(&m),
+        }
+    }
+    // Unit quaternion stored as (x, y, z, w).
+    pub fn from_quaternion(q: [f64; 4]) -> Self {
+        let [x, y, z, w] = q;
+        let m = [
+            [
+                1.0 - 2.0 * (y * y + z * z),
+                2.0 * (x * y - z * w),
+                2.0 * (x * z + y * w),
+                0.0,
+            ],
+            [
+                2.0 * (x * y + z * w),
+                1.0 - 2.0 * (x * x + z * z),
+                2.0 * (y * z - x * w),
+                0.0,
+            ],
+            [
+                2.0 * (x * z - y * w),
+                2.0 * (y * z + x * w),
+                1.0 - 2.0 * (x * x + y * y),
+                0.0,
+            ],
+            [0.0, 0.0, 0.0, 1.0],
+        ];
+        Self {
+            m,
+            inv: transpose(&m),
+        }
+    }
+    pub fn inverse(&self) -> Self {
+        Self {
+            m: self.inv,
+            inv: self.m,
+        }
+    }
+    pub fn point(&self, p: Vec3) -> Vec3 {
+        let m = &self.m;
+        let x = m[0][0] * p.x + m[0][1] * p.y + m[0][2] * p.z + m[0][3];
+        let y = m[1][0] * p.x + m[1][1] * p.y + m[1][2] * p.z + m[1][3];
+        let z = m[2][0] * p.x + m[2][1] * p.y + m[2][2] * p.z + m[2][3];
+        let w = m[3][0] * p.x + m[3][1] * p.y + m[3][2] * p.z + m[3][3];
+        if w == 1.0 {
+            Vec3::new(x, y, z)
+        } else {
+            Vec3::new(x, y, z) / w
+        }
+    }
+    pub fn vector(&self, v: Vec3) -> Vec3 {
+        let m = &self.m;
+        Vec3::new(
+            m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+            m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+            m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z,
+        )
+    }
+    // Normals go through the inverse transpose, the result is not unit.
+    pub fn normal(&self, n: Vec3) -> Vec3 {
+        let m = &self.inv;
+        Vec3::new(
+            m[0][0] * n.x + m[1][0] * n.y + m[2][0] * n.z,
+            m[0][1] * n.x + m[1][1] * n.y + m[2][1] * n.z,
+            m[0][2] * n.x + m[1][2] * n.y + m[2][2] * n.z,
+        )
+    }
+    // Mirroring transforms turn counterclockwise triangles clockwise.
+    pub fn swaps_handedness(&self) -> bool {
+        let m = &self.m;
+        let det = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+        det < 0.0
+    }
+    // The scale factor when the transform maps spheres to spheres, that is
+    // rotations, translations and uniform scales.
+    pub fn uniform_scale(&self) -> Option<f64> {
+        let x = self.vector(Vec3::new(1.0, 0.0, 0.0));
+        let y = self.vector(Vec3::new(0.0, 1.0, 0.0));
+        let z = self.vector(Vec3::new(0.0, 0.0, 1.0));
+        let s = x.length();
+        let eps = 1e-9 * s * s;
+        let similar = ((y * y) - s * s).abs() < eps
+            && ((z * z) - s * s).abs() < eps
+            && (x * y).abs() < eps
+            && (y * z).abs() < eps
+            && (z * x).abs() < eps
+            && self.m[3] == [0.0, 0.0, 0.0, 1.0];
+        if similar && s > 0.0 {
+            Option::Some(s)
+        } else {
+            Option::None
+        }
+    }
+}
+// a * b applies b first.
+impl Mul for Transform {
+    type Output = Self;
+
+    fn mul(self, other: Self) -> Self {
+        Self {
+            m: mul(&self.m, &other.m),
+            inv: mul(&other.inv, &self.inv),
+        }
+    }
+}
+
+// Any hittable placed by an affine transform. Rays are taken into the
+// object's space without normalizing, so t is the same on both sides.
+pub struct Transformed {
+    ptr: Arc<dyn Hittable>,
+    t: Transform,
+    mybox: Option<Aabb>,
+}
+#[allow(dead_code)]
+impl Transformed {
+    pub fn new(ptr: Arc<dyn Hittable>, t: Transform) -> Self {
+        let mybox = ptr.bounding_box(0.0, 1.0).map(|b| {
+            let mut mn = Vec3::new(INF, INF, INF);
+            let mut mx = Vec3::new(-INF, -INF, -INF);
+            for k in 0..8 {
+                let corner = Vec3::new(
+                    if k & 1 == 0 { b.mn.x } else { b.mx.x },
+                    if k & 2 == 0 { b.mn.y } else { b.mx.y },
+                    if k & 4 == 0 { b.mn.z } else { b.mx.z },
+                );
+                let p = t.point(corner);
+                for c in 0..3 {
+                    *mn.get_mut(c) = mn.get(c).min(p.get(c));
+                    *mx.get_mut(c) = mx.get(c).max(p.get(c));
+                }
+            }
+            Aabb::new(mn, mx)
+        });
+        Self { ptr, t, mybox }
+    }
+}
+impl Hittable for Transformed {
+    fn hit(&self, this_ray: &Ray, tmn: f64, tmx: f64) -> Option<HitRecord> {
+        let inv = self.t.inverse();
+        let local = Ray::new(
+            inv.point(this_ray.ori),
+            inv.vector(this_ray.dir),
+            this_ray.tm,
+        );
+        let mut rec = self.ptr.hit(&local, tmn, tmx)?;
+        // the inverse transpose keeps which side of the surface the ray is on
+        rec.p = self.t.point(rec.p);
+        rec.nor = self.t.normal(rec.nor).unit();
+        rec.geo_nor = self.t.normal(rec.geo_nor).unit();
+        rec.dpdu = self.t.vector(rec.dpdu);
+        rec.dpdv = self.t.vector(rec.dpdv);
+        rec.dndu = self.t.normal(rec.dndu);
+        rec.dndv = self.t.normal(rec.dndv);
+        Option::Some(rec)
+    }
+    fn bounding_box(&self, _t0: f64, _t1: f64) -> Option<Aabb> {
+        self.mybox.clone()
+    }
+    // Sampled in the local frame. A unit direction d there becomes M d in
+    // the world, which stretches solid angle by |det M| / |M d|^3.
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+        let inv = self.t.inverse();
+        let d = inv.vector(v).unit();
+        let (x, y, z) = (
+            self.t.vector(Vec3::new(1.0, 0.0, 0.0)),
+            self.t.vector(Vec3::new(0.0, 1.0, 0.0)),
+            self.t.vector(Vec3::new(0.0, 0.0, 1.0)),
+        );
+        let det = (x * Vec3::cross(y, z)).abs();
+        self.ptr.pdf_value(inv.point(o), d) * self.t.vector(d).length().powi(3) / det
+    }
+    fn random(&self, o: Vec3) -> Vec3 {
+        let inv = self.t.inverse();
+        self.t.vector(self.ptr.random(inv.point(o)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hit::tests::check_light_sampling;
+    use crate::hit::{Sphere, XyRect};
+    use crate::material::NOMaterial;
+
+    #[test]
+    fn test_compose_and_invert() {
+        let t = Transform::translate(Vec3::new(1.0, 2.0, 3.0))
+            * Transform::rotate(Vec3::new(1.0, 1.0, 0.0), 30.0)
+            * Transform::scale(Vec3::new(2.0, -1.0, 0.5));
+        let general = Transform::from_matrix(t.m).unwrap();
+        let p = Vec3::new(0.3, -0.7, 2.0);
+        let back = general.inverse().point(t.point(p));
+        assert!((back - p).length() < 1e-12);
+        assert!(t.swaps_handedness());
+        // normals stay perpendicular to transformed tangents
+        let (tan, n) = (Vec3::new(1.0, 1.0, 0.0), Vec3::new(1.0, -1.0, 2.0));
+        assert!((t.vector(tan) * t.normal(n)).abs() < 1e-12);
+        let q = Transform::from_quaternion([0.0, 0.0, (0.5f64).sqrt(), (0.5f64).sqrt()]);
+        let r = Transform::rotate(Vec3::new(0.0, 0.0, 1.0), 90.0);
+        assert!((q.point(p) - r.point(p)).length() < 1e-12);
+    }
+
+    #[test]
+    fn test_transformed_hit() {
+        // x^2 / 4 + 4 y^2 + z^2 = 1 around (1, 0, 0)
+        let sphere = Arc::new(Sphere::new(Vec3::zero(), 1.0, Arc::new(NOMaterial {})));
+        let t = Transform::translate(Vec3::new(1.0, 0.0, 0.0))
+            * Transform::scale(Vec3::new(2.0, 0.5, 1.0));
+        let ellipsoid = Transformed::new(sphere.clone(), t);
+        let b = ellipsoid.bounding_box(0.0, 1.0).unwrap();
+        assert!((b.mn - Vec3::new(-1.0, -0.5, -1.0)).length() < 1e-12);
+        assert!((b.mx - Vec3::new(3.0, 0.5, 1.0)).length() < 1e-12);
+        // t is measured along the world ray as given
+        let this_ray = Ray::new(Vec3::new(1.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 2.0), 0.0);
+        let rec = ellipsoid.hit(&this_ray, 0.001, INF).unwrap();
+        assert!((rec.t - 2.0).abs() < 1e-9);
+        assert!((rec.nor - Vec3::new(0.0, 0.0, -1.0)).length() < 1e-9);
+        // off the axes the normal is the gradient, not the scaled normal
+        let this_ray = Ray::new(Vec3::new(-5.0, 0.25, 0.0), Vec3::new(1.0, 0.0, 0.0), 0.0);
+        let rec = ellipsoid.hit(&this_ray, 0.001, INF).unwrap();
+        let x = 1.0 - 3f64.sqrt();
+        assert!((rec.p - Vec3::new(x, 0.25, 0.0)).length() < 1e-9);
+        let gradient = Vec3::new((x - 1.0) / 4.0, 4.0 * 0.25, 0.0).unit();
+        assert!((rec.nor - gradient).length() < 1e-9 && rec.nor_dir);
+        assert!((rec.dpdu * rec.nor).abs() < 1e-9 && (rec.dpdv * rec.nor).abs() < 1e-9);
+        // a mirror keeps the ray on the outside
+        let mirrored = Transformed::new(sphere, Transform::scale(Vec3::new(-1.0, 1.0, 1.0)));
+        let this_ray = Ray::new(Vec3::new(-5.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), 0.0);
+        let rec = mirrored.hit(&this_ray, 0.001, INF).unwrap();
+        assert!(rec.nor_dir && (rec.nor - Vec3::new(-1.0, 0.0, 0.0)).length() < 1e-9);
+    }
+
+    #[test]
+    fn test_transformed_light_sampling() {
+        // a sheared and turned square light
+        let rect = Arc::new(XyRect::new(
+            -1.0,
+            1.0,
+            -1.0,
+            1.0,
+            0.0,
+            Arc::new(NOMaterial {}),
+        ));
+        let mut shear = IDENTITY;
+        shear[0][1] = 0.7;
+        let t = Transform::translate(Vec3::new(0.5, 0.0, 1.0))
+            * Transform::rotate(Vec3::new(0.0, 1.0, 1.0), 35.0)
+            * Transform::from_matrix(shear).unwrap();
+        check_light_sampling(&Transformed::new(rect, t), Vec3::new(0.2, -0.3, -3.0));
+    }
+}
@@ -231,6 +231,9 @@ impl Vec3 {
             z: self.z / len,
         }
     }
+    pub fn luminance(&self) -> f64 {
+        0.2126 * self.x + 0.7152 * self.y + 0.0722 * self.z
+    }
     pub fn change(&self, other: Self) -> Self {
         Self {
             x: self.x * other.x,
@@ -361,13 +364,13 @@ mod tests {
 
     #[test]
     fn test_squared_length() {
-        assert_eq!(Vec3::new(1.0, 2.0, 3.0).squared_length(), 14.0 as f64);
+        assert_eq!(Vec3::new(1.0, 2.0, 3.0).squared_length(), 14.0_f64);
     }
     #[test]
     fn test_length() {
         assert_eq!(
             Vec3::new(3.0, 4.0, 5.0).length(),
-            ((3.0 * 3.0 + 4.0 * 4.0 + 5.0 * 5.0) as f64).sqrt()
+            (3.0_f64 * 3.0 + 4.0 * 4.0 + 5.0 * 5.0).sqrt()
         );
     }
     #[test]